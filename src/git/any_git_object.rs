@@ -3,14 +3,17 @@ use crate::{
         commits::Commit,
         compression::decompress,
         file_tree::FileTree,
+        filter::Filters,
         git_blob::Blob,
         git_object_trait::{GitObject, GitObjectType},
-        git_tree::Tree,
+        git_tag::Tag,
+        git_tree::{FileMode, Tree},
     },
-    utils::helpers::{from_utf8_with_context, get_object_file_path, parse_with_context},
+    utils::helpers::parse_with_context,
 };
-use anyhow::{anyhow, Context, Ok, Result};
-use std::{fs, path::Path};
+use anyhow::{anyhow, bail, Context, Ok, Result};
+use flate2::Compression;
+use std::{fs, path::Path, str::FromStr};
 use strum::EnumTryAs;
 
 #[derive(EnumTryAs, Debug, Clone)]
@@ -18,6 +21,7 @@ pub enum AnyGitObject {
     Blob(Blob),
     Tree(Tree),
     Commit(Commit),
+    Tag(Tag),
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -28,9 +32,9 @@ impl From<[u8; 20]> for Sha {
         Self(value)
     }
 }
-impl Into<[u8; 20]> for Sha {
-    fn into(self) -> [u8; 20] {
-        self.0
+impl From<Sha> for [u8; 20] {
+    fn from(val: Sha) -> Self {
+        val.0
     }
 }
 impl AsRef<[u8]> for Sha {
@@ -40,21 +44,33 @@ impl AsRef<[u8]> for Sha {
 }
 impl std::fmt::Display for Sha {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(&self.0))
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+impl FromStr for Sha {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Sha(hex::decode(s)
+            .with_context(|| format!("failed to decode object id {s:?}"))?
+            .try_into()
+            .map_err(|vec: Vec<_>| {
+                anyhow!("expected object id to have 20 bytes, got {}", vec.len())
+            })?))
     }
 }
 impl std::fmt::Debug for Sha {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Sha").field(&hex::encode(&self.0)).finish()
+        f.debug_tuple("Sha").field(&hex::encode(self.0)).finish()
     }
 }
 
 impl AnyGitObject {
-    pub fn read<P: AsRef<Path>>(sha: &str, path: P) -> Result<Self> {
-        let path = get_object_file_path(&sha, path);
-
+    // a loose object from its file in the objects directory
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let raw_content =
-            fs::read(&path).with_context(|| format!("failed to read object file at {path:?}"))?;
+            fs::read(path).with_context(|| format!("failed to read object file at {path:?}"))?;
 
         AnyGitObject::decode(raw_content)
             .with_context(|| format!("failed to parse object file content for {path:?}"))
@@ -65,6 +81,7 @@ impl AnyGitObject {
             Self::Blob(blob) => blob.encode_body(),
             Self::Tree(tree) => tree.encode_body(),
             Self::Commit(commit) => commit.encode_body(),
+            Self::Tag(tag) => tag.encode_body(),
         }
     }
 
@@ -73,6 +90,16 @@ impl AnyGitObject {
             Self::Blob(blob) => blob.write(path),
             Self::Tree(tree) => tree.write(path),
             Self::Commit(commit) => commit.write(path),
+            Self::Tag(tag) => tag.write(path),
+        }
+    }
+
+    pub fn write_with_level<P: AsRef<Path>>(&self, path: &P, level: Compression) -> Result<()> {
+        match self {
+            Self::Blob(blob) => blob.write_with_level(path, level),
+            Self::Tree(tree) => tree.write_with_level(path, level),
+            Self::Commit(commit) => commit.write_with_level(path, level),
+            Self::Tag(tag) => tag.write_with_level(path, level),
         }
     }
 
@@ -81,21 +108,52 @@ impl AnyGitObject {
             Self::Blob(blob) => blob.sha1(),
             Self::Tree(tree) => tree.sha1(),
             Self::Commit(commit) => commit.sha1(),
+            Self::Tag(tag) => tag.sha1(),
+        }
+    }
+
+    // the objects this one points at in the same repository. a submodule's commit lives in the
+    // submodule, so gitlink entries aren't among them
+    pub fn referenced_objects(&self) -> Vec<Sha> {
+        match self {
+            Self::Blob(_) => vec![],
+            Self::Tree(tree) => tree
+                .entries()
+                .iter()
+                .filter(|entry| !matches!(entry.mode, FileMode::Gitlink))
+                .map(|entry| entry.hash.clone())
+                .collect(),
+            Self::Commit(commit) => std::iter::once(commit.tree_hash.clone())
+                .chain(commit.parent_hash.iter().cloned())
+                .collect(),
+            Self::Tag(tag) => vec![tag.object_hash.clone()],
         }
     }
 
-    pub fn generate<P: AsRef<Path>>(path: P) -> Result<Self> {
+    // the blob of a file or the tree of a directory. with `filters`, content is stored as the
+    // clean filter makes it, looked up by the work tree path given along with them
+    pub fn generate<P: AsRef<Path>>(path: P, filters: Option<(&Filters, &str)>) -> Result<Self> {
         let path = path.as_ref();
 
         if path.is_file() {
             let content =
                 fs::read(path).with_context(|| format!("failed to read file at {path:?}"))?;
+            let content = match filters {
+                Some((filters, name)) => filters
+                    .clean(name, content)
+                    .with_context(|| format!("failed to filter {path:?} as {name}"))?,
+                None => content,
+            };
             Ok(Self::Blob(Blob::new(content)))
         } else if path.is_dir() {
             let file_tree = FileTree::new(path)?;
-            Ok(Self::Tree(file_tree.tree_object().with_context(|| {
-                format!("failed to generate tree object from {path:?}")
-            })?))
+            let (file_tree, filters) = match filters {
+                Some((filters, name)) => (file_tree.with_prefix(name), Some(filters)),
+                None => (file_tree, None),
+            };
+            Ok(Self::Tree(file_tree.tree_object(filters).with_context(
+                || format!("failed to generate tree object from {path:?}"),
+            )?))
         } else {
             Err(anyhow!(
                 "failed to generate git object: unsupported file type at {path:?}"
@@ -105,16 +163,34 @@ impl AnyGitObject {
 
     fn decode(raw_content: Vec<u8>) -> Result<Self> {
         let decompressed_content =
-            decompress(raw_content).with_context(|| format!("failed to decompress object file"))?;
+            decompress(raw_content).with_context(|| "failed to decompress object file")?;
+
+        let (object_type_str, content_size, content) = Self::split_header(&decompressed_content)?;
+
+        let object_type = parse_with_context(object_type_str)
+            .with_context(|| "failed to decode git object: failed to decode object type")?;
+
+        if content.len() != content_size {
+            bail!(
+                "failed to decode git object: header declares {content_size} bytes, but the body has {}",
+                content.len()
+            );
+        }
 
+        Self::from_body(object_type, content.to_vec())
+    }
+
+    // splits a decompressed object into its type, declared size and body, without requiring the
+    // type to be one we know how to decode
+    pub fn split_header(decompressed_content: &[u8]) -> Result<(&str, usize, &[u8])> {
         let [header_bytes, content]: [&[_]; 2] = decompressed_content
             .splitn(2, |b| b == &b'\0')
             .collect::<Vec<_>>()
             .try_into()
             .map_err(|_| anyhow!("invalid object file: expected it to contain {:?}", "\0"))?;
 
-        let header_str = from_utf8_with_context(header_bytes.to_vec())
-            .with_context(|| format!("failed to parse object file header"))?;
+        let header_str = std::str::from_utf8(header_bytes)
+            .with_context(|| "failed to parse object file header")?;
 
         let [object_type_str, content_size_str]: [&str; 2] = header_str
             .splitn(2, ' ')
@@ -128,21 +204,27 @@ impl AnyGitObject {
                 )
             })?;
 
-        let object_type = parse_with_context(object_type_str).with_context(|| {
-            format!("failed to decode git object: failed to decode object type")
-        })?;
+        let content_size = parse_with_context(content_size_str)
+            .with_context(|| "failed to decode git object: failed to decode content size")?;
 
-        let content_size = parse_with_context(content_size_str).with_context(|| {
-            format!("failed to decode git object: failed to decode content size")
-        })?;
+        Ok((object_type_str, content_size, content))
+    }
 
-        assert_eq!(content.len(), content_size);
+    pub fn get_type(&self) -> GitObjectType {
+        match self {
+            Self::Blob(_) => GitObjectType::Blob,
+            Self::Tree(_) => GitObjectType::Tree,
+            Self::Commit(_) => GitObjectType::Commit,
+            Self::Tag(_) => GitObjectType::Tag,
+        }
+    }
 
-        let content = content.to_vec();
+    pub fn from_body(object_type: GitObjectType, content: Vec<u8>) -> Result<Self> {
         match object_type {
-            GitObjectType::Blob => Ok(Self::Blob(Blob::decode_body(content.to_vec())?)),
-            GitObjectType::Tree => Ok(Self::Tree(Tree::decode_body(content.to_vec())?)),
-            GitObjectType::Commit => Ok(Self::Commit(Commit::decode_body(content.to_vec())?)),
+            GitObjectType::Blob => Ok(Self::Blob(Blob::decode_body(content)?)),
+            GitObjectType::Tree => Ok(Self::Tree(Tree::decode_body(content)?)),
+            GitObjectType::Commit => Ok(Self::Commit(Commit::decode_body(content)?)),
+            GitObjectType::Tag => Ok(Self::Tag(Tag::decode_body(content)?)),
         }
     }
 }
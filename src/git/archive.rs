@@ -0,0 +1,813 @@
+use crate::git::{any_git_object::Sha, git_tree::FileMode, object_store::ObjectStore};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::{write::DeflateEncoder, write::GzEncoder, Compression, Crc};
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+};
+use strum::EnumString;
+
+// tar works in blocks of this many bytes, headers take one and contents are padded to them
+const BLOCK_SIZE: u64 = 512;
+// the archive as a whole is padded to 20 blocks, like git and tar do
+const RECORD_SIZE: u64 = 20 * BLOCK_SIZE;
+// git's default tar.umask, taken off every entry's permissions
+const TAR_UMASK: u32 = 0o002;
+// sizes above what the 11 octal digits of a ustar header hold go in a pax header instead
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+const USTAR_NAME_SIZE: usize = 100;
+const USTAR_PREFIX_SIZE: usize = 155;
+
+// zip's field sizes without the zip64 extensions, which aren't written
+const ZIP_MAX_SIZE: u64 = u32::MAX as u64;
+const ZIP_MAX_ENTRIES: usize = u16::MAX as usize;
+// the zip spec version an entry needs to be extracted, 2.0 for deflate and 1.0 otherwise
+const ZIP_VERSION_DEFLATE: u16 = 20;
+const ZIP_VERSION_STORED: u16 = 10;
+// "made by" unix with spec 2.3, which tells readers the external attributes hold a unix mode
+const ZIP_CREATOR_UNIX: u16 = 0x0317;
+// general purpose flags: sizes and crc follow the data, and the name is UTF-8
+const ZIP_FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+// the MS-DOS directory attribute
+const ZIP_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+// the extended timestamp field, holding the modification time as unix seconds
+const ZIP_EXTRA_MTIME: u16 = 0x5455;
+
+// the formats `archive --format` takes, the names being git's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+pub enum ArchiveFormat {
+    #[strum(serialize = "tar")]
+    Tar,
+    #[strum(serialize = "tar.gz", serialize = "tgz")]
+    TarGz,
+    #[strum(serialize = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    // the format an output file's name asks for, like git picks it for `-o`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        [
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar", Self::Tar),
+            (".zip", Self::Zip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| name.ends_with(extension))
+        .map(|(_, format)| format)
+    }
+}
+
+// the archive of `tree` in `format`, its entries dated `mtime` and their paths starting with
+// `prefix`. `commit` is recorded in the archive where git puts it: a pax header for tar, the
+// comment for zip. `level` applies to the gzip stream and to every deflated zip entry, 0 stores
+// zip entries as they are
+#[allow(clippy::too_many_arguments)]
+pub fn write_archive<W: Write>(
+    out: W,
+    store: &ObjectStore,
+    tree: &Sha,
+    prefix: &str,
+    commit: Option<&Sha>,
+    mtime: u64,
+    format: ArchiveFormat,
+    level: Compression,
+) -> Result<()> {
+    match format {
+        ArchiveFormat::Tar => {
+            let mut tar = TarWriter::new(out, mtime, commit)?;
+            walk_prefixed_entries(store, tree, prefix, &mut |path, mode, sha| {
+                tar.write_entry(store, path, mode, sha)
+            })?;
+            tar.finish()?;
+        }
+        // like `gzip -cn`, which git runs for it: no file name and no time in the gzip header
+        ArchiveFormat::TarGz => {
+            let mut tar = TarWriter::new(GzEncoder::new(out, level), mtime, commit)?;
+            walk_prefixed_entries(store, tree, prefix, &mut |path, mode, sha| {
+                tar.write_entry(store, path, mode, sha)
+            })?;
+            tar.finish()?
+                .finish()
+                .and_then(|mut out| out.flush())
+                .with_context(|| "write_archive: failed to finish the gzip stream")?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new(out, mtime, level);
+            walk_prefixed_entries(store, tree, prefix, &mut |path, mode, sha| {
+                zip.write_entry(store, path, mode, sha)
+            })?;
+            zip.finish(commit)?;
+        }
+    }
+    Ok(())
+}
+
+// `git archive --format=tar`: the tree's entries as a ustar archive with the pax extensions git
+// uses. each blob is streamed into the output as its entry is written, so nothing but the entry
+// at hand is held in memory
+pub struct TarWriter<W: Write> {
+    out: W,
+    mtime: u64,
+    // bytes written so far, the trailer pads the archive to a whole record
+    written: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    // an archive whose entries carry `mtime`. for an archive of a commit git stores its id in a
+    // global pax header, where `git get-tar-commit-id` finds it
+    pub fn new(out: W, mtime: u64, commit: Option<&Sha>) -> Result<Self> {
+        let mut writer = Self {
+            out,
+            mtime,
+            written: 0,
+        };
+        if let Some(commit) = commit {
+            let records = pax_record("comment", commit.to_string().as_bytes());
+            let header = writer.header(
+                b"pax_global_header",
+                b"",
+                0o100666,
+                records.len() as u64,
+                b'g',
+                b"",
+            );
+            writer.write_blocked(&header)?;
+            writer.write_blocked(&records)?;
+        }
+        Ok(writer)
+    }
+
+    // the entry for `path`, with a trailing `/` for directories. `sha` names the blob of a file or
+    // symlink, and only identifies a directory in the names of pax headers
+    pub fn write_entry(
+        &mut self,
+        store: &ObjectStore,
+        path: &str,
+        mode: &FileMode,
+        sha: &Sha,
+    ) -> Result<()> {
+        let (typeflag, permissions) = match mode {
+            FileMode::Directory | FileMode::Gitlink => (b'5', 0o777 & !TAR_UMASK),
+            FileMode::Symbolic => (b'2', 0o777),
+            FileMode::Executable => (b'0', 0o777 & !TAR_UMASK),
+            FileMode::Regular => (b'0', 0o666 & !TAR_UMASK),
+        };
+
+        let (size, mut content) = match mode {
+            FileMode::Directory | FileMode::Gitlink => (0, None),
+            _ => {
+                let (size, content) = store
+                    .open_blob(sha)
+                    .with_context(|| format!("TarWriter::write_entry: failed to read {path}"))?;
+                (size, Some(content))
+            }
+        };
+
+        let mut extended = vec![];
+        let path = path.as_bytes();
+        // a path too long for the name field goes partly into the prefix field, or whole into a
+        // pax header when there's no `/` to split it at
+        let (prefix, name) = match split_path(path) {
+            _ if path.len() <= USTAR_NAME_SIZE => (vec![], path.to_vec()),
+            Some((prefix, name)) => (prefix.to_vec(), name.to_vec()),
+            None => {
+                extended.extend(pax_record("path", path));
+                (vec![], format!("{sha}.data").into_bytes())
+            }
+        };
+
+        let mut linkname = vec![];
+        if let (FileMode::Symbolic, Some(content)) = (mode, content.as_mut()) {
+            content
+                .read_to_end(&mut linkname)
+                .with_context(|| "TarWriter::write_entry: failed to read symlink target")?;
+            if linkname.len() > USTAR_NAME_SIZE {
+                extended.extend(pax_record("linkpath", &linkname));
+                linkname = format!("see {sha}.paxheader").into_bytes();
+            }
+        }
+
+        let mut header_size = match mode {
+            FileMode::Regular | FileMode::Executable => size,
+            _ => 0,
+        };
+        if header_size > USTAR_MAX_SIZE {
+            extended.extend(pax_record("size", size.to_string().as_bytes()));
+            header_size = 0;
+        }
+
+        if !extended.is_empty() {
+            let header = self.header(
+                format!("{sha}.paxheader").as_bytes(),
+                b"",
+                0o100666,
+                extended.len() as u64,
+                b'x',
+                b"",
+            );
+            self.write_blocked(&header)?;
+            self.write_blocked(&extended)?;
+        }
+        let header = self.header(
+            &name,
+            &prefix,
+            permissions,
+            header_size,
+            typeflag,
+            &linkname,
+        );
+        self.write_blocked(&header)?;
+
+        if let (FileMode::Regular | FileMode::Executable, Some(content)) = (mode, content) {
+            let copied = io::copy(&mut content.take(size), &mut self.out)
+                .with_context(|| "TarWriter::write_entry: failed to write file content")?;
+            if copied != size {
+                bail!("TarWriter::write_entry: blob {sha} ended after {copied} of {size} bytes");
+            }
+            self.written += copied;
+            self.pad_to(BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    // the end of the archive: at least two empty blocks, up to the end of a record
+    pub fn finish(mut self) -> Result<W> {
+        self.write_zeros(2 * BLOCK_SIZE)?;
+        self.pad_to(RECORD_SIZE)?;
+        self.out
+            .flush()
+            .with_context(|| "TarWriter::finish: failed to flush the archive")?;
+        Ok(self.out)
+    }
+
+    fn header(
+        &self,
+        name: &[u8],
+        prefix: &[u8],
+        mode: u32,
+        size: u64,
+        typeflag: u8,
+        linkname: &[u8],
+    ) -> [u8; BLOCK_SIZE as usize] {
+        let mut header = [0; BLOCK_SIZE as usize];
+        let mut field = |offset: usize, value: &[u8]| {
+            header[offset..offset + value.len()].copy_from_slice(value);
+        };
+        field(0, name);
+        field(100, format!("{:07o}", mode & 0o7777).as_bytes());
+        // uid and gid
+        field(108, b"0000000");
+        field(116, b"0000000");
+        field(124, format!("{size:011o}").as_bytes());
+        field(136, format!("{:011o}", self.mtime).as_bytes());
+        field(156, &[typeflag]);
+        field(157, linkname);
+        field(257, b"ustar\0");
+        field(263, b"00");
+        field(265, b"root");
+        field(297, b"root");
+        // device numbers
+        field(329, b"0000000");
+        field(337, b"0000000");
+        field(345, prefix);
+
+        // summed with the checksum field itself taken as spaces
+        let checksum = header[..148].iter().map(|b| *b as u32).sum::<u32>()
+            + 8 * b' ' as u32
+            + header[156..].iter().map(|b| *b as u32).sum::<u32>();
+        header[148..156].copy_from_slice(format!("{checksum:07o}\0").as_bytes());
+        header
+    }
+
+    // `data` followed by the zeros up to the next block
+    fn write_blocked(&mut self, data: &[u8]) -> Result<()> {
+        self.out
+            .write_all(data)
+            .with_context(|| "TarWriter: failed to write the archive")?;
+        self.written += data.len() as u64;
+        self.pad_to(BLOCK_SIZE)
+    }
+
+    fn pad_to(&mut self, size: u64) -> Result<()> {
+        let padding = (size - self.written % size) % size;
+        self.write_zeros(padding)
+    }
+
+    fn write_zeros(&mut self, count: u64) -> Result<()> {
+        io::copy(&mut io::repeat(0).take(count), &mut self.out)
+            .with_context(|| "TarWriter: failed to write the archive")?;
+        self.written += count;
+        Ok(())
+    }
+}
+
+// the entries of `tree` below `prefix`. like git, a prefix naming a directory (ending in `/`) gets
+// an entry of its own first, for the root tree, while any other prefix is just prepended to the
+// names
+fn walk_prefixed_entries<F>(
+    store: &ObjectStore,
+    tree: &Sha,
+    prefix: &str,
+    visit: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&str, &FileMode, &Sha) -> Result<()>,
+{
+    if prefix.ends_with('/') {
+        visit(prefix, &FileMode::Directory, tree)?;
+    }
+    walk_archive_entries(store, tree, prefix, visit)
+}
+
+// every entry below `tree` in the order git archives them, a directory (with a trailing `/`)
+// right before what's in it
+pub fn walk_archive_entries<F>(
+    store: &ObjectStore,
+    tree: &Sha,
+    base: &str,
+    visit: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&str, &FileMode, &Sha) -> Result<()>,
+{
+    let tree = store
+        .read(tree)
+        .with_context(|| format!("walk_archive_entries: failed to read tree {tree}"))?
+        .try_as_tree()
+        .ok_or_else(|| anyhow!("walk_archive_entries: expected {tree} to be a tree"))?;
+
+    for entry in tree.entries() {
+        match entry.mode {
+            FileMode::Directory => {
+                let path = format!("{base}{}/", entry.name);
+                visit(&path, &entry.mode, &entry.hash)?;
+                walk_archive_entries(store, &entry.hash, &path, visit)?;
+            }
+            // a submodule's content isn't part of the repository, it shows up as an empty
+            // directory
+            FileMode::Gitlink => {
+                visit(&format!("{base}{}/", entry.name), &entry.mode, &entry.hash)?
+            }
+            _ => visit(&format!("{base}{}", entry.name), &entry.mode, &entry.hash)?,
+        }
+    }
+    Ok(())
+}
+
+// a long path split at a `/` into the ustar prefix and name fields, when there's a split that
+// fits, the way git's get_path_prefix picks it
+fn split_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut index = path.len();
+    if index > 1 && path[index - 1] == b'/' {
+        index -= 1;
+    }
+    index = index.min(USTAR_PREFIX_SIZE);
+    loop {
+        index -= 1;
+        if index == 0 || path[index] == b'/' {
+            break;
+        }
+    }
+    let rest = path.len() - index - 1;
+    (index > 0 && rest <= USTAR_NAME_SIZE).then(|| (&path[..index], &path[index + 1..]))
+}
+
+// `<length> <keyword>=<value>\n`, where the length counts its own digits
+fn pax_record(keyword: &str, value: &[u8]) -> Vec<u8> {
+    let mut length = 1 + 1 + keyword.len() + 1 + value.len() + 1;
+    let mut digits = 1;
+    while length / 10 >= digits {
+        length += 1;
+        digits *= 10;
+    }
+    let mut record = format!("{length} {keyword}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+// `git archive --format=zip`: a file's blob is streamed through the compressor into the output,
+// its crc and sizes following in a data descriptor, and the central directory listing every
+// entry is written at the end. modes are kept the way git does: executables and symlinks carry
+// their unix mode in the external attributes, directories the MS-DOS directory attribute
+pub struct ZipWriter<W: Write> {
+    out: W,
+    level: Compression,
+    time: u16,
+    date: u16,
+    mtime: u32,
+    // bytes written so far, where the next local header starts
+    written: u64,
+    // the central directory's entries, written by finish
+    directory: Vec<u8>,
+    entries: usize,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(out: W, mtime: u64, level: Compression) -> Self {
+        let (date, time) = dos_date_time(mtime);
+        Self {
+            out,
+            level,
+            time,
+            date,
+            mtime: mtime.min(u32::MAX as u64) as u32,
+            written: 0,
+            directory: vec![],
+            entries: 0,
+        }
+    }
+
+    // the entry for `path`, with a trailing `/` for directories
+    pub fn write_entry(
+        &mut self,
+        store: &ObjectStore,
+        path: &str,
+        mode: &FileMode,
+        sha: &Sha,
+    ) -> Result<()> {
+        if self.entries == ZIP_MAX_ENTRIES {
+            bail!("ZipWriter::write_entry: more than {ZIP_MAX_ENTRIES} entries need zip64");
+        }
+        let offset = self.written;
+        if offset > ZIP_MAX_SIZE {
+            bail!("ZipWriter::write_entry: archives over 4 GiB need zip64");
+        }
+
+        let (mut creator, attributes) = match mode {
+            FileMode::Directory | FileMode::Gitlink => (0, ZIP_ATTRIBUTE_DIRECTORY),
+            FileMode::Symbolic => (ZIP_CREATOR_UNIX, (mode.mode() | 0o777) << 16),
+            FileMode::Executable => (ZIP_CREATOR_UNIX, mode.mode() << 16),
+            FileMode::Regular => (0, 0),
+        };
+        let mut flags = if path.is_ascii() { 0 } else { ZIP_FLAG_UTF8 };
+        if flags & ZIP_FLAG_UTF8 != 0 {
+            creator = ZIP_CREATOR_UNIX;
+        }
+
+        let entry = match mode {
+            FileMode::Directory | FileMode::Gitlink => {
+                self.write_local_header(path, flags, ZIP_METHOD_STORED, 0, 0, 0)?;
+                (ZIP_METHOD_STORED, 0, 0, 0)
+            }
+            // link targets are short, they're read first so their header is complete
+            FileMode::Symbolic => {
+                let (_, mut content) = store
+                    .open_blob(sha)
+                    .with_context(|| format!("ZipWriter::write_entry: failed to read {path}"))?;
+                let mut target = vec![];
+                content
+                    .read_to_end(&mut target)
+                    .with_context(|| format!("ZipWriter::write_entry: failed to read {path}"))?;
+                let mut crc = Crc::new();
+                crc.update(&target);
+                let size = target.len() as u32;
+                self.write_local_header(path, flags, ZIP_METHOD_STORED, crc.sum(), size, size)?;
+                self.write(&target)?;
+                (ZIP_METHOD_STORED, crc.sum(), size, size)
+            }
+            FileMode::Regular | FileMode::Executable => {
+                let (size, content) = store
+                    .open_blob(sha)
+                    .with_context(|| format!("ZipWriter::write_entry: failed to read {path}"))?;
+                if size > ZIP_MAX_SIZE {
+                    bail!("ZipWriter::write_entry: {path} is over 4 GiB, which needs zip64");
+                }
+                let method = match (self.level.level(), size) {
+                    (_, 0) | (0, _) => ZIP_METHOD_STORED,
+                    _ => ZIP_METHOD_DEFLATE,
+                };
+                flags |= ZIP_FLAG_DATA_DESCRIPTOR;
+                self.write_local_header(path, flags, method, 0, 0, 0)?;
+
+                let mut content = CrcReader {
+                    inner: content.take(size),
+                    crc: Crc::new(),
+                };
+                let start = self.written;
+                let copied = match method {
+                    ZIP_METHOD_DEFLATE => {
+                        let level = self.level;
+                        let mut encoder = DeflateEncoder::new(&mut *self, level);
+                        let copied = io::copy(&mut content, &mut encoder);
+                        encoder.finish()?;
+                        copied
+                    }
+                    _ => io::copy(&mut content, &mut *self),
+                }
+                .with_context(|| format!("ZipWriter::write_entry: failed to write {path}"))?;
+                if copied != size {
+                    bail!(
+                        "ZipWriter::write_entry: blob {sha} ended after {copied} of {size} bytes"
+                    );
+                }
+                let compressed = self.written - start;
+                if compressed > ZIP_MAX_SIZE {
+                    bail!("ZipWriter::write_entry: {path} compressed is over 4 GiB, which needs zip64");
+                }
+
+                let crc = content.crc.sum();
+                let mut descriptor = vec![];
+                put_u32(&mut descriptor, 0x08074b50);
+                put_u32(&mut descriptor, crc);
+                put_u32(&mut descriptor, compressed as u32);
+                put_u32(&mut descriptor, size as u32);
+                self.write(&descriptor)?;
+                (method, crc, compressed as u32, size as u32)
+            }
+        };
+
+        let (method, crc, compressed, size) = entry;
+        let directory = &mut self.directory;
+        put_u32(directory, 0x02014b50);
+        put_u16(directory, creator);
+        put_u16(directory, zip_version(method));
+        put_u16(directory, flags);
+        put_u16(directory, method);
+        put_u16(directory, self.time);
+        put_u16(directory, self.date);
+        put_u32(directory, crc);
+        put_u32(directory, compressed);
+        put_u32(directory, size);
+        put_u16(directory, path.len() as u16);
+        put_u16(directory, 9);
+        // comment length, disk number and internal attributes
+        put_u16(directory, 0);
+        put_u16(directory, 0);
+        put_u16(directory, 0);
+        put_u32(directory, attributes);
+        put_u32(directory, offset as u32);
+        directory.extend_from_slice(path.as_bytes());
+        put_extra_mtime(directory, self.mtime);
+        self.entries += 1;
+        Ok(())
+    }
+
+    // the central directory and the record pointing at it, with `commit` as the archive's
+    // comment like git writes it
+    pub fn finish(mut self, commit: Option<&Sha>) -> Result<W> {
+        let offset = self.written;
+        let directory = std::mem::take(&mut self.directory);
+        if offset + directory.len() as u64 > ZIP_MAX_SIZE {
+            bail!("ZipWriter::finish: archives over 4 GiB need zip64");
+        }
+        self.write(&directory)?;
+
+        let comment = commit.map(Sha::to_string).unwrap_or_default();
+        let mut end = vec![];
+        put_u32(&mut end, 0x06054b50);
+        // this disk, and the one the directory starts on
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, self.entries as u16);
+        put_u16(&mut end, self.entries as u16);
+        put_u32(&mut end, directory.len() as u32);
+        put_u32(&mut end, offset as u32);
+        put_u16(&mut end, comment.len() as u16);
+        end.extend_from_slice(comment.as_bytes());
+        self.write(&end)?;
+
+        self.out
+            .flush()
+            .with_context(|| "ZipWriter::finish: failed to flush the archive")?;
+        Ok(self.out)
+    }
+
+    fn write_local_header(
+        &mut self,
+        path: &str,
+        flags: u16,
+        method: u16,
+        crc: u32,
+        compressed: u32,
+        size: u32,
+    ) -> Result<()> {
+        let mut header = vec![];
+        put_u32(&mut header, 0x04034b50);
+        put_u16(&mut header, zip_version(method));
+        put_u16(&mut header, flags);
+        put_u16(&mut header, method);
+        put_u16(&mut header, self.time);
+        put_u16(&mut header, self.date);
+        put_u32(&mut header, crc);
+        put_u32(&mut header, compressed);
+        put_u32(&mut header, size);
+        put_u16(&mut header, path.len() as u16);
+        put_u16(&mut header, 9);
+        header.extend_from_slice(path.as_bytes());
+        put_extra_mtime(&mut header, self.mtime);
+        self.write(&header)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.write_all(data)
+            .with_context(|| "ZipWriter: failed to write the archive")
+    }
+}
+
+// counts what goes through, so the compressed size and the offsets are known
+impl<W: Write> Write for ZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// a blob's content, with the crc of what was read so far
+struct CrcReader<R: Read> {
+    inner: R,
+    crc: Crc,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn zip_version(method: u16) -> u16 {
+    match method {
+        ZIP_METHOD_DEFLATE => ZIP_VERSION_DEFLATE,
+        _ => ZIP_VERSION_STORED,
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+// the extended timestamp field with just the modification time, 9 bytes in all
+fn put_extra_mtime(buffer: &mut Vec<u8>, mtime: u32) {
+    put_u16(buffer, ZIP_EXTRA_MTIME);
+    put_u16(buffer, 5);
+    // flags: the modification time is present
+    buffer.push(1);
+    put_u32(buffer, mtime);
+}
+
+// the MS-DOS date and time of the unix time `epoch` in UTC, with two second precision. dates
+// before 1980 can't be represented and are clamped to it
+fn dos_date_time(epoch: u64) -> (u16, u16) {
+    let days = epoch / 86400;
+    let seconds = epoch % 86400;
+
+    // days since 1970-01-01 to a civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let date = (((year - 1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((seconds / 3600) as u16) << 11
+        | ((seconds % 3600 / 60) as u16) << 5
+        | (seconds % 60 / 2) as u16;
+    (date, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{
+        git_blob::Blob,
+        git_object_trait::GitObject,
+        git_tree::{Tree, TreeEntry},
+    };
+    use tempfile::TempDir;
+
+    fn entry(mode: FileMode, name: &str, hash: Sha) -> TreeEntry {
+        TreeEntry {
+            mode,
+            name: name.to_owned(),
+            hash,
+        }
+    }
+
+    // a repository holding `a.txt` and `sub/b.txt`, and its root tree
+    fn fixture() -> Result<(TempDir, Sha)> {
+        let dir = tempfile::tempdir()?;
+        let level = Compression::default();
+        let a = Blob::new(b"a\n".to_vec());
+        let b = Blob::new(b"b\n".to_vec());
+        a.write_with_level(dir.path(), level)?;
+        b.write_with_level(dir.path(), level)?;
+        let sub = Tree::new(vec![entry(FileMode::Regular, "b.txt", b.sha1()?)]);
+        sub.write_with_level(dir.path(), level)?;
+        let root = Tree::new(vec![
+            entry(FileMode::Regular, "a.txt", a.sha1()?),
+            entry(FileMode::Directory, "sub", sub.sha1()?),
+        ]);
+        root.write_with_level(dir.path(), level)?;
+        Ok((dir, root.sha1()?))
+    }
+
+    fn archive(format: ArchiveFormat, prefix: &str) -> Result<Vec<u8>> {
+        let (dir, tree) = fixture()?;
+        let store = ObjectStore::new(dir.path());
+        let mut out = vec![];
+        write_archive(
+            &mut out,
+            &store,
+            &tree,
+            prefix,
+            None,
+            0,
+            format,
+            Compression::default(),
+        )?;
+        Ok(out)
+    }
+
+    // the entry names of a tar archive, its prefix and name fields joined
+    fn tar_paths(archive: &[u8]) -> Vec<String> {
+        let field = |block: &[u8]| {
+            let end = block.iter().position(|b| *b == 0).unwrap_or(block.len());
+            String::from_utf8_lossy(&block[..end]).into_owned()
+        };
+        let mut paths = vec![];
+        let mut offset = 0;
+        while offset + BLOCK_SIZE as usize <= archive.len() {
+            let header = &archive[offset..offset + BLOCK_SIZE as usize];
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let (name, prefix) = (field(&header[..100]), field(&header[345..500]));
+            paths.push(match prefix.is_empty() {
+                true => name,
+                false => format!("{prefix}/{name}"),
+            });
+            let size = u64::from_str_radix(&field(&header[124..135]), 8).unwrap();
+            offset += BLOCK_SIZE as usize * (1 + size.div_ceil(BLOCK_SIZE) as usize);
+        }
+        paths
+    }
+
+    // the entry names of a zip archive, read from its central directory
+    fn zip_paths(archive: &[u8]) -> Vec<String> {
+        let u16_at = |at: usize| u16::from_le_bytes([archive[at], archive[at + 1]]) as usize;
+        let u32_at =
+            |at: usize| u32::from_le_bytes(archive[at..at + 4].try_into().unwrap()) as usize;
+        // the end of central directory record, without a comment for an archive of a tree
+        let end = archive.len() - 22;
+        assert_eq!(&archive[end..end + 4], b"PK\x05\x06");
+        let mut offset = u32_at(end + 16);
+        let mut paths = vec![];
+        for _ in 0..u16_at(end + 10) {
+            assert_eq!(&archive[offset..offset + 4], b"PK\x01\x02");
+            let name_length = u16_at(offset + 28);
+            let name = &archive[offset + 46..offset + 46 + name_length];
+            paths.push(String::from_utf8_lossy(name).into_owned());
+            offset += 46 + name_length + u16_at(offset + 30) + u16_at(offset + 32);
+        }
+        paths
+    }
+
+    #[test]
+    fn a_directory_prefix_gets_its_own_entry() -> Result<()> {
+        let expected = ["dir/", "dir/a.txt", "dir/sub/", "dir/sub/b.txt"];
+        assert_eq!(tar_paths(&archive(ArchiveFormat::Tar, "dir/")?), expected);
+        assert_eq!(zip_paths(&archive(ArchiveFormat::Zip, "dir/")?), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn any_other_prefix_is_prepended_to_each_name() -> Result<()> {
+        let expected = ["pre-a.txt", "pre-sub/", "pre-sub/b.txt"];
+        assert_eq!(tar_paths(&archive(ArchiveFormat::Tar, "pre-")?), expected);
+        assert_eq!(zip_paths(&archive(ArchiveFormat::Zip, "pre-")?), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn no_prefix_archives_the_tree_as_it_is() -> Result<()> {
+        let expected = ["a.txt", "sub/", "sub/b.txt"];
+        assert_eq!(tar_paths(&archive(ArchiveFormat::Tar, "")?), expected);
+        assert_eq!(zip_paths(&archive(ArchiveFormat::Zip, "")?), expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{git::ignore::IgnorePattern, utils::helpers::common_dir};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+// the state an attribute is in for a path, with no rule naming it it's unspecified (None):
+// https://git-scm.com/docs/gitattributes#_description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    // `attr`
+    Set,
+    // `-attr`
+    Unset,
+    // `attr=value`
+    Value(String),
+}
+
+// a line of a gitattributes file, the pattern and what it does to each attribute it names.
+// `!attr` takes the attribute back to unspecified, kept as None
+#[derive(Debug, Clone)]
+struct AttributeRule {
+    pattern: IgnorePattern,
+    attributes: Vec<(String, Option<AttributeValue>)>,
+}
+
+// gitattributes rules from `.git/info/attributes` and the `.gitattributes` of every directory,
+// the latter loaded the first time a path below them is looked up, like IgnoreMatcher. the lazily
+// loaded parts are behind mutexes, write-tree looks attributes up from several threads
+#[derive(Debug)]
+pub struct AttributeMatcher {
+    root: PathBuf,
+    info: Vec<AttributeRule>,
+    // keyed by the directory relative to the root, "" being the root itself
+    per_directory: Mutex<HashMap<String, Vec<AttributeRule>>>,
+    // every attribute named so far, in the order git comes across them: the `binary` macro's,
+    // then those of the root `.gitattributes`, info/attributes and the other directories as
+    // they're loaded. `check-attr -a` lists attributes in this order
+    names: Mutex<Vec<String>>,
+}
+
+impl AttributeMatcher {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let info = read_rules(&common_dir(&root).join("info/attributes"), "")?;
+        Ok(Self {
+            root,
+            info,
+            per_directory: Mutex::new(HashMap::new()),
+            names: Mutex::new(
+                ["binary", "diff", "merge", "text"]
+                    .map(str::to_owned)
+                    .to_vec(),
+            ),
+        })
+    }
+
+    // the value of attribute `name` for the file at `path`, relative to the root. info/attributes
+    // beats the `.gitattributes` files, deeper files beat shallower ones and later lines earlier
+    // ones
+    pub fn get(&self, path: &str, name: &str) -> Result<Option<AttributeValue>> {
+        Ok(value_of(&self.rules(path)?, path, name))
+    }
+
+    // every attribute that's set, unset or has a value for `path`
+    pub fn get_all(&self, path: &str) -> Result<Vec<(String, AttributeValue)>> {
+        let rules = self.rules(path)?;
+        Ok(lock(&self.names)
+            .iter()
+            .filter_map(|name| Some((name.clone(), value_of(&rules, path, name)?)))
+            .collect())
+    }
+
+    // the rules that may apply to `path`, from the lowest priority to the highest
+    fn rules(&self, path: &str) -> Result<Vec<AttributeRule>> {
+        let mut rules = vec![];
+        let directories =
+            std::iter::once("").chain(path.match_indices('/').map(|(index, _)| &path[..index]));
+        for directory in directories {
+            rules.extend(self.directory_rules(directory)?);
+        }
+        rules.extend(self.info.iter().cloned());
+        Ok(rules)
+    }
+
+    fn directory_rules(&self, directory: &str) -> Result<Vec<AttributeRule>> {
+        if let Some(rules) = lock(&self.per_directory).get(directory) {
+            return Ok(rules.clone());
+        }
+
+        let rules = read_rules(&self.root.join(directory).join(".gitattributes"), directory)?;
+        self.register(&rules);
+        // git reads the root `.gitattributes` before info/attributes, which decides the order
+        // their attributes are listed in. the root is loaded on the first lookup, not up front, so
+        // a checkout can still write it after the matcher is made
+        if directory.is_empty() {
+            self.register(&self.info);
+        }
+        lock(&self.per_directory).insert(directory.to_owned(), rules.clone());
+        Ok(rules)
+    }
+
+    fn register(&self, rules: &[AttributeRule]) {
+        let mut names = lock(&self.names);
+        for (name, _) in rules.iter().flat_map(|rule| &rule.attributes) {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+}
+
+// the lock is only held for lookups and inserts that can't panic halfway, so a poisoned one still
+// holds consistent data
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// what the last of `rules` that matches `path` and names attribute `name` makes of it
+fn value_of(rules: &[AttributeRule], path: &str, name: &str) -> Option<AttributeValue> {
+    rules
+        .iter()
+        .rev()
+        .filter(|rule| rule.pattern.matches(path, false))
+        .find_map(|rule| {
+            rule.attributes
+                .iter()
+                .rev()
+                .find(|(attribute, _)| attribute == name)
+        })
+        .and_then(|(_, value)| value.clone())
+}
+
+fn read_rules(path: &Path, base: &str) -> Result<Vec<AttributeRule>> {
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("AttributeMatcher: failed to read {path:?}"))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| AttributeRule::parse(line, base))
+        .collect())
+}
+
+impl AttributeRule {
+    fn parse(line: &str, base: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let pattern = fields.next()?;
+        // git rejects negative patterns in attributes files, and `[attr]` lines define macros,
+        // which aren't supported
+        if pattern.starts_with('#') || pattern.starts_with('!') || pattern.starts_with("[attr]") {
+            return None;
+        }
+
+        let mut attributes = vec![];
+        for field in fields {
+            if let Some(name) = field.strip_prefix('-') {
+                attributes.push((name.to_owned(), Some(AttributeValue::Unset)));
+            } else if let Some(name) = field.strip_prefix('!') {
+                attributes.push((name.to_owned(), None));
+            } else if let Some((name, value)) = field.split_once('=') {
+                attributes.push((
+                    name.to_owned(),
+                    Some(AttributeValue::Value(value.to_owned())),
+                ));
+            } else if field == "binary" {
+                // the one built-in macro, a file git shouldn't treat as text
+                attributes.push(("binary".to_owned(), Some(AttributeValue::Set)));
+                for name in ["diff", "merge", "text"] {
+                    attributes.push((name.to_owned(), Some(AttributeValue::Unset)));
+                }
+            } else {
+                attributes.push((field.to_owned(), Some(AttributeValue::Set)));
+            }
+        }
+
+        Some(Self {
+            pattern: IgnorePattern::parse(pattern, base)?,
+            attributes,
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use crate::git::{
+    any_git_object::Sha,
+    commits::Commit,
+    diff::{matching_lines, split_lines},
+    git_tree::FileMode,
+    object_store::ObjectStore,
+};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BinaryHeap, HashMap};
+
+// a line of the blamed file with the commit that last changed it
+pub struct BlameLine {
+    pub commit: Sha,
+    pub content: Vec<u8>,
+}
+
+// the blob `path` names in each commit walked so far. history mostly passes a file on unchanged,
+// so comparing the cached blob ids spares reading and diffing the same content at every commit
+struct BlobCache<'a> {
+    store: &'a ObjectStore,
+    path: &'a str,
+    blobs: HashMap<Sha, Option<Sha>>,
+}
+
+impl BlobCache<'_> {
+    fn blob(&mut self, sha: &Sha, commit: &Commit) -> Result<Option<Sha>> {
+        if let Some(blob) = self.blobs.get(sha) {
+            return Ok(blob.clone());
+        }
+
+        let blob = find_blob(self.store, &commit.tree_hash, self.path).with_context(|| {
+            format!("BlobCache::blob: failed to look up {} in {sha}", self.path)
+        })?;
+        self.blobs.insert(sha.clone(), blob.clone());
+        Ok(blob)
+    }
+}
+
+// the commit every line of `path` at `start` comes from. lines are handed from a commit to the
+// first parent that has them too, newest commits first, until no parent has them; a parent with
+// the same blob takes all of them without a diff
+pub fn blame(store: &ObjectStore, start: &Sha, path: &str) -> Result<Vec<BlameLine>> {
+    let mut blobs = BlobCache {
+        store,
+        path,
+        blobs: HashMap::new(),
+    };
+    let mut commits = HashMap::new();
+    let start_commit = read_commit(store, start)?;
+    let blob = blobs
+        .blob(start, &start_commit)?
+        .ok_or_else(|| anyhow!("blame: no such path {path} in {start}"))?;
+    let blamed = read_blob(store, &blob)?;
+    let line_count = split_lines(&blamed).len();
+
+    // for every commit still suspected of lines: the lines as (index in the blamed file, index in
+    // the commit's version of it)
+    let mut suspects = HashMap::from([(start.clone(), (0..line_count).map(|i| (i, i)).collect())]);
+    let mut queue = BinaryHeap::from([(start_commit.committer().epoch, start.0)]);
+    commits.insert(start.clone(), start_commit);
+    let mut origins = vec![None; line_count];
+
+    while let Some((_, sha)) = queue.pop() {
+        let sha = Sha(sha);
+        let Some(mut lines): Option<Vec<(usize, usize)>> = suspects.remove(&sha) else {
+            continue;
+        };
+        let commit = commits[&sha].clone();
+        let blob = blobs
+            .blob(&sha, &commit)?
+            .ok_or_else(|| anyhow!("blame: {path} is missing from {sha}"))?;
+        let mut content = None;
+
+        for parent in &commit.parent_hash {
+            if lines.is_empty() {
+                break;
+            }
+            if !commits.contains_key(parent) {
+                commits.insert(parent.clone(), read_commit(store, parent)?);
+            }
+            let parent_commit = &commits[parent];
+            let Some(parent_blob) = blobs.blob(parent, parent_commit)? else {
+                continue;
+            };
+
+            let passed = if parent_blob == blob {
+                std::mem::take(&mut lines)
+            } else {
+                let content = match &content {
+                    Some(content) => content,
+                    None => content.insert(read_blob(store, &blob)?),
+                };
+                let parent_content = read_blob(store, &parent_blob)?;
+                let in_parent =
+                    matching_lines(&split_lines(&parent_content), &split_lines(content))
+                        .into_iter()
+                        .map(|(parent_index, index)| (index, parent_index))
+                        .collect::<HashMap<_, _>>();
+
+                let (passed, kept) = lines
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|(_, index)| in_parent.contains_key(index));
+                lines = kept;
+                passed
+                    .into_iter()
+                    .map(|(line, index)| (line, in_parent[&index]))
+                    .collect()
+            };
+
+            if !passed.is_empty() {
+                suspects
+                    .entry(parent.clone())
+                    .or_insert_with(Vec::new)
+                    .extend(passed);
+                queue.push((parent_commit.committer().epoch, parent.0));
+            }
+        }
+
+        for (line, _) in lines {
+            origins[line] = Some(sha.clone());
+        }
+    }
+
+    split_lines(&blamed)
+        .into_iter()
+        .zip(origins)
+        .map(|(line, origin)| {
+            Ok(BlameLine {
+                commit: origin.ok_or_else(|| anyhow!("blame: a line of {path} has no origin"))?,
+                content: line.to_vec(),
+            })
+        })
+        .collect()
+}
+
+// the blob at `/`-separated `path` below the tree, or None when there's no file there
+fn find_blob(store: &ObjectStore, tree: &Sha, path: &str) -> Result<Option<Sha>> {
+    let mut tree = tree.clone();
+    let mut components = path.split('/').peekable();
+    while let Some(component) = components.next() {
+        let entries = store
+            .read(&tree)?
+            .try_as_tree()
+            .ok_or_else(|| anyhow!("find_blob: expected {tree} to be a tree"))?;
+        let Some(entry) = entries
+            .entries()
+            .iter()
+            .find(|entry| entry.name == component)
+        else {
+            return Ok(None);
+        };
+        match (&entry.mode, components.peek()) {
+            (FileMode::Directory, Some(_)) => tree = entry.hash.clone(),
+            (FileMode::Directory | FileMode::Gitlink, None) | (_, Some(_)) => return Ok(None),
+            (_, None) => return Ok(Some(entry.hash.clone())),
+        }
+    }
+    Ok(None)
+}
+
+fn read_commit(store: &ObjectStore, sha: &Sha) -> Result<Commit> {
+    store
+        .read_commit(sha)
+        .with_context(|| format!("blame: failed to read commit {sha}"))
+}
+
+fn read_blob(store: &ObjectStore, sha: &Sha) -> Result<Vec<u8>> {
+    Ok(store
+        .read(sha)
+        .with_context(|| format!("blame: failed to read blob {sha}"))?
+        .try_as_blob()
+        .ok_or_else(|| anyhow!("blame: expected {sha} to be a blob"))?
+        .content()
+        .clone())
+}
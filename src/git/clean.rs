@@ -0,0 +1,175 @@
+use crate::git::{ignore::IgnoreMatcher, index::Index};
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// what `git clean` removes from the work tree at `root`: files the index doesn't track, and with
+// `directories` untracked directories too. ignored files are left alone unless `include_ignored`
+pub struct Cleaner {
+    root: PathBuf,
+    index: Index,
+    ignore: IgnoreMatcher,
+    directories: bool,
+    include_ignored: bool,
+}
+
+impl Cleaner {
+    pub fn new<P: AsRef<Path>>(root: P, directories: bool, include_ignored: bool) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        Ok(Self {
+            index: Index::read(&root).with_context(|| "Cleaner::new: failed to read the index")?,
+            ignore: IgnoreMatcher::new(&root)
+                .with_context(|| "Cleaner::new: failed to read ignore rules")?,
+            root,
+            directories,
+            include_ignored,
+        })
+    }
+
+    // the paths clean removes, relative to the root and sorted. directories removed as a whole
+    // end with `/`
+    pub fn untracked(&self) -> Result<Vec<String>> {
+        Ok(self.untracked_below("")?.0)
+    }
+
+    // removes a path untracked returned
+    pub fn remove(&self, name: &str) -> Result<()> {
+        match name.strip_suffix('/') {
+            Some(directory) => fs::remove_dir_all(self.root.join(directory)).with_context(|| {
+                format!("Cleaner::remove: failed to remove directory {directory}")
+            }),
+            None => fs::remove_file(self.root.join(name))
+                .with_context(|| format!("Cleaner::remove: failed to remove {name}")),
+        }
+    }
+
+    // untracked paths below `directory` (`""` for the root). the flag tells whether everything in
+    // `directory` is removable, so its parent can list it as one entry instead
+    fn untracked_below(&self, directory: &str) -> Result<(Vec<String>, bool)> {
+        let mut entries = fs::read_dir(self.root.join(directory))
+            .with_context(|| format!("Cleaner: failed to read directory {directory:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Cleaner: failed to read entry in {directory:?}"))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut untracked = vec![];
+        let mut all_removable = true;
+        for entry in entries {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name == ".git" {
+                all_removable = false;
+                continue;
+            }
+            let name = if directory.is_empty() {
+                file_name
+            } else {
+                format!("{directory}/{file_name}")
+            };
+            let is_dir = entry.file_type()?.is_dir();
+
+            if !self.include_ignored && self.ignore.is_ignored(&name, is_dir)? {
+                all_removable = false;
+            } else if !is_dir {
+                if self.index.contains(&name) {
+                    all_removable = false;
+                } else {
+                    untracked.push(name);
+                }
+            } else if self.index.contains_directory(&name) {
+                all_removable = false;
+                untracked.extend(self.untracked_below(&name)?.0);
+            } else if self.directories {
+                // like git, untracked directories are only touched with -d
+                match self.untracked_below(&name)? {
+                    (_, true) => untracked.push(format!("{name}/")),
+                    (nested, false) => {
+                        all_removable = false;
+                        untracked.extend(nested);
+                    }
+                }
+            } else {
+                all_removable = false;
+            }
+        }
+
+        Ok((untracked, all_removable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{any_git_object::Sha, index::IndexEntry};
+    use tempfile::TempDir;
+
+    // a work tree with a tracked file in `src`, untracked files in the root, in `src` and in
+    // `new`, and an ignored file and directory
+    fn work_tree() -> Result<TempDir> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join(".git"))?;
+        for directory in ["src", "new", "build"] {
+            fs::create_dir(root.join(directory))?;
+        }
+        for file in [
+            ".gitignore",
+            "notes.txt",
+            "debug.log",
+            "src/main.rs",
+            "src/scratch.rs",
+            "new/file",
+            "build/out",
+        ] {
+            fs::write(root.join(file), file)?;
+        }
+        fs::write(root.join(".gitignore"), "*.log\nbuild/\n")?;
+
+        let mut index = Index::read(root)?;
+        for file in [".gitignore", "src/main.rs"] {
+            index.insert(IndexEntry::new(file, 0o100644, Sha([0; 20])));
+        }
+        index.write(root)?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn untracked_files_but_not_directories_by_default() -> Result<()> {
+        let dir = work_tree()?;
+        let cleaner = Cleaner::new(dir.path(), false, false)?;
+        assert_eq!(cleaner.untracked()?, ["notes.txt", "src/scratch.rs"]);
+        Ok(())
+    }
+
+    #[test]
+    fn untracked_directories_go_as_a_whole() -> Result<()> {
+        let dir = work_tree()?;
+        let cleaner = Cleaner::new(dir.path(), true, false)?;
+        assert_eq!(
+            cleaner.untracked()?,
+            ["new/", "notes.txt", "src/scratch.rs"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignored_paths_only_with_include_ignored() -> Result<()> {
+        let dir = work_tree()?;
+        let cleaner = Cleaner::new(dir.path(), true, true)?;
+        let untracked = cleaner.untracked()?;
+        assert_eq!(
+            untracked,
+            ["build/", "debug.log", "new/", "notes.txt", "src/scratch.rs"]
+        );
+
+        for name in &untracked {
+            cleaner.remove(name)?;
+        }
+        assert!(Cleaner::new(dir.path(), true, true)?
+            .untracked()?
+            .is_empty());
+        assert!(dir.path().join("src/main.rs").is_file());
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+use crate::git::{any_git_object::Sha, object_store::ObjectStore};
+use anyhow::{anyhow, bail, Context, Result};
+use sha1_smol::Sha1;
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+// a single commit-graph file: https://git-scm.com/docs/gitformat-commit-graph. generations are the
+// version 1 ones (topological levels), which git reads as well. split graphs and the optional
+// chunks aren't written, and are ignored or refused when reading
+const GRAPH_SIGNATURE: &[u8] = b"CGPH";
+const GRAPH_VERSION: u8 = 1;
+const SHA1_HASH_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+// a chunk id and the offset the chunk starts at
+const CHUNK_ENTRY_SIZE: usize = 4 + 8;
+const FANOUT_SIZE: usize = 256 * 4;
+const CHECKSUM_SIZE: usize = 20;
+
+const OID_FANOUT: &[u8; 4] = b"OIDF";
+const OID_LOOKUP: &[u8; 4] = b"OIDL";
+const COMMIT_DATA: &[u8; 4] = b"CDAT";
+const EXTRA_EDGES: &[u8; 4] = b"EDGE";
+
+// tree id, the positions of the first two parents, then generation and commit time
+const COMMIT_DATA_SIZE: usize = 20 + 4 + 4 + 8;
+const PARENT_NONE: u32 = 0x7000_0000;
+// in place of the second parent: the parents from the second on are listed in the extra edges
+// chunk, starting at the index in the remaining bits
+const EXTRA_EDGES_NEEDED: u32 = 0x8000_0000;
+// ends a commit's list in the extra edges chunk
+const LAST_EDGE: u32 = 0x8000_0000;
+// the generation takes the top 30 bits of a word, the commit time the other 34
+const MAX_GENERATION: u32 = 0x3fff_ffff;
+
+// the generation of commits the graph doesn't hold. the graph holds every ancestor of its
+// commits, so they can be the ancestor of no commit in it
+pub const GENERATION_INFINITY: u32 = u32::MAX;
+
+// what a history walk needs of a commit, taken from the commit graph when it's there so the
+// commit doesn't have to be read and decompressed
+#[derive(Debug, Clone)]
+pub struct CommitNode {
+    pub tree: Sha,
+    pub parents: Vec<Sha>,
+    // 1 for root commits, one more than the highest of the parents otherwise
+    pub generation: u32,
+    pub commit_time: u64,
+}
+
+#[derive(Debug)]
+pub struct CommitGraph {
+    data: Vec<u8>,
+    commit_count: usize,
+    oid_fanout: usize,
+    oid_lookup: usize,
+    commit_data: usize,
+    extra_edges: Option<(usize, usize)>,
+}
+
+impl CommitGraph {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .with_context(|| format!("CommitGraph::open: failed to read {path:?}"))?;
+
+        if data.len() < HEADER_SIZE + CHUNK_ENTRY_SIZE + CHECKSUM_SIZE
+            || &data[..4] != GRAPH_SIGNATURE
+        {
+            bail!("CommitGraph::open: {path:?} is not a commit graph");
+        }
+        if data[4] != GRAPH_VERSION || data[5] != SHA1_HASH_VERSION {
+            bail!(
+                "CommitGraph::open: unsupported version {} (hash version {}) in {path:?}",
+                data[4],
+                data[5]
+            );
+        }
+        if data[7] != 0 {
+            bail!("CommitGraph::open: {path:?} is part of a split commit graph");
+        }
+
+        // a chunk runs up to where the next one starts, the table ends with an entry marking the
+        // end of the last chunk
+        let chunk_count = data[6] as usize;
+        let table_end = HEADER_SIZE + (chunk_count + 1) * CHUNK_ENTRY_SIZE;
+        if data.len() < table_end + CHECKSUM_SIZE {
+            bail!("CommitGraph::open: {path:?} is truncated");
+        }
+        let entry = |index: usize| {
+            let start = HEADER_SIZE + index * CHUNK_ENTRY_SIZE;
+            let offset = u64::from_be_bytes(data[start + 4..start + 12].try_into().unwrap());
+            (&data[start..start + 4], offset as usize)
+        };
+        let mut chunks = HashMap::new();
+        for index in 0..chunk_count {
+            let (id, start) = entry(index);
+            let (_, end) = entry(index + 1);
+            if start < table_end || end < start || end > data.len() - CHECKSUM_SIZE {
+                bail!("CommitGraph::open: chunk {id:?} of {path:?} is out of bounds");
+            }
+            chunks.insert(id, (start, end));
+        }
+        let chunk = |id: &[u8; 4]| {
+            chunks
+                .get(&id[..])
+                .copied()
+                .ok_or_else(|| anyhow!("CommitGraph::open: {path:?} has no {id:?} chunk"))
+        };
+
+        let (oid_fanout, fanout_end) = chunk(OID_FANOUT)?;
+        if fanout_end - oid_fanout != FANOUT_SIZE {
+            bail!("CommitGraph::open: fanout table in {path:?} has the wrong size");
+        }
+        let fanout = |byte: usize| {
+            let start = oid_fanout + byte * 4;
+            u32::from_be_bytes(data[start..start + 4].try_into().unwrap())
+        };
+        if (1..256).any(|byte| fanout(byte) < fanout(byte - 1)) {
+            bail!("CommitGraph::open: fanout table in {path:?} isn't sorted");
+        }
+        let commit_count = fanout(255) as usize;
+
+        let (oid_lookup, lookup_end) = chunk(OID_LOOKUP)?;
+        let (commit_data, commit_data_end) = chunk(COMMIT_DATA)?;
+        if lookup_end - oid_lookup != commit_count * 20
+            || commit_data_end - commit_data != commit_count * COMMIT_DATA_SIZE
+        {
+            bail!("CommitGraph::open: {path:?} doesn't hold {commit_count} commits");
+        }
+        let extra_edges = chunks.get(&EXTRA_EDGES[..]).copied();
+
+        Ok(Self {
+            data,
+            commit_count,
+            oid_fanout,
+            oid_lookup,
+            commit_data,
+            extra_edges,
+        })
+    }
+
+    // the commit `sha` as the graph has it, if it has it
+    pub fn node(&self, sha: &Sha) -> Result<Option<CommitNode>> {
+        self.position(sha)
+            .map(|position| self.node_at(position))
+            .transpose()
+    }
+
+    // where `sha` is among the sorted commit ids, found like in a pack index
+    fn position(&self, sha: &Sha) -> Option<usize> {
+        let first_byte = sha.0[0] as usize;
+        let start = match first_byte {
+            0 => 0,
+            _ => self.word(self.oid_fanout + (first_byte - 1) * 4) as usize,
+        };
+        let end = self.word(self.oid_fanout + first_byte * 4) as usize;
+
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.sha_at(middle).cmp(&sha.0[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    fn node_at(&self, position: usize) -> Result<CommitNode> {
+        let start = self.commit_data + position * COMMIT_DATA_SIZE;
+        let parent = |position: u32| -> Result<Sha> {
+            let position = position as usize;
+            if position >= self.commit_count {
+                bail!("CommitGraph::node: parent position {position} is out of range");
+            }
+            Ok(Sha(self.sha_at(position).try_into().unwrap()))
+        };
+
+        let mut parents = vec![];
+        let (first, second) = (self.word(start + 20), self.word(start + 24));
+        if first != PARENT_NONE {
+            parents.push(parent(first)?);
+        }
+        if second & EXTRA_EDGES_NEEDED != 0 {
+            let (edges, edges_end) = self.extra_edges.ok_or_else(|| {
+                anyhow!("CommitGraph::node: octopus merge without an extra edges chunk")
+            })?;
+            let mut edge = edges + (second & !EXTRA_EDGES_NEEDED) as usize * 4;
+            loop {
+                if edge + 4 > edges_end {
+                    bail!("CommitGraph::node: extra edges run past the end of their chunk");
+                }
+                let word = self.word(edge);
+                parents.push(parent(word & !LAST_EDGE)?);
+                if word & LAST_EDGE != 0 {
+                    break;
+                }
+                edge += 4;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(parent(second)?);
+        }
+
+        let generation_and_time = self.word(start + 28);
+        Ok(CommitNode {
+            tree: Sha(self.data[start..start + 20].try_into().unwrap()),
+            parents,
+            generation: generation_and_time >> 2,
+            commit_time: (generation_and_time as u64 & 0b11) << 32 | self.word(start + 32) as u64,
+        })
+    }
+
+    fn sha_at(&self, position: usize) -> &[u8] {
+        let start = self.oid_lookup + position * 20;
+        &self.data[start..start + 20]
+    }
+
+    fn word(&self, start: usize) -> u32 {
+        u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap())
+    }
+}
+
+// writes the commit graph of every commit reachable from `tips` to the store's
+// `info/commit-graph`, replacing the one there. returns how many commits it holds
+pub fn write_commit_graph(store: &ObjectStore, tips: &[Sha]) -> Result<usize> {
+    let mut nodes = HashMap::new();
+    let mut pending = tips.to_vec();
+    while let Some(sha) = pending.pop() {
+        if nodes.contains_key(&sha) {
+            continue;
+        }
+        let node = store
+            .read_commit_node(&sha)
+            .with_context(|| format!("write_commit_graph: failed to read commit {sha}"))?;
+        pending.extend(node.parents.iter().cloned());
+        nodes.insert(sha, node);
+    }
+
+    let mut shas = nodes.keys().cloned().collect::<Vec<_>>();
+    shas.sort_unstable_by_key(|sha| sha.0);
+    let positions = shas
+        .iter()
+        .enumerate()
+        .map(|(position, sha)| (sha.clone(), position as u32))
+        .collect::<HashMap<_, _>>();
+    let parents = shas
+        .iter()
+        .map(|sha| {
+            nodes[sha]
+                .parents
+                .iter()
+                .map(|parent| positions[parent])
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let generations = generations(&parents);
+
+    let mut fanout = [0u32; 256];
+    for sha in &shas {
+        fanout[sha.0[0] as usize] += 1;
+    }
+    let mut oid_fanout = vec![];
+    let mut count = 0;
+    for commits in fanout {
+        count += commits;
+        oid_fanout.extend(count.to_be_bytes());
+    }
+
+    let mut oid_lookup = vec![];
+    let mut commit_data = vec![];
+    let mut extra_edges = vec![];
+    for (position, sha) in shas.iter().enumerate() {
+        oid_lookup.extend(sha.0);
+
+        let node = &nodes[sha];
+        let parents = &parents[position];
+        commit_data.extend(node.tree.0);
+        commit_data.extend(parents.first().unwrap_or(&PARENT_NONE).to_be_bytes());
+        match parents.len() {
+            0 | 1 => commit_data.extend(PARENT_NONE.to_be_bytes()),
+            2 => commit_data.extend(parents[1].to_be_bytes()),
+            _ => {
+                let start = (extra_edges.len() / 4) as u32;
+                commit_data.extend((start | EXTRA_EDGES_NEEDED).to_be_bytes());
+                for (index, parent) in parents[1..].iter().enumerate() {
+                    let last = if index == parents.len() - 2 {
+                        LAST_EDGE
+                    } else {
+                        0
+                    };
+                    extra_edges.extend((parent | last).to_be_bytes());
+                }
+            }
+        }
+        // like git, only the low 34 bits of the commit time are kept
+        let high_time = ((node.commit_time >> 32) & 0b11) as u32;
+        commit_data.extend((generations[position] << 2 | high_time).to_be_bytes());
+        commit_data.extend((node.commit_time as u32).to_be_bytes());
+    }
+
+    let mut chunks = vec![
+        (OID_FANOUT, oid_fanout),
+        (OID_LOOKUP, oid_lookup),
+        (COMMIT_DATA, commit_data),
+    ];
+    if !extra_edges.is_empty() {
+        chunks.push((EXTRA_EDGES, extra_edges));
+    }
+
+    let mut graph = GRAPH_SIGNATURE.to_vec();
+    graph.extend([GRAPH_VERSION, SHA1_HASH_VERSION, chunks.len() as u8, 0]);
+    let mut offset = (HEADER_SIZE + (chunks.len() + 1) * CHUNK_ENTRY_SIZE) as u64;
+    for (id, chunk) in &chunks {
+        graph.extend(*id);
+        graph.extend(offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    graph.extend([0; 4]);
+    graph.extend(offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        graph.extend(chunk);
+    }
+    let checksum = Sha1::from(&graph).digest().bytes();
+    graph.extend(checksum);
+
+    let path = store.commit_graph_path();
+    let info_dir = path
+        .parent()
+        .expect("the commit graph is in the info directory");
+    fs::create_dir_all(info_dir)
+        .with_context(|| format!("write_commit_graph: failed to create {info_dir:?}"))?;
+    let lock_path = path.with_extension("lock");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+        .and_then(|mut file| file.write_all(&graph))
+        .with_context(|| format!("write_commit_graph: failed to write {lock_path:?}"))?;
+    fs::rename(&lock_path, &path).with_context(|| {
+        let _ = fs::remove_file(&lock_path);
+        format!("write_commit_graph: failed to replace {path:?}")
+    })?;
+
+    Ok(shas.len())
+}
+
+// the generation of every commit, given the positions of each one's parents. parents are worked
+// out before their children off an explicit stack, histories are too deep to recurse
+fn generations(parents: &[Vec<u32>]) -> Vec<u32> {
+    let mut generations = vec![0u32; parents.len()];
+    for start in 0..parents.len() {
+        let mut stack = vec![start];
+        while let Some(&position) = stack.last() {
+            if generations[position] != 0 {
+                stack.pop();
+                continue;
+            }
+            let pending = parents[position]
+                .iter()
+                .map(|&parent| parent as usize)
+                .filter(|&parent| generations[parent] == 0)
+                .collect::<Vec<_>>();
+            if pending.is_empty() {
+                let highest = parents[position]
+                    .iter()
+                    .map(|&parent| generations[parent as usize])
+                    .max()
+                    .unwrap_or(0);
+                generations[position] = (highest + 1).min(MAX_GENERATION);
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    generations
+}
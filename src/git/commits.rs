@@ -5,10 +5,14 @@ use crate::{
     },
     utils::helpers::from_utf8_with_context,
 };
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use bytes::BufMut;
 use hex;
-use std::{io::Write, str::FromStr};
+use std::{
+    io::Write,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Debug, Clone)]
 pub struct CommitActor {
@@ -39,11 +43,11 @@ impl FromStr for CommitActor {
             anyhow!("failed to parse commit object file: failed to find author epoch")
         })?;
 
-        let timezone = vec.get(vec.len() - 1).ok_or_else(|| {
+        let timezone = vec.last().ok_or_else(|| {
             anyhow!("failed to parse commit object file: failed to find author timezone")
         })?;
 
-        if email.chars().next() != Some('<') || email.chars().last() != Some('>') {
+        if !email.starts_with('<') || !email.ends_with('>') {
             return Err(anyhow!(
                 "failed to parse commit object file: expected author email to be enclosed in angle brackets"
             ));
@@ -55,13 +59,254 @@ impl FromStr for CommitActor {
             name: name.to_owned(),
             email: email.to_owned(),
             epoch: epoch.parse().with_context(|| {
-                format!("failed to parse commit object file: failed to parse author epoch")
+                "failed to parse commit object file: failed to parse author epoch"
             })?,
             timezone: timezone.to_string(),
         })
     }
 }
 
+impl CommitActor {
+    // the identity recorded for commits and reflog entries this tool creates on its own
+    pub fn current() -> Self {
+        CommitActor {
+            name: "John Doe".to_string(),
+            email: "john.doe@codecrafte.rs".to_string(),
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            timezone: "+0000".to_string(),
+        }
+    }
+
+    // the timestamp in git's default date format, in the actor's own timezone:
+    // `Thu Oct 16 09:05:00 2026 +0200`
+    pub fn date(&self) -> String {
+        const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let time = self.local_time();
+        format!(
+            "{} {} {} {:02}:{:02}:{:02} {} {}",
+            WEEKDAYS[time.weekday],
+            MONTHS[time.month - 1],
+            time.day,
+            time.seconds / 3600,
+            time.seconds / 60 % 60,
+            time.seconds % 60,
+            time.year,
+            self.timezone
+        )
+    }
+
+    // the timestamp in git's iso format, in the actor's own timezone: `2026-10-16 09:05:00 +0200`
+    pub fn iso_date(&self) -> String {
+        let time = self.local_time();
+        format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            time.year,
+            time.month,
+            time.day,
+            time.seconds / 3600,
+            time.seconds / 60 % 60,
+            time.seconds % 60,
+            self.timezone
+        )
+    }
+
+    // what's implausible about the date: a zero timestamp, one further than a day in the future,
+    // or a timezone other than `+hhmm`/`-hhmm` with minutes below 60
+    pub fn date_problems(&self, now: u64) -> Vec<String> {
+        let mut problems = vec![];
+        if self.epoch == 0 {
+            problems.push("zero timestamp".to_owned());
+        }
+        if self.epoch > now.saturating_add(FUTURE_DATE_SLACK) {
+            problems.push(format!("timestamp {} is in the future", self.epoch));
+        }
+        if parse_timezone(&self.timezone).is_none() || self.timezone[3..] >= *"60" {
+            problems.push(format!("malformed timezone {:?}", self.timezone));
+        }
+        problems
+    }
+
+    fn local_time(&self) -> LocalTime {
+        let offset = parse_timezone(&self.timezone).unwrap_or(0);
+        let local = self.epoch as i64 + offset;
+        let (days, seconds) = (local.div_euclid(86400), local.rem_euclid(86400));
+
+        // days since 1970-01-01 to a civil date, from
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let era_days = days + 719468;
+        let era = era_days.div_euclid(146097);
+        let day_of_era = era_days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+
+        LocalTime {
+            year: year_of_era + era * 400 + i64::from(month <= 2),
+            month: month as usize,
+            day,
+            seconds,
+            // 1970-01-01 was a thursday
+            weekday: (days + 4).rem_euclid(7) as usize,
+        }
+    }
+}
+
+// a timestamp broken down in some timezone, weekdays counting from sunday
+struct LocalTime {
+    year: i64,
+    month: usize,
+    day: i64,
+    seconds: i64,
+    weekday: usize,
+}
+
+// `+hhmm` or `-hhmm` as an offset in seconds
+fn parse_timezone(timezone: &str) -> Option<i64> {
+    let (sign, digits) = match timezone.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+// how far past the current time a date may lie before it's taken for a broken clock or import
+const FUTURE_DATE_SLACK: u64 = 86400;
+
+// seconds in each unit a relative date can be given in. months and years are approximated by 30
+// and 365 days
+const DATE_UNITS: &[(&str, u64)] = &[
+    ("second", 1),
+    ("minute", 60),
+    ("hour", 3600),
+    ("day", 86400),
+    ("week", 7 * 86400),
+    ("month", 30 * 86400),
+    ("year", 365 * 86400),
+];
+
+// a date as the options filtering by date take it: `now`, `yesterday`, `@<epoch>`, relative to
+// `now` like `2 weeks ago` or `2.weeks.ago`, or absolute like `2024-01-15`,
+// `2024-01-15 13:45[:30]` and `2024-01-15T13:45:30 +0200`. absolute dates without a timezone are
+// taken as utc
+pub fn parse_date(date: &str, now: u64) -> Result<u64> {
+    let date = date.trim();
+    match date {
+        "now" => return Ok(now),
+        "yesterday" => return Ok(now.saturating_sub(86400)),
+        _ => {}
+    }
+    if let Some(epoch) = date.strip_prefix('@') {
+        return epoch
+            .parse()
+            .with_context(|| format!("parse_date: invalid timestamp {date:?}"));
+    }
+
+    let words = date
+        .split(|c: char| c.is_whitespace() || c == '.')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    if let Some((&"ago", amounts)) = words.split_last() {
+        if amounts.is_empty() || amounts.len() % 2 != 0 {
+            bail!("parse_date: expected `<n> <unit>... ago`, got {date:?}");
+        }
+        let mut seconds = 0u64;
+        for pair in amounts.chunks(2) {
+            let count: u64 = pair[0]
+                .parse()
+                .with_context(|| format!("parse_date: invalid count in {date:?}"))?;
+            let unit = pair[1].strip_suffix('s').unwrap_or(pair[1]);
+            let (_, size) = DATE_UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .ok_or_else(|| anyhow!("parse_date: unknown unit {:?} in {date:?}", pair[1]))?;
+            seconds = seconds.saturating_add(count.saturating_mul(*size));
+        }
+        return Ok(now.saturating_sub(seconds));
+    }
+
+    parse_absolute_date(date).ok_or_else(|| anyhow!("parse_date: unrecognized date {date:?}"))
+}
+
+fn parse_absolute_date(date: &str) -> Option<u64> {
+    let (day, rest) = date.split_at_checked(10).unwrap_or((date, ""));
+    let mut fields = day.splitn(3, '-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (fields.next()??, fields.next()??, fields.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let rest = rest.trim_start_matches(['T', ' ']);
+    let (time, timezone) = rest.split_once(' ').unwrap_or((rest, ""));
+    let (time, timezone) = match time.starts_with(['+', '-']) {
+        true => ("", time),
+        false => (time, timezone.trim()),
+    };
+    let mut seconds = 0;
+    if !time.is_empty() {
+        let parts = time
+            .split(':')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let (hours, minutes, secs) = match parts[..] {
+            [hours, minutes] => (hours, minutes, 0),
+            [hours, minutes, secs] => (hours, minutes, secs),
+            _ => return None,
+        };
+        if hours > 23 || minutes > 59 || secs > 60 {
+            return None;
+        }
+        seconds = hours * 3600 + minutes * 60 + secs;
+    }
+    let offset = match timezone {
+        "" => 0,
+        timezone => parse_timezone(timezone)?,
+    };
+
+    let epoch = days_from_civil(year, month, day) * 86400 + seconds - offset;
+    u64::try_from(epoch).ok()
+}
+
+// days since 1970-01-01 of a civil date, from
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl std::fmt::Display for CommitActor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.epoch, self.timezone
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Commit {
     pub tree_hash: Sha,
@@ -75,13 +320,13 @@ impl GitObject for Commit {
     fn encode_body(&self) -> Result<Vec<u8>> {
         let mut buf = (vec![]).writer();
 
-        buf.write(format!("tree {}\n", hex::encode(&self.tree_hash)).as_bytes())?;
+        buf.write_all(format!("tree {}\n", hex::encode(&self.tree_hash)).as_bytes())?;
 
         for parent_hash in &self.parent_hash {
-            buf.write(format!("parent {}\n", hex::encode(parent_hash)).as_bytes())?;
+            buf.write_all(format!("parent {}\n", hex::encode(parent_hash)).as_bytes())?;
         }
 
-        buf.write(
+        buf.write_all(
             format!(
                 "author {} <{}> {} {}\n",
                 self.author.name, self.author.email, self.author.epoch, self.author.timezone,
@@ -91,7 +336,7 @@ impl GitObject for Commit {
 
         let committer = self.committer.as_ref().unwrap_or(&self.author);
 
-        buf.write(
+        buf.write_all(
             format!(
                 "committer {} <{}> {} {}\n",
                 committer.name, committer.email, committer.epoch, committer.timezone,
@@ -99,7 +344,7 @@ impl GitObject for Commit {
             .as_bytes(),
         )?;
 
-        buf.write(format!("\n{}", self.commit_message).as_bytes())?;
+        buf.write_all(format!("\n{}", self.commit_message).as_bytes())?;
 
         Ok(buf.into_inner())
     }
@@ -113,16 +358,19 @@ impl GitObject for Commit {
                 if iter.peek() == Some(&b'\n') {
                     iter.next();
                     None
+                } else if iter.peek().is_none() {
+                    // truncated header without the blank line before the message
+                    None
                 } else {
                     let iter = iter.by_ref();
                     Some((|| -> Result<_> {
                         let key = String::from_utf8(iter.take_while(|b| b != &b' ').collect())
                             .with_context(|| {
-                                format!("failed to parse commit object file: failed to parse key")
+                                "failed to parse commit object file: failed to parse key"
                             })?;
                         let value = String::from_utf8(iter.take_while(|b| b != &b'\n').collect())
                             .with_context(|| {
-                            format!("failed to parse commit object file: failed to parse value")
+                            "failed to parse commit object file: failed to parse value"
                         })?;
                         Ok((key, value))
                     })())
@@ -130,9 +378,7 @@ impl GitObject for Commit {
             }
         })
         .collect::<Result<Vec<_>, _>>()
-        .with_context(|| {
-            format!("failed to parse commit object file: failed to parse key-value pairs")
-        })?;
+        .with_context(|| "failed to parse commit object file: failed to parse key-value pairs")?;
 
         let tree_hash = Sha(pairs
             .iter()
@@ -159,7 +405,7 @@ impl GitObject for Commit {
             .map(|arr| arr.map(Sha))
             .collect::<Result<Vec<_>, _>>()
             .with_context(|| {
-                format!("failed to parse commit object file: failed to parse parent hashes")
+                "failed to parse commit object file: failed to parse parent hashes"
             })?;
 
         let author = pairs
@@ -180,7 +426,7 @@ impl GitObject for Commit {
             })?;
 
         let commit_message = from_utf8_with_context(iter.collect()).with_context(|| {
-            format!("failed to parse commit object file: failed to parse commit message")
+            "failed to parse commit object file: failed to parse commit message"
         })?;
 
         let commit = Commit {
@@ -200,12 +446,37 @@ impl GitObject for Commit {
 }
 
 impl Commit {
+    // parents are kept in the given order, the first one being the commit this one continues.
+    // naming the same parent twice is almost always a mistake, so it's refused here
     pub fn new(
         tree_hash: [u8; 20],
         parent_hashes: Vec<[u8; 20]>,
         author: CommitActor,
         committer: Option<CommitActor>,
         commit_message: String,
+    ) -> Result<Self> {
+        for (index, parent_hash) in parent_hashes.iter().enumerate() {
+            if parent_hashes[..index].contains(parent_hash) {
+                bail!("Commit::new: duplicate parent {}", hex::encode(parent_hash));
+            }
+        }
+
+        Ok(Self::with_duplicate_parents(
+            tree_hash,
+            parent_hashes,
+            author,
+            committer,
+            commit_message,
+        ))
+    }
+
+    // like new, for the rare history that really lists a parent more than once
+    pub fn with_duplicate_parents(
+        tree_hash: [u8; 20],
+        parent_hashes: Vec<[u8; 20]>,
+        author: CommitActor,
+        committer: Option<CommitActor>,
+        commit_message: String,
     ) -> Self {
         Self {
             tree_hash: tree_hash.into(),
@@ -215,4 +486,96 @@ impl Commit {
             commit_message,
         }
     }
+
+    pub fn author(&self) -> &CommitActor {
+        &self.author
+    }
+
+    pub fn committer(&self) -> &CommitActor {
+        self.committer.as_ref().unwrap_or(&self.author)
+    }
+
+    // the date problems of the author and committer lines, and a commit made before it was
+    // authored
+    pub fn date_problems(&self, now: u64) -> Vec<String> {
+        let (author, committer) = (self.author(), self.committer());
+        let mut problems = author
+            .date_problems(now)
+            .into_iter()
+            .map(|problem| format!("author: {problem}"))
+            .chain(
+                committer
+                    .date_problems(now)
+                    .into_iter()
+                    .map(|problem| format!("committer: {problem}")),
+            )
+            .collect::<Vec<_>>();
+        if committer.epoch < author.epoch {
+            problems.push(format!(
+                "committer date {} is before author date {}",
+                committer.epoch, author.epoch
+            ));
+        }
+        problems
+    }
+
+    pub fn message(&self) -> &str {
+        &self.commit_message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor() -> CommitActor {
+        "A U Thor <author@example.com> 1700000000 +0100"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn parent_order_survives_decode_and_encode() -> Result<()> {
+        // parents out of sha order, which must stay as they are
+        let body = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            parent cccccccccccccccccccccccccccccccccccccccc\n\
+            parent aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n\
+            parent bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n\
+            author A U Thor <author@example.com> 1700000000 +0100\n\
+            committer A U Thor <author@example.com> 1700000000 +0100\n\
+            \n\
+            octopus\n"
+            .to_vec();
+
+        let commit = Commit::decode_body(body.clone())?;
+        assert_eq!(
+            commit.parent_hash,
+            [[0xcc; 20], [0xaa; 20], [0xbb; 20]].map(Sha)
+        );
+        assert_eq!(commit.encode_body()?, body);
+        Ok(())
+    }
+
+    #[test]
+    fn only_with_duplicate_parents_lists_a_parent_twice() -> Result<()> {
+        let parents = vec![[0xaa; 20], [0xbb; 20], [0xaa; 20]];
+        let duplicate = Commit::new(
+            [0; 20],
+            parents.clone(),
+            actor(),
+            None,
+            "merge\n".to_owned(),
+        );
+        assert!(duplicate.is_err());
+
+        let commit =
+            Commit::with_duplicate_parents([0; 20], parents, actor(), None, "merge\n".to_owned());
+        assert_eq!(
+            commit.parent_hash,
+            [[0xaa; 20], [0xbb; 20], [0xaa; 20]].map(Sha)
+        );
+        let decoded = Commit::decode_body(commit.encode_body()?)?;
+        assert_eq!(decoded.parent_hash, commit.parent_hash);
+        Ok(())
+    }
 }
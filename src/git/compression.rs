@@ -1,28 +1,52 @@
-use anyhow::{Context, Result};
+use crate::{git::config::Config, utils::helpers::parse_with_context};
+use anyhow::{bail, Context, Result};
 use flate2::read::ZlibDecoder as ZlibReadDecoder;
 use flate2::write::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
 use std::io::{Read, Write};
 
-pub fn compress(input: Vec<u8>) -> Result<Vec<u8>> {
-    let mut encoder = ZlibEncoder::new(Vec::new(), Default::default());
+// zlib level for loose objects: `core.looseCompression`, else `core.compression`
+pub fn loose_compression(config: &Config) -> Result<Compression> {
+    configured_compression(config, "core.looseCompression")
+}
+
+// zlib level for packs: `pack.compression`, else `core.compression`
+pub fn pack_compression(config: &Config) -> Result<Compression> {
+    configured_compression(config, "pack.compression")
+}
+
+// -1 stands for zlib's own default, which is also what's used when nothing is configured
+fn configured_compression(config: &Config, key: &str) -> Result<Compression> {
+    let Some(value) = config.get(key).or_else(|| config.get("core.compression")) else {
+        return Ok(Compression::default());
+    };
+    match parse_with_context::<i32>(value)? {
+        -1 => Ok(Compression::default()),
+        level @ 0..=9 => Ok(Compression::new(level as u32)),
+        level => bail!("bad zlib compression level {level}"),
+    }
+}
+
+pub fn compress(input: Vec<u8>, level: Compression) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), level);
 
     encoder
         .write_all(&input)
-        .with_context(|| format!("failed to write input to zlib encoder"))?;
+        .with_context(|| "failed to write input to zlib encoder")?;
 
     encoder
         .finish()
-        .with_context(|| format!("failed to finish zlib encoder"))
+        .with_context(|| "failed to finish zlib encoder")
 }
 
 pub fn decompress(input: Vec<u8>) -> Result<Vec<u8>> {
     let mut decoder = ZlibDecoder::new(Vec::new());
     decoder
         .write_all(&input)
-        .with_context(|| format!("failed to write input to zlib decoder"))?;
+        .with_context(|| "failed to write input to zlib decoder")?;
     decoder
         .finish()
-        .with_context(|| format!("failed to finish zlib decoder"))
+        .with_context(|| "failed to finish zlib decoder")
 }
 
 struct IterRead<I: Iterator<Item = u8>> {
@@ -52,7 +76,7 @@ pub fn decompress_slice(content: &[u8]) -> Result<(Vec<u8>, u64)> {
     let mut buff = vec![];
     let buff_size = decoder
         .read_to_end(&mut buff)
-        .with_context(|| format!("decompress_up_to_size: failed to finish zlib decoder"))?;
+        .with_context(|| "decompress_slice: failed to finish zlib decoder")?;
 
     if false {
         println!(
@@ -0,0 +1,281 @@
+use crate::utils::helpers::common_dir;
+use anyhow::{bail, Context, Result};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// settings from git's config files, later files and later lines taking precedence:
+// https://git-scm.com/docs/git-config#_configuration_file
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // keys are `section.name` or `section.subsection.name`, with section and name lowercased
+    values: Vec<(String, String)>,
+}
+
+impl Config {
+    // the system and global files, all there is before a repository exists
+    pub fn global() -> Result<Self> {
+        let mut config = Self::default();
+        if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+            config.read_file(Path::new("/etc/gitconfig"))?;
+        }
+        for path in global_paths() {
+            config.read_file(&path)?;
+        }
+        Ok(config)
+    }
+
+    // the global files followed by the config of the repository at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut config = Self::global()?;
+        config.read_file(&common_dir(path).join("config"))?;
+        Ok(config)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.values
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value of a key that can be given several times, like `remote.<name>.fetch`, in the
+    // order they were read
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.values
+            .iter()
+            .filter(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // a size like `512m`: a number with an optional k, m or g suffix, case-insensitive
+    pub fn get_size(&self, key: &str) -> Result<Option<u64>> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let (number, unit) = match value.char_indices().last() {
+            Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+                let unit = match suffix.to_ascii_lowercase() {
+                    'k' => 1 << 10,
+                    'm' => 1 << 20,
+                    'g' => 1 << 30,
+                    _ => bail!("Config::get_size: bad unit in {key} = {value:?}"),
+                };
+                (&value[..index], unit)
+            }
+            _ => (value, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .map(Some)
+            .with_context(|| format!("Config::get_size: bad size {key} = {value:?}"))
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<()> {
+        if !path.is_file() {
+            return Ok(());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Config::read_file: failed to read {path:?}"))?;
+        self.parse(&content)
+            .with_context(|| format!("Config::read_file: failed to parse {path:?}"))
+    }
+
+    fn parse(&mut self, content: &str) -> Result<()> {
+        let mut section: Option<String> = None;
+        let mut lines = content.lines().enumerate();
+
+        while let Some((number, line)) = lines.next() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let line = if let Some(header) = line.strip_prefix('[') {
+                let end = header
+                    .find(']')
+                    .with_context(|| format!("line {}: unterminated section header", number + 1))?;
+                section = Some(
+                    parse_section(&header[..end])
+                        .with_context(|| format!("line {}: invalid section header", number + 1))?,
+                );
+                // a variable may follow the header on the same line
+                let rest = header[end + 1..].trim_start();
+                if rest.is_empty() || rest.starts_with('#') || rest.starts_with(';') {
+                    continue;
+                }
+                rest
+            } else {
+                line
+            };
+
+            let Some(section) = &section else {
+                bail!("line {}: variable outside of a section", number + 1);
+            };
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value)),
+                None => (
+                    line.split([' ', '\t', '#', ';']).next().unwrap_or(line),
+                    None,
+                ),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                bail!("line {}: invalid variable name {name:?}", number + 1);
+            }
+
+            // a variable without `=` is a boolean set to true
+            let value = match value {
+                Some(value) => {
+                    let mut value = value.to_owned();
+                    // a trailing backslash continues the value on the next line
+                    while ends_with_continuation(&value) {
+                        value.pop();
+                        match lines.next() {
+                            Some((_, next)) => value.push_str(next),
+                            None => break,
+                        }
+                    }
+                    parse_value(&value)
+                        .with_context(|| format!("line {}: invalid value", number + 1))?
+                }
+                None => "true".to_owned(),
+            };
+
+            self.values
+                .push((format!("{section}.{}", name.to_ascii_lowercase()), value));
+        }
+        Ok(())
+    }
+}
+
+// $GIT_CONFIG_GLOBAL alone, or the XDG file followed by `~/.gitconfig`
+fn global_paths() -> Vec<PathBuf> {
+    if let Some(path) = env::var_os("GIT_CONFIG_GLOBAL") {
+        return vec![PathBuf::from(path)];
+    }
+
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    xdg.map(|dir| dir.join("git/config"))
+        .into_iter()
+        .chain(home.map(|home| home.join(".gitconfig")))
+        .collect()
+}
+
+// `section`, `section "subsection"` or the deprecated `section.subsection`. only the subsection
+// of the quoted form keeps its case
+fn parse_section(header: &str) -> Result<String> {
+    let header = header.trim();
+    let (name, subsection) = match header.split_once([' ', '\t']) {
+        Some((name, rest)) => {
+            let quoted = rest
+                .trim()
+                .strip_prefix('"')
+                .and_then(|rest| rest.strip_suffix('"'))
+                .with_context(|| format!("expected a quoted subsection in {header:?}"))?;
+            let mut subsection = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => subsection.extend(chars.next()),
+                    _ => subsection.push(c),
+                }
+            }
+            (name, Some(subsection))
+        }
+        None => match header.split_once('.') {
+            Some((name, subsection)) => (name, Some(subsection.to_ascii_lowercase())),
+            None => (header, None),
+        },
+    };
+
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        bail!("invalid section name {name:?}");
+    }
+    Ok(match subsection {
+        Some(subsection) => format!("{}.{subsection}", name.to_ascii_lowercase()),
+        None => name.to_ascii_lowercase(),
+    })
+}
+
+// the value with quotes removed, escapes resolved, and comments and surrounding whitespace
+// outside of quotes dropped
+fn parse_value(raw: &str) -> Result<String> {
+    let mut value = String::new();
+    // whitespace is only kept once something follows it
+    let mut pending_space = String::new();
+    let mut quoted = false;
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                quoted = !quoted;
+            }
+            '\\' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some(c @ ('\\' | '"')) => value.push(c),
+                    other => bail!("unknown escape sequence \\{}", other.unwrap_or(' ')),
+                }
+            }
+            '#' | ';' if !quoted => break,
+            c if c.is_whitespace() && !quoted => {
+                if !value.is_empty() {
+                    pending_space.push(c);
+                }
+            }
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+
+    if quoted {
+        bail!("unterminated quote");
+    }
+    Ok(value)
+}
+
+// an odd number of trailing backslashes, an even number are escaped backslashes
+fn ends_with_continuation(value: &str) -> bool {
+    value.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+// `section.name` and `section.subsection.name` with the parts git compares case-insensitively
+// lowercased
+fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_ascii_lowercase(),
+            &key[first..last],
+            key[last..].to_ascii_lowercase()
+        ),
+        _ => key.to_ascii_lowercase(),
+    }
+}
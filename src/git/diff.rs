@@ -0,0 +1,268 @@
+// git looks for NUL in the first 8000 bytes to decide whether content is binary
+const BINARY_CHECK_SIZE: usize = 8000;
+
+// splits content into lines that keep their terminating newline, so joining them gives back the
+// exact input (including a missing newline at the end)
+pub fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    content.split_inclusive(|b| b == &b'\n').collect()
+}
+
+// binary content isn't diffed or merged line by line
+pub fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_CHECK_SIZE)].contains(&0)
+}
+
+// pairs of indices `(a_index, b_index)` of lines both sides have in common, in increasing order.
+// uses the linear space variant of Myers' O(ND) algorithm: http://www.xmailserver.org/diff2.pdf
+pub fn matching_lines<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut matches = vec![];
+    collect_matches(a, b, 0, 0, &mut matches);
+    matches
+}
+
+fn collect_matches<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    a_offset: usize,
+    b_offset: usize,
+    matches: &mut Vec<(usize, usize)>,
+) {
+    // the common prefix and suffix are matched directly, which keeps the search below small for
+    // the usual case of a few edits in a large file
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    matches.extend((0..prefix).map(|i| (a_offset + i, b_offset + i)));
+
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if !middle_a.is_empty() && !middle_b.is_empty() {
+        // the snake splits the edit script in two halves that are solved on their own
+        let (x, y, u, v) = middle_snake(middle_a, middle_b);
+        let (a_offset, b_offset) = (a_offset + prefix, b_offset + prefix);
+        collect_matches(&middle_a[..x], &middle_b[..y], a_offset, b_offset, matches);
+        matches.extend((0..u - x).map(|i| (a_offset + x + i, b_offset + y + i)));
+        collect_matches(
+            &middle_a[u..],
+            &middle_b[v..],
+            a_offset + u,
+            b_offset + v,
+            matches,
+        );
+    }
+
+    matches.extend((0..suffix).map(|i| {
+        (
+            a_offset + a.len() - suffix + i,
+            b_offset + b.len() - suffix + i,
+        )
+    }));
+}
+
+// the diagonal run `(x, y)..(u, v)` in the middle of a shortest edit script, found by searching
+// from both ends at once until the two searches overlap. only the furthest point of every
+// diagonal is kept, so memory stays linear in the input size
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+
+    // `forward[k + offset]` is the furthest x reached on diagonal k from the start,
+    // `backward[k + offset]` the same from the end with both inputs reversed
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    let index = |k: isize| (k + offset) as usize;
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && forward[index(k - 1)] < forward[index(k + 1)]) {
+                forward[index(k + 1)]
+            } else {
+                forward[index(k - 1)] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            while x < n && x - k < m && a[x as usize] == b[(x - k) as usize] {
+                x += 1;
+            }
+            forward[index(k)] = x;
+
+            // a forward diagonal k is the backward diagonal `delta - k`
+            let reverse_k = delta - k;
+            if delta % 2 != 0
+                && (-(d - 1)..=d - 1).contains(&reverse_k)
+                && x + backward[index(reverse_k)] >= n
+            {
+                return (
+                    start_x as usize,
+                    start_y as usize,
+                    x as usize,
+                    (x - k) as usize,
+                );
+            }
+        }
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && backward[index(k - 1)] < backward[index(k + 1)]) {
+                backward[index(k + 1)]
+            } else {
+                backward[index(k - 1)] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            while x < n && x - k < m && a[(n - 1 - x) as usize] == b[(m - 1 - x + k) as usize] {
+                x += 1;
+            }
+            backward[index(k)] = x;
+
+            let forward_k = delta - k;
+            if delta % 2 == 0 && (-d..=d).contains(&forward_k) && x + forward[index(forward_k)] >= n
+            {
+                return (
+                    (n - x) as usize,
+                    (m - x + k) as usize,
+                    (n - start_x) as usize,
+                    (m - start_y) as usize,
+                );
+            }
+        }
+    }
+    unreachable!("middle_snake: the searches always meet within (n + m) / 2 steps")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    // the length of the longest common subsequence, by the textbook quadratic table
+    fn lcs_len(a: &[char], b: &[char]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    // matches pair up equal lines, increase on both sides, and are as many as can be
+    fn assert_shortest_script(a: &str, b: &str) -> Vec<(usize, usize)> {
+        let (a, b) = (chars(a), chars(b));
+        let matches = matching_lines(&a, &b);
+        assert!(matches.iter().all(|&(x, y)| a[x] == b[y]));
+        assert!(matches
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        assert_eq!(matches.len(), lcs_len(&a, &b));
+        matches
+    }
+
+    #[test]
+    fn empty_sides() {
+        assert_eq!(assert_shortest_script("", ""), vec![]);
+        assert_eq!(assert_shortest_script("abc", ""), vec![]);
+        assert_eq!(assert_shortest_script("", "abc"), vec![]);
+    }
+
+    #[test]
+    fn all_equal() {
+        assert_eq!(
+            assert_shortest_script("abcd", "abcd"),
+            vec![(0, 0), (1, 1), (2, 2), (3, 3)]
+        );
+    }
+
+    #[test]
+    fn all_different() {
+        assert_eq!(assert_shortest_script("abc", "xyz"), vec![]);
+        assert_eq!(assert_shortest_script("a", "bcdef"), vec![]);
+    }
+
+    #[test]
+    fn paper_example() {
+        // the example of the Myers paper. it has several edit scripts of 5 steps, splitting at
+        // the middle snake picks a different one than the paper's figure
+        assert_eq!(
+            assert_shortest_script("abcabba", "cbabac"),
+            vec![(1, 1), (3, 2), (4, 3), (6, 4)]
+        );
+    }
+
+    #[test]
+    fn repeated_lines() {
+        assert_shortest_script("aaaa", "aa");
+        assert_shortest_script("abababab", "babababa");
+        assert_shortest_script("aabbaabb", "ababab");
+        assert_eq!(
+            assert_shortest_script("xaaay", "aaa"),
+            vec![(1, 0), (2, 1), (3, 2)]
+        );
+    }
+
+    #[test]
+    fn inserts_and_deletes_around_common_lines() {
+        assert_eq!(
+            assert_shortest_script("abc", "xabcy"),
+            vec![(0, 1), (1, 2), (2, 3)]
+        );
+        assert_eq!(
+            assert_shortest_script("axbycz", "abc"),
+            vec![(0, 0), (2, 1), (4, 2)]
+        );
+    }
+
+    #[test]
+    fn random_inputs_match_the_quadratic_table() {
+        // a fixed linear congruential generator, so failures can be reproduced
+        let mut state = 0x2545_f491_u64;
+        let mut next = |bound: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % bound
+        };
+        for _ in 0..500 {
+            let mut text = || {
+                (0..next(12))
+                    .map(|_| (b'a' + next(3) as u8) as char)
+                    .collect::<String>()
+            };
+            let (a, b) = (text(), text());
+            assert_shortest_script(&a, &b);
+        }
+    }
+
+    #[test]
+    fn text_without_nul_is_not_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"plain text\n\twith \xff high bytes\n"));
+        assert!(!is_binary(&[b'a'; 2 * BINARY_CHECK_SIZE]));
+    }
+
+    #[test]
+    fn an_embedded_nul_is_binary() {
+        assert!(is_binary(b"\0"));
+        assert!(is_binary(b"text\0more text\n"));
+    }
+
+    #[test]
+    fn only_the_first_8000_bytes_are_checked() {
+        let mut content = vec![b'a'; BINARY_CHECK_SIZE + 1];
+        content[BINARY_CHECK_SIZE - 1] = 0;
+        assert!(is_binary(&content), "a NUL at the last byte checked");
+
+        let mut content = vec![b'a'; BINARY_CHECK_SIZE + 1];
+        content[BINARY_CHECK_SIZE] = 0;
+        assert!(!is_binary(&content), "a NUL at the first byte not checked");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::git::{
+    attributes::{AttributeMatcher, AttributeValue},
+    config::Config,
+    diff::is_binary,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// distinguishes the temporary files of the textconv commands one process runs
+static TEXTCONV_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// how `.gitattributes` has files diffed: `-diff` shows them as binary, `diff` as text, and
+// `diff=<driver>` picks a driver whose `diff.<driver>.textconv` command turns the content into
+// the text that's diffed: https://git-scm.com/docs/gitattributes#_performing_text_diffs_of_binary_files.
+// files without the attribute are binary when their content looks like it
+#[derive(Debug)]
+pub struct DiffDrivers {
+    root: PathBuf,
+    attributes: AttributeMatcher,
+    config: Config,
+}
+
+impl DiffDrivers {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        Ok(Self {
+            attributes: AttributeMatcher::new(&root)
+                .with_context(|| "DiffDrivers::new: failed to read attributes")?,
+            config: Config::load(&root)
+                .with_context(|| "DiffDrivers::new: failed to read config")?,
+            root,
+        })
+    }
+
+    // the content of the file at `path` (relative to the root) as it's diffed, and whether it's
+    // binary, which only gets it reported as differing
+    pub fn prepare(&self, path: &str, content: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        let attribute = self.attributes.get(path, "diff").with_context(|| {
+            format!("DiffDrivers::prepare: failed to read attributes of {path}")
+        })?;
+        match attribute {
+            Some(AttributeValue::Unset) => Ok((content, true)),
+            Some(AttributeValue::Set) => Ok((content, false)),
+            Some(AttributeValue::Value(driver)) => {
+                match self.config.get(&format!("diff.{driver}.textconv")) {
+                    // the converted content is text, whatever it looks like
+                    Some(command) => {
+                        Ok((run_textconv(&self.root, command, path, &content)?, false))
+                    }
+                    None => {
+                        let binary = is_binary(&content);
+                        Ok((content, binary))
+                    }
+                }
+            }
+            None => {
+                let binary = is_binary(&content);
+                Ok((content, binary))
+            }
+        }
+    }
+}
+
+// the output of the shell command `command` given a temporary file holding `content` as its
+// argument, like git does. the file keeps the name of `path`, for commands that go by the
+// extension
+fn run_textconv(root: &Path, command: &str, path: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let file = env::temp_dir().join(format!(
+        "git-textconv-{}-{}-{name}",
+        std::process::id(),
+        TEXTCONV_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&file, content).with_context(|| format!("run_textconv: failed to write {file:?}"))?;
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} \"$@\""))
+        .arg(command)
+        .arg(&file)
+        .current_dir(root)
+        .output();
+    let _ = fs::remove_file(&file);
+    let output = output.with_context(|| format!("run_textconv: failed to run {command:?}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "run_textconv: {command:?} exited with {} on {path}",
+            output.status
+        );
+    }
+    Ok(output.stdout)
+}
@@ -1,101 +1,301 @@
-use crate::git::{
-    git_blob::Blob,
-    git_object_trait::GitObject,
-    git_tree::{Tree, TreeEntry},
+use crate::{
+    git::{
+        compression::loose_compression,
+        config::Config,
+        filter::Filters,
+        git_blob::Blob,
+        git_object_trait::{write_encoded_object, GitObject},
+        git_tree::{Tree, TreeEntry},
+        progress::Progress,
+    },
+    utils::helpers::get_object_file_path,
 };
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use flate2::Compression;
+use rayon::prelude::*;
 use std::{
     fs,
+    os::unix::ffi::OsStringExt,
     path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
 };
 
+// a directory of the work tree, turned into tree objects one directory at a time: a directory's
+// files are hashed and its subdirectories written before its tree is made, so each thread only
+// holds the entries of the directories on its way down. files and subdirectories are handled in
+// parallel, their entries sorted by Tree::new whatever order they finish in
 #[derive(Debug, Clone)]
 pub struct FileTree {
-    entries: Vec<FileTreeNode>,
+    root: PathBuf,
+    // where the root lies below the work tree, with a trailing `/` unless it's the work tree
+    // itself. filters are looked up by the files' paths from there
+    prefix: String,
 }
 
 #[derive(Debug, Clone)]
 enum FileTreeNode {
     File(PathBuf),
-    Directory(PathBuf, FileTree),
+    // stored as a blob holding the path it points at
+    Symlink(PathBuf),
+    Directory(PathBuf),
+    // a fifo, socket or device, which git can't store
+    Special(PathBuf),
 }
 
 impl FileTree {
     pub fn new<T: AsRef<Path>>(path: T) -> Result<Self> {
-        let path = path.as_ref();
-        let mut entries = vec![];
-
-        let dir_entries = path
-            .read_dir()
-            .with_context(|| format!("failed to get directory entries at {path:?}"))?
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
-            .with_context(|| format!("failed to read directory entry at {path:?}"))?;
-
-        for entry in dir_entries {
-            let path = entry.path();
-
-            let file_name = path
-                .file_name()
-                .with_context(|| format!("failed to get file name from {path:?}"))?;
-
-            if file_name == ".git" {
-                continue;
-            }
-
-            if path.is_file() {
-                entries.push(FileTreeNode::File(path));
-            } else if path.is_dir() {
-                let subtree = FileTree::new(&path)?;
-                entries.push(FileTreeNode::Directory(path, subtree));
-            }
+        let root = path.as_ref().to_path_buf();
+        if !root.is_dir() {
+            bail!("FileTree::new: {root:?} is not a directory");
         }
+        Ok(Self {
+            root,
+            prefix: String::new(),
+        })
+    }
 
-        Ok(Self { entries })
+    // a tree of the directory at `prefix` below the work tree
+    pub fn with_prefix(self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/');
+        Self {
+            prefix: match prefix {
+                "" => String::new(),
+                _ => format!("{prefix}/"),
+            },
+            ..self
+        }
     }
 
-    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<Tree> {
-        self.parse_tree_object(&Some(path))
+    // writes the blobs and trees to the object store at `path`, reporting every file written
+    // to `progress`. with `filters`, files are stored as their clean filter makes them
+    pub fn write<P: AsRef<Path>>(
+        &self,
+        path: P,
+        filters: Option<&Filters>,
+        progress: &mut (dyn Progress + Send),
+    ) -> Result<(Tree, WriteStats)> {
+        let path = path.as_ref();
+        let level = Config::load(path)
+            .and_then(|config| loose_compression(&config))
+            .with_context(|| "failed to read compression level")?;
+        let files = self.file_count()?;
+        progress.update(0, files);
+        let writer = ObjectWriter {
+            path,
+            level,
+            state: Mutex::new(WriterState {
+                progress,
+                files,
+                files_done: 0,
+                stats: WriteStats::default(),
+            }),
+        };
+        let tree = directory_tree(&self.root, &self.prefix, filters, Some(&writer))?;
+        let state = writer.state.into_inner().expect("a writer thread panicked");
+        state.progress.finish();
+        Ok((tree, state.stats))
     }
 
-    pub fn tree_object(&self) -> Result<Tree> {
-        self.parse_tree_object::<&str>(&None)
+    pub fn tree_object(&self, filters: Option<&Filters>) -> Result<Tree> {
+        directory_tree(&self.root, &self.prefix, filters, None)
     }
 
-    fn parse_tree_object<P: AsRef<Path>>(&self, parent_path: &Option<P>) -> Result<Tree> {
-        let entries = self
-            .entries
-            .iter()
-            .map(|entry| match entry {
-                FileTreeNode::File(path) => {
-                    let content = fs::read(path)
-                        .with_context(|| format!("failed to read file at {path:?}"))?;
-                    let blob = Blob::new(content);
-                    if let Some(parent_path) = parent_path {
-                        blob.write(parent_path).with_context(|| {
-                            format!("failed to write object file for blob from {path:?}")
-                        })?;
-                    }
-                    anyhow::Ok(TreeEntry::new(&blob, path).with_context(|| {
-                        format!("failed to create tree entry for file at {path:?}")
-                    })?)
-                }
-                FileTreeNode::Directory(path, tree) => {
-                    let tree_object = tree.parse_tree_object(parent_path)?;
-                    anyhow::Ok(TreeEntry::new(&tree_object, path).with_context(|| {
-                        format!("failed to create tree entry for directory at {path:?}")
-                    })?)
+    // the files in the tree and all of its subtrees
+    pub fn file_count(&self) -> Result<usize> {
+        fn count(path: &Path) -> Result<usize> {
+            directory_entries(path)?
+                .iter()
+                .map(|entry| match entry {
+                    FileTreeNode::File(_) | FileTreeNode::Symlink(_) => Ok(1),
+                    FileTreeNode::Directory(path) => count(path),
+                    FileTreeNode::Special(_) => Ok(0),
+                })
+                .sum()
+        }
+        count(&self.root)
+    }
+}
+
+// the files, symlinks, subdirectories and special files directly in the directory at `path`
+fn directory_entries(path: &Path) -> Result<Vec<FileTreeNode>> {
+    let dir_entries = path
+        .read_dir()
+        .with_context(|| format!("failed to get directory entries at {path:?}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to read directory entry at {path:?}"))?;
+
+    let mut entries = vec![];
+    for entry in dir_entries {
+        let path = entry.path();
+
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("failed to get file name from {path:?}"))?;
+
+        if file_name == ".git" {
+            continue;
+        }
+
+        // the entry itself, a symlink isn't followed to what it points at
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("failed to get file type of {path:?}"))?;
+        if file_type.is_symlink() {
+            entries.push(FileTreeNode::Symlink(path));
+        } else if file_type.is_file() {
+            entries.push(FileTreeNode::File(path));
+        } else if file_type.is_dir() {
+            entries.push(FileTreeNode::Directory(path));
+        } else {
+            entries.push(FileTreeNode::Special(path));
+        }
+    }
+    Ok(entries)
+}
+
+// the tree of the directory at `path`, `prefix` below the work tree, with its subdirectories'
+// trees built (and written) first. a subdirectory's tree is dropped as soon as its entry has been
+// made
+fn directory_tree(
+    path: &Path,
+    prefix: &str,
+    filters: Option<&Filters>,
+    writer: Option<&ObjectWriter>,
+) -> Result<Tree> {
+    let entries = directory_entries(path)?
+        .into_par_iter()
+        .map(|entry| node_entry(entry, prefix, filters, writer))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let tree_object = Tree::new(entries);
+
+    if let Some(writer) = writer {
+        writer
+            .write(&tree_object)
+            .with_context(|| "failed to write tree object")?;
+    }
+    Ok(tree_object)
+}
+
+// the tree entry for `node`, in the directory `prefix` below the work tree, with its objects
+// written. like git, fifos, sockets and devices are left out, though not silently
+fn node_entry(
+    node: FileTreeNode,
+    prefix: &str,
+    filters: Option<&Filters>,
+    writer: Option<&ObjectWriter>,
+) -> Result<Option<TreeEntry>> {
+    let relative_path = |path: &Path| -> Result<String> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("failed to get file name from {path:?}"))?;
+        Ok(format!("{prefix}{name}"))
+    };
+
+    let entry = match node {
+        // only a file's content goes through filters, a symlink's target is stored as it is
+        FileTreeNode::File(path) => {
+            let content =
+                fs::read(&path).with_context(|| format!("failed to read file at {path:?}"))?;
+            let content = match filters {
+                Some(filters) => {
+                    let relative_path = relative_path(&path)?;
+                    filters
+                        .clean(&relative_path, content)
+                        .with_context(|| format!("failed to filter {relative_path}"))?
                 }
-            })
-            .collect::<Result<Vec<_>>>()?;
+                None => content,
+            };
+            blob_entry(&path, content, writer)?
+        }
+        FileTreeNode::Symlink(path) => {
+            let target = fs::read_link(&path)
+                .with_context(|| format!("failed to read symlink at {path:?}"))?;
+            blob_entry(&path, target.into_os_string().into_vec(), writer)?
+        }
+        FileTreeNode::Directory(path) => {
+            let prefix = format!("{}/", relative_path(&path)?);
+            let tree_object = directory_tree(&path, &prefix, filters, writer)?;
+            TreeEntry::new(&tree_object, &path)
+                .with_context(|| format!("failed to create tree entry for directory at {path:?}"))?
+        }
+        FileTreeNode::Special(path) => {
+            eprintln!(
+                "warning: skipping {path:?}, which is not a regular file, symlink or directory"
+            );
+            return Ok(None);
+        }
+    };
+    Ok(Some(entry))
+}
 
-        let tree_object = Tree::new(entries);
+// the entry for the file or symlink at `path`, whose blob holds `content`
+fn blob_entry(path: &Path, content: Vec<u8>, writer: Option<&ObjectWriter>) -> Result<TreeEntry> {
+    let blob = Blob::new(content);
+    if let Some(writer) = writer {
+        writer
+            .write(&blob)
+            .with_context(|| format!("failed to write object file for blob from {path:?}"))?;
+        writer.file_done();
+    }
+    TreeEntry::new(&blob, path)
+        .with_context(|| format!("failed to create tree entry for file at {path:?}"))
+}
+
+// what writing a tree did to the object store
+#[derive(Debug, Clone, Default)]
+pub struct WriteStats {
+    // objects that weren't in the store yet
+    pub written: usize,
+    // objects already there, with nothing to write
+    pub reused: usize,
+    // the compressed size of the written objects
+    pub bytes: u64,
+}
 
-        if let Some(parent_path) = parent_path {
-            tree_object
-                .write(parent_path)
-                .with_context(|| "failed to write tree object")?;
+// shared by the threads writing objects, which only lock it to count what they did
+struct ObjectWriter<'a> {
+    path: &'a Path,
+    level: Compression,
+    state: Mutex<WriterState<'a>>,
+}
+
+struct WriterState<'a> {
+    progress: &'a mut (dyn Progress + Send),
+    files: usize,
+    files_done: usize,
+    stats: WriteStats,
+}
+
+impl<'a> ObjectWriter<'a> {
+    fn write<T: GitObject>(&self, object: &T) -> Result<()> {
+        let sha = object.sha1()?;
+        if get_object_file_path(&sha.to_string(), self.path).exists() {
+            self.state().stats.reused += 1;
+            return Ok(());
+        }
+
+        let encoded = object.encode(self.level)?;
+        {
+            let mut state = self.state();
+            state.stats.written += 1;
+            state.stats.bytes += encoded.len() as u64;
         }
-        Ok(tree_object)
+        write_encoded_object(encoded, &sha, self.path)
+    }
+
+    fn file_done(&self) {
+        let mut state = self.state();
+        state.files_done += 1;
+        let (done, files) = (state.files_done, state.files);
+        state.progress.update(done, files);
+    }
+
+    fn state(&self) -> MutexGuard<'_, WriterState<'a>> {
+        self.state.lock().expect("a writer thread panicked")
     }
 }
@@ -0,0 +1,125 @@
+use crate::git::{
+    attributes::{AttributeMatcher, AttributeValue},
+    config::Config,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+// the clean and smudge commands of the filter drivers `.gitattributes` assigns with
+// `filter=<name>`, configured as `filter.<name>.clean` and `filter.<name>.smudge`:
+// https://git-scm.com/docs/gitattributes#_filter. files without a filter, or whose filter has no
+// command for the direction, pass through unchanged
+#[derive(Debug)]
+pub struct Filters {
+    root: PathBuf,
+    attributes: AttributeMatcher,
+    config: Config,
+}
+
+impl Filters {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        Ok(Self {
+            attributes: AttributeMatcher::new(&root)
+                .with_context(|| "Filters::new: failed to read attributes")?,
+            config: Config::load(&root).with_context(|| "Filters::new: failed to read config")?,
+            root,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // the content of the work tree file at `path` (relative to the root) as it's stored in a blob
+    pub fn clean(&self, path: &str, content: Vec<u8>) -> Result<Vec<u8>> {
+        self.apply(path, content, "clean")
+    }
+
+    // the content a blob checked out at `path` (relative to the root) gets in the work tree
+    pub fn smudge(&self, path: &str, content: Vec<u8>) -> Result<Vec<u8>> {
+        self.apply(path, content, "smudge")
+    }
+
+    // whether `path` has a filter driver at all. content of any other path is what it is, and can
+    // be hashed without being read into memory first
+    pub fn applies_to(&self, path: &str) -> Result<bool> {
+        Ok(self.driver(path, "applies_to")?.is_some())
+    }
+
+    fn driver(&self, path: &str, caller: &str) -> Result<Option<String>> {
+        let value = self
+            .attributes
+            .get(path, "filter")
+            .with_context(|| format!("Filters::{caller}: failed to read attributes of {path}"))?;
+        Ok(match value {
+            Some(AttributeValue::Value(driver)) => Some(driver),
+            _ => None,
+        })
+    }
+
+    fn apply(&self, path: &str, content: Vec<u8>, direction: &str) -> Result<Vec<u8>> {
+        let Some(driver) = self.driver(path, direction)? else {
+            return Ok(content);
+        };
+        let required = self.config.get(&format!("filter.{driver}.required")) == Some("true");
+        let Some(command) = self.config.get(&format!("filter.{driver}.{direction}")) else {
+            if required {
+                bail!("Filters::{direction}: {path}: required filter {driver:?} has no {direction} command");
+            }
+            return Ok(content);
+        };
+
+        match run_filter(&self.root, command, path, &content) {
+            Ok(filtered) => Ok(filtered),
+            Err(err) if required => Err(err).with_context(|| {
+                format!("Filters::{direction}: required filter {driver:?} failed on {path}")
+            }),
+            // like git, a filter that isn't required may fail and leaves the content as it is
+            Err(err) => {
+                eprintln!("warning: {direction} filter {driver:?} failed on {path}: {err:#}");
+                Ok(content)
+            }
+        }
+    }
+}
+
+// pipes `content` through the shell command `command`, run in the work tree with `%f` standing
+// for the file's path
+fn run_filter(root: &Path, command: &str, path: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let command = command.replace("%f", &shell_quote(path));
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("run_filter: failed to run {command:?}"))?;
+
+    // written from another thread, a filter may not read all of its input before writing
+    let mut stdin = child.stdin.take().expect("the filter's stdin is piped");
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(content));
+        let output = child.wait_with_output();
+        // a filter that exits without reading everything closes the pipe early, only its exit
+        // status tells whether that's a failure
+        let _ = writer.join();
+        output
+    })
+    .with_context(|| format!("run_filter: failed to wait for {command:?}"))?;
+
+    if !output.status.success() {
+        bail!("run_filter: {command:?} exited with {}", output.status);
+    }
+    Ok(output.stdout)
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
@@ -1,6 +1,15 @@
-use crate::git::git_object_trait::{GitObject, GitObjectType};
+use crate::git::{
+    any_git_object::Sha,
+    git_object_trait::{hash_object_stream, GitObject, GitObjectType},
+};
 use anyhow::Result;
 
+// e69de29bb2d1d6434b8b29ae775ad8c2e48c5391
+pub const EMPTY_BLOB_SHA: Sha = Sha([
+    0xe6, 0x9d, 0xe2, 0x9b, 0xb2, 0xd1, 0xd6, 0x43, 0x4b, 0x8b, 0x29, 0xae, 0x77, 0x5a, 0xd8, 0xc2,
+    0xe4, 0x8c, 0x53, 0x91,
+]);
+
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct BlobContent(pub Vec<u8>);
@@ -18,9 +27,9 @@ impl From<Vec<u8>> for BlobContent {
         Self(value)
     }
 }
-impl Into<Vec<u8>> for BlobContent {
-    fn into(self) -> Vec<u8> {
-        self.0
+impl From<BlobContent> for Vec<u8> {
+    fn from(val: BlobContent) -> Self {
+        val.0
     }
 }
 impl AsRef<Vec<u8>> for BlobContent {
@@ -40,8 +49,11 @@ impl Blob {
             content: content.into(),
         }
     }
+    pub fn empty() -> Self {
+        Self::new(vec![])
+    }
     pub fn content(&self) -> &Vec<u8> {
-        &self.content.as_ref()
+        self.content.as_ref()
     }
 }
 
@@ -54,6 +66,15 @@ impl GitObject for Blob {
         Ok(self.content.clone().into())
     }
 
+    // hashed straight from the content, encode_body would copy it first
+    fn sha1(&self) -> Result<Sha> {
+        hash_object_stream(
+            Self::get_type().as_ref(),
+            self.content.0.len() as u64,
+            self.content.0.as_slice(),
+        )
+    }
+
     fn decode_body(raw_content: Vec<u8>) -> Result<Self> {
         Ok(Blob {
             content: raw_content.to_vec().into(),
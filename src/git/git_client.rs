@@ -1,15 +1,34 @@
-use crate::git::{
-    any_git_object::{AnyGitObject, Sha},
-    commits::Commit,
-    compression::decompress_slice,
-    git_blob::{Blob, BlobContent},
-    git_object_trait::GitObject,
-    git_tree::{FileMode, Tree},
+use crate::{
+    git::{
+        any_git_object::{AnyGitObject, Sha},
+        compression::{loose_compression, pack_compression},
+        config::Config,
+        filter::Filters,
+        git_object_trait::sync_objects,
+        git_tree::{FileMode, Tree},
+        object_store::ObjectStore,
+        packfile::{big_file_threshold, Packfile, DEFAULT_MAX_DELTA_DEPTH},
+        reachability::{is_ancestor, reachable_objects, reachable_objects_excluding},
+        refs::RefStore,
+        refspec::Refspec,
+        tree_walk::flatten_tree,
+    },
+    utils::helpers::git_dir,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
 use reqwest::{Client, Response, Url};
-use std::{collections::HashMap, fmt::Debug, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use strum::EnumTryAs;
 use tokio;
 use url::ParseError;
@@ -17,6 +36,14 @@ use url::ParseError;
 pub struct GitClient {
     url: Url,
     client: Client,
+    max_delta_depth: usize,
+    // set from outside, e.g. on Ctrl-C, to make a running clone stop at the next step
+    cancel: Arc<AtomicBool>,
+    // with fsync_objects, clone syncs the objects it writes to disk after every this many
+    write_batch_size: usize,
+    // off by default: clone only syncs the object directories once at the end, a clone cut short
+    // by a crash is thrown away anyway. on, every object file is synced as well
+    fsync_objects: bool,
 }
 
 impl GitClient {
@@ -25,8 +52,8 @@ impl GitClient {
             url.to_string()
         } else if url.ends_with(".git") {
             format!("{}/", url)
-        } else if url.ends_with("/") {
-            format!("{}.git/", &url[..url.len() - 1])
+        } else if let Some(url) = url.strip_suffix('/') {
+            format!("{}.git/", url)
         } else {
             format!("{}.git/", url)
         };
@@ -37,15 +64,60 @@ impl GitClient {
             format!("{}/", url)
         };
 
-        let url = Url::parse(&url)
-            .map_err(|err| anyhow!(err).context(format!("failed to create GitClient")))?;
+        let url =
+            Url::parse(&url).map_err(|err| anyhow!(err).context("failed to create GitClient"))?;
 
         Ok(Self {
             url,
             client: Client::new(),
+            max_delta_depth: DEFAULT_MAX_DELTA_DEPTH,
+            cancel: Arc::default(),
+            write_batch_size: DEFAULT_WRITE_BATCH_SIZE,
+            fsync_objects: false,
         })
     }
 
+    pub fn with_max_delta_depth(mut self, max_delta_depth: usize) -> Self {
+        self.max_delta_depth = max_delta_depth;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_write_batch_size(mut self, write_batch_size: usize) -> Self {
+        self.write_batch_size = write_batch_size.max(1);
+        self
+    }
+
+    pub fn with_fsync(mut self) -> Self {
+        self.fsync_objects = true;
+        self
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(anyhow!(Cancelled));
+        }
+        Ok(())
+    }
+
+    // runs `future` unless the client is cancelled first, so a slow server doesn't hold up the
+    // cancellation
+    async fn unless_cancelled<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let cancelled = async {
+            while !self.cancel.load(Ordering::Relaxed) {
+                tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+            }
+        };
+        tokio::select! {
+            result = future => result,
+            _ = cancelled => Err(anyhow!(Cancelled)),
+        }
+    }
+
     async fn send_pkt_line_request<T: IntoIterator<Item = PktLine>>(
         &self,
         content: T,
@@ -53,14 +125,13 @@ impl GitClient {
     ) -> Result<Response> {
         let url = self
             .url
-            .join("git-upload-pack")
+            .join(UPLOAD_PACK_SERVICE)
             .with_context(|| "send_pkt_line_request failed: failed to get upload pack URL")?;
 
         let content = content
             .into_iter()
             .chain(std::iter::once(last_pkt_line.unwrap_or(PktLine::FlushPkt)))
-            .map(|line| line.to_bytes())
-            .flatten()
+            .flat_map(|line| line.to_bytes())
             .collect::<Vec<_>>();
 
         let response = self
@@ -87,30 +158,12 @@ impl GitClient {
             anyhow!("send_want_request failed: wants must contain at least one element")
         })?;
 
-        let first_line = if let Some(GitCapabilities(capabilities)) = capabilities {
-            PktLine::StringDataPkt(format!(
-                "{} {}",
-                first_want.to_string(),
-                capabilities.join(" ")
-            ))
-        } else {
-            first_want.to_pkt_line()
-        };
-
-        let content = std::iter::once(first_line)
+        let content = std::iter::once(first_want_line(first_want, capabilities))
             .chain(wants.map(|want| want.to_pkt_line()))
             .chain(std::iter::once(PktLine::FlushPkt))
-            .chain(
-                haves
-                    .map(|haves| {
-                        haves
-                            .into_iter()
-                            .map(|have| have.to_pkt_line())
-                            .chain(std::iter::once(PktLine::FlushPkt))
-                    })
-                    .into_iter()
-                    .flatten(),
-            )
+            // the haves are terminated by `done` or the flush send_pkt_line_request appends, an
+            // extra flush here would end the stateless exchange before `done` is read
+            .chain(haves.into_iter().flatten().map(|have| have.to_pkt_line()))
             .collect::<Vec<_>>();
         let last_pkt_line = if is_done {
             Some(PktLine::StringDataPkt("done".to_string()))
@@ -133,118 +186,57 @@ impl GitClient {
             .with_context(|| "send_want_request failed: failed to get response bytes")
     }
 
-    pub async fn clone<P: AsRef<Path>>(&self, path: &P) -> Result<()> {
+    // clones into `path`, checking out the server's HEAD branch and calling the server `remote`.
+    // with `single_branch` only that branch is fetched and tracked, otherwise every branch the
+    // server has, each one sent as a want of its own. tags come along when they point into the
+    // fetched history
+    pub async fn clone<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        remote: &str,
+        single_branch: bool,
+        quiet: bool,
+    ) -> Result<()> {
         let ref_discovery = self
-            .ref_discovery()
+            .unless_cancelled(self.ref_discovery())
             .await
             .with_context(|| "GitClient::clone: failed to fetch refs")?;
-
-        let mut want_response = self
-            .send_want_request(
-                vec![WantPkt {
-                    object_id: ref_discovery.head_object_id.clone(),
-                }],
-                None,
-                None,
-                true,
-            )
-            .await
-            .with_context(|| "GitClient::clone: failed to send want request")?
-            .into_iter();
-
-        let line = PktLine::read(want_response.by_ref())
-            .with_context(|| "GitClient::clone: failed to read pkt line")?;
-
-        // seems like the server sends NAK if there are no common objects, which will always be the
-        // case during a clone operation: https://git-scm.com/docs/pack-protocol#_packfile_negotiation
-        assert!(matches!(line, PktLine::StringDataPkt(str) if str == "NAK"));
-        let packfile = Packfile::read(want_response.collect::<Vec<_>>())
-            .with_context(|| "GitClient::clone: failed to read packfile")?;
-
-        // TODO: validate checksum
-        let (deltas, git_objects): (Vec<_>, Vec<_>) =
-            packfile.chunks.into_iter().partition(|chunk| match chunk {
-                PackfileObject::ObjRefDelta { .. } => true,
-                PackfileObject::Blob(_) | PackfileObject::Commit(_) | PackfileObject::Tree(_) => {
-                    false
-                }
-            });
-
-        let mut object_map = git_objects
-          .into_iter()
-          .map(|chunk| {
-              (|| -> Result<_> {
-                  Ok(match chunk {
-                      PackfileObject::Commit(commit) => {
-                          (commit.sha1()?, AnyGitObject::Commit(commit))
-                      }
-                      PackfileObject::Tree(tree) => (tree.sha1()?, AnyGitObject::Tree(tree)),
-                      PackfileObject::Blob(blob) => (blob.sha1()?, AnyGitObject::Blob(blob)),
-                      other => unreachable!("GitClient::clone: unexpected object type: git_objects should onlt contain git objects, but got {other:?}"),
-                  })
-              })()
-              .with_context(|| "GitClient::clone: failed to compute sha for git object")
-          })
-          .collect::<Result<HashMap<_, _>>>()
-          .with_context(|| "GitClient::clone: failed to create object map")?;
-
-        let deltas = deltas.into_iter().map(|obj| match obj {
-          PackfileObject::ObjRefDelta(delta) => (delta.obj_name.clone(), delta),
-          other => unreachable!("GitClient::clone: unexpected object type: deltas should only contain deltas, but got {other:?}"),
-      });
-
-        // println!("\n\nApplying deltas");
-        for (obj_name, delta) in deltas {
-            let obj: &AnyGitObject = object_map.get(&obj_name).ok_or_else(|| {
-                anyhow!("GitClient::clone: failed to find object with name {obj_name:?}")
-            })?;
-
-            let encoded_obj = obj
-                .encode_body()
-                .with_context(|| "GitClient::clone: failed to encode object body")?;
-
-            assert_eq!(
-                encoded_obj.len(),
-                delta.base_obj_size,
-                "GitClient::clone: object size doesn't match delta base object size"
-            );
-
-            let output = DeltaInstruction::apply(&delta.instructions, &encoded_obj);
-
-            let new_obj = match obj {
-                AnyGitObject::Commit(_) => Commit::decode_body(output).map(AnyGitObject::Commit),
-                AnyGitObject::Tree(_) => Tree::decode_body(output).map(AnyGitObject::Tree),
-                AnyGitObject::Blob(_) => Blob::decode_body(output).map(AnyGitObject::Blob),
+        let (head_ref, head_sha) = ref_discovery
+            .head()
+            .with_context(|| "GitClient::clone: failed to find HEAD's branch")?;
+        let branch = head_ref.strip_prefix("refs/heads/").unwrap_or(head_ref);
+
+        let advertised = ref_discovery.refs.iter().collect::<BTreeMap<_, _>>();
+        let branches = match single_branch {
+            true => BTreeMap::from([(branch, head_sha)]),
+            false => advertised
+                .iter()
+                .filter_map(|(name, sha)| Some((name.strip_prefix("refs/heads/")?, *sha)))
+                .collect(),
+        };
+        let mut wants = vec![];
+        for sha in branches.values() {
+            if !wants.contains(*sha) {
+                wants.push(Sha::clone(sha));
             }
-            .with_context(|| "GitClient::clone: failed to decode object after delta")?;
-
-            assert_eq!(
-                new_obj.encode_body()?.len(),
-                delta.target_obj_size,
-                "GitClient::clone: object size doesn't match delta target object size"
-            );
-
-            object_map.insert(
-                new_obj.sha1().with_context(|| {
-                    "GitClient::clone: failed to compute sha for object after delta"
-                })?,
-                new_obj,
-            );
         }
+        let want_count = wants.len();
+
+        let object_map = self
+            .unless_cancelled(self.fetch_pack(&ref_discovery, wants, quiet))
+            .await
+            .with_context(|| "GitClient::clone: failed to fetch pack")?;
 
         let head = object_map
-            .get(&ref_discovery.head_object_id)
+            .get(head_sha)
             .ok_or_else(|| {
-                anyhow!(
-                    "GitClient::clone: failed to find HEAD object with SHA {:?}",
-                    ref_discovery.head_object_id
-                )
+                anyhow!("GitClient::clone: failed to find HEAD object with SHA {head_sha:?}")
             })?
             .try_as_commit_ref()
             .ok_or_else(|| {
                 anyhow!(
                     "GitClient::clone: expected HEAD object to be a commit, but got {:?}",
-                    object_map.get(&ref_discovery.head_object_id)
+                    object_map.get(head_sha)
                 )
             })?;
 
@@ -264,34 +256,593 @@ impl GitClient {
                 )
             })?;
 
-        tokio::fs::create_dir(&path.as_ref().join(".git"))
+        tokio::fs::create_dir(git_dir(path.as_ref()))
             .await
             .with_context(|| "GitClient::clone: failed to create .git directory")?;
+        // the remote and the branch following it, so fetch knows where to fetch from later. a
+        // single branch clone keeps fetching just that branch
+        let fetch_refspec = match single_branch {
+            true => format!("+refs/heads/{branch}:refs/remotes/{remote}/{branch}"),
+            false => format!("+refs/heads/*:refs/remotes/{remote}/*"),
+        };
+        tokio::fs::write(
+            git_dir(path.as_ref()).join("config"),
+            format!(
+                "[core]\n\trepositoryformatversion = 0\n\tbare = false\n\
+                 [remote \"{remote}\"]\n\turl = {}\n\tfetch = {fetch_refspec}\n\
+                 [branch \"{branch}\"]\n\tremote = {remote}\n\tmerge = {head_ref}\n",
+                self.url
+            ),
+        )
+        .await
+        .with_context(|| "GitClient::clone: failed to write config")?;
+        tokio::fs::write(
+            git_dir(path.as_ref()).join("HEAD"),
+            format!("ref: {head_ref}\n"),
+        )
+        .await
+        .with_context(|| "GitClient::clone: failed to write HEAD")?;
+
+        let level = Config::load(path)
+            .and_then(|config| loose_compression(&config))
+            .with_context(|| "GitClient::clone: failed to read compression level")?;
+        let mut batch = vec![];
+        for (sha, obj) in &object_map {
+            self.check_cancelled()?;
+            obj.write_with_level(&path, level).with_context(|| {
+                format!("GitClient::clone: failed to write object to filesystem {obj:#?}")
+            })?;
+            batch.push(sha.clone());
+            if batch.len() == self.write_batch_size && self.fsync_objects {
+                sync_objects(&batch, path, true)
+                    .with_context(|| "GitClient::clone: failed to sync objects")?;
+                batch.clear();
+            }
+        }
+        if self.fsync_objects {
+            sync_objects(&batch, path, true)
+        } else {
+            sync_objects(&object_map.keys().cloned().collect::<Vec<_>>(), path, false)
+        }
+        .with_context(|| "GitClient::clone: failed to sync objects")?;
+        self.check_cancelled()?;
+
+        // the checked out branch, a remote-tracking ref for every fetched branch, and the tags
+        // whose objects came with them
+        let refs = RefStore::new(path);
+        let message = format!("clone: from {}", self.url);
+        refs.update(head_ref, head_sha, &message)
+            .with_context(|| format!("GitClient::clone: failed to write {head_ref}"))?;
+        for (name, sha) in &branches {
+            refs.update(&format!("refs/remotes/{remote}/{name}"), sha, &message)
+                .with_context(|| format!("GitClient::clone: failed to write {remote}/{name}"))?;
+        }
+        tokio::fs::write(
+            git_dir(path.as_ref()).join(format!("refs/remotes/{remote}/HEAD")),
+            format!("ref: refs/remotes/{remote}/{branch}\n"),
+        )
+        .await
+        .with_context(|| format!("GitClient::clone: failed to write {remote}/HEAD"))?;
+        for (name, sha) in advertised {
+            if name.starts_with("refs/tags/")
+                && !name.ends_with("^{}")
+                && object_map.contains_key(sha)
+            {
+                refs.update(name, sha, &message)
+                    .with_context(|| format!("GitClient::clone: failed to write {name}"))?;
+            }
+        }
+
+        self.check_cancelled()?;
+        let mut missing = vec![];
+        let filters = Filters::new(path)
+            .with_context(|| "GitClient::clone: failed to set up content filters")?;
+        GitClient::write_tree(path, tree, &object_map, &filters, &mut missing)
+            .with_context(|| "GitClient::clone: failed to write tree object to filesystem")?;
+        // a filtered pack leaves blobs out, the files needing them just aren't checked out
+        if !missing.is_empty() {
+            eprintln!(
+                "warning: skipped {} files whose blobs weren't fetched:",
+                missing.len()
+            );
+            for file in &missing {
+                eprintln!("\t{}", file.strip_prefix(path).unwrap_or(file).display());
+            }
+        }
+
+        if !quiet {
+            // a single branch clone asks for one commit, the others for every distinct branch tip
+            println!(
+                "received {} objects for {want_count} wanted commits ({})",
+                object_map.len(),
+                match single_branch {
+                    true => format!("single branch {branch}"),
+                    false => format!("{} branches", branches.len()),
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    // sets up a bare repository at `path` holding every ref the server advertises, under the same
+    // names, and the objects they need. `path` itself is the git directory
+    pub async fn mirror<P: AsRef<Path>>(&self, path: &P, quiet: bool) -> Result<()> {
+        let path = path.as_ref();
+        let ref_discovery = self
+            .unless_cancelled(self.ref_discovery())
+            .await
+            .with_context(|| "GitClient::mirror: failed to fetch refs")?;
 
+        let mut wants = vec![];
+        for (_, sha) in ref_discovery
+            .refs
+            .iter()
+            .filter(|(name, _)| !name.ends_with("^{}"))
+        {
+            if !wants.contains(sha) {
+                wants.push(sha.clone());
+            }
+        }
+        let object_map = self
+            .unless_cancelled(self.fetch_pack(&ref_discovery, wants, quiet))
+            .await
+            .with_context(|| "GitClient::mirror: failed to fetch pack")?;
+
+        for dir in ["objects", "refs"] {
+            tokio::fs::create_dir_all(path.join(dir))
+                .await
+                .with_context(|| format!("GitClient::mirror: failed to create {dir} directory"))?;
+        }
+        tokio::fs::write(
+            path.join("config"),
+            format!(
+                "[core]\n\trepositoryformatversion = 0\n\tbare = true\n\
+                 [remote \"origin\"]\n\turl = {}\n\tfetch = +refs/*:refs/*\n\tmirror = true\n",
+                self.url
+            ),
+        )
+        .await
+        .with_context(|| "GitClient::mirror: failed to write config")?;
+        // HEAD is what makes `path` recognizable as a git directory, write_refs points it at the
+        // remote's branch right after
+        tokio::fs::write(path.join("HEAD"), "ref: refs/heads/main\n")
+            .await
+            .with_context(|| "GitClient::mirror: failed to write HEAD")?;
+
+        let level = Config::load(path)
+            .and_then(|config| loose_compression(&config))
+            .with_context(|| "GitClient::mirror: failed to read compression level")?;
         for obj in object_map.values() {
-            obj.write(&path).with_context(|| {
-                format!("GitClient::clone: failed to write object to filesystem {obj:#?}")
+            self.check_cancelled()?;
+            obj.write_with_level(&path, level).with_context(|| {
+                format!("GitClient::mirror: failed to write object to filesystem {obj:#?}")
             })?;
         }
+        self.check_cancelled()?;
 
         ref_discovery
             .write(&path)
             .await
-            .with_context(|| "GitClient::clone: failed to write ref discovery to filesystem")?;
+            .with_context(|| "GitClient::mirror: failed to write ref discovery to filesystem")?;
 
-        GitClient::write_tree(path, tree, &object_map)
-            .with_context(|| "GitClient::clone: failed to write tree object to filesystem")?;
+        Ok(())
+    }
+
+    // asks the server for `wants` with nothing in common and returns the objects of the pack it
+    // answers with
+    async fn fetch_pack(
+        &self,
+        ref_discovery: &GitRefDiscoveryResponse,
+        wants: Vec<Sha>,
+        quiet: bool,
+    ) -> Result<HashMap<Sha, AnyGitObject>> {
+        let capabilities = fetch_pack_capabilities(&ref_discovery.capabilities, quiet);
+
+        let mut want_response = self
+            .send_want_request(
+                wants
+                    .into_iter()
+                    .map(|object_id| WantPkt { object_id })
+                    .collect(),
+                None,
+                Some(capabilities),
+                true,
+            )
+            .await
+            .with_context(|| "GitClient::fetch_pack: failed to send want request")?
+            .into_iter();
+
+        let line = PktLine::read(want_response.by_ref())
+            .and_then(PktLine::error_for_err_line)
+            .with_context(|| "GitClient::fetch_pack: failed to read pkt line")?;
+
+        // seems like the server sends NAK if there are no common objects, which will always be the
+        // case without haves: https://git-scm.com/docs/pack-protocol#_packfile_negotiation
+        if !matches!(&line, PktLine::StringDataPkt(str) if str == "NAK") {
+            bail!("GitClient::fetch_pack: expected NAK, got {line:?}");
+        }
+        let packfile = Packfile::read(want_response.collect::<Vec<_>>())
+            .with_context(|| "GitClient::fetch_pack: failed to read packfile")?;
+
+        packfile
+            .unpack(self.max_delta_depth)
+            .with_context(|| "GitClient::fetch_pack: failed to unpack packfile")
+    }
+
+    // updates the local refs `refspecs` map the server's refs to, downloading whatever objects
+    // they need. an update that isn't a fast-forward needs a `+` refspec and is rejected
+    // otherwise. with `prune`, refs the refspecs write to but no longer get anything are deleted
+    pub async fn fetch<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        remote: &str,
+        refspecs: &[Refspec],
+        prune: bool,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let store = ObjectStore::new(path);
+        let refs = RefStore::new(path);
+
+        let ref_discovery = self
+            .ref_discovery()
+            .await
+            .with_context(|| "GitClient::fetch: failed to fetch refs")?;
+
+        // every local ref to update, with the remote ref it comes from and whether it's forced.
+        // a later refspec mapping to the same ref wins
+        let mut updates = BTreeMap::new();
+        for refspec in refspecs {
+            for (name, sha, dst) in refspec.expand(&ref_discovery.refs) {
+                updates.insert(dst, (name, sha, refspec.force));
+            }
+        }
+
+        let mut wants = vec![];
+        for (_, sha, _) in updates.values() {
+            if !store.contains(sha)? && !wants.contains(*sha) {
+                wants.push(Sha::clone(sha));
+            }
+        }
+
+        if !wants.is_empty() {
+            let haves = refs
+                .list()
+                .with_context(|| "GitClient::fetch: failed to list local refs")?
+                .into_values()
+                .filter(|sha| store.contains(sha).unwrap_or(false))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .map(|object_id| HavePkt { object_id })
+                .collect::<Vec<_>>();
+
+            let capabilities = ref_discovery.capabilities.select([
+                NO_PROGRESS_CAPABILITY,
+                INCLUDE_TAG_CAPABILITY,
+                OFS_DELTA_CAPABILITY,
+            ]);
+
+            let want_response = self
+                .send_want_request(
+                    wants
+                        .into_iter()
+                        .map(|object_id| WantPkt { object_id })
+                        .collect(),
+                    (!haves.is_empty()).then_some(haves),
+                    Some(capabilities),
+                    true,
+                )
+                .await
+                .with_context(|| "GitClient::fetch: failed to send want request")?;
+
+            // without multi_ack the server ACKs the first common commit it finds and answers
+            // `done` with another ACK, or a NAK if there was none
+            let mut pack_data = &want_response[..];
+            while !pack_data.starts_with(b"PACK") {
+                let line = PktLine::read(pack_data.iter().copied())
+                    .and_then(PktLine::error_for_err_line)
+                    .with_context(|| "GitClient::fetch: failed to read pkt line")?;
+                if !matches!(&line, PktLine::StringDataPkt(str) if str == "NAK" || str.starts_with("ACK "))
+                {
+                    bail!("GitClient::fetch: expected ACK or NAK, got {line:?}");
+                }
+                pack_data = &pack_data[line.to_bytes().len()..];
+            }
+
+            let packfile = Packfile::read(pack_data.to_vec())
+                .with_context(|| "GitClient::fetch: failed to read packfile")?;
+            let level = Config::load(path)
+                .and_then(|config| loose_compression(&config))
+                .with_context(|| "GitClient::fetch: failed to read compression level")?;
+            for obj in packfile
+                .unpack(self.max_delta_depth)
+                .with_context(|| "GitClient::fetch: failed to unpack packfile")?
+                .values()
+            {
+                obj.write_with_level(&path, level).with_context(|| {
+                    format!("GitClient::fetch: failed to write object to filesystem {obj:#?}")
+                })?;
+            }
+        }
+
+        let local = refs
+            .list()
+            .with_context(|| "GitClient::fetch: failed to list local refs")?;
+
+        let mut rejected = 0;
+        for (name, (src, sha, force)) in &updates {
+            let (src, dst) = (short_ref_name(src), short_ref_name(name));
+            match local.get(name) {
+                Some(old) if old == *sha => continue,
+                Some(old) if is_ancestor(&store, old, sha)? => {
+                    refs.update(name, sha, "fetch: fast-forward")?;
+                    println!(
+                        "   {}..{}  {src} -> {dst}",
+                        &old.to_string()[..7],
+                        &sha.to_string()[..7]
+                    );
+                }
+                Some(old) if *force => {
+                    refs.update(name, sha, "fetch: forced-update")?;
+                    println!(
+                        " + {}...{} {src} -> {dst}  (forced update)",
+                        &old.to_string()[..7],
+                        &sha.to_string()[..7]
+                    );
+                }
+                Some(_) => {
+                    println!(" ! [rejected]        {src} -> {dst}  (non-fast-forward)");
+                    rejected += 1;
+                }
+                None => {
+                    refs.update(name, sha, "fetch: storing head")?;
+                    let kind = match name.starts_with("refs/tags/") {
+                        true => "[new tag]   ",
+                        false => "[new branch]",
+                    };
+                    println!(" * {kind}      {src} -> {dst}");
+                }
+            }
+        }
+
+        if prune {
+            // `<remote>/HEAD` mirrors the remote's default branch rather than a branch
+            let remote_head = format!("refs/remotes/{remote}/HEAD");
+            for name in local.keys().filter(|name| {
+                refspecs.iter().any(|refspec| refspec.dst_matches(name))
+                    && !updates.contains_key(*name)
+                    && **name != remote_head
+            }) {
+                refs.delete(name, "fetch: pruned")?;
+                println!(" - [deleted]         (none) -> {}", short_ref_name(name));
+            }
+        }
+
+        if rejected > 0 {
+            bail!("GitClient::fetch: {rejected} refs were rejected as non-fast-forward updates");
+        }
+        Ok(())
+    }
+
+    // updates `remote_ref` on the server to the commit `local_ref` points at. unless `force` is
+    // set, the server's current commit has to be an ancestor of ours. a `thin` pack leaves out the
+    // delta bases the server already has, unless it says it can't take such packs
+    pub async fn push<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        local_ref: &str,
+        remote_ref: &str,
+        remote: &str,
+        force: bool,
+        thin: bool,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let store = ObjectStore::new(path);
+        let refs = RefStore::new(path);
+
+        let new = refs
+            .resolve(local_ref)
+            .with_context(|| format!("GitClient::push: failed to resolve {local_ref}"))?
+            .ok_or_else(|| anyhow!("GitClient::push: {local_ref} does not match any ref"))?;
+
+        let (remote_refs, capabilities) = self
+            .discover_refs(RECEIVE_PACK_SERVICE)
+            .await
+            .with_context(|| "GitClient::push: failed to fetch refs")?;
+        let old = remote_refs.get(remote_ref);
+
+        if old == Some(&new) {
+            println!("Everything up-to-date");
+            return Ok(());
+        }
+
+        // a remote commit we don't have can't be an ancestor of anything local
+        let old_known = match old {
+            Some(old) => store.contains(old)?,
+            None => false,
+        };
+        let fast_forward = match old {
+            Some(old) => old_known && is_ancestor(&store, old, &new)?,
+            None => true,
+        };
+        if !fast_forward && !force {
+            bail!(
+                "GitClient::push: updates to {remote_ref} were rejected (non-fast-forward), fetch first or push with --force"
+            );
+        }
+
+        // everything reachable from a tip the server advertised is already there, so only the
+        // objects new since then go into the pack
+        let mut remote_tips = vec![];
+        for sha in remote_refs.values() {
+            if store.contains(sha)? {
+                remote_tips.push(sha.clone());
+            }
+        }
+        let remote_objects = reachable_objects(&store, &remote_tips)
+            .collect::<Result<HashSet<_>>>()
+            .with_context(|| "GitClient::push: failed to walk the remote's objects")?;
+        let objects =
+            reachable_objects_excluding(&store, std::slice::from_ref(&new), remote_objects)
+                .map(|sha| store.read(&sha?))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| "GitClient::push: failed to collect objects to send")?;
+
+        // blobs are deltified against other versions of their path, for a thin pack also against
+        // the ones in the commits the pushed history grows from
+        let thin = thin && !capabilities.contains(NO_THIN_CAPABILITY);
+        let mut paths = HashMap::new();
+        let mut edges = vec![];
+        let mut pushed_commits = HashSet::new();
+        for object in &objects {
+            if matches!(object, AnyGitObject::Commit(_)) {
+                pushed_commits.insert(object.sha1()?);
+            }
+        }
+        for object in &objects {
+            let AnyGitObject::Commit(commit) = object else {
+                continue;
+            };
+            for (name, (_, sha)) in flatten_tree(&store, &commit.tree_hash)
+                .with_context(|| "GitClient::push: failed to list the pushed files")?
+            {
+                paths.entry(sha).or_insert(name);
+            }
+            edges.extend(
+                commit
+                    .parent_hash
+                    .iter()
+                    .filter(|parent| !pushed_commits.contains(*parent))
+                    .cloned(),
+            );
+        }
+        let mut thin_bases = vec![];
+        if thin {
+            let pushed_paths = paths.values().collect::<HashSet<_>>();
+            let mut seen = HashSet::new();
+            for edge in edges {
+                let commit = store
+                    .read_commit(&edge)
+                    .with_context(|| format!("GitClient::push: failed to read commit {edge}"))?;
+                for (name, (_, sha)) in
+                    flatten_tree(&store, &commit.tree_hash).with_context(|| {
+                        format!("GitClient::push: failed to list the files of {edge}")
+                    })?
+                {
+                    if pushed_paths.contains(&name) && seen.insert(sha.clone()) {
+                        thin_bases.push((name, store.read(&sha)?));
+                    }
+                }
+            }
+        }
+
+        let config =
+            Config::load(path).with_context(|| "GitClient::push: failed to read config")?;
+        let level = pack_compression(&config)
+            .with_context(|| "GitClient::push: failed to read pack compression level")?;
+        let big_file_threshold = big_file_threshold(&config)
+            .with_context(|| "GitClient::push: failed to read core.bigFileThreshold")?;
+        let pack = Packfile::encode(&objects, &paths, &thin_bases, level, big_file_threshold)
+            .with_context(|| "GitClient::push: failed to generate packfile")?;
+
+        let zero = Sha([0; 20]);
+        let GitCapabilities(capabilities) =
+            capabilities.select([REPORT_STATUS_CAPABILITY, NO_PROGRESS_CAPABILITY]);
+        let command = format!(
+            "{} {new} {remote_ref}\0{}",
+            old.unwrap_or(&zero),
+            capabilities.join(" ")
+        );
+
+        let url = self
+            .url
+            .join(RECEIVE_PACK_SERVICE)
+            .with_context(|| "GitClient::push: failed to get receive pack URL")?;
+        let body = [PktLine::StringDataPkt(command), PktLine::FlushPkt]
+            .iter()
+            .flat_map(PktLine::to_bytes)
+            .chain(pack)
+            .collect::<Vec<_>>();
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", RECEIVE_PACK_CONTENT_TYPE)
+            .body(body)
+            .send()
+            .await
+            .with_context(|| "GitClient::push: failed to send request")?
+            .error_for_status()
+            .with_context(|| "GitClient::push: HTTP status")?
+            .bytes()
+            .await
+            .with_context(|| "GitClient::push: failed to get response bytes")?;
+
+        // report-status: `unpack ok` followed by `ok <ref>` or `ng <ref> <reason>` per command
+        if capabilities
+            .iter()
+            .any(|capability| capability == REPORT_STATUS_CAPABILITY)
+        {
+            for line in PktLine::read_many(response) {
+                let line = match line.and_then(PktLine::error_for_err_line)? {
+                    PktLine::FlushPkt => break,
+                    PktLine::StringDataPkt(str) => str,
+                    PktLine::BinaryDataPkt(data) => String::from_utf8_lossy(&data).into_owned(),
+                    // v2 framing has no place in a v0 report-status
+                    other => bail!("GitClient::push: unexpected {other:?} in report-status"),
+                };
+                if let Some(status) = line.strip_prefix("unpack ") {
+                    if status != "ok" {
+                        bail!("GitClient::push: remote failed to unpack: {status}");
+                    }
+                } else if let Some(rejection) = line.strip_prefix("ng ") {
+                    bail!("GitClient::push: remote rejected {rejection}");
+                }
+            }
+        }
+
+        let short_new = &new.to_string()[..7];
+        let local_name = local_ref.trim_start_matches("refs/heads/");
+        let remote_name = remote_ref.trim_start_matches("refs/heads/");
+        match old {
+            Some(old) if !fast_forward => println!(
+                " + {}...{short_new} {local_name} -> {remote_name} (forced update)",
+                &old.to_string()[..7]
+            ),
+            Some(old) => println!(
+                "   {}..{short_new}  {local_name} -> {remote_name}",
+                &old.to_string()[..7]
+            ),
+            None => println!(" * [new branch]      {local_name} -> {remote_name}"),
+        }
+
+        if let Some(branch) = remote_ref.strip_prefix("refs/heads/") {
+            refs.update(
+                &format!("refs/remotes/{remote}/{branch}"),
+                &new,
+                "update by push",
+            )?;
+        }
 
         Ok(())
     }
 
+    // checks out `tree` below `path`, passing files through their smudge filters. files whose
+    // blob isn't in `object_map` are left out and added to `missing` instead
     fn write_tree<P: AsRef<Path> + ?Sized>(
         path: &P,
         tree: &Tree,
         object_map: &HashMap<Sha, AnyGitObject>,
+        filters: &Filters,
+        missing: &mut Vec<PathBuf>,
     ) -> Result<()> {
         let path = path.as_ref();
-        for entry in tree.entries() {
+        // attributes are read from the work tree, so a directory's `.gitattributes` has to be
+        // there before the files it applies to
+        let (attributes, others): (Vec<_>, Vec<_>) = tree
+            .entries()
+            .iter()
+            .partition(|entry| entry.name == ".gitattributes");
+        for entry in attributes.into_iter().chain(others) {
             let subpath = path.join(&entry.name);
             match &entry.mode {
                 FileMode::Directory => {
@@ -313,19 +864,19 @@ impl GitClient {
                               object_map.get(&entry.hash)
                           )
                       })?;
-                    GitClient::write_tree(&subpath, subtree, object_map).with_context(|| {
-                        format!("GitClient::write_tree: failed to write tree object to {subpath:?}")
-                    })?;
+                    GitClient::write_tree(&subpath, subtree, object_map, filters, missing)
+                        .with_context(|| {
+                            format!(
+                                "GitClient::write_tree: failed to write tree object to {subpath:?}"
+                            )
+                        })?;
                 }
                 FileMode::Regular => {
-                    let blob = object_map
-                      .get(&entry.hash)
-                      .ok_or_else(|| {
-                          anyhow!(
-                              "GitClient::write_tree: failed to find blob object with SHA {:?}",
-                              entry.hash
-                          )
-                      })?
+                    let Some(blob) = object_map.get(&entry.hash) else {
+                        missing.push(subpath);
+                        continue;
+                    };
+                    let blob = blob
                       .try_as_blob_ref()
                       .ok_or_else(|| {
                           anyhow!(
@@ -333,7 +884,19 @@ impl GitClient {
                               object_map.get(&entry.hash)
                           )
                       })?;
-                    std::fs::write(&subpath, blob.content()).with_context(|| {
+                    // `.gitattributes` itself is written as it is, its rules aren't loaded yet
+                    let content = match entry.name.as_str() {
+                        ".gitattributes" => blob.content().clone(),
+                        _ => {
+                            let name = subpath.strip_prefix(filters.root()).unwrap_or(&subpath);
+                            filters
+                                .smudge(&name.to_string_lossy(), blob.content().clone())
+                                .with_context(|| {
+                                    format!("GitClient::write_tree: failed to filter {subpath:?}")
+                                })?
+                        }
+                    };
+                    std::fs::write(&subpath, content).with_context(|| {
                         format!("GitClient::write_tree: failed to write blob object to {subpath:?}")
                     })?;
                 }
@@ -347,92 +910,165 @@ impl GitClient {
     }
 
     async fn ref_discovery(&self) -> Result<GitRefDiscoveryResponse> {
-        let url = into_anyhow_result(self.url.join("info/refs").and_then(|mut url| {
-            url.set_query(Some("service=git-upload-pack"));
-            Ok(url)
+        let (mut refs, capabilities) = self
+            .discover_refs(UPLOAD_PACK_SERVICE)
+            .await
+            .with_context(|| "GitClient::ref_discovery: failed to discover refs")?;
+
+        // HEAD is missing when it's unborn or hidden, the branch to check out is guessed then
+        let head_object_id = refs.remove("HEAD");
+        Ok(GitRefDiscoveryResponse {
+            refs,
+            head_object_id,
+            capabilities,
+        })
+    }
+
+    // the advertisement is the same for both services, the capabilities follow a NUL on the first
+    // ref line: https://git-scm.com/docs/http-protocol#_smart_clients
+    async fn discover_refs(
+        &self,
+        service: &str,
+    ) -> Result<(HashMap<String, Sha>, GitCapabilities)> {
+        let url = into_anyhow_result(self.url.join("info/refs").map(|mut url| {
+            url.set_query(Some(&format!("service={service}")));
+            url
         }))
-        .with_context(|| "GitClient::ref_discovery: failed to get upload pack URL")?;
+        .with_context(|| format!("GitClient::discover_refs: failed to get {service} URL"))?;
 
         let response = self
             .client
             .get(url)
             .send()
             .await
-            .with_context(|| "GitClient::ref_discovery: failed to send request")?
+            .with_context(|| "GitClient::discover_refs: failed to send request")?
             .error_for_status()
-            .with_context(|| "GitClient::ref_discovery: request failed: network")?
+            .with_context(|| "GitClient::discover_refs: request failed: network")?
             .bytes()
             .await
-            .with_context(|| "GitClient::ref_discovery: failed to get response bytes")?;
+            .with_context(|| "GitClient::discover_refs: failed to get response bytes")?;
 
-        let mut iter = PktLine::read_many(response);
+        let mut iter =
+            PktLine::read_many(response).map(|line| line.and_then(PktLine::error_for_err_line));
 
-        assert!(matches!(
-            iter.next(),
-            Some(Ok(PktLine::StringDataPkt(str))) if str == "# service=git-upload-pack"
-        ));
-        assert!(matches!(iter.next(), Some(Ok(PktLine::FlushPkt))));
-
-        let head_line = iter
+        // the advertisement is all text, whether or not the server ends its lines with `\n`
+        let service_line = iter
             .next()
-            .ok_or_else(|| anyhow!("expected head line"))??
-            .try_as_string_data_pkt()
-            .with_context(|| "GitClient::ref_discovery: expected string data pkt")?;
-
-        let mut head_line_chars = head_line.chars().peekable();
-        let head = GitRef::read(head_line_chars.by_ref().take_while(|c| c != &'\0'))
-            .with_context(|| "GitClient::ref_discovery: failed to parse head ref")?;
-        if head.name != "HEAD" {
-            bail!("GitClient::ref_discovery: expected HEAD ref in head line");
-        }
-        let head_object_id = head.object_id;
-        let capabilities = GitCapabilities::read(head_line_chars).with_context(|| {
-            "GitClient::ref_discovery: failed to parse capabilities in head line"
-        })?;
-        let refs = iter
-            .take_while(|result| !matches!(result, Ok(PktLine::FlushPkt)))
-            .map(|result| match result? {
-                PktLine::StringDataPkt(str) => GitRef::read(str.chars()),
-                _ => bail!("GitClient::ref_discovery: expected string data pkt"),
-            })
-            .map(|el| el.map(|git_ref| (git_ref.name, git_ref.object_id)))
-            .collect::<Result<HashMap<_, _>>>()
-            .with_context(|| "GitClient::ref_discovery: failed to parse response")?;
-        Ok(GitRefDiscoveryResponse {
-            refs,
-            head_object_id,
-            capabilities,
-        })
+            .ok_or_else(|| anyhow!("GitClient::discover_refs: expected service line"))??
+            .into_text()
+            .with_context(|| "GitClient::discover_refs: failed to read service line")?;
+        // some servers pad the line with whitespace, which git doesn't mind either
+        let announced = service_line
+            .trim()
+            .strip_prefix("# service=")
+            .map(str::trim_start);
+        if announced != Some(service) {
+            bail!("GitClient::discover_refs: unexpected service line {service_line:?}, expected {service}");
+        }
+        // the service line's section ends with a flush, anything else in it is skipped like git
+        // does
+        loop {
+            match iter.next() {
+                Some(Ok(PktLine::FlushPkt)) => break,
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    return Err(err).with_context(|| {
+                        "GitClient::discover_refs: failed to read the service section"
+                    })
+                }
+                None => bail!("GitClient::discover_refs: expected a flush after the service line"),
+            }
+        }
+
+        // the capabilities are taken from whichever ref line carries them. that's the first one,
+        // HEAD when the server has it, but not every server sends HEAD or puts them there
+        let mut refs = HashMap::new();
+        let mut capabilities = None;
+        for line in iter.take_while(|result| !matches!(result, Ok(PktLine::FlushPkt))) {
+            let line = line
+                .and_then(PktLine::into_text)
+                .with_context(|| "GitClient::discover_refs: failed to read ref line")?;
+            let (ref_line, advertised) = match line.split_once('\0') {
+                Some((ref_line, advertised)) => (ref_line, Some(advertised)),
+                None => (line.as_str(), None),
+            };
+            if let (Some(advertised), None) = (advertised, &capabilities) {
+                capabilities =
+                    Some(GitCapabilities::read(advertised.chars()).with_context(|| {
+                        format!(
+                            "GitClient::discover_refs: failed to parse capabilities in {line:?}"
+                        )
+                    })?);
+            }
+
+            let git_ref = GitRef::read(ref_line.chars())
+                .with_context(|| format!("GitClient::discover_refs: failed to parse {line:?}"))?;
+            // a repository without refs advertises a placeholder that only carries the
+            // capabilities
+            if git_ref.name != "capabilities^{}" {
+                refs.insert(git_ref.name, git_ref.object_id);
+            }
+        }
+        Ok((refs, capabilities.unwrap_or(GitCapabilities(vec![]))))
     }
 }
 
 #[derive(Debug)]
 struct GitRefDiscoveryResponse {
     refs: HashMap<String, Sha>,
-    head_object_id: Sha,
-    #[allow(dead_code)]
+    head_object_id: Option<Sha>,
     capabilities: GitCapabilities,
 }
 
 impl GitRefDiscoveryResponse {
-    async fn write<P: AsRef<Path>>(&self, path: &P) -> Result<()> {
-        let path = path.as_ref().join(".git");
-        let head_ref = self
+    // the ref the server's HEAD points at and its commit. the server names it in a `symref`
+    // capability, older ones leave it to be guessed from the branches pointing at the same
+    // commit. a server that doesn't advertise HEAD at all gets its default branch guessed from
+    // the branches it has
+    fn head(&self) -> Result<(&str, &Sha)> {
+        let branches = self
             .refs
             .iter()
-            .find(|(_, sha)| sha == &&self.head_object_id)
-            .ok_or_else(|| {
-                anyhow!("GitRefDiscoveryResponse::write: failed to find HEAD ref in refs")
-            })?
-            .0;
+            .filter(|(name, _)| name.starts_with("refs/heads/"))
+            .map(|(name, sha)| (name.as_str(), sha))
+            .collect::<BTreeMap<_, _>>();
+        // the usual default branches come first, then the rest in order
+        let preferred = || {
+            DEFAULT_BRANCH_REFS
+                .iter()
+                .filter_map(|name| branches.get_key_value(name))
+                .chain(&branches)
+                .map(|(name, sha)| (*name, *sha))
+        };
+
+        if let Some(head_ref) = self.capabilities.symref_target("HEAD") {
+            if let Some(sha) = self.refs.get(head_ref).or(self.head_object_id.as_ref()) {
+                return Ok((head_ref, sha));
+            }
+        }
+        let head = match &self.head_object_id {
+            Some(head_object_id) => preferred().find(|(_, sha)| sha == &head_object_id),
+            None => preferred().next(),
+        };
+        head.ok_or_else(|| {
+            anyhow!("GitRefDiscoveryResponse::head: failed to find a branch for HEAD in refs")
+        })
+    }
+
+    async fn write<P: AsRef<Path>>(&self, path: &P) -> Result<()> {
+        let path = git_dir(path);
+        let (head_ref, _) = self
+            .head()
+            .with_context(|| "GitRefDiscoveryResponse::write: failed to find HEAD's branch")?;
         tokio::fs::write(&path.join("HEAD"), format!("ref: {head_ref}\n"))
             .await
             .with_context(|| {
                 "GitRefDiscoveryResponse::write: failed to write HEAD ref to filesystem"
             })?;
-        for (name, object_id) in &self.refs {
-            let path = path.join(&name);
-            println!("writing ref {name:?} to filesystem {path:?}: {object_id:?}");
+        // peeled entries (`refs/tags/v1.0^{}`) describe the object an annotated tag points to,
+        // they aren't refs of their own
+        for (name, object_id) in self.refs.iter().filter(|(name, _)| !name.ends_with("^{}")) {
+            let path = path.join(name);
             tokio::fs::create_dir_all(path.parent().unwrap())
               .await
               .with_context(|| {
@@ -452,36 +1088,85 @@ impl GitRefDiscoveryResponse {
     }
 }
 
+// the error a cancelled clone stops with, callers can look for it with `downcast_ref`
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "clone cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// objects clone writes between syncs
+const DEFAULT_WRITE_BATCH_SIZE: usize = 1000;
+
+// how often a pending network read looks at the cancellation flag
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// a ref name the way fetch shows it, without `refs/heads/`, `refs/tags/` or `refs/remotes/`
+fn short_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .into_iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
 fn into_anyhow_result<T>(result: Result<T, ParseError>) -> Result<T> {
     result.map_err(|err| anyhow!(err).context("failed to parse URL"))
 }
 
 #[derive(Debug, EnumTryAs)]
+#[allow(clippy::enum_variant_names)]
 enum PktLine {
     StringDataPkt(String),
     BinaryDataPkt(Vec<u8>),
     FlushPkt,
+    // protocol v2 separates the sections of a message with `0001` and ends a response with
+    // `0002`: https://git-scm.com/docs/protocol-v2#_packet_line_framing
+    DelimPkt,
+    ResponseEndPkt,
 }
 
 impl PktLine {
     fn read<T: IntoIterator<Item = u8>>(iter: T) -> Result<Self> {
         let mut iter = iter.into_iter();
-        let pkt_len_str = String::from_utf8(iter.by_ref().take(4).collect::<Vec<_>>())
-            .with_context(|| "PktLine::read: failed to read pkt-len")?;
-        let pkt_len = u64::from_str_radix(&pkt_len_str, 16)
-            .with_context(|| format!("PktLine::read: failed to parse pkt-len: {pkt_len_str}"))?;
+        let pkt_len_bytes = iter.by_ref().take(4).collect::<Vec<_>>();
+        if pkt_len_bytes.len() < 4 {
+            bail!(
+                "PktLine::read: stream ended after {} of the 4 pkt-len bytes",
+                pkt_len_bytes.len()
+            );
+        }
+        // from_str_radix would also take a leading sign
+        if !pkt_len_bytes.iter().all(u8::is_ascii_hexdigit) {
+            bail!(
+                "PktLine::read: pkt-len {:?} is not 4 hex digits",
+                String::from_utf8_lossy(&pkt_len_bytes)
+            );
+        }
+        let pkt_len = usize::from_str_radix(std::str::from_utf8(&pkt_len_bytes)?, 16)?;
 
-        if pkt_len == 0 {
-            return Ok(Self::FlushPkt);
-        } else if pkt_len <= 4 {
-            return Err(anyhow!("PktLine::read: pkt-len is too small: {pkt_len}").into());
+        match pkt_len {
+            0 => return Ok(Self::FlushPkt),
+            1 => return Ok(Self::DelimPkt),
+            2 => return Ok(Self::ResponseEndPkt),
+            _ => {}
+        }
+        if pkt_len <= 4 {
+            return Err(anyhow!("PktLine::read: pkt-len is too small: {pkt_len}"));
         }
 
-        let pkt_data = iter
-            .take((pkt_len - 4).try_into().with_context(|| {
-                format!("PktLine::read: failed to convert pkt-len to usize: {pkt_len}")
-            })?)
-            .collect::<Vec<_>>();
+        let pkt_data = iter.take(pkt_len - 4).collect::<Vec<_>>();
+        if pkt_data.len() < pkt_len - 4 {
+            bail!(
+                "PktLine::read: pkt-len {pkt_len} announces {} data bytes, but the stream ends after {}",
+                pkt_len - 4,
+                pkt_data.len()
+            );
+        }
 
         if pkt_data.last() == Some(&b'\n') {
             Ok(Self::StringDataPkt(
@@ -493,6 +1178,40 @@ impl PktLine {
         }
     }
 
+    // the data of a pkt holding text, without its optional trailing `\n`. read only recognizes
+    // text by that newline, which not every server sends
+    fn into_text(self) -> Result<String> {
+        match self {
+            PktLine::StringDataPkt(str) => Ok(str),
+            PktLine::BinaryDataPkt(mut data) => {
+                if data.last() == Some(&b'\n') {
+                    data.pop();
+                }
+                String::from_utf8(data)
+                    .with_context(|| "PktLine::into_text: failed to parse pkt-data as string")
+            }
+            other => bail!("PktLine::into_text: expected a data pkt, got {other:?}"),
+        }
+    }
+
+    // servers report fatal errors as a data pkt of the form `ERR <message>`:
+    // https://git-scm.com/docs/pack-protocol#_pkt_line_format
+    fn error_for_err_line(self) -> Result<Self> {
+        let data = match &self {
+            PktLine::StringDataPkt(str) => str.as_bytes(),
+            PktLine::BinaryDataPkt(data) => data.as_slice(),
+            PktLine::FlushPkt | PktLine::DelimPkt | PktLine::ResponseEndPkt => return Ok(self),
+        };
+
+        match data.strip_prefix(b"ERR ") {
+            Some(message) => bail!(
+                "remote: {}",
+                String::from_utf8_lossy(message).trim_end_matches('\n')
+            ),
+            None => Ok(self),
+        }
+    }
+
     fn read_many<T: IntoIterator<Item = u8>>(iter: T) -> impl Iterator<Item = Result<Self>> {
         let mut iter = iter.into_iter().peekable();
         std::iter::from_fn(move || {
@@ -516,6 +1235,8 @@ impl PktLine {
                 pkt
             }
             PktLine::FlushPkt => b"0000".to_vec(),
+            PktLine::DelimPkt => b"0001".to_vec(),
+            PktLine::ResponseEndPkt => b"0002".to_vec(),
         }
     }
 }
@@ -548,6 +1269,16 @@ impl GitRef {
     }
 }
 
+// what a server not advertising HEAD most likely has as its default branch
+const DEFAULT_BRANCH_REFS: [&str; 2] = ["refs/heads/main", "refs/heads/master"];
+
+static NO_PROGRESS_CAPABILITY: &str = "no-progress";
+// receive-pack advertises this when it can't complete thin packs
+static NO_THIN_CAPABILITY: &str = "no-thin";
+static INCLUDE_TAG_CAPABILITY: &str = "include-tag";
+static REPORT_STATUS_CAPABILITY: &str = "report-status";
+static OFS_DELTA_CAPABILITY: &str = "ofs-delta";
+
 #[derive(Debug)]
 struct GitCapabilities(Vec<String>);
 
@@ -561,9 +1292,61 @@ impl GitCapabilities {
             .collect();
         Ok(Self(capabilities))
     }
+
+    fn contains(&self, capability: &str) -> bool {
+        self.0.iter().any(|advertised| advertised == capability)
+    }
+
+    // the ref the symbolic ref `name` points at, from a `symref=<name>:<target>` capability
+    fn symref_target(&self, name: &str) -> Option<&str> {
+        self.0.iter().find_map(|capability| {
+            capability
+                .strip_prefix("symref=")?
+                .strip_prefix(name)?
+                .strip_prefix(':')
+        })
+    }
+
+    // only the capabilities the server advertised may be requested:
+    // https://git-scm.com/docs/protocol-capabilities
+    fn select<'a, T: IntoIterator<Item = &'a str>>(&self, wanted: T) -> Self {
+        Self(
+            wanted
+                .into_iter()
+                .filter(|capability| self.contains(capability))
+                .map(|capability| capability.to_owned())
+                .collect(),
+        )
+    }
 }
 
+// what fetch_pack asks for of the capabilities the server advertised
+fn fetch_pack_capabilities(advertised: &GitCapabilities, quiet: bool) -> GitCapabilities {
+    advertised.select(
+        [
+            quiet.then_some(NO_PROGRESS_CAPABILITY),
+            Some(INCLUDE_TAG_CAPABILITY),
+            Some(OFS_DELTA_CAPABILITY),
+        ]
+        .into_iter()
+        .flatten(),
+    )
+}
+
+// the capabilities the client asks for follow the first want, after a space
+fn first_want_line(first_want: WantPkt, capabilities: Option<GitCapabilities>) -> PktLine {
+    match capabilities.filter(|GitCapabilities(capabilities)| !capabilities.is_empty()) {
+        Some(GitCapabilities(capabilities)) => {
+            PktLine::StringDataPkt(format!("{} {}", first_want, capabilities.join(" ")))
+        }
+        None => first_want.to_pkt_line(),
+    }
+}
+
+static UPLOAD_PACK_SERVICE: &str = "git-upload-pack";
 static UPLOAD_PACK_CONTENT_TYPE: &str = "application/x-git-upload-pack-request";
+static RECEIVE_PACK_SERVICE: &str = "git-receive-pack";
+static RECEIVE_PACK_CONTENT_TYPE: &str = "application/x-git-receive-pack-request";
 #[derive(Debug)]
 struct WantPkt {
     object_id: Sha,
@@ -571,9 +1354,9 @@ struct WantPkt {
 
 impl PktMessage for WantPkt {}
 
-impl ToString for WantPkt {
-    fn to_string(&self) -> String {
-        format!("want {}", hex::encode(&self.object_id))
+impl std::fmt::Display for WantPkt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "want {}", hex::encode(&self.object_id))
     }
 }
 
@@ -583,9 +1366,9 @@ struct HavePkt {
 }
 
 impl PktMessage for HavePkt {}
-impl ToString for HavePkt {
-    fn to_string(&self) -> String {
-        format!("have {}", hex::encode(&self.object_id))
+impl std::fmt::Display for HavePkt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "have {}", hex::encode(&self.object_id))
     }
 }
 
@@ -619,300 +1402,110 @@ impl<T: PktMessage + ToString> ToPktLine for T {
 
 trait PktMessage {}
 
-#[derive(Debug)]
-struct Packfile {
-    #[allow(dead_code)]
-    version: u32,
-    #[allow(dead_code)]
-    checksum: Sha,
-    chunks: Vec<PackfileObject>,
-}
-
-impl Packfile {
-    fn read<T: IntoIterator<Item = u8>>(iter: T) -> Result<Self> {
-        let mut iter = iter.into_iter().peekable();
-        assert_eq!(
-            iter.by_ref().take(4).collect::<Vec<_>>(),
-            b"PACK",
-            "Packfile::read: packfiles should start with \"PACK\""
-        );
-
-        let version =
-            u32::from_be_bytes(read_array(iter.by_ref()).with_context(|| {
-                anyhow!("Packfile::read: failed to convert version bytes to u32")
-            })?);
-        assert_eq!(
-            version, 2,
-            "Packfile::read: expected version 2, got {version}"
-        );
-
-        let object_amount = u32::from_be_bytes(read_array(iter.by_ref()).with_context(|| {
-            anyhow!("Packfile::read: failed to convert object amount bytes to u32")
-        })?);
-
-        println!("object_amount: {object_amount}");
-        let (binary_data, checksum) = {
-            let mut rest: Vec<_> = iter.collect();
-            let checksum = Sha(rest.split_off(rest.len() - 20).try_into().map_err(|_| {
-                anyhow!("Packfile::read: failed to convert checksum bytes to [u8; 20]")
-            })?);
-            (rest, checksum)
-        };
-        println!("checksum: {checksum:?}");
-
-        let mut bytes_read = 0;
-
-        let chunks: Vec<_> = (0..object_amount)
-            .map(|_| -> Result<_> {
-                let (obj, bytes_read_obj) = PackfileObject::decode(&binary_data[bytes_read..])
-                    .with_context(|| anyhow!("Packfile::read: failed to decode object"))?;
-                bytes_read += usize::try_from(bytes_read_obj).with_context(|| {
-                    anyhow!("Packfile::read: failed to convert bytes_read_obj usize")
-                })?;
-                Ok(obj)
-            })
-            .collect::<Result<_, _>>()
-            .with_context(|| "Packfile::read: failed to read chunks")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{git_blob::Blob, git_tree::TreeEntry};
 
-        Ok(Packfile {
-            version,
-            checksum,
-            chunks,
-        })
+    fn advertised(capabilities: &str) -> GitCapabilities {
+        GitCapabilities::read(capabilities.chars()).unwrap()
     }
-}
 
-const VARINT_ENCODING_BITS: u8 = 7;
-const VARINT_CONTINUE_FLAG: u8 = 1 << VARINT_ENCODING_BITS;
-const VARINT_OBJ_TYPE_FLAG: u8 = 0b01110000;
-const VARINT_FIRST_BYTE_ENCONDING_BITS: u8 = 4;
-
-fn read_variable_length_integer<T: IntoIterator<Item = u8>>(
-    iter: T,
-    get_obj_type: bool,
-) -> Result<(usize, Option<u8>, u8)> {
-    let mut iter = iter.into_iter();
-    let mut obj_type = None;
-    let mut value: usize = 0;
-    let mut length: u8 = 0;
-    let mut bytes_read: u8 = 0;
-
-    loop {
-        bytes_read += 1;
-        let byte = iter
-            .next()
-            .ok_or_else(|| anyhow!("failed to read variable length integer"))?;
-        let is_last = (byte & VARINT_CONTINUE_FLAG) == 0;
-        let (data, offset) = if obj_type.is_some() || !get_obj_type {
-            (byte & !VARINT_CONTINUE_FLAG, VARINT_ENCODING_BITS)
-        } else {
-            obj_type = Some((byte & !VARINT_CONTINUE_FLAG) >> VARINT_FIRST_BYTE_ENCONDING_BITS);
-            (
-                byte & !VARINT_CONTINUE_FLAG & !VARINT_OBJ_TYPE_FLAG,
-                VARINT_FIRST_BYTE_ENCONDING_BITS,
-            )
+    fn first_line_words(advertised: &GitCapabilities, quiet: bool) -> Vec<String> {
+        let first_want = WantPkt {
+            object_id: Sha([0xab; 20]),
         };
-        value |= (data as usize) << length;
-        if is_last {
-            break;
+        match first_want_line(first_want, Some(fetch_pack_capabilities(advertised, quiet))) {
+            PktLine::StringDataPkt(line) => line.split(' ').map(str::to_owned).collect(),
+            line => panic!("expected a data line, got {line:?}"),
         }
-        length += offset;
     }
-    Ok((value, obj_type, bytes_read))
-}
-
-fn read_array<const N: usize, T: IntoIterator<Item = u8>>(iter: T) -> Result<[u8; N]> {
-    iter.into_iter()
-        .take(N)
-        .collect::<Vec<_>>()
-        .try_into()
-        .map_err(|vec: Vec<_>| {
-            anyhow!(
-                "failed to read array: expected {N} bytes, got {}",
-                vec.len()
-            )
-        })
-}
-
-#[derive(Debug, Clone)]
-enum PackfileObject {
-    Commit(Commit),
-    Tree(Tree),
-    Blob(Blob),
-    ObjRefDelta(ObjRefDelta),
-}
 
-#[derive(Debug, Clone)]
-struct ObjRefDelta {
-    base_obj_size: usize,
-    target_obj_size: usize,
-    obj_name: Sha,
-    instructions: Vec<DeltaInstruction>,
-}
-
-impl PackfileObject {
-    fn decode(content: &[u8]) -> Result<(Self, u64)> {
-        let (expected_size, obj_type, bytes_read_varint) =
-            read_variable_length_integer(content.into_iter().copied(), true)
-                .with_context(|| anyhow!("PackfileObject::decode: failed to read object size"))?;
-        let obj_type = obj_type.ok_or_else(|| {
-          anyhow!("PackfileObject::decode: failed to read variable length integer: couldn't find object type")
-      })?;
-        let bytes_read_varint = usize::try_from(bytes_read_varint).with_context(|| {
-            anyhow!("PackfileObject::decode: failed to convert bytes_read_varint to usize")
-        })?;
-        let content = &content[bytes_read_varint..];
-        let bytes_read_varint = u64::try_from(bytes_read_varint).with_context(|| {
-            anyhow!("PackfileObject::decode: failed to convert bytes_read_varint to u64")
-        })?;
+    #[test]
+    fn fetch_pack_asks_for_ofs_delta() {
+        let advertised =
+            advertised("multi_ack thin-pack side-band-64k ofs-delta no-progress include-tag");
 
-        let decode_zlib = |content: &[u8]| -> Result<(Vec<u8>, u64)> {
-            let (content, bytes_read) = decompress_slice(content)?;
-            assert_eq!(
-              expected_size,
-              content.len(),
-              "PackfileObject::decode({obj_type}): object size doesn't match decompressed content size"
-          );
-            Ok((content, bytes_read))
-        };
+        let words = first_line_words(&advertised, false);
+        assert_eq!(words[..2], ["want", &"ab".repeat(20)]);
+        assert!(words.iter().any(|word| word == "ofs-delta"), "{words:?}");
+        assert!(!words.iter().any(|word| word == "no-progress"), "{words:?}");
 
-        match obj_type {
-            1 => {
-                let (content, bytes_read) = decode_zlib(content)?;
-                Ok((
-                    Self::Commit(Commit::decode_body(content)?),
-                    bytes_read + bytes_read_varint,
-                ))
-            }
-            2 => {
-                let (content, bytes_read) = decode_zlib(content)?;
-                Ok((
-                    Self::Tree(Tree::decode_body(content)?),
-                    bytes_read + bytes_read_varint,
-                ))
-            }
-            3 => {
-                let (content, bytes_read) = decode_zlib(content)?;
-                Ok((
-                    Self::Blob(Blob::new(content)),
-                    bytes_read + bytes_read_varint,
-                ))
-            }
-            7 => {
-                let obj_name = Sha(content.get(..20).ok_or_else(|| {
-                  anyhow!(
-                      "PackfileObject::decode({obj_type}): expected object name to be 20 bytes, got {}",
-                      content.len()
-                  )
-              })?.to_vec().try_into().map_err(|_| {
-                  anyhow!(
-                      "PackfileObject::decode({obj_type}): failed to convert object name to Sha"
-                  )
-              })?);
-                let (content, bytes_read) = decode_zlib(&content.get(20..).ok_or_else(|| {
-                  anyhow!(
-                      "PackfileObject::decode({obj_type}): content bytes are missing, expected more than 20 bytes in content but got {}",
-                      content.len()
-                  )
-              })?)?;
-                let mut content = content.into_iter();
-                let (base_obj_size, ..) = read_variable_length_integer(content.by_ref(), false)
-                    .with_context(|| {
-                        anyhow!("PackfileObject::decode: failed to read object size")
-                    })?;
-                let (target_obj_size, ..) = read_variable_length_integer(content.by_ref(), false)
-                    .with_context(|| {
-                    anyhow!("PackfileObject::decode: failed to read object size")
-                })?;
-                let instructions = DeltaInstruction::read_many(content).collect::<Result<Vec<_>>>().with_context(|| {
-                  anyhow!("PackfileObject::decode({obj_type}): failed to parse delta instructions")
-              })?;
-                let obj = Self::ObjRefDelta(ObjRefDelta {
-                    base_obj_size,
-                    target_obj_size,
-                    instructions,
-                    obj_name,
-                });
-                Ok((obj, bytes_read + 20 + bytes_read_varint))
-            }
-            _ => bail!("PackfileObject::decode({obj_type}): unsupported object type"),
-        }
+        let words = first_line_words(&advertised, true);
+        assert!(words.iter().any(|word| word == "ofs-delta"), "{words:?}");
+        assert!(words.iter().any(|word| word == "no-progress"), "{words:?}");
     }
-}
-#[derive(Debug, Clone)]
-enum DeltaInstruction {
-    Copy { offset: usize, length: usize },
-    Insert(BlobContent),
-}
 
-impl DeltaInstruction {
-    fn read<T: IntoIterator<Item = u8>>(iter: T) -> Result<Self> {
-        let mut iter = iter.into_iter().peekable();
-        let first_byte = iter
-            .next()
-            .ok_or_else(|| anyhow!("DeltaInstruction::read: empty iterator"))?;
-        let is_insert = (first_byte & 0b1000_0000) == 0;
-        if is_insert {
-            let byte_count = first_byte as usize;
-            // println!("byte_count: {byte_count} ({first_byte:#08b})");
-            Ok(Self::Insert(
-                iter.take(byte_count).collect::<Vec<_>>().into(),
-            ))
-        } else {
-            let flags = first_byte & !0b1000_0000;
-            // println!("flags: {flags:#07b}");
-            let mut offset: usize = 0;
-            for i in 0..4 {
-                if (flags & (1 << i)) != 0 {
-                    let to_apply = (iter
-                        .next()
-                        .ok_or_else(|| anyhow!("DeltaInstruction::read: expected offset byte"))?
-                        as usize)
-                        << (i * 8);
-                    offset |= to_apply;
-                }
-            }
-            let mut length: usize = 0;
-            for i in 4..6 {
-                if (flags & (1 << i)) != 0 {
-                    let to_apply = (iter
-                        .next()
-                        .ok_or_else(|| anyhow!("DeltaInstruction::read: expected size byte"))?
-                        as usize)
-                        << ((i - 4) * 8);
-                    length |= to_apply;
-                }
-            }
-            Ok(Self::Copy { offset, length })
-        }
+    #[test]
+    fn fetch_pack_asks_only_for_what_is_advertised() {
+        let words = first_line_words(&advertised("multi_ack side-band-64k"), true);
+        assert_eq!(words, ["want", &"ab".repeat(20)]);
     }
 
-    fn read_many<T: IntoIterator<Item = u8>>(iter: T) -> impl Iterator<Item = Result<Self>> {
-        let mut iter = iter.into_iter().peekable();
-        std::iter::from_fn(move || {
-            if iter.peek().is_some() {
-                Some(
-                    Self::read(&mut iter)
-                        .with_context(|| "DeltaInstruction::read_many: failed to read instruction"),
-                )
-            } else {
-                None
-            }
-        })
+    // a client that's cancelled before it starts, at an address nothing listens on
+    fn cancelled_client() -> Result<GitClient> {
+        Ok(GitClient::new("http://127.0.0.1:9/repo.git")?
+            .with_cancellation(Arc::new(AtomicBool::new(true))))
     }
 
-    fn apply(instructions: &Vec<DeltaInstruction>, source: &[u8]) -> Vec<u8> {
-        let mut output = vec![];
-        for instruction in instructions {
-            match instruction {
-                DeltaInstruction::Copy { offset, length } => {
-                    output.extend(&source[*offset..*offset + *length]);
-                }
-                DeltaInstruction::Insert(data) => {
-                    output.extend(data.as_ref());
-                }
-            }
+    #[tokio::test]
+    async fn cancelled_clone_and_mirror_stop_with_cancelled() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let clone = cancelled_client()?
+            .clone(&dir.path(), "origin", false, true)
+            .await;
+        let mirror = cancelled_client()?.mirror(&dir.path(), true).await;
+        for result in [clone, mirror] {
+            let err = result.expect_err("a cancelled client doesn't get anywhere");
+            assert!(err.downcast_ref::<Cancelled>().is_some(), "{err:#}");
         }
-        output
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn write_tree_smudges_files_by_the_checked_out_gitattributes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join(".git"))?;
+        std::fs::write(
+            dir.path().join(".git/config"),
+            "[filter \"upper\"]\n\tsmudge = tr a-z A-Z\n",
+        )?;
+
+        let mut object_map = HashMap::new();
+        let mut entry = |mode, name: &str, object: AnyGitObject| -> Result<TreeEntry> {
+            let hash = object.sha1()?;
+            object_map.insert(hash.clone(), object);
+            Ok(TreeEntry {
+                mode,
+                name: name.to_owned(),
+                hash,
+            })
+        };
+        let blob = |content: &str| AnyGitObject::Blob(Blob::new(content.as_bytes().to_vec()));
+        let sub = Tree::new(vec![entry(FileMode::Regular, "b.txt", blob("two\n"))?]);
+        let tree = Tree::new(vec![
+            entry(FileMode::Regular, "a.txt", blob("one\n"))?,
+            entry(FileMode::Regular, "a.md", blob("one\n"))?,
+            entry(FileMode::Directory, "sub", AnyGitObject::Tree(sub))?,
+            entry(
+                FileMode::Regular,
+                ".gitattributes",
+                blob("*.txt filter=upper\n"),
+            )?,
+        ]);
+
+        // like clone, the filters are set up before the root .gitattributes is checked out
+        let filters = Filters::new(dir.path())?;
+        let mut missing = vec![];
+        GitClient::write_tree(dir.path(), &tree, &object_map, &filters, &mut missing)?;
+        assert!(missing.is_empty());
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name));
+        assert_eq!(read("a.txt")?, "ONE\n");
+        assert_eq!(read("sub/b.txt")?, "TWO\n");
+        assert_eq!(read("a.md")?, "one\n");
+        assert_eq!(read(".gitattributes")?, "*.txt filter=upper\n");
+        Ok(())
     }
 }
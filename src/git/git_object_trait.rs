@@ -1,16 +1,28 @@
 use crate::{
     git::{
         any_git_object::Sha,
-        compression::{compress, decompress},
+        compression::{compress, decompress, loose_compression},
+        config::Config,
     },
     utils::helpers::{get_object_file_path, get_object_folder_path},
 };
-use anyhow::{anyhow, Context, Result};
-use sha::{sha1::Sha1, utils::Digest};
-use std::{fs, path::Path};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::Compression;
+use sha1_smol::Sha1;
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{Read, Write},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use strum::{AsRefStr, EnumString};
 
-#[derive(EnumString, AsRefStr, Debug)]
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+static TMP_OBJECT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(EnumString, AsRefStr, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GitObjectType {
     #[strum(serialize = "blob")]
     Blob,
@@ -18,6 +30,34 @@ pub enum GitObjectType {
     Tree,
     #[strum(serialize = "commit")]
     Commit,
+    #[strum(serialize = "tag")]
+    Tag,
+}
+
+// hashes `<type> <size>\0<body>` while reading the body in chunks, so neither the body nor the
+// header-prefixed encoding has to be held in memory at once
+pub fn hash_object_stream<R: Read>(object_type: &str, size: u64, mut body: R) -> Result<Sha> {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{object_type} {size}\0").as_bytes());
+
+    let mut buf = [0; HASH_CHUNK_SIZE];
+    let mut bytes_hashed = 0;
+    loop {
+        let bytes_read = body
+            .read(&mut buf)
+            .with_context(|| "failed to generate object hash: failed to read body")?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+        bytes_hashed += bytes_read as u64;
+    }
+
+    if bytes_hashed != size {
+        bail!("failed to generate object hash: expected {size} bytes of body, got {bytes_hashed}");
+    }
+
+    Ok(Sha(hasher.digest().bytes()))
 }
 
 pub trait GitObject: Sized {
@@ -33,67 +73,133 @@ pub trait GitObject: Sized {
     }
 
     fn sha1(&self) -> Result<Sha> {
-        Ok(Sha((Sha1::default()
-            .digest(
-                &self
-                    .encode_uncompressed()
-                    .with_context(|| format!("failed to generate object hash: encoding failed"))?,
-            )
-            .0)
-            .into_iter()
-            .flat_map(|v| v.to_be_bytes())
-            .collect::<Vec<_>>()
-            .try_into()
-            .map_err(|_| {
-                anyhow!("unreachable: [u32; 5] couldn't be converted to [u8; 20]")
-            })?))
+        let body = self
+            .encode_body()
+            .with_context(|| "failed to generate object hash: encoding failed")?;
+        hash_object_stream(
+            Self::get_type().as_ref(),
+            body.len() as u64,
+            body.as_slice(),
+        )
     }
 
     fn encode_uncompressed(&self) -> Result<Vec<u8>> {
         let mut buf = self
             .get_header()
-            .with_context(|| format!("failed to encode git object: get_header failed"))?
+            .with_context(|| "failed to encode git object: get_header failed")?
             .into_bytes();
 
         buf.extend(
             self.encode_body()
-                .with_context(|| format!("failed to encode git object: encode_body failed"))?,
+                .with_context(|| "failed to encode git object: encode_body failed")?,
         );
         Ok(buf)
     }
 
-    fn encode(&self) -> Result<Vec<u8>> {
-        compress(self.encode_uncompressed()?)
-            .with_context(|| format!("failed to encode git object: content compression failed"))
+    fn encode(&self, level: Compression) -> Result<Vec<u8>> {
+        compress(self.encode_uncompressed()?, level)
+            .with_context(|| "failed to encode git object: content compression failed")
     }
 
     fn write<P: AsRef<Path> + ?Sized>(&self, path: &P) -> Result<()> {
-        let encoded = self.encode()?;
-        let sha = hex::encode(
-            self.sha1()
-                .with_context(|| "failed to write object: hash failed")?,
+        let level = Config::load(path)
+            .and_then(|config| loose_compression(&config))
+            .with_context(|| "failed to write object: failed to read compression level")?;
+        self.write_with_level(path, level)
+    }
+
+    // like write, for callers writing many objects that look the level up once
+    fn write_with_level<P: AsRef<Path> + ?Sized>(
+        &self,
+        path: &P,
+        level: Compression,
+    ) -> Result<()> {
+        let sha = self
+            .sha1()
+            .with_context(|| "failed to write object: hash failed")?;
+        write_encoded_object(self.encode(level)?, &sha, path)
+    }
+}
+
+// writes an already compressed object as a loose object file
+pub fn write_encoded_object<P: AsRef<Path> + ?Sized>(
+    encoded: Vec<u8>,
+    sha: &Sha,
+    path: &P,
+) -> Result<()> {
+    let sha = sha.to_string();
+
+    let folder_path = get_object_folder_path(&sha, path);
+    let file_path = get_object_file_path(&sha, path);
+
+    if !Path::new(&folder_path).exists() {
+        fs::create_dir_all(&folder_path)
+            .with_context(|| format!("failed to create object folder at {folder_path:?}"))?;
+    } else if !fs::metadata(&folder_path)?.is_dir() {
+        return Err(anyhow!("object folder is not a directory: {folder_path:?}"));
+    }
+
+    if false {
+        #[cfg(debug_assertions)]
+        eprintln!(
+            "Writing object file at {file_path:?}: {:?}",
+            String::from_utf8_lossy(&decompress(encoded.clone())?)
         );
+    }
 
-        let folder_path = get_object_folder_path(&sha, path);
-        let file_path = get_object_file_path(&sha, path);
+    // objects are content-addressed, so an existing file already holds the same content
+    if file_path.exists() {
+        return Ok(());
+    }
 
-        if !Path::new(&folder_path).exists() {
-            fs::create_dir_all(&folder_path)
-                .with_context(|| format!("failed to create object folder at {folder_path:?}"))?;
-        } else if !fs::metadata(&folder_path)?.is_dir() {
-            return Err(anyhow!("object folder is not a directory: {folder_path:?}"));
-        }
+    // write to a uniquely named temp file and rename it into place, so concurrent writers of
+    // the same object never observe (or leave behind) a partially written file
+    let tmp_path = folder_path.join(format!(
+        "tmp_obj_{}_{}",
+        std::process::id(),
+        TMP_OBJECT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
 
-        if false {
-            #[cfg(debug_assertions)]
-            eprintln!(
-                "Writing object file at {file_path:?}: {:?}",
-                String::from_utf8_lossy(&decompress(encoded.clone())?)
-            );
-        }
+    let mut tmp_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .with_context(|| format!("failed to create temporary object file at {tmp_path:?}"))?;
 
-        fs::write(&file_path, encoded)
-            .with_context(|| format!("failed to write object file at {file_path:?}"))?;
-        Ok(())
+    tmp_file
+        .write_all(&encoded)
+        .and_then(|_| fs::rename(&tmp_path, &file_path))
+        .or_else(|err| {
+            let _ = fs::remove_file(&tmp_path);
+            // losing the race against another writer of the same object is fine
+            if file_path.exists() {
+                Ok(())
+            } else {
+                Err(err)
+            }
+        })
+        .with_context(|| format!("failed to write object file at {file_path:?}"))?;
+    Ok(())
+}
+
+// makes the loose objects `shas` durable: each object file is synced when `files` is set, then
+// every fan-out directory holding them once, so the renames into place survive a crash
+pub fn sync_objects<P: AsRef<Path> + ?Sized>(shas: &[Sha], path: &P, files: bool) -> Result<()> {
+    let mut folders = BTreeSet::new();
+    for sha in shas {
+        let sha = sha.to_string();
+        if files {
+            let file_path = get_object_file_path(&sha, path);
+            fs::File::open(&file_path)
+                .and_then(|file| file.sync_all())
+                .with_context(|| format!("failed to sync object file at {file_path:?}"))?;
+        }
+        folders.insert(get_object_folder_path(&sha, path));
+    }
+    for folder_path in folders {
+        fs::File::open(&folder_path)
+            .and_then(|folder| folder.sync_all())
+            .with_context(|| format!("failed to sync object folder at {folder_path:?}"))?;
     }
+    Ok(())
 }
@@ -0,0 +1,98 @@
+use crate::{
+    git::{
+        any_git_object::Sha,
+        commits::CommitActor,
+        git_object_trait::{GitObject, GitObjectType},
+    },
+    utils::helpers::{from_utf8_with_context, parse_with_context},
+};
+use anyhow::{anyhow, Context, Result};
+use bytes::BufMut;
+use std::{io::Write, str::FromStr};
+
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub object_hash: Sha,
+    pub object_type: GitObjectType,
+    pub name: String,
+    pub tagger: Option<CommitActor>,
+    pub message: String,
+}
+
+impl GitObject for Tag {
+    fn get_type() -> GitObjectType {
+        GitObjectType::Tag
+    }
+
+    fn encode_body(&self) -> Result<Vec<u8>> {
+        let mut buf = (vec![]).writer();
+
+        buf.write_all(format!("object {}\n", self.object_hash).as_bytes())?;
+        buf.write_all(format!("type {}\n", self.object_type.as_ref()).as_bytes())?;
+        buf.write_all(format!("tag {}\n", self.name).as_bytes())?;
+
+        if let Some(tagger) = &self.tagger {
+            buf.write_all(
+                format!(
+                    "tagger {} <{}> {} {}\n",
+                    tagger.name, tagger.email, tagger.epoch, tagger.timezone,
+                )
+                .as_bytes(),
+            )?;
+        }
+
+        buf.write_all(format!("\n{}", self.message).as_bytes())?;
+
+        Ok(buf.into_inner())
+    }
+
+    fn decode_body(from: Vec<u8>) -> Result<Self> {
+        let content = from_utf8_with_context(from)
+            .with_context(|| "failed to parse tag object file: expected utf8 content")?;
+
+        let (headers, message) = content.split_once("\n\n").unwrap_or((&content, ""));
+
+        let pairs = headers
+            .lines()
+            .map(|line| {
+                line.split_once(' ').ok_or_else(|| {
+                    anyhow!(
+                        "failed to parse tag object file: expected key-value pair, got {line:?}"
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let find = |key: &str| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| *v)
+                .ok_or_else(|| anyhow!("failed to parse tag object file: failed to find {key}"))
+        };
+
+        let object_hash = Sha(hex::decode(find("object")?)
+            .with_context(|| "failed to parse tag object file: failed to parse object hash")?
+            .try_into()
+            .map_err(|_| {
+                anyhow!("failed to parse tag object file: expected object hash to contain exactly 20 bytes")
+            })?);
+
+        let object_type = parse_with_context(find("type")?)
+            .with_context(|| "failed to parse tag object file: failed to parse object type")?;
+
+        let tagger = find("tagger")
+            .ok()
+            .map(CommitActor::from_str)
+            .transpose()
+            .with_context(|| "failed to parse tag object file: failed to parse tagger")?;
+
+        Ok(Tag {
+            object_hash,
+            object_type,
+            name: find("tag")?.to_owned(),
+            tagger,
+            message: message.to_owned(),
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     git::{any_git_object::Sha, git_object_trait::GitObject, git_object_trait::GitObjectType},
-    utils::helpers::{from_utf8_with_context, parse_bytes_with_context},
+    utils::helpers::{from_utf8_with_context, parse_with_context},
 };
 use anyhow::{anyhow, Context, Result};
 use std::fs;
@@ -8,6 +8,12 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use strum::{AsRefStr, EnumString};
 
+// 4b825dc642cb6eb9a060e54bf8d69288fbee4904, git treats it as present in every repository
+pub const EMPTY_TREE_SHA: Sha = Sha([
+    0x4b, 0x82, 0x5d, 0xc6, 0x42, 0xcb, 0x6e, 0xb9, 0xa0, 0x60, 0xe5, 0x4b, 0xf8, 0xd6, 0x92, 0x88,
+    0xfb, 0xee, 0x49, 0x04,
+]);
+
 #[derive(Debug, Clone)]
 pub struct Tree(pub Vec<TreeEntry>);
 #[derive(Debug, Clone)]
@@ -27,16 +33,53 @@ pub enum FileMode {
     Symbolic,
     #[strum(serialize = "40000")]
     Directory,
+    // a submodule, the entry names a commit of another repository
+    #[strum(serialize = "160000")]
+    Gitlink,
+}
+
+impl FileMode {
+    // the numeric mode the index records for an entry
+    pub fn mode(&self) -> u32 {
+        match self {
+            Self::Regular => 0o100644,
+            Self::Executable => 0o100755,
+            Self::Symbolic => 0o120000,
+            Self::Directory => 0o040000,
+            Self::Gitlink => 0o160000,
+        }
+    }
+
+    // the type of object an entry with this mode refers to, known without reading the object
+    pub fn object_type(&self) -> GitObjectType {
+        match self {
+            Self::Directory => GitObjectType::Tree,
+            Self::Gitlink => GitObjectType::Commit,
+            _ => GitObjectType::Blob,
+        }
+    }
+
+    pub fn from_mode(mode: u32) -> Result<Self> {
+        match mode {
+            0o100644 => Ok(Self::Regular),
+            0o100755 => Ok(Self::Executable),
+            0o120000 => Ok(Self::Symbolic),
+            0o040000 => Ok(Self::Directory),
+            0o160000 => Ok(Self::Gitlink),
+            other => Err(anyhow!("unsupported file mode {other:o}")),
+        }
+    }
 }
 
 impl From<fs::Metadata> for FileMode {
     fn from(metadata: fs::Metadata) -> Self {
+        // symlinks always carry 0o777 permissions, so they're told apart first
         if metadata.is_dir() {
             Self::Directory
-        } else if metadata.permissions().mode() & 0o111 != 0 {
-            Self::Executable
         } else if metadata.is_symlink() {
             Self::Symbolic
+        } else if metadata.permissions().mode() & 0o111 != 0 {
+            Self::Executable
         } else {
             Self::Regular
         }
@@ -45,9 +88,17 @@ impl From<fs::Metadata> for FileMode {
 
 impl Tree {
     pub fn new(mut entries: Vec<TreeEntry>) -> Self {
-        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        // git orders directories as if their name ended with `/`
+        entries.sort_by_cached_key(|entry| match entry.mode {
+            FileMode::Directory => format!("{}/", entry.name),
+            _ => entry.name.clone(),
+        });
         Self(entries)
     }
+    // what a root commit's changes are compared against
+    pub fn empty() -> Self {
+        Self(vec![])
+    }
     pub fn entries(&self) -> &Vec<TreeEntry> {
         &self.0
     }
@@ -56,7 +107,8 @@ impl Tree {
 impl TreeEntry {
     pub fn new<Obj: GitObject, P: AsRef<Path>>(object: &Obj, path: P) -> Result<Self> {
         let path = path.as_ref();
-        let metadata = path.metadata().with_context(|| {
+        // a symlink's own metadata, it's stored as a link whatever it points at
+        let metadata = path.symlink_metadata().with_context(|| {
             format!("failed to create tree entry: failed to get metadata for file at {path:?}")
         })?;
 
@@ -79,11 +131,15 @@ impl TreeEntry {
         let mut iter = iter.into_iter();
         let iter = iter.by_ref();
 
-        let mode: FileMode = parse_bytes_with_context(iter.take_while(|b| b != &b' ').collect())
-            .with_context(|| format!("failed to parse tree entry mode"))?;
+        // git writes `40000` for directories, but trees from old versions of it can carry a
+        // zero-padded `040000`, which names the same mode
+        let mode = from_utf8_with_context(iter.take_while(|b| b != &b' ').collect())
+            .with_context(|| "failed to parse tree entry mode")?;
+        let mode: FileMode = parse_with_context(mode.trim_start_matches('0'))
+            .with_context(|| format!("failed to parse tree entry mode {mode:?}"))?;
 
         let name = from_utf8_with_context(iter.take_while(|b| b != &b'\0').collect())
-            .with_context(|| format!("failed to parse tree entry name"))?;
+            .with_context(|| "failed to parse tree entry name")?;
 
         let hash = Sha(iter.take(20).collect::<Vec<_>>().try_into().map_err(|_| {
             anyhow!("failed to parse tree entry sha1: expected it to contain exactly 20 bytes")
@@ -98,7 +154,7 @@ impl TreeEntry {
         encoded.push(b' ');
         encoded.extend_from_slice(self.name.as_bytes());
         encoded.push(b'\0');
-        encoded.extend_from_slice(&self.hash.as_ref());
+        encoded.extend_from_slice(self.hash.as_ref());
         encoded
     }
 }
@@ -123,9 +179,82 @@ impl GitObject for Tree {
         while iter.peek().is_some() {
             entries.push(
                 TreeEntry::decode(&mut iter)
-                    .with_context(|| format!("failed to parse tree object file entry"))?,
+                    .with_context(|| "failed to parse tree object file entry")?,
             );
         }
         Ok(Tree::new(entries))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::git_blob::{Blob, EMPTY_BLOB_SHA};
+
+    #[test]
+    fn empty_objects_hash_to_gits_ids() -> Result<()> {
+        assert_eq!(Tree::empty().sha1()?, EMPTY_TREE_SHA);
+        assert_eq!(
+            EMPTY_TREE_SHA.to_string(),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        assert_eq!(Blob::empty().sha1()?, EMPTY_BLOB_SHA);
+        assert_eq!(
+            EMPTY_BLOB_SHA.to_string(),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+        Ok(())
+    }
+
+    fn entry(mode: FileMode, name: &str) -> TreeEntry {
+        TreeEntry {
+            mode,
+            name: name.to_owned(),
+            hash: Sha([0x11; 20]),
+        }
+    }
+
+    // `<mode> <name>\0` and the raw sha, the way git writes tree entries
+    fn encoded(mode: &str, name: &str) -> Vec<u8> {
+        let mut encoded = format!("{mode} {name}\0").into_bytes();
+        encoded.extend([0x11; 20]);
+        encoded
+    }
+
+    #[test]
+    fn entries_encode_like_git() {
+        for (mode, text) in [
+            (FileMode::Regular, "100644"),
+            (FileMode::Executable, "100755"),
+            (FileMode::Symbolic, "120000"),
+            (FileMode::Directory, "40000"),
+            (FileMode::Gitlink, "160000"),
+        ] {
+            assert_eq!(
+                entry(mode, "name").encode(),
+                encoded(text, "name"),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn entries_decode_back() -> Result<()> {
+        for text in ["100644", "100755", "120000", "40000", "160000"] {
+            let decoded = TreeEntry::decode(encoded(text, "name"))?;
+            assert_eq!(decoded.mode.as_ref(), text);
+            assert_eq!(decoded.name, "name");
+            assert_eq!(decoded.hash, Sha([0x11; 20]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_zero_padded_directory_mode_decodes() -> Result<()> {
+        let decoded = TreeEntry::decode(encoded("040000", "dir"))?;
+        assert!(matches!(decoded.mode, FileMode::Directory));
+        // and is written back the way git writes it today
+        assert_eq!(decoded.encode(), encoded("40000", "dir"));
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use crate::utils::helpers::common_dir;
+use anyhow::{Context, Result};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// gitignore rules from `.git/info/exclude` and the `.gitignore` of every directory, the latter
+// loaded the first time a path below them is checked: https://git-scm.com/docs/gitignore
+#[derive(Debug)]
+pub struct IgnoreMatcher {
+    root: PathBuf,
+    exclude: Vec<IgnorePattern>,
+    // keyed by the directory relative to the root, "" being the root itself
+    per_directory: RefCell<HashMap<String, Vec<IgnorePattern>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IgnorePattern {
+    // directory the defining file lives in, anchored patterns are relative to it
+    base: String,
+    glob: String,
+    negated: bool,
+    directory_only: bool,
+    anchored: bool,
+    // the pattern as it's written, and the file and line it's on, for `check-ignore -v`
+    text: String,
+    source: Option<(String, usize)>,
+}
+
+impl IgnoreMatcher {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let exclude_path = common_dir(&root).join("info/exclude");
+        // named relative to the work tree where it can be, like git does
+        let exclude_source = exclude_path
+            .strip_prefix(&root)
+            .unwrap_or(&exclude_path)
+            .to_string_lossy()
+            .into_owned();
+        let exclude = read_patterns(&exclude_path, "", &exclude_source)?;
+        Ok(Self {
+            root,
+            exclude,
+            per_directory: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> Result<bool> {
+        Ok(self
+            .matching_pattern(path, is_dir)?
+            .is_some_and(|pattern| !pattern.negated))
+    }
+
+    // the pattern deciding whether `path` is ignored, which may be a negated one. everything
+    // below an ignored directory is ignored too, since git never looks inside it
+    pub fn matching_pattern(&self, path: &str, is_dir: bool) -> Result<Option<IgnorePattern>> {
+        let parents = path
+            .match_indices('/')
+            .map(|(index, _)| &path[..index])
+            .collect::<Vec<_>>();
+        for directory in parents {
+            if let Some(pattern) = self.last_match(directory, true)? {
+                if !pattern.negated {
+                    return Ok(Some(pattern));
+                }
+            }
+        }
+        self.last_match(path, is_dir)
+    }
+
+    fn last_match(&self, path: &str, is_dir: bool) -> Result<Option<IgnorePattern>> {
+        let mut patterns = self.exclude.clone();
+
+        // deeper files take precedence, so they come last
+        let directories =
+            std::iter::once("").chain(path.match_indices('/').map(|(index, _)| &path[..index]));
+        for directory in directories {
+            patterns.extend(self.directory_patterns(directory)?);
+        }
+
+        Ok(patterns
+            .into_iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir)))
+    }
+
+    fn directory_patterns(&self, directory: &str) -> Result<Vec<IgnorePattern>> {
+        if let Some(patterns) = self.per_directory.borrow().get(directory) {
+            return Ok(patterns.clone());
+        }
+
+        let source = match directory {
+            "" => ".gitignore".to_owned(),
+            directory => format!("{directory}/.gitignore"),
+        };
+        let patterns = read_patterns(&self.root.join(&source), directory, &source)?;
+        self.per_directory
+            .borrow_mut()
+            .insert(directory.to_owned(), patterns.clone());
+        Ok(patterns)
+    }
+}
+
+// the patterns of the file at `path`, `source` being how it's named to the user
+fn read_patterns(path: &Path, base: &str, source: &str) -> Result<Vec<IgnorePattern>> {
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("IgnoreMatcher: failed to read {path:?}"))?;
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            IgnorePattern::parse(line, base).map(|pattern| pattern.with_source(source, index + 1))
+        })
+        .collect())
+}
+
+impl IgnorePattern {
+    pub fn parse(line: &str, base: &str) -> Option<Self> {
+        // trailing spaces are dropped unless escaped with a backslash
+        let mut text = line.trim_end_matches(' ').to_owned();
+        if text.ends_with('\\') && line.len() > text.len() {
+            text.push(' ');
+        }
+        if text.is_empty() || text.starts_with('#') {
+            return None;
+        }
+
+        let (negated, glob) = match text.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            // `\#` and `\!` start patterns that would otherwise be comments or negations
+            None if text.starts_with("\\#") || text.starts_with("\\!") => (false, &text[1..]),
+            None => (false, text.as_str()),
+        };
+        let (directory_only, glob) = match glob.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, glob),
+        };
+        // a slash anywhere but at the end ties the pattern to the directory of its file
+        let anchored = glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob).to_owned();
+
+        Some(Self {
+            base: base.to_owned(),
+            glob,
+            negated,
+            directory_only,
+            anchored,
+            text,
+            source: None,
+        })
+    }
+
+    pub fn with_source(self, file: &str, line: usize) -> Self {
+        Self {
+            source: Some((file.to_owned(), line)),
+            ..self
+        }
+    }
+
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    // the pattern as written in its file
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // the file the pattern comes from and its line number in it
+    pub fn source(&self) -> Option<(&str, usize)> {
+        self.source
+            .as_ref()
+            .map(|(file, line)| (file.as_str(), *line))
+    }
+
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path
+                .strip_prefix(&self.base)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+
+        if self.anchored {
+            wildmatch(self.glob.as_bytes(), relative.as_bytes())
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            wildmatch(self.glob.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+// glob matching where `*`, `?` and classes stop at `/` and `**` spans directories
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[pattern.iter().take_while(|b| **b == b'*').count()..];
+            match rest.strip_prefix(b"/") {
+                // `**/` matches zero or more leading directories
+                Some(rest) => {
+                    wildmatch(rest, text)
+                        || text
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, b)| **b == b'/')
+                            .any(|(index, _)| wildmatch(rest, &text[index + 1..]))
+                }
+                None => (0..=text.len()).any(|index| wildmatch(rest, &text[index..])),
+            }
+        }
+        Some(b'*') => (0..=text.len())
+            .take_while(|index| *index == 0 || text[index - 1] != b'/')
+            .any(|index| wildmatch(&pattern[1..], &text[index..])),
+        Some(b'?') => {
+            text.first().is_some_and(|b| *b != b'/') && wildmatch(&pattern[1..], &text[1..])
+        }
+        Some(b'[') => match (
+            text.first(),
+            match_class(&pattern[1..], text.first().copied()),
+        ) {
+            (Some(b), Some((matched, rest))) => {
+                *b != b'/' && matched && wildmatch(rest, &text[1..])
+            }
+            // an unterminated class is taken literally
+            (Some(b'['), None) => wildmatch(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && wildmatch(&pattern[2..], &text[1..])
+        }
+        Some(b) => text.first() == Some(b) && wildmatch(&pattern[1..], &text[1..]),
+    }
+}
+
+// matches `byte` against the class starting right after `[`, returning the result and the rest
+// of the pattern after the closing `]`
+fn match_class(pattern: &[u8], byte: Option<u8>) -> Option<(bool, &[u8])> {
+    let byte = byte?;
+    let (negated, mut index) = match pattern.first() {
+        Some(b'!' | b'^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&b) = pattern.get(index) {
+        if b == b']' && !first {
+            return Some((matched != negated, &pattern[index + 1..]));
+        }
+        first = false;
+        if pattern.get(index + 1) == Some(&b'-')
+            && pattern.get(index + 2).is_some_and(|b| *b != b']')
+        {
+            matched |= (b..=pattern[index + 2]).contains(&byte);
+            index += 3;
+        } else {
+            matched |= b == byte;
+            index += 1;
+        }
+    }
+    None
+}
@@ -0,0 +1,301 @@
+use crate::{
+    git::{any_git_object::Sha, git_tree::FileMode},
+    utils::helpers::git_dir,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use sha1_smol::Sha1;
+use std::{collections::HashMap, fs, io::Write, os::unix::fs::MetadataExt, path::Path};
+
+const INDEX_HEADER_SIZE: usize = 12;
+const INDEX_CHECKSUM_SIZE: usize = 20;
+// stat fields, sha and flags that precede the name of every entry
+const ENTRY_FIXED_SIZE: usize = 62;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+// the staging area at `.git/index`: https://git-scm.com/docs/index-format
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub version: u32,
+    pub entries: Vec<IndexEntry>,
+    // mtime of the index file when it was read, see is_stat_clean
+    timestamp: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha: Sha,
+    pub flags: u16,
+    pub extended_flags: Option<u16>,
+    pub name: String,
+}
+
+impl Index {
+    // a repository without an index file has nothing staged yet
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = git_dir(path).join("index");
+        if !path.is_file() {
+            return Ok(Self {
+                version: 2,
+                entries: vec![],
+                timestamp: None,
+            });
+        }
+
+        let data =
+            fs::read(&path).with_context(|| format!("Index::read: failed to read {path:?}"))?;
+        let timestamp = fs::metadata(&path)
+            .map(|metadata| (metadata.mtime() as u32, metadata.mtime_nsec() as u32))
+            .ok();
+        if data.len() < INDEX_HEADER_SIZE + INDEX_CHECKSUM_SIZE || &data[..4] != b"DIRC" {
+            bail!("Index::read: {path:?} is not an index file");
+        }
+
+        let (content, checksum) = data.split_at(data.len() - INDEX_CHECKSUM_SIZE);
+        if Sha1::from(content).digest().bytes() != checksum {
+            bail!("Index::read: checksum mismatch in {path:?}");
+        }
+
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if !(2..=3).contains(&version) {
+            bail!("Index::read: unsupported index version {version} in {path:?}");
+        }
+        let entry_count = u32::from_be_bytes(data[8..12].try_into()?);
+
+        let mut offset = INDEX_HEADER_SIZE;
+        let mut entries = vec![];
+        for _ in 0..entry_count {
+            let (entry, entry_size) = IndexEntry::decode(&content[offset..])
+                .with_context(|| format!("Index::read: failed to read entry at {offset}"))?;
+            entries.push(entry);
+            offset += entry_size;
+        }
+
+        Ok(Self {
+            version,
+            entries,
+            timestamp,
+        })
+    }
+
+    // extensions (like the cached tree) aren't kept, git recreates whatever it needs
+    pub fn write<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = git_dir(path).join("index");
+        self.entries
+            .sort_by(|a, b| a.name.cmp(&b.name).then(a.stage().cmp(&b.stage())));
+
+        let mut data = b"DIRC".to_vec();
+        data.extend(self.version.to_be_bytes());
+        data.extend(
+            u32::try_from(self.entries.len())
+                .with_context(|| "Index::write: too many entries")?
+                .to_be_bytes(),
+        );
+        for entry in &self.entries {
+            data.extend(entry.encode());
+        }
+        let checksum = Sha1::from(&data).digest().bytes();
+        data.extend(checksum);
+
+        // git takes `index.lock` as its lock file, renaming it over the index publishes it
+        let lock_path = path.with_extension("lock");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .and_then(|mut file| file.write_all(&data))
+            .with_context(|| format!("Index::write: failed to write {lock_path:?}"))?;
+        fs::rename(&lock_path, &path).with_context(|| {
+            let _ = fs::remove_file(&lock_path);
+            format!("Index::write: failed to replace {path:?}")
+        })
+    }
+
+    // replaces the entries for `entry`'s path, a conflicted path becomes resolved
+    pub fn insert(&mut self, entry: IndexEntry) {
+        self.remove(&entry.name);
+        self.entries.push(entry);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|entry| entry.name != name);
+        self.entries.len() != count
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.iter().any(|entry| entry.stage() != 0)
+    }
+
+    // the entries at `stage` by their path, for callers looking up many paths at once
+    pub fn entries_at_stage(&self, stage: u16) -> HashMap<&str, &IndexEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.stage() == stage)
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|entry| entry.name == name)
+    }
+
+    // whether the file `entry` was recorded from is unchanged, judged by its lstat `metadata`
+    // alone so it doesn't have to be hashed again. a file modified in the same instant the index
+    // was written could still have the recorded stat data, so such racily clean entries are never
+    // trusted: https://git-scm.com/docs/racy-git
+    pub fn is_stat_clean(&self, entry: &IndexEntry, metadata: &fs::Metadata) -> bool {
+        let racy = self
+            .timestamp
+            .map_or(true, |timestamp| entry.mtime >= timestamp);
+        !racy
+            && entry.mtime == (metadata.mtime() as u32, metadata.mtime_nsec() as u32)
+            && entry.ctime == (metadata.ctime() as u32, metadata.ctime_nsec() as u32)
+            && entry.ino == metadata.ino() as u32
+            && entry.uid == metadata.uid()
+            && entry.gid == metadata.gid()
+            && entry.size == metadata.size() as u32
+            && entry.mode == FileMode::from(metadata.clone()).mode()
+    }
+
+    // whether any tracked path lives below the directory `name`
+    pub fn contains_directory(&self, name: &str) -> bool {
+        let prefix = format!("{name}/");
+        self.entries
+            .iter()
+            .any(|entry| entry.name.starts_with(&prefix))
+    }
+}
+
+impl IndexEntry {
+    // an entry for content that isn't checked out yet, so there is no stat data to record
+    pub fn new(name: &str, mode: u32, sha: Sha) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            sha,
+            flags: name.len().min(FLAG_NAME_MASK as usize) as u16,
+            extended_flags: None,
+            name: name.to_owned(),
+        }
+    }
+
+    // an entry for a file as it currently is in the working tree, `metadata` being its lstat
+    pub fn from_metadata(name: &str, sha: Sha, metadata: &fs::Metadata) -> Self {
+        // git truncates the stat fields to 32 bits as well
+        Self {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode: FileMode::from(metadata.clone()).mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            ..Self::new(name, 0, sha)
+        }
+    }
+
+    // 0 for a regular entry, 1-3 for the base/ours/theirs versions of a conflict
+    pub fn stage(&self) -> u16 {
+        (self.flags & FLAG_STAGE_MASK) >> FLAG_STAGE_SHIFT
+    }
+
+    pub fn with_stage(mut self, stage: u16) -> Self {
+        self.flags = (self.flags & !FLAG_STAGE_MASK) | (stage << FLAG_STAGE_SHIFT);
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for word in [
+            self.ctime.0,
+            self.ctime.1,
+            self.mtime.0,
+            self.mtime.1,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            data.extend(word.to_be_bytes());
+        }
+        data.extend(self.sha.as_ref());
+        data.extend(self.flags.to_be_bytes());
+        if let Some(extended_flags) = self.extended_flags {
+            data.extend(extended_flags.to_be_bytes());
+        }
+        data.extend(self.name.as_bytes());
+
+        let padded_size = (data.len() + 8) & !7;
+        data.resize(padded_size, 0);
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<(Self, usize)> {
+        if data.len() < ENTRY_FIXED_SIZE {
+            bail!("IndexEntry::decode: entry is truncated");
+        }
+        let word =
+            |index: usize| u32::from_be_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+
+        let flags = u16::from_be_bytes(data[60..62].try_into()?);
+        let (extended_flags, name_start) = if flags & FLAG_EXTENDED != 0 {
+            let extended = data
+                .get(62..64)
+                .ok_or_else(|| anyhow!("IndexEntry::decode: extended flags are truncated"))?;
+            (
+                Some(u16::from_be_bytes(extended.try_into()?)),
+                ENTRY_FIXED_SIZE + 2,
+            )
+        } else {
+            (None, ENTRY_FIXED_SIZE)
+        };
+
+        let name_length = data[name_start..]
+            .iter()
+            .position(|b| b == &0)
+            .ok_or_else(|| anyhow!("IndexEntry::decode: name isn't NUL-terminated"))?;
+        let name = String::from_utf8(data[name_start..name_start + name_length].to_vec())
+            .with_context(|| "IndexEntry::decode: name isn't valid utf8")?;
+
+        // entries are padded with 1-8 NUL bytes to a multiple of eight
+        let entry_size = (name_start + name_length + 8) & !7;
+
+        Ok((
+            Self {
+                ctime: (word(0), word(1)),
+                mtime: (word(2), word(3)),
+                dev: word(4),
+                ino: word(5),
+                mode: word(6),
+                uid: word(7),
+                gid: word(8),
+                size: word(9),
+                sha: Sha(data[40..60].try_into()?),
+                flags,
+                extended_flags,
+                name,
+            },
+            entry_size,
+        ))
+    }
+}
@@ -0,0 +1,204 @@
+use crate::git::{
+    any_git_object::Sha,
+    diff::{is_binary, matching_lines, split_lines},
+    git_blob::Blob,
+    git_object_trait::GitObject,
+    git_tree::FileMode,
+    object_store::ObjectStore,
+    tree_walk::flatten_tree,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+#[derive(Debug)]
+pub struct TreeMerge {
+    pub entries: BTreeMap<String, (FileMode, Sha)>,
+    // paths whose entry holds conflict markers, or one side's version when markers can't be used
+    pub conflicts: Vec<MergeConflict>,
+}
+
+// the three versions of a conflicted path, which the index records as stages 1 to 3
+#[derive(Debug)]
+pub struct MergeConflict {
+    pub name: String,
+    pub base: Option<(FileMode, Sha)>,
+    pub ours: Option<(FileMode, Sha)>,
+    pub theirs: Option<(FileMode, Sha)>,
+}
+
+impl MergeConflict {
+    fn new(
+        name: &str,
+        base: Option<&(FileMode, Sha)>,
+        ours: Option<&(FileMode, Sha)>,
+        theirs: Option<&(FileMode, Sha)>,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            base: base.cloned(),
+            ours: ours.cloned(),
+            theirs: theirs.cloned(),
+        }
+    }
+}
+
+// three-way merge of `ours` and `theirs` against their common `base`, every blob it creates is
+// written to the repository at `path`
+pub fn merge_trees<P: AsRef<Path>>(
+    path: P,
+    store: &ObjectStore,
+    base: &Sha,
+    ours: &Sha,
+    theirs: &Sha,
+    labels: (&str, &str),
+) -> Result<TreeMerge> {
+    let base = flatten_tree(store, base)?;
+    let ours = flatten_tree(store, ours)?;
+    let theirs = flatten_tree(store, theirs)?;
+
+    let mut entries = BTreeMap::new();
+    let mut conflicts = vec![];
+
+    let paths = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect::<BTreeSet<_>>();
+
+    for name in paths {
+        let (b, o, t) = (base.get(name), ours.get(name), theirs.get(name));
+        let same = |x: Option<&(FileMode, Sha)>, y: Option<&(FileMode, Sha)>| {
+            x.map(|(mode, sha)| (mode.as_ref(), sha)) == y.map(|(mode, sha)| (mode.as_ref(), sha))
+        };
+
+        let merged = if same(o, t) || same(b, t) {
+            o.cloned()
+        } else if same(b, o) {
+            t.cloned()
+        } else {
+            match (o, t) {
+                (Some((our_mode, our_sha)), Some((their_mode, their_sha))) => {
+                    let read = |sha: &Sha| -> Result<Vec<u8>> {
+                        Ok(store
+                            .read(sha)?
+                            .try_as_blob()
+                            .ok_or_else(|| anyhow!("merge_trees: expected {sha} to be a blob"))?
+                            .content()
+                            .to_vec())
+                    };
+                    let base_content = match b {
+                        Some((_, sha)) => read(sha)?,
+                        None => vec![],
+                    };
+                    let (content, conflicted) =
+                        merge_blobs(&base_content, &read(our_sha)?, &read(their_sha)?, labels);
+                    if conflicted {
+                        conflicts.push(MergeConflict::new(name, b, o, t));
+                    }
+
+                    let blob = Blob::new(content);
+                    blob.write(path.as_ref()).with_context(|| {
+                        format!("merge_trees: failed to write merged blob for {name}")
+                    })?;
+                    // a mode change on one side survives a content merge
+                    let mode = match b {
+                        Some((base_mode, _)) if base_mode.as_ref() == our_mode.as_ref() => {
+                            their_mode.clone()
+                        }
+                        _ => our_mode.clone(),
+                    };
+                    Some((mode, blob.sha1()?))
+                }
+                // modified on one side and deleted on the other: the modified version is kept
+                // so nothing is lost while the conflict gets resolved
+                (kept, other) => {
+                    conflicts.push(MergeConflict::new(name, b, o, t));
+                    kept.or(other).cloned()
+                }
+            }
+        };
+
+        if let Some(entry) = merged {
+            entries.insert(name.clone(), entry);
+        }
+    }
+
+    Ok(TreeMerge { entries, conflicts })
+}
+
+// line-based three-way merge, returns the merged content and whether it contains conflicts
+pub fn merge_blobs(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: (&str, &str),
+) -> (Vec<u8>, bool) {
+    if [base, ours, theirs].into_iter().any(is_binary) {
+        return (ours.to_vec(), true);
+    }
+
+    let (base, ours, theirs) = (split_lines(base), split_lines(ours), split_lines(theirs));
+    let our_matches = matching_lines(&base, &ours)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let their_matches = matching_lines(&base, &theirs)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let mut merged = vec![];
+    let mut conflicted = false;
+    let (mut b, mut o, mut t) = (0, 0, 0);
+
+    // lines matched on both sides are stable, the hunks between two stable lines are resolved
+    // by taking whichever side changed them
+    loop {
+        let next_stable = (b..base.len()).find(|i| {
+            our_matches.get(i).is_some_and(|&j| j >= o)
+                && their_matches.get(i).is_some_and(|&j| j >= t)
+        });
+        let (base_end, our_end, their_end) = match next_stable {
+            Some(i) => (i, our_matches[&i], their_matches[&i]),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        let base_hunk = &base[b..base_end];
+        let our_hunk = &ours[o..our_end];
+        let their_hunk = &theirs[t..their_end];
+
+        if our_hunk == base_hunk || our_hunk == their_hunk {
+            merged.extend(their_hunk.concat());
+        } else if their_hunk == base_hunk {
+            merged.extend(our_hunk.concat());
+        } else {
+            conflicted = true;
+            merged.extend(format!("<<<<<<< {}\n", labels.0).into_bytes());
+            push_conflict_side(&mut merged, our_hunk);
+            merged.extend(b"=======\n");
+            push_conflict_side(&mut merged, their_hunk);
+            merged.extend(format!(">>>>>>> {}\n", labels.1).into_bytes());
+        }
+
+        match next_stable {
+            Some(i) => {
+                merged.extend_from_slice(base[i]);
+                (b, o, t) = (i + 1, our_end + 1, their_end + 1);
+            }
+            None => break,
+        }
+    }
+
+    (merged, conflicted)
+}
+
+// markers have to start on a line of their own, even when a side ends without a newline
+fn push_conflict_side(merged: &mut Vec<u8>, lines: &[&[u8]]) {
+    let content = lines.concat();
+    let terminated = content.is_empty() || content.ends_with(b"\n");
+    merged.extend(content);
+    if !terminated {
+        merged.push(b'\n');
+    }
+}
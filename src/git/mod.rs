@@ -1,8 +1,32 @@
 pub mod any_git_object;
+pub mod archive;
+pub mod attributes;
+pub mod blame;
+pub mod clean;
+pub mod commit_graph;
 pub mod commits;
 pub mod compression;
+pub mod config;
+pub mod diff;
+pub mod diff_driver;
 pub mod file_tree;
+pub mod filter;
 pub mod git_blob;
 pub mod git_client;
 pub mod git_object_trait;
+pub mod git_tag;
 pub mod git_tree;
+pub mod ignore;
+pub mod index;
+pub mod merge;
+pub mod name_rev;
+pub mod object_store;
+pub mod pack_index;
+pub mod pack_reader;
+pub mod packfile;
+pub mod progress;
+pub mod reachability;
+pub mod refs;
+pub mod refspec;
+pub mod textdiff;
+pub mod tree_walk;
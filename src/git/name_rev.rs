@@ -0,0 +1,192 @@
+use crate::git::{
+    any_git_object::{AnyGitObject, Sha},
+    object_store::ObjectStore,
+    refs::RefStore,
+};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+// going to a merge's second or later parent costs as much as this many first-parent steps, so a
+// name only goes through a merge when there's no straight way to the commit
+const MERGE_TRAVERSAL_WEIGHT: usize = 65535;
+
+// how a commit is reached from a ref: `generation` first-parent steps below `tip`, which already
+// spells out any merges taken on the way
+struct RevName {
+    tip: String,
+    // the date of the tag or commit the ref points at, older tips win ties
+    tip_date: u64,
+    from_tag: bool,
+    generation: usize,
+    distance: usize,
+}
+
+impl RevName {
+    // git's is_better_name: names from tags beat the others, then the shortest way wins
+    fn is_better_than(&self, other: &RevName) -> bool {
+        let effective = |name: &RevName| {
+            name.distance
+                + if name.generation > 0 {
+                    MERGE_TRAVERSAL_WEIGHT
+                } else {
+                    0
+                }
+        };
+        if self.from_tag && other.from_tag {
+            return effective(self) < effective(other);
+        }
+        if self.from_tag != other.from_tag {
+            return self.from_tag;
+        }
+        if self.distance != other.distance {
+            return self.distance < other.distance;
+        }
+        self.tip_date < other.tip_date
+    }
+
+    // `main~3`, or the tip itself when the commit is the one the ref points at
+    fn name(&self) -> String {
+        match self.generation {
+            0 => self.tip.clone(),
+            generation => format!("{}~{generation}", tip_without_peel(&self.tip)),
+        }
+    }
+
+    // the tip naming the `number`-th parent of this commit, like `main~3^2`
+    fn parent_tip(&self, number: usize) -> String {
+        let tip = tip_without_peel(&self.tip);
+        match self.generation {
+            0 => format!("{tip}^{number}"),
+            generation => format!("{tip}~{generation}^{number}"),
+        }
+    }
+}
+
+// an annotated tag names its commit as `tags/v1.0^0`, which is dropped once steps follow
+fn tip_without_peel(tip: &str) -> &str {
+    tip.strip_suffix("^0").unwrap_or(tip)
+}
+
+// a name like `main~3` or `tags/v1.0^2` for every commit reachable from the refs (only tags with
+// `tags_only`), the way `git name-rev` picks them: refs under refs/heads lose that prefix, the
+// others just `refs/`
+pub fn name_commits(
+    store: &ObjectStore,
+    refs: &RefStore,
+    tags_only: bool,
+) -> Result<HashMap<Sha, String>> {
+    let mut tips = vec![];
+    for (name, sha) in refs
+        .list()
+        .with_context(|| "name_commits: failed to list refs")?
+    {
+        if tags_only && !name.starts_with("refs/tags/") {
+            continue;
+        }
+        let from_tag = name.starts_with("refs/tags/");
+        let short = name
+            .strip_prefix("refs/heads/")
+            .or_else(|| name.strip_prefix("refs/"))
+            .unwrap_or(&name);
+
+        // tags are followed down to the commit, their own date standing for the tip
+        let mut target = sha;
+        let mut tip_date = None;
+        let mut peeled = false;
+        let commit = loop {
+            match store
+                .read(&target)
+                .with_context(|| format!("name_commits: failed to read {target}"))?
+            {
+                AnyGitObject::Tag(tag) => {
+                    tip_date = tip_date.or(tag.tagger.as_ref().map(|tagger| tagger.epoch));
+                    target = tag.object_hash;
+                    peeled = true;
+                }
+                AnyGitObject::Commit(commit) => break Some(commit),
+                _ => break None,
+            }
+        };
+        // refs to trees and blobs don't name any commit
+        let Some(commit) = commit else {
+            continue;
+        };
+
+        tips.push((
+            target,
+            RevName {
+                tip: if peeled {
+                    format!("{short}^0")
+                } else {
+                    short.to_owned()
+                },
+                tip_date: tip_date.unwrap_or(commit.committer().epoch),
+                from_tag,
+                generation: 0,
+                distance: 0,
+            },
+        ));
+    }
+    // tags first, then the oldest tips, so the same commit gets the same name as in git
+    tips.sort_by_key(|(_, name)| (!name.from_tag, name.tip_date));
+
+    let mut parents: HashMap<Sha, Vec<Sha>> = HashMap::new();
+    let mut names: HashMap<Sha, RevName> = HashMap::new();
+    for (sha, name) in tips {
+        if names
+            .get(&sha)
+            .is_some_and(|existing| !name.is_better_than(existing))
+        {
+            continue;
+        }
+        names.insert(sha.clone(), name);
+
+        let mut pending = vec![sha];
+        while let Some(sha) = pending.pop() {
+            if !parents.contains_key(&sha) {
+                let commit = store
+                    .read_commit(&sha)
+                    .with_context(|| format!("name_commits: failed to read commit {sha}"))?;
+                parents.insert(sha.clone(), commit.parent_hash);
+            }
+
+            let mut renamed = vec![];
+            for (index, parent) in parents[&sha].iter().enumerate() {
+                let name = &names[&sha];
+                let number = index + 1;
+                let candidate = if number > 1 {
+                    RevName {
+                        tip: name.parent_tip(number),
+                        tip_date: name.tip_date,
+                        from_tag: name.from_tag,
+                        generation: 0,
+                        distance: name.distance + MERGE_TRAVERSAL_WEIGHT,
+                    }
+                } else {
+                    RevName {
+                        tip: name.tip.clone(),
+                        tip_date: name.tip_date,
+                        from_tag: name.from_tag,
+                        generation: name.generation + 1,
+                        distance: name.distance + 1,
+                    }
+                };
+                if names
+                    .get(parent)
+                    .is_some_and(|existing| !candidate.is_better_than(existing))
+                {
+                    continue;
+                }
+                names.insert(parent.clone(), candidate);
+                renamed.push(parent.clone());
+            }
+            // the first parent is walked first
+            pending.extend(renamed.into_iter().rev());
+        }
+    }
+
+    Ok(names
+        .into_iter()
+        .map(|(sha, name)| (sha, name.name()))
+        .collect())
+}
@@ -0,0 +1,503 @@
+use crate::{
+    git::{
+        any_git_object::{AnyGitObject, Sha},
+        commit_graph::{CommitGraph, CommitNode, GENERATION_INFINITY},
+        commits::Commit,
+        compression::decompress,
+        config::Config,
+        git_blob::{Blob, EMPTY_BLOB_SHA},
+        git_tree::{Tree, EMPTY_TREE_SHA},
+        pack_reader::{PackReader, DEFAULT_DELTA_BASE_CACHE_LIMIT},
+        packfile::max_delta_depth,
+    },
+    utils::helpers::common_dir,
+};
+use anyhow::{anyhow, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+// alternates listing further alternates are followed this deep, like in git
+const MAX_ALTERNATE_DEPTH: usize = 5;
+// decoded commits kept around when `core.commitCacheSize` doesn't say otherwise
+const DEFAULT_COMMIT_CACHE_SIZE: usize = 50_000;
+
+#[derive(Debug)]
+pub struct ObjectStore {
+    objects_dir: PathBuf,
+    packs: OnceCell<Vec<PackReader>>,
+    // object directories from `objects/info/alternates`, searched after this one
+    alternates: OnceCell<Vec<ObjectStore>>,
+    // every object id of the store and its alternates, sorted, once abbreviations need them
+    object_ids: OnceCell<Vec<Sha>>,
+    // commits already decoded by read_commit, sized by the config on first use
+    commits: OnceCell<RefCell<CommitCache>>,
+    // `info/commit-graph`, unless there's none or `core.commitGraph` is false
+    commit_graph: OnceCell<Option<CommitGraph>>,
+}
+
+impl ObjectStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self::at_objects_dir(common_dir(path).join("objects"))
+    }
+
+    fn at_objects_dir(objects_dir: PathBuf) -> Self {
+        Self {
+            objects_dir,
+            packs: OnceCell::new(),
+            alternates: OnceCell::new(),
+            object_ids: OnceCell::new(),
+            commits: OnceCell::new(),
+            commit_graph: OnceCell::new(),
+        }
+    }
+
+    // this store followed by its alternates, in the order objects are looked up
+    fn stores(&self) -> Result<Vec<&ObjectStore>> {
+        if self.alternates.get().is_none() {
+            let mut alternates = vec![];
+            read_alternates(&self.objects_dir, 0, &mut alternates)?;
+            let _ = self.alternates.set(
+                alternates
+                    .into_iter()
+                    .map(ObjectStore::at_objects_dir)
+                    .collect(),
+            );
+        }
+
+        Ok(std::iter::once(self)
+            .chain(self.alternates.get().into_iter().flatten())
+            .collect())
+    }
+
+    pub fn packs(&self) -> Result<&Vec<PackReader>> {
+        if let Some(packs) = self.packs.get() {
+            return Ok(packs);
+        }
+
+        let pack_dir = self.objects_dir().join("pack");
+        let mut packs = vec![];
+        if pack_dir.is_dir() {
+            let config = Config::load(self.objects_dir.parent().unwrap_or(&self.objects_dir))
+                .with_context(|| "ObjectStore::packs: failed to load config")?;
+            let delta_base_cache_limit = config
+                .get_size("core.deltaBaseCacheLimit")?
+                .unwrap_or(DEFAULT_DELTA_BASE_CACHE_LIMIT);
+            let max_delta_depth = max_delta_depth(&config)?;
+
+            for entry in fs::read_dir(&pack_dir)
+                .with_context(|| format!("ObjectStore::packs: failed to read {pack_dir:?}"))?
+            {
+                let path = entry
+                    .with_context(|| {
+                        format!("ObjectStore::packs: failed to read entry in {pack_dir:?}")
+                    })?
+                    .path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "pack")
+                {
+                    packs.push(
+                        PackReader::open(&path)?
+                            .with_delta_base_cache_limit(delta_base_cache_limit)
+                            .with_max_delta_depth(max_delta_depth),
+                    );
+                }
+            }
+        }
+
+        Ok(self.packs.get_or_init(|| packs))
+    }
+
+    pub fn objects_dir(&self) -> PathBuf {
+        self.objects_dir.clone()
+    }
+
+    pub fn contains(&self, sha: &Sha) -> Result<bool> {
+        for store in self.stores()? {
+            if store.loose_object_path(sha).is_file() {
+                return Ok(true);
+            }
+            for pack in store.packs()? {
+                if pack.find(sha)?.is_some() {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn read(&self, sha: &Sha) -> Result<AnyGitObject> {
+        self.read_at_depth(sha, 0)
+    }
+
+    // a blob's size and a reader for its content. a loose blob is decompressed as it's read, so
+    // it never has to fit in memory; a packed one may be a delta and is read whole first
+    pub fn open_blob(&self, sha: &Sha) -> Result<(u64, Box<dyn Read>)> {
+        for store in self.stores()? {
+            let path = store.loose_object_path(sha);
+            if !path.is_file() {
+                continue;
+            }
+            let file = fs::File::open(&path)
+                .with_context(|| format!("ObjectStore::open_blob: failed to open {path:?}"))?;
+            let mut content = BufReader::new(ZlibDecoder::new(file));
+            let mut header = vec![];
+            content
+                .read_until(b'\0', &mut header)
+                .with_context(|| format!("ObjectStore::open_blob: failed to read {path:?}"))?;
+            let size = std::str::from_utf8(&header)
+                .ok()
+                .and_then(|header| header.strip_suffix('\0')?.strip_prefix("blob "))
+                .and_then(|size| size.parse::<u64>().ok())
+                .ok_or_else(|| anyhow!("ObjectStore::open_blob: {sha} is not a blob"))?;
+            return Ok((size, Box::new(content.take(size))));
+        }
+
+        let content: Vec<u8> = self
+            .read(sha)?
+            .try_as_blob()
+            .ok_or_else(|| anyhow!("ObjectStore::open_blob: {sha} is not a blob"))?
+            .content
+            .into();
+        Ok((content.len() as u64, Box::new(io::Cursor::new(content))))
+    }
+
+    // like read, for a commit. history walks come back to the same commits a lot, so they're
+    // cached after the first read
+    pub fn read_commit(&self, sha: &Sha) -> Result<Commit> {
+        if self.commits.get().is_none() {
+            // the objects directory sits in the repository whose config applies
+            let config = Config::load(self.objects_dir.parent().unwrap_or(&self.objects_dir))
+                .with_context(|| "ObjectStore::read_commit: failed to load config")?;
+            let capacity = match config.get("core.commitCacheSize") {
+                Some(value) => value.parse().with_context(|| {
+                    format!("ObjectStore::read_commit: bad core.commitCacheSize {value:?}")
+                })?,
+                None => DEFAULT_COMMIT_CACHE_SIZE,
+            };
+            let _ = self.commits.set(RefCell::new(CommitCache::new(capacity)));
+        }
+        let cache = self.commits.get().expect("commit cache was just set");
+
+        if let Some(commit) = cache.borrow_mut().get(sha) {
+            return Ok(commit);
+        }
+        let commit = self
+            .read(sha)?
+            .try_as_commit()
+            .ok_or_else(|| anyhow!("ObjectStore::read_commit: expected {sha} to be a commit"))?;
+        cache.borrow_mut().insert(sha.clone(), commit.clone());
+        Ok(commit)
+    }
+
+    pub fn commit_graph_path(&self) -> PathBuf {
+        self.objects_dir.join("info/commit-graph")
+    }
+
+    // only the graph of this store is read. it's written from a walk of the whole history, so it
+    // has the commits of alternates that this repository's refs lead to anyway
+    pub fn commit_graph(&self) -> Result<Option<&CommitGraph>> {
+        if let Some(graph) = self.commit_graph.get() {
+            return Ok(graph.as_ref());
+        }
+
+        let config = Config::load(self.objects_dir.parent().unwrap_or(&self.objects_dir))
+            .with_context(|| "ObjectStore::commit_graph: failed to load config")?;
+        let path = self.commit_graph_path();
+        let graph = if config.get("core.commitGraph") == Some("false") || !path.is_file() {
+            None
+        } else {
+            // like git, a broken graph only means walking without it
+            match CommitGraph::open(&path) {
+                Ok(graph) => Some(graph),
+                Err(err) => {
+                    eprintln!("warning: ignoring commit graph: {err:#}");
+                    None
+                }
+            }
+        };
+        Ok(self.commit_graph.get_or_init(|| graph).as_ref())
+    }
+
+    // the parents, tree and dates of a commit, from the commit graph when it has the commit
+    pub fn read_commit_node(&self, sha: &Sha) -> Result<CommitNode> {
+        if let Some(graph) = self.commit_graph()? {
+            if let Some(node) = graph.node(sha)? {
+                return Ok(node);
+            }
+        }
+        let commit = self.read_commit(sha)?;
+        Ok(CommitNode {
+            tree: commit.tree_hash.clone(),
+            commit_time: commit.committer().epoch,
+            parents: commit.parent_hash,
+            generation: GENERATION_INFINITY,
+        })
+    }
+
+    // the generation of `sha` in the commit graph, which needn't even have the object
+    pub fn commit_generation(&self, sha: &Sha) -> Result<u32> {
+        match self.commit_graph()? {
+            Some(graph) => Ok(graph
+                .node(sha)?
+                .map_or(GENERATION_INFINITY, |node| node.generation)),
+            None => Ok(GENERATION_INFINITY),
+        }
+    }
+
+    // type string and body size from a loose object's header, which may name types outside
+    // blob/tree/commit/tag (e.g. from `hash-object --literally`)
+    fn loose_header(&self, sha: &Sha) -> Result<Option<(String, u64)>> {
+        let Some(path) = self
+            .stores()?
+            .into_iter()
+            .map(|store| store.loose_object_path(sha))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+
+        let decompressed = decompress(
+            fs::read(&path)
+                .with_context(|| format!("ObjectStore::loose_header: failed to read {path:?}"))?,
+        )
+        .with_context(|| format!("ObjectStore::loose_header: failed to decompress {path:?}"))?;
+        let (object_type, size, _) = AnyGitObject::split_header(&decompressed)
+            .with_context(|| format!("ObjectStore::loose_header: invalid header in {path:?}"))?;
+        Ok(Some((object_type.to_string(), size as u64)))
+    }
+
+    pub fn read_type(&self, sha: &Sha) -> Result<String> {
+        match self.loose_header(sha)? {
+            Some((object_type, _)) => Ok(object_type),
+            None => Ok(self.read(sha)?.get_type().as_ref().to_string()),
+        }
+    }
+
+    pub fn read_size(&self, sha: &Sha) -> Result<u64> {
+        match self.loose_header(sha)? {
+            Some((_, size)) => Ok(size),
+            None => Ok(self.read(sha)?.encode_body()?.len() as u64),
+        }
+    }
+
+    // bytes the object takes up in the store: the compressed loose file, or the pack entry
+    pub fn disk_size(&self, sha: &Sha) -> Result<u64> {
+        for store in self.stores()? {
+            let path = store.loose_object_path(sha);
+            if path.is_file() {
+                return Ok(fs::metadata(&path)
+                    .with_context(|| format!("ObjectStore::disk_size: failed to stat {path:?}"))?
+                    .len());
+            }
+
+            for pack in store.packs()? {
+                if let Some(offset) = pack.find(sha)? {
+                    return pack.entry_size(offset);
+                }
+            }
+        }
+
+        Err(anyhow!("ObjectStore::disk_size: object {sha} not found"))
+    }
+
+    fn read_at_depth(&self, sha: &Sha, depth: usize) -> Result<AnyGitObject> {
+        for store in self.stores()? {
+            let path = store.loose_object_path(sha);
+            if path.is_file() {
+                return AnyGitObject::read(&path)
+                    .with_context(|| format!("ObjectStore::read: failed to read object {sha}"));
+            }
+
+            for pack in store.packs()? {
+                if let Some(offset) = pack.find(sha)? {
+                    // delta bases may live in any of the stores
+                    return pack
+                        .read_at(offset, depth, &|base: &Sha, depth| {
+                            self.read_at_depth(base, depth)
+                        })
+                        .with_context(|| {
+                            format!(
+                                "ObjectStore::read: failed to read object {sha} from {:?}",
+                                pack.path()
+                            )
+                        });
+                }
+            }
+        }
+
+        // the empty tree and blob can be read whether or not they were ever written, git does the
+        // same for the empty tree
+        if sha == &EMPTY_TREE_SHA {
+            return Ok(AnyGitObject::Tree(Tree::empty()));
+        }
+        if sha == &EMPTY_BLOB_SHA {
+            return Ok(AnyGitObject::Blob(Blob::empty()));
+        }
+
+        Err(anyhow!("ObjectStore::read: object {sha} not found"))
+    }
+
+    // only the objects of this store, alternates belong to other repositories
+    pub fn loose_objects(&self) -> Result<Vec<Sha>> {
+        let objects_dir = self.objects_dir();
+        let mut shas = vec![];
+
+        for folder in fs::read_dir(&objects_dir).with_context(|| {
+            format!("ObjectStore::loose_objects: failed to read {objects_dir:?}")
+        })? {
+            let folder = folder.with_context(|| {
+                format!("ObjectStore::loose_objects: failed to read entry in {objects_dir:?}")
+            })?;
+            let prefix = folder.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !folder.path().is_dir() {
+                continue;
+            }
+
+            for file in fs::read_dir(folder.path()).with_context(|| {
+                format!(
+                    "ObjectStore::loose_objects: failed to read {:?}",
+                    folder.path()
+                )
+            })? {
+                let file = file.with_context(|| {
+                    format!(
+                        "ObjectStore::loose_objects: failed to read entry in {:?}",
+                        folder.path()
+                    )
+                })?;
+                let name = format!("{prefix}{}", file.file_name().to_string_lossy());
+                if let Ok(Ok(sha)) = hex::decode(&name).map(<[u8; 20]>::try_from) {
+                    shas.push(Sha(sha));
+                }
+            }
+        }
+
+        Ok(shas)
+    }
+
+    // the shortest prefix of `sha`, at least `min_len` hex digits, that no other object starts
+    // with. in sorted order only the neighbours of `sha` can share a longer prefix with it
+    pub fn find_unique_abbrev(&self, sha: &Sha, min_len: usize) -> Result<String> {
+        let object_ids = self.object_ids()?;
+        let position = object_ids.partition_point(|object_id| object_id.0 < sha.0);
+        let next = match object_ids.get(position) {
+            Some(object_id) if object_id == sha => object_ids.get(position + 1),
+            next => next,
+        };
+        let shared = position
+            .checked_sub(1)
+            .map(|previous| &object_ids[previous])
+            .into_iter()
+            .chain(next)
+            .map(|neighbour| shared_hex_digits(&neighbour.0, &sha.0))
+            .max()
+            .unwrap_or(0);
+
+        let hex = sha.to_string();
+        Ok(hex[..(shared + 1).max(min_len).min(hex.len())].to_owned())
+    }
+
+    // every object of the store and its alternates, loose or packed, sorted
+    pub fn object_ids(&self) -> Result<&Vec<Sha>> {
+        if let Some(object_ids) = self.object_ids.get() {
+            return Ok(object_ids);
+        }
+
+        let mut object_ids = vec![];
+        for store in self.stores()? {
+            object_ids.extend(store.loose_objects()?);
+            for pack in store.packs()? {
+                object_ids.extend(pack.object_ids()?);
+            }
+        }
+        object_ids.sort_unstable_by_key(|object_id| object_id.0);
+        object_ids.dedup();
+
+        Ok(self.object_ids.get_or_init(|| object_ids))
+    }
+
+    pub fn loose_object_path(&self, sha: &Sha) -> PathBuf {
+        let sha = sha.to_string();
+        self.objects_dir.join(&sha[..2]).join(&sha[2..])
+    }
+}
+
+// decoded commits, at most `capacity` of them. they're kept in two generations: a commit found in
+// the older one moves to the newer, and the older one is dropped whenever the newer one fills up,
+// so the commits not used for longest go first without keeping track of every use
+#[derive(Debug)]
+struct CommitCache {
+    capacity: usize,
+    recent: HashMap<Sha, Commit>,
+    older: HashMap<Sha, Commit>,
+}
+
+impl CommitCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: HashMap::new(),
+            older: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, sha: &Sha) -> Option<Commit> {
+        if let Some(commit) = self.recent.get(sha) {
+            return Some(commit.clone());
+        }
+        let commit = self.older.remove(sha)?;
+        self.insert(sha.clone(), commit.clone());
+        Some(commit)
+    }
+
+    // a capacity of 0 turns the cache off
+    fn insert(&mut self, sha: Sha, commit: Commit) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.recent.len() >= self.capacity.div_ceil(2) {
+            self.older = std::mem::take(&mut self.recent);
+        }
+        self.recent.insert(sha, commit);
+    }
+}
+
+// how many leading hex digits two object ids have in common
+fn shared_hex_digits(a: &[u8; 20], b: &[u8; 20]) -> usize {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(byte) if a[byte] >> 4 == b[byte] >> 4 => byte * 2 + 1,
+        Some(byte) => byte * 2,
+        None => 40,
+    }
+}
+
+// the object directories listed in `objects_dir/info/alternates` and, recursively, in theirs.
+// relative entries are relative to `objects_dir`, like in git
+fn read_alternates(objects_dir: &Path, depth: usize, alternates: &mut Vec<PathBuf>) -> Result<()> {
+    let path = objects_dir.join("info/alternates");
+    if depth > MAX_ALTERNATE_DEPTH || !path.is_file() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("read_alternates: failed to read {path:?}"))?;
+    for line in content.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let alternate = objects_dir.join(line);
+        if !alternate.is_dir() || alternates.contains(&alternate) {
+            continue;
+        }
+        alternates.push(alternate.clone());
+        read_alternates(&alternate, depth + 1, alternates)?;
+    }
+    Ok(())
+}
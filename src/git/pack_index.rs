@@ -0,0 +1,271 @@
+use crate::git::{any_git_object::Sha, packfile::Packfile};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::Crc;
+use sha1_smol::Sha1;
+use std::{fs, path::Path};
+
+// version 2 pack index: https://git-scm.com/docs/pack-format#_version_2_pack_idx_files_support_packs_larger_than_4_gib_and
+const INDEX_MAGIC: &[u8] = b"\xfftOc";
+const INDEX_HEADER_SIZE: usize = 8;
+const FANOUT_SIZE: usize = 256 * 4;
+// sha, crc32 and 32-bit offset of every object
+const ENTRY_SIZE: usize = 20 + 4 + 4;
+// checksum of the pack followed by the checksum of the index itself
+const INDEX_TRAILER_SIZE: usize = 20 + 20;
+// a 32-bit offset with this bit set is an index into the table of 64-bit offsets
+const LARGE_OFFSET_FLAG: u32 = 0x8000_0000;
+
+#[derive(Debug)]
+pub struct PackIndex {
+    data: Vec<u8>,
+    object_count: usize,
+}
+
+impl PackIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let data =
+            fs::read(&path).with_context(|| format!("PackIndex::open: failed to read {path:?}"))?;
+
+        if data.len() < INDEX_HEADER_SIZE + FANOUT_SIZE + INDEX_TRAILER_SIZE
+            || &data[..4] != INDEX_MAGIC
+        {
+            bail!("PackIndex::open: {path:?} is not a version 2 pack index");
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 {
+            bail!("PackIndex::open: expected version 2 in {path:?}, got {version}");
+        }
+
+        let fanout = |byte: usize| {
+            let start = INDEX_HEADER_SIZE + byte * 4;
+            u32::from_be_bytes(data[start..start + 4].try_into().unwrap())
+        };
+        if (1..256).any(|byte| fanout(byte) < fanout(byte - 1)) {
+            bail!("PackIndex::open: fanout table in {path:?} isn't sorted");
+        }
+        let object_count = fanout(255) as usize;
+
+        // whatever follows the fixed size tables is the 64-bit offset table
+        let large_offsets_size =
+            (data.len() - INDEX_HEADER_SIZE - FANOUT_SIZE - INDEX_TRAILER_SIZE)
+                .checked_sub(object_count * ENTRY_SIZE);
+        if !large_offsets_size.is_some_and(|size| size % 8 == 0) {
+            bail!("PackIndex::open: {path:?} is truncated");
+        }
+
+        Ok(Self { data, object_count })
+    }
+
+    // checksum of the pack this index belongs to
+    pub fn pack_checksum(&self) -> &[u8] {
+        let end = self.data.len() - 20;
+        &self.data[end - 20..end]
+    }
+
+    // offset of `sha` in the pack. the fanout table narrows the search down to the objects sharing
+    // its first byte, the sorted sha table is binary searched from there
+    pub fn find(&self, sha: &Sha) -> Option<u64> {
+        let first_byte = sha.0[0] as usize;
+        let start = match first_byte {
+            0 => 0,
+            _ => self.fanout(first_byte - 1),
+        };
+        let end = self.fanout(first_byte);
+
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.sha_at(middle).cmp(&sha.0[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return self.offset_at(middle),
+            }
+        }
+        None
+    }
+
+    // every object in the pack, in sha order
+    pub fn object_ids(&self) -> impl Iterator<Item = Sha> + '_ {
+        (0..self.object_count).map(|index| Sha(self.sha_at(index).try_into().unwrap()))
+    }
+
+    fn fanout(&self, byte: usize) -> usize {
+        self.word(INDEX_HEADER_SIZE + byte * 4) as usize
+    }
+
+    fn sha_at(&self, index: usize) -> &[u8] {
+        let start = INDEX_HEADER_SIZE + FANOUT_SIZE + index * 20;
+        &self.data[start..start + 20]
+    }
+
+    fn offset_at(&self, index: usize) -> Option<u64> {
+        let offsets_start = INDEX_HEADER_SIZE + FANOUT_SIZE + self.object_count * (20 + 4);
+        let offset = self.word(offsets_start + index * 4);
+        if offset & LARGE_OFFSET_FLAG == 0 {
+            return Some(offset as u64);
+        }
+
+        let large_offsets_start = offsets_start + self.object_count * 4;
+        let start = large_offsets_start + (offset & !LARGE_OFFSET_FLAG) as usize * 8;
+        let large_offset = self
+            .data
+            .get(start..start + 8)
+            .filter(|_| start + 8 <= self.data.len() - INDEX_TRAILER_SIZE)?;
+        Some(u64::from_be_bytes(large_offset.try_into().ok()?))
+    }
+
+    fn word(&self, start: usize) -> u32 {
+        u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap())
+    }
+}
+
+// builds the version 2 index of the whole pack file `pack`, returning it with the pack's
+// checksum. every delta is resolved to learn the sha of the object behind it, the crc32 is taken
+// over each entry as it's stored in the pack
+pub fn index_pack(pack: &[u8], max_delta_depth: usize) -> Result<(Vec<u8>, Sha)> {
+    let packfile =
+        Packfile::read(pack.iter().copied()).with_context(|| "index_pack: failed to read pack")?;
+    let checksum = packfile.checksum().clone();
+    let mut entry_offsets = packfile
+        .chunks
+        .iter()
+        .map(|(offset, _)| *offset)
+        .collect::<Vec<_>>();
+    let (_, offsets) = packfile
+        .unpack_with_offsets(max_delta_depth)
+        .with_context(|| "index_pack: failed to resolve objects")?;
+
+    // an entry runs up to the next one, the last up to the trailing checksum
+    entry_offsets.push((pack.len() - 20) as u64);
+    let mut entries = entry_offsets
+        .windows(2)
+        .map(|window| {
+            let sha = offsets
+                .get(&window[0])
+                .ok_or_else(|| anyhow!("index_pack: no object resolved at offset {}", window[0]))?;
+            let mut crc = Crc::new();
+            crc.update(&pack[window[0] as usize..window[1] as usize]);
+            Ok((sha.clone(), crc.sum(), window[0]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|(sha, _, _)| sha.0);
+    if entries.windows(2).any(|window| window[0].0 == window[1].0) {
+        bail!("index_pack: pack holds the same object twice");
+    }
+
+    Ok((encode_index(&entries, &checksum), checksum))
+}
+
+// the index for `entries`, `(sha, crc32, offset)` sorted by sha
+fn encode_index(entries: &[(Sha, u32, u64)], pack_checksum: &Sha) -> Vec<u8> {
+    let mut index = INDEX_MAGIC.to_vec();
+    index.extend(2u32.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for (sha, _, _) in entries {
+        fanout[sha.0[0] as usize] += 1;
+    }
+    let mut count = 0;
+    for objects in fanout {
+        count += objects;
+        index.extend(count.to_be_bytes());
+    }
+
+    for (sha, _, _) in entries {
+        index.extend(sha.0);
+    }
+    for (_, crc, _) in entries {
+        index.extend(crc.to_be_bytes());
+    }
+    // offsets that don't fit in 31 bits go to the 64-bit table, pointed to by their position in it
+    let mut large_offsets = vec![];
+    for (_, _, offset) in entries {
+        match u32::try_from(*offset) {
+            Ok(offset) if offset & LARGE_OFFSET_FLAG == 0 => index.extend(offset.to_be_bytes()),
+            _ => {
+                index.extend((large_offsets.len() as u32 | LARGE_OFFSET_FLAG).to_be_bytes());
+                large_offsets.push(*offset);
+            }
+        }
+    }
+    for offset in large_offsets {
+        index.extend(offset.to_be_bytes());
+    }
+
+    index.extend(pack_checksum.0);
+    let checksum = Sha1::from(&index).digest().bytes();
+    index.extend(checksum);
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::packfile::DEFAULT_MAX_DELTA_DEPTH;
+
+    // a commit, its tree and three blobs, two of them deltas, packed by git. the second index was
+    // written with `git index-pack --index-version=2,0x40`, which moves every offset above 0x40 to
+    // the 64-bit table
+    const FIXTURE_PACK: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/fixture.pack"
+    ));
+    const FIXTURE_INDEX: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fixture.idx");
+    const FIXTURE_LARGE_OFFSETS_INDEX: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/fixture-large-offsets.idx"
+    );
+    const FIXTURE_CHECKSUM: &str = "1599318c5c6969b216acc856543ad24848fecdc4";
+    // as `git verify-pack -v` lists them
+    const FIXTURE_OFFSETS: [(&str, u64); 5] = [
+        ("22d0735ba286183490f7a028fb6d68deeb1ee181", 12),
+        ("8cb3c612d5ee07b3167dfb2d1c14ef4daf1f5d48", 126),
+        ("a268de96c6464bb4515003da90364cde7e5e75c1", 218),
+        ("1c99002b20b3c0e11a95c8423601a38fff9b3675", 408),
+        ("7cab485a468c76adc53a3e32e3244bd11767b8d6", 442),
+    ];
+
+    #[test]
+    fn find_returns_the_offsets_git_recorded() -> Result<()> {
+        for path in [FIXTURE_INDEX, FIXTURE_LARGE_OFFSETS_INDEX] {
+            let index = PackIndex::open(path)?;
+            assert_eq!(hex::encode(index.pack_checksum()), FIXTURE_CHECKSUM);
+            for (sha, offset) in FIXTURE_OFFSETS {
+                assert_eq!(index.find(&sha.parse()?), Some(offset), "{sha} in {path}");
+            }
+            // shares its first byte with an object in the pack
+            let missing = "22d0735ba286183490f7a028fb6d68deeb1ee180".parse()?;
+            assert_eq!(index.find(&missing), None);
+            assert_eq!(index.find(&"ff".repeat(20).parse()?), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn offset_at_reads_the_64_bit_table() -> Result<()> {
+        let index = PackIndex::open(FIXTURE_LARGE_OFFSETS_INDEX)?;
+        let mut expected = FIXTURE_OFFSETS;
+        expected.sort();
+        for (position, sha) in index.object_ids().enumerate() {
+            let (expected_sha, offset) = expected[position];
+            assert_eq!(sha.to_string(), expected_sha);
+            assert_eq!(index.offset_at(position), Some(offset));
+            // only the commit's offset fits below 0x40
+            let large = index.word(
+                INDEX_HEADER_SIZE + FANOUT_SIZE + FIXTURE_OFFSETS.len() * (20 + 4) + position * 4,
+            ) & LARGE_OFFSET_FLAG
+                != 0;
+            assert_eq!(large, offset > 0x40, "{sha}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn index_pack_writes_the_index_git_does() -> Result<()> {
+        let (index, checksum) = index_pack(FIXTURE_PACK, DEFAULT_MAX_DELTA_DEPTH)?;
+        assert_eq!(checksum.to_string(), FIXTURE_CHECKSUM);
+        assert_eq!(index, fs::read(FIXTURE_INDEX)?);
+        Ok(())
+    }
+}
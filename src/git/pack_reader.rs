@@ -0,0 +1,363 @@
+use crate::git::{
+    any_git_object::{AnyGitObject, Sha},
+    pack_index::PackIndex,
+    packfile::{
+        check_delta_depth, resolve_delta, PackfileObject, DEFAULT_MAX_DELTA_DEPTH, PACK_HEADER_SIZE,
+    },
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// with mmap the pack stays on disk and only the pages that are decoded get loaded, without it the
+// whole pack has to be read into memory
+#[cfg(feature = "mmap")]
+type PackData = memmap2::Mmap;
+#[cfg(not(feature = "mmap"))]
+type PackData = Vec<u8>;
+
+const PACK_TRAILER_SIZE: usize = 20;
+// bytes of resolved delta bases kept per pack when `core.deltaBaseCacheLimit` doesn't say
+// otherwise, git's default
+pub const DEFAULT_DELTA_BASE_CACHE_LIMIT: u64 = 96 << 20;
+
+#[derive(Debug)]
+pub struct PackReader {
+    path: PathBuf,
+    data: PackData,
+    object_count: u32,
+    // the `.idx` next to the pack, packs received without one are scanned instead
+    index: Option<PackIndex>,
+    offsets: OnceCell<HashMap<Sha, u64>>,
+    // objects that offset deltas were applied to, by their offset. reading many deltas of the same
+    // chain would otherwise rebuild its bases from the start for every one of them
+    delta_bases: RefCell<DeltaBaseCache>,
+    max_delta_depth: usize,
+}
+
+impl PackReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        #[cfg(feature = "mmap")]
+        let data = {
+            let file = fs::File::open(&path)
+                .with_context(|| format!("PackReader::open: failed to open {path:?}"))?;
+            // SAFETY: packs are immutable once written, git only ever replaces them as a whole
+            unsafe { memmap2::Mmap::map(&file) }
+                .with_context(|| format!("PackReader::open: failed to mmap {path:?}"))?
+        };
+        #[cfg(not(feature = "mmap"))]
+        let data = fs::read(&path)
+            .with_context(|| format!("PackReader::open: failed to read {path:?}"))?;
+
+        if data.len() < PACK_HEADER_SIZE + PACK_TRAILER_SIZE || &data[..4] != b"PACK" {
+            bail!("PackReader::open: {path:?} is not a packfile");
+        }
+
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 {
+            bail!("PackReader::open: expected version 2 in {path:?}, got {version}");
+        }
+
+        let object_count = u32::from_be_bytes(data[8..12].try_into()?);
+
+        let index_path = path.with_extension("idx");
+        let index = if index_path.is_file() {
+            let index = PackIndex::open(&index_path)?;
+            if index.pack_checksum() != &data[data.len() - PACK_TRAILER_SIZE..] {
+                bail!("PackReader::open: {index_path:?} doesn't belong to {path:?}");
+            }
+            Some(index)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path,
+            data,
+            object_count,
+            index,
+            offsets: OnceCell::new(),
+            delta_bases: RefCell::new(DeltaBaseCache::new(DEFAULT_DELTA_BASE_CACHE_LIMIT)),
+            max_delta_depth: DEFAULT_MAX_DELTA_DEPTH,
+        })
+    }
+
+    pub fn with_max_delta_depth(self, max_delta_depth: usize) -> Self {
+        Self {
+            max_delta_depth,
+            ..self
+        }
+    }
+
+    // a limit of 0 turns the delta base cache off
+    pub fn with_delta_base_cache_limit(self, limit: u64) -> Self {
+        Self {
+            delta_bases: RefCell::new(DeltaBaseCache::new(limit)),
+            ..self
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn decode_at(&self, offset: u64) -> Result<(PackfileObject, u64)> {
+        let content = usize::try_from(offset)
+            .ok()
+            .filter(|offset| *offset >= PACK_HEADER_SIZE)
+            .and_then(|offset| self.data.get(offset..self.data.len() - PACK_TRAILER_SIZE))
+            .ok_or_else(|| {
+                anyhow!(
+                    "PackReader::decode_at: offset {offset} is outside of {:?}",
+                    self.path
+                )
+            })?;
+
+        PackfileObject::decode(content).with_context(|| {
+            format!(
+                "PackReader::decode_at: failed to decode object at offset {offset} in {:?}",
+                self.path
+            )
+        })
+    }
+
+    // length of the entry's header plus its compressed data, for deltas that's the delta itself
+    pub fn entry_size(&self, offset: u64) -> Result<u64> {
+        let (_, bytes_read) = self.decode_at(offset)?;
+        Ok(bytes_read)
+    }
+
+    // `lookup` provides the bases of ref deltas, offset deltas are resolved within the pack.
+    // `depth` is the length of the delta chain that led to this read, which `lookup` has to pass
+    // on so chains spanning several packs are limited as well
+    pub fn read_at<F>(&self, offset: u64, depth: usize, lookup: &F) -> Result<AnyGitObject>
+    where
+        F: Fn(&Sha, usize) -> Result<AnyGitObject>,
+    {
+        let (object, _) = self.decode_at(offset)?;
+
+        let check_depth = |base: &dyn std::fmt::Display| {
+            check_delta_depth(
+                "PackReader::read_at",
+                depth + 1,
+                self.max_delta_depth,
+                format!("{base} in {:?}", self.path),
+            )
+        };
+
+        Ok(match object {
+            PackfileObject::Commit(commit) => AnyGitObject::Commit(commit),
+            PackfileObject::Tree(tree) => AnyGitObject::Tree(tree),
+            PackfileObject::Blob(blob) => AnyGitObject::Blob(blob),
+            PackfileObject::Tag(tag) => AnyGitObject::Tag(tag),
+            PackfileObject::ObjOfsDelta(delta) => {
+                let base_offset = offset.checked_sub(delta.base_distance).ok_or_else(|| {
+                    anyhow!("PackReader::read_at: offset delta at {offset} points before the pack")
+                })?;
+                check_depth(&format!("offset {base_offset}"))?;
+                let base = self.read_base(base_offset, delta.base_obj_size, depth + 1, lookup)?;
+                resolve_delta(
+                    &base,
+                    delta.base_obj_size,
+                    delta.target_obj_size,
+                    &delta.instructions,
+                )?
+            }
+            PackfileObject::ObjRefDelta(delta) => {
+                check_depth(&delta.obj_name)?;
+                let base = lookup(&delta.obj_name, depth + 1).with_context(|| {
+                    format!(
+                        "PackReader::read_at: failed to find base {} of delta at {offset}",
+                        delta.obj_name
+                    )
+                })?;
+                resolve_delta(
+                    &base,
+                    delta.base_obj_size,
+                    delta.target_obj_size,
+                    &delta.instructions,
+                )?
+            }
+        })
+    }
+
+    // the object at `offset` that a delta applies to, `size` being the size the delta expects it
+    // to have
+    fn read_base<F>(
+        &self,
+        offset: u64,
+        size: usize,
+        depth: usize,
+        lookup: &F,
+    ) -> Result<AnyGitObject>
+    where
+        F: Fn(&Sha, usize) -> Result<AnyGitObject>,
+    {
+        if let Some(base) = self.delta_bases.borrow_mut().get(offset) {
+            return Ok(base);
+        }
+        let base = self.read_at(offset, depth, lookup)?;
+        self.delta_bases
+            .borrow_mut()
+            .insert(offset, base.clone(), size as u64);
+        Ok(base)
+    }
+
+    pub fn find(&self, sha: &Sha) -> Result<Option<u64>> {
+        if let Some(index) = &self.index {
+            return Ok(index.find(sha));
+        }
+
+        if self.offsets.get().is_none() {
+            let offsets = self
+                .scan()
+                .with_context(|| format!("PackReader::find: failed to index {:?}", self.path))?;
+            let _ = self.offsets.set(offsets);
+        }
+
+        Ok(self
+            .offsets
+            .get()
+            .and_then(|offsets| offsets.get(sha))
+            .copied())
+    }
+
+    pub fn object_ids(&self) -> Result<Vec<Sha>> {
+        if let Some(index) = &self.index {
+            return Ok(index.object_ids().collect());
+        }
+
+        if self.offsets.get().is_none() {
+            let offsets = self.scan().with_context(|| {
+                format!("PackReader::object_ids: failed to index {:?}", self.path)
+            })?;
+            let _ = self.offsets.set(offsets);
+        }
+
+        Ok(self
+            .offsets
+            .get()
+            .into_iter()
+            .flat_map(|offsets| offsets.keys().cloned())
+            .collect())
+    }
+
+    fn read_with_offsets(
+        &self,
+        offset: u64,
+        depth: usize,
+        offsets: &HashMap<Sha, u64>,
+    ) -> Result<AnyGitObject> {
+        self.read_at(offset, depth, &|sha: &Sha, depth| match offsets.get(sha) {
+            Some(base_offset) => self.read_with_offsets(*base_offset, depth, offsets),
+            None => Err(anyhow!("PackReader::scan: base {sha} isn't resolved yet")),
+        })
+    }
+
+    // without an index the only way to learn an entry's sha is to decode and hash it
+    fn scan(&self) -> Result<HashMap<Sha, u64>> {
+        let mut offsets = HashMap::new();
+        let mut pending = vec![];
+        let mut offset = PACK_HEADER_SIZE as u64;
+
+        for index in 0..self.object_count {
+            let (object, bytes_read) = self.decode_at(offset).with_context(|| {
+                format!(
+                    "PackReader::scan: failed to decode object {} of {}",
+                    index + 1,
+                    self.object_count
+                )
+            })?;
+            let is_delta = matches!(
+                object,
+                PackfileObject::ObjRefDelta(_) | PackfileObject::ObjOfsDelta(_)
+            );
+            pending.push((offset, is_delta));
+            offset += bytes_read;
+        }
+
+        // ref deltas may point at entries that appear later in the pack, so keep resolving until
+        // no more progress is made
+        loop {
+            let mut unresolved = vec![];
+            let mut last_error = None;
+            for (offset, is_delta) in pending.iter().copied() {
+                match self.read_with_offsets(offset, 0, &offsets) {
+                    Ok(object) => {
+                        offsets.insert(object.sha1()?, offset);
+                    }
+                    Err(err) if is_delta => {
+                        unresolved.push((offset, is_delta));
+                        last_error = Some(err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if unresolved.is_empty() {
+                return Ok(offsets);
+            } else if unresolved.len() == pending.len() {
+                return Err(
+                    last_error.unwrap_or_else(|| anyhow!("PackReader::scan: unresolvable deltas"))
+                )
+                .with_context(|| {
+                    format!(
+                        "PackReader::scan: {} deltas have bases missing from the pack",
+                        unresolved.len()
+                    )
+                });
+            }
+            pending = unresolved;
+        }
+    }
+}
+
+// resolved delta bases, taking up at most `limit` bytes. like the commit cache they're kept in two
+// generations, the older one dropped whenever the newer one has used up its half of the limit
+#[derive(Debug)]
+struct DeltaBaseCache {
+    limit: u64,
+    recent: HashMap<u64, (AnyGitObject, u64)>,
+    recent_size: u64,
+    older: HashMap<u64, (AnyGitObject, u64)>,
+}
+
+impl DeltaBaseCache {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            recent: HashMap::new(),
+            recent_size: 0,
+            older: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, offset: u64) -> Option<AnyGitObject> {
+        if let Some((base, _)) = self.recent.get(&offset) {
+            return Some(base.clone());
+        }
+        let (base, size) = self.older.remove(&offset)?;
+        self.insert(offset, base.clone(), size);
+        Some(base)
+    }
+
+    // a base bigger than a generation isn't kept at all
+    fn insert(&mut self, offset: u64, base: AnyGitObject, size: u64) {
+        let generation_limit = self.limit / 2;
+        if size > generation_limit {
+            return;
+        }
+        if self.recent_size + size > generation_limit {
+            self.older = std::mem::take(&mut self.recent);
+            self.recent_size = 0;
+        }
+        self.recent_size += size;
+        self.recent.insert(offset, (base, size));
+    }
+}
@@ -0,0 +1,1081 @@
+use crate::git::{
+    any_git_object::{AnyGitObject, Sha},
+    commits::Commit,
+    compression::{compress, decompress_slice},
+    config::Config,
+    git_blob::{Blob, BlobContent},
+    git_object_trait::GitObject,
+    git_tag::Tag,
+    git_tree::Tree,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::Compression;
+use sha1_smol::Sha1;
+use std::{collections::HashMap, fmt::Display};
+
+#[derive(Debug)]
+pub(crate) struct Packfile {
+    #[allow(dead_code)]
+    version: u32,
+    checksum: Sha,
+    // every entry with its offset from the start of the pack
+    pub(crate) chunks: Vec<(u64, PackfileObject)>,
+}
+
+impl Packfile {
+    pub(crate) fn read<T: IntoIterator<Item = u8>>(iter: T) -> Result<Self> {
+        let mut iter = iter.into_iter().peekable();
+        if iter.by_ref().take(4).collect::<Vec<_>>() != b"PACK" {
+            bail!("Packfile::read: packfiles should start with \"PACK\"");
+        }
+
+        let version =
+            u32::from_be_bytes(read_array(iter.by_ref()).with_context(|| {
+                anyhow!("Packfile::read: failed to convert version bytes to u32")
+            })?);
+        if version != 2 {
+            bail!("Packfile::read: expected version 2, got {version}");
+        }
+
+        let object_amount = u32::from_be_bytes(read_array(iter.by_ref()).with_context(|| {
+            anyhow!("Packfile::read: failed to convert object amount bytes to u32")
+        })?);
+
+        let (binary_data, checksum) = {
+            let mut rest: Vec<_> = iter.collect();
+            if rest.len() < 20 {
+                bail!("Packfile::read: pack is too short to hold its checksum");
+            }
+            let checksum = Sha(rest.split_off(rest.len() - 20).try_into().map_err(|_| {
+                anyhow!("Packfile::read: failed to convert checksum bytes to [u8; 20]")
+            })?);
+            (rest, checksum)
+        };
+
+        // the trailing checksum is the sha1 of everything before it
+        let mut hasher = Sha1::new();
+        hasher.update(b"PACK");
+        hasher.update(&version.to_be_bytes());
+        hasher.update(&object_amount.to_be_bytes());
+        hasher.update(&binary_data);
+        if hasher.digest().bytes() != checksum.0 {
+            bail!("Packfile::read: pack checksum mismatch, expected {checksum}");
+        }
+
+        let mut bytes_read = 0;
+
+        let chunks: Vec<_> = (0..object_amount)
+            .map(|index| -> Result<_> {
+                let (obj, bytes_read_obj) = PackfileObject::decode(&binary_data[bytes_read..])
+                    .with_context(|| {
+                        // offsets count from the start of the pack, like in `git verify-pack`
+                        anyhow!(
+                            "Packfile::read: failed to decode object {} of {object_amount} at offset {}",
+                            index + 1,
+                            bytes_read + PACK_HEADER_SIZE
+                        )
+                    })?;
+                let offset = (bytes_read + PACK_HEADER_SIZE) as u64;
+                bytes_read += usize::try_from(bytes_read_obj).with_context(|| {
+                    anyhow!("Packfile::read: failed to convert bytes_read_obj usize")
+                })?;
+                Ok((offset, obj))
+            })
+            .collect::<Result<_, _>>()
+            .with_context(|| "Packfile::read: failed to read chunks")?;
+
+        Ok(Packfile {
+            version,
+            checksum,
+            chunks,
+        })
+    }
+
+    // every object of the pack by its sha, deltas applied. bases have to be in the pack too,
+    // thin packs that leave them out can't be unpacked
+    pub(crate) fn unpack(self, max_delta_depth: usize) -> Result<HashMap<Sha, AnyGitObject>> {
+        Ok(self.unpack_with_offsets(max_delta_depth)?.0)
+    }
+
+    // like unpack, along with the sha of the object at each entry offset
+    pub(crate) fn unpack_with_offsets(
+        self,
+        max_delta_depth: usize,
+    ) -> Result<(HashMap<Sha, AnyGitObject>, HashMap<u64, Sha>)> {
+        let (deltas, git_objects): (Vec<_>, Vec<_>) =
+            self.chunks.into_iter().partition(|(_, chunk)| match chunk {
+                PackfileObject::ObjRefDelta { .. } | PackfileObject::ObjOfsDelta(_) => true,
+                PackfileObject::Blob(_)
+                | PackfileObject::Commit(_)
+                | PackfileObject::Tree(_)
+                | PackfileObject::Tag(_) => false,
+            });
+
+        let mut object_map = HashMap::new();
+        // offset deltas name their base by where its entry starts
+        let mut offsets = HashMap::new();
+        for (offset, chunk) in git_objects {
+            let (sha, object) = (|| -> Result<_> {
+                Ok(match chunk {
+                    PackfileObject::Commit(commit) => (commit.sha1()?, AnyGitObject::Commit(commit)),
+                    PackfileObject::Tree(tree) => (tree.sha1()?, AnyGitObject::Tree(tree)),
+                    PackfileObject::Blob(blob) => (blob.sha1()?, AnyGitObject::Blob(blob)),
+                    PackfileObject::Tag(tag) => (tag.sha1()?, AnyGitObject::Tag(tag)),
+                    other => unreachable!("Packfile::unpack: unexpected object type: git_objects should only contain git objects, but got {other:?}"),
+                })
+            })()
+            .with_context(|| "Packfile::unpack: failed to compute sha for git object")?;
+            offsets.insert(offset, sha.clone());
+            object_map.insert(sha, object);
+        }
+
+        resolve_deltas(&mut object_map, &mut offsets, deltas, max_delta_depth)
+            .with_context(|| "Packfile::unpack: failed to resolve deltas")?;
+
+        Ok((object_map, offsets))
+    }
+
+    pub(crate) fn checksum(&self) -> &Sha {
+        &self.checksum
+    }
+
+    // packs `objects`. a blob `paths` has a path for may be stored as a delta against another
+    // version of that path: one earlier in the pack or, for a thin pack, one of `thin_bases`, which
+    // the receiving side already has and which stay out of the pack. without `thin_bases` every
+    // delta base is in the pack itself. objects larger than `big_file_threshold` bytes are always
+    // stored whole and never become bases
+    pub(crate) fn encode(
+        objects: &[AnyGitObject],
+        paths: &HashMap<Sha, String>,
+        thin_bases: &[(String, AnyGitObject)],
+        level: Compression,
+        big_file_threshold: u64,
+    ) -> Result<Vec<u8>> {
+        let is_big = |body: &[u8]| body.len() as u64 > big_file_threshold;
+        let object_amount = u32::try_from(objects.len())
+            .with_context(|| "Packfile::encode: too many objects for a single pack")?;
+
+        let mut pack = b"PACK".to_vec();
+        pack.extend(2u32.to_be_bytes());
+        pack.extend(object_amount.to_be_bytes());
+
+        // the latest versions seen of every path
+        let mut candidates = HashMap::new();
+        for (path, base) in thin_bases {
+            let body = base.encode_body()?;
+            if is_big(&body) {
+                continue;
+            }
+            let base = DeltaCandidate {
+                sha: base.sha1()?,
+                body,
+                depth: 0,
+            };
+            remember_candidate(&mut candidates, path, base);
+        }
+
+        for object in objects {
+            let obj_type = match object {
+                AnyGitObject::Commit(_) => 1,
+                AnyGitObject::Tree(_) => 2,
+                AnyGitObject::Blob(_) => 3,
+                AnyGitObject::Tag(_) => 4,
+            };
+            let sha = object
+                .sha1()
+                .with_context(|| "Packfile::encode: failed to hash object")?;
+            let body = object
+                .encode_body()
+                .with_context(|| "Packfile::encode: failed to encode object")?;
+            let path = paths
+                .get(&sha)
+                .filter(|_| matches!(object, AnyGitObject::Blob(_)) && !is_big(&body));
+
+            // the smallest delta against the candidates, when it beats storing the object whole
+            let delta = path
+                .and_then(|path| candidates.get(path.as_str()))
+                .and_then(|versions| {
+                    versions
+                        .iter()
+                        .filter(|base| base.depth < DEFAULT_MAX_DELTA_DEPTH)
+                        .map(|base| {
+                            (
+                                base.sha.clone(),
+                                encode_delta(&base.body, &body),
+                                base.depth + 1,
+                            )
+                        })
+                        .min_by_key(|(_, delta, _)| delta.len())
+                })
+                .filter(|(_, delta, _)| delta.len() < body.len());
+
+            let depth = match delta {
+                Some((base, delta, depth)) => {
+                    // a ref delta names its base by sha, which works for bases outside the pack
+                    pack.extend(write_object_header(7, delta.len()));
+                    pack.extend(base.0);
+                    pack.extend(
+                        compress(delta, level)
+                            .with_context(|| "Packfile::encode: failed to compress delta")?,
+                    );
+                    depth
+                }
+                None => {
+                    pack.extend(write_object_header(obj_type, body.len()));
+                    pack.extend(
+                        compress(body.clone(), level)
+                            .with_context(|| "Packfile::encode: failed to compress object")?,
+                    );
+                    0
+                }
+            };
+
+            if let Some(path) = path {
+                remember_candidate(&mut candidates, path, DeltaCandidate { sha, body, depth });
+            }
+        }
+
+        let checksum = Sha1::from(&pack).digest().bytes();
+        pack.extend(checksum);
+        Ok(pack)
+    }
+}
+
+// how many earlier versions of a path are tried as delta bases
+const DELTA_WINDOW: usize = 10;
+
+// an object a delta can be taken against, with the length of its own delta chain
+struct DeltaCandidate {
+    sha: Sha,
+    body: Vec<u8>,
+    depth: usize,
+}
+
+// keeps the last DELTA_WINDOW candidates of `path`
+fn remember_candidate<'a>(
+    candidates: &mut HashMap<&'a str, Vec<DeltaCandidate>>,
+    path: &'a str,
+    candidate: DeltaCandidate,
+) {
+    let versions = candidates.entry(path).or_default();
+    versions.push(candidate);
+    if versions.len() > DELTA_WINDOW {
+        versions.remove(0);
+    }
+}
+// the base is indexed in blocks of this many bytes, matches shorter than that aren't found
+const DELTA_BLOCK_SIZE: usize = 16;
+// the longest copy and insert a single delta instruction can hold. a copy size of 0 would stand
+// for 0x10000, which is never written
+const DELTA_MAX_COPY: usize = 0xffff;
+const DELTA_MAX_INSERT: usize = 0x7f;
+
+// the delta data rebuilding `target` from `base`: both sizes followed by copy and insert
+// instructions, the inverse of read_delta_data
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = write_size(base.len());
+    delta.extend(write_size(target.len()));
+
+    // where each block of the base first starts
+    let mut blocks = HashMap::new();
+    for (index, block) in base.chunks_exact(DELTA_BLOCK_SIZE).enumerate() {
+        blocks.entry(block).or_insert(index * DELTA_BLOCK_SIZE);
+    }
+
+    let mut insert = vec![];
+    let mut position = 0;
+    while position < target.len() {
+        let matched = target
+            .get(position..position + DELTA_BLOCK_SIZE)
+            .and_then(|block| blocks.get(block))
+            .map(|&offset| {
+                let length = base[offset..]
+                    .iter()
+                    .zip(&target[position..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (offset, length)
+            });
+        let Some((mut offset, mut length)) = matched else {
+            insert.push(target[position]);
+            position += 1;
+            continue;
+        };
+
+        write_inserts(&mut delta, &mut insert);
+        position += length;
+        while length > 0 {
+            let size = length.min(DELTA_MAX_COPY);
+            write_copy(&mut delta, offset, size);
+            offset += size;
+            length -= size;
+        }
+    }
+    write_inserts(&mut delta, &mut insert);
+    delta
+}
+
+// the sizes at the start of delta data, seven bits a byte
+fn write_size(mut size: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = size as u8 & !VARINT_CONTINUE_FLAG;
+        size >>= VARINT_ENCODING_BITS;
+        if size == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | VARINT_CONTINUE_FLAG);
+    }
+}
+
+// a copy instruction: a flag byte saying which offset and size bytes follow, zero bytes are left out
+fn write_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let mut flags = 0b1000_0000;
+    let mut bytes = vec![];
+    for (bit, byte) in (offset as u32).to_le_bytes().into_iter().enumerate() {
+        if byte != 0 {
+            flags |= 1 << bit;
+            bytes.push(byte);
+        }
+    }
+    for (bit, byte) in (size as u16).to_le_bytes().into_iter().enumerate() {
+        if byte != 0 {
+            flags |= 1 << (bit + 4);
+            bytes.push(byte);
+        }
+    }
+    delta.push(flags);
+    delta.extend(bytes);
+}
+
+// the pending literal bytes as insert instructions, emptying `insert`
+fn write_inserts(delta: &mut Vec<u8>, insert: &mut Vec<u8>) {
+    for chunk in insert.chunks(DELTA_MAX_INSERT) {
+        delta.push(chunk.len() as u8);
+        delta.extend(chunk);
+    }
+    insert.clear();
+}
+
+// `PACK`, the version and the object count
+pub(crate) const PACK_HEADER_SIZE: usize = 12;
+
+// git's default for `core.bigFileThreshold`, larger objects aren't deltified
+pub const DEFAULT_BIG_FILE_THRESHOLD: u64 = 512 << 20;
+
+pub fn big_file_threshold(config: &Config) -> Result<u64> {
+    Ok(config
+        .get_size("core.bigFileThreshold")?
+        .unwrap_or(DEFAULT_BIG_FILE_THRESHOLD))
+}
+
+// git's own default for `pack.depth`, deeper chains are rejected to keep hostile packs from
+// recursing without bound
+pub const DEFAULT_MAX_DELTA_DEPTH: usize = 50;
+
+// the longest delta chain read paths follow, `core.maxDeltaDepth` or git's default
+pub fn max_delta_depth(config: &Config) -> Result<usize> {
+    Ok(config
+        .get_size("core.maxDeltaDepth")?
+        .map_or(DEFAULT_MAX_DELTA_DEPTH, |depth| depth as usize))
+}
+
+// every reader of packed objects goes through this. `depth` is the number of deltas the object
+// is rebuilt from, up to and including the one applied to `base`
+pub(crate) fn check_delta_depth(
+    caller: &str,
+    depth: usize,
+    max_delta_depth: usize,
+    base: impl Display,
+) -> Result<()> {
+    if depth > max_delta_depth {
+        bail!(
+            "{caller}: delta chain exceeds the maximum depth of {max_delta_depth} at base {base}"
+        );
+    }
+    Ok(())
+}
+
+const VARINT_ENCODING_BITS: u8 = 7;
+const VARINT_CONTINUE_FLAG: u8 = 1 << VARINT_ENCODING_BITS;
+const VARINT_OBJ_TYPE_FLAG: u8 = 0b01110000;
+const VARINT_FIRST_BYTE_ENCONDING_BITS: u8 = 4;
+
+fn read_variable_length_integer<T: IntoIterator<Item = u8>>(
+    iter: T,
+    get_obj_type: bool,
+) -> Result<(usize, Option<u8>, u8)> {
+    let mut iter = iter.into_iter();
+    let mut obj_type = None;
+    let mut value: usize = 0;
+    let mut length: u8 = 0;
+    let mut bytes_read: u8 = 0;
+
+    loop {
+        bytes_read += 1;
+        let byte = iter
+            .next()
+            .ok_or_else(|| anyhow!("failed to read variable length integer"))?;
+        let is_last = (byte & VARINT_CONTINUE_FLAG) == 0;
+        let (data, offset) = if obj_type.is_some() || !get_obj_type {
+            (byte & !VARINT_CONTINUE_FLAG, VARINT_ENCODING_BITS)
+        } else {
+            obj_type = Some((byte & !VARINT_CONTINUE_FLAG) >> VARINT_FIRST_BYTE_ENCONDING_BITS);
+            (
+                byte & !VARINT_CONTINUE_FLAG & !VARINT_OBJ_TYPE_FLAG,
+                VARINT_FIRST_BYTE_ENCONDING_BITS,
+            )
+        };
+        value |= (data as usize) << length;
+        if is_last {
+            break;
+        }
+        length += offset;
+    }
+    Ok((value, obj_type, bytes_read))
+}
+
+// inverse of read_variable_length_integer with get_obj_type set: the type and the low four size
+// bits share the first byte
+fn write_object_header(obj_type: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(obj_type << VARINT_FIRST_BYTE_ENCONDING_BITS) | (size as u8 & 0x0f)];
+    let mut rest = size >> VARINT_FIRST_BYTE_ENCONDING_BITS;
+
+    while rest > 0 {
+        *header.last_mut().unwrap() |= VARINT_CONTINUE_FLAG;
+        header.push(rest as u8 & !VARINT_CONTINUE_FLAG);
+        rest >>= VARINT_ENCODING_BITS;
+    }
+    header
+}
+
+fn read_array<const N: usize, T: IntoIterator<Item = u8>>(iter: T) -> Result<[u8; N]> {
+    iter.into_iter()
+        .take(N)
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|vec: Vec<_>| {
+            anyhow!(
+                "failed to read array: expected {N} bytes, got {}",
+                vec.len()
+            )
+        })
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum PackfileObject {
+    Commit(Commit),
+    Tree(Tree),
+    Blob(Blob),
+    Tag(Tag),
+    ObjOfsDelta(ObjOfsDelta),
+    ObjRefDelta(ObjRefDelta),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ObjRefDelta {
+    pub(crate) base_obj_size: usize,
+    pub(crate) target_obj_size: usize,
+    pub(crate) obj_name: Sha,
+    pub(crate) instructions: Vec<DeltaInstruction>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ObjOfsDelta {
+    pub(crate) base_obj_size: usize,
+    pub(crate) target_obj_size: usize,
+    // distance from the start of this entry back to the start of its base entry
+    pub(crate) base_distance: u64,
+    pub(crate) instructions: Vec<DeltaInstruction>,
+}
+
+// the base distance of an offset delta uses its own varint flavour, where every continuation adds
+// one before shifting: https://git-scm.com/docs/pack-format
+fn read_offset_encoding(content: &[u8]) -> Result<(u64, usize)> {
+    let mut bytes = content.iter();
+    let mut byte = *bytes
+        .next()
+        .ok_or_else(|| anyhow!("failed to read offset encoding: no bytes left"))?;
+    let mut value = u64::from(byte & !VARINT_CONTINUE_FLAG);
+    let mut bytes_read = 1;
+
+    while byte & VARINT_CONTINUE_FLAG != 0 {
+        byte = *bytes
+            .next()
+            .ok_or_else(|| anyhow!("failed to read offset encoding: truncated"))?;
+        value = ((value + 1) << VARINT_ENCODING_BITS) | u64::from(byte & !VARINT_CONTINUE_FLAG);
+        bytes_read += 1;
+    }
+
+    Ok((value, bytes_read))
+}
+
+fn read_delta_data(content: Vec<u8>) -> Result<(usize, usize, Vec<DeltaInstruction>)> {
+    let mut content = content.into_iter();
+    let (base_obj_size, ..) = read_variable_length_integer(content.by_ref(), false)
+        .with_context(|| anyhow!("read_delta_data: failed to read base object size"))?;
+    let (target_obj_size, ..) = read_variable_length_integer(content.by_ref(), false)
+        .with_context(|| anyhow!("read_delta_data: failed to read target object size"))?;
+    let instructions = DeltaInstruction::read_many(content)
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| anyhow!("read_delta_data: failed to parse delta instructions"))?;
+    Ok((base_obj_size, target_obj_size, instructions))
+}
+
+pub(crate) fn resolve_delta(
+    base: &AnyGitObject,
+    base_obj_size: usize,
+    target_obj_size: usize,
+    instructions: &Vec<DeltaInstruction>,
+) -> Result<AnyGitObject> {
+    let encoded_base = base
+        .encode_body()
+        .with_context(|| "resolve_delta: failed to encode base object body")?;
+
+    if encoded_base.len() != base_obj_size {
+        bail!(
+            "resolve_delta: base object size {} doesn't match delta base object size {base_obj_size}",
+            encoded_base.len()
+        );
+    }
+
+    let output = DeltaInstruction::apply(instructions, &encoded_base)?;
+
+    if output.len() != target_obj_size {
+        bail!(
+            "resolve_delta: output size {} doesn't match delta target object size {target_obj_size}",
+            output.len()
+        );
+    }
+
+    match base {
+        AnyGitObject::Commit(_) => Commit::decode_body(output).map(AnyGitObject::Commit),
+        AnyGitObject::Tree(_) => Tree::decode_body(output).map(AnyGitObject::Tree),
+        AnyGitObject::Blob(_) => Blob::decode_body(output).map(AnyGitObject::Blob),
+        AnyGitObject::Tag(_) => Tag::decode_body(output).map(AnyGitObject::Tag),
+    }
+    .with_context(|| "resolve_delta: failed to decode object after delta")
+}
+
+// what a delta applies to: an object named by its sha, or the entry at an offset of the same pack
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DeltaBase {
+    Name(Sha),
+    Offset(u64),
+}
+
+impl std::fmt::Display for DeltaBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Offset(offset) => write!(f, "offset {offset}"),
+        }
+    }
+}
+
+// a delta waiting for its base, with the offset of its own entry
+struct PendingDelta {
+    offset: u64,
+    base_obj_size: usize,
+    target_obj_size: usize,
+    instructions: Vec<DeltaInstruction>,
+}
+
+// applies the deltas of a pack, each given with the offset of its entry, against `object_map`.
+// `offsets` names the whole objects of the pack by their offsets, and the resolved deltas are
+// added to it. every reconstructed object is inserted right away, so deltas whose base is itself a delta resolve as soon as that base is
+// available
+pub(crate) fn resolve_deltas(
+    object_map: &mut HashMap<Sha, AnyGitObject>,
+    offsets: &mut HashMap<u64, Sha>,
+    deltas: Vec<(u64, PackfileObject)>,
+    max_delta_depth: usize,
+) -> Result<()> {
+    let mut waiting: HashMap<DeltaBase, Vec<PendingDelta>> = HashMap::new();
+    for (offset, delta) in deltas {
+        let (base, delta) = match delta {
+            PackfileObject::ObjRefDelta(delta) => (
+                DeltaBase::Name(delta.obj_name),
+                PendingDelta {
+                    offset,
+                    base_obj_size: delta.base_obj_size,
+                    target_obj_size: delta.target_obj_size,
+                    instructions: delta.instructions,
+                },
+            ),
+            PackfileObject::ObjOfsDelta(delta) => (
+                DeltaBase::Offset(offset.checked_sub(delta.base_distance).ok_or_else(|| {
+                    anyhow!("resolve_deltas: delta at offset {offset} points before the pack")
+                })?),
+                PendingDelta {
+                    offset,
+                    base_obj_size: delta.base_obj_size,
+                    target_obj_size: delta.target_obj_size,
+                    instructions: delta.instructions,
+                },
+            ),
+            other => bail!("resolve_deltas: expected a delta at offset {offset}, got {other:?}"),
+        };
+        waiting.entry(base).or_default().push(delta);
+    }
+
+    // length of the delta chain each resolved object was reconstructed from
+    let mut depths: HashMap<Sha, usize> = HashMap::new();
+    let mut ready = waiting
+        .keys()
+        .filter(|base| match base {
+            DeltaBase::Name(name) => object_map.contains_key(name),
+            DeltaBase::Offset(offset) => offsets.contains_key(offset),
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    while let Some(base) = ready.pop() {
+        let Some(deltas) = waiting.remove(&base) else {
+            continue;
+        };
+        let base_name = match &base {
+            DeltaBase::Name(name) => name.clone(),
+            DeltaBase::Offset(offset) => offsets[offset].clone(),
+        };
+
+        let depth = depths.get(&base_name).copied().unwrap_or(0) + 1;
+        check_delta_depth("resolve_deltas", depth, max_delta_depth, &base_name)?;
+
+        for delta in deltas {
+            let base = object_map.get(&base_name).ok_or_else(|| {
+                anyhow!("resolve_deltas: failed to find object with name {base_name}")
+            })?;
+
+            let object = resolve_delta(
+                base,
+                delta.base_obj_size,
+                delta.target_obj_size,
+                &delta.instructions,
+            )
+            .with_context(|| format!("resolve_deltas: failed to apply delta to {base_name}"))?;
+
+            let object_name = object
+                .sha1()
+                .with_context(|| "resolve_deltas: failed to compute sha for object after delta")?;
+
+            for resolved in [
+                DeltaBase::Name(object_name.clone()),
+                DeltaBase::Offset(delta.offset),
+            ] {
+                if waiting.contains_key(&resolved) {
+                    ready.push(resolved);
+                }
+            }
+            offsets.insert(delta.offset, object_name.clone());
+            depths.insert(object_name.clone(), depth);
+            object_map.insert(object_name, object);
+        }
+    }
+
+    // whatever is still waiting has a base that is missing from the pack or only reachable
+    // through a cycle of deltas
+    if !waiting.is_empty() {
+        bail!(
+            "resolve_deltas: {} deltas can't be resolved, their bases are missing or form a cycle: {}",
+            waiting.values().map(Vec::len).sum::<usize>(),
+            waiting
+                .keys()
+                .map(|base| base.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+impl PackfileObject {
+    pub(crate) fn decode(content: &[u8]) -> Result<(Self, u64)> {
+        let (expected_size, obj_type, bytes_read_varint) =
+            read_variable_length_integer(content.iter().copied(), true)
+                .with_context(|| anyhow!("PackfileObject::decode: failed to read object size"))?;
+        let obj_type = obj_type.ok_or_else(|| {
+          anyhow!("PackfileObject::decode: failed to read variable length integer: couldn't find object type")
+      })?;
+        let bytes_read_varint = usize::from(bytes_read_varint);
+        let content = &content[bytes_read_varint..];
+        let bytes_read_varint = u64::try_from(bytes_read_varint).with_context(|| {
+            anyhow!("PackfileObject::decode: failed to convert bytes_read_varint to u64")
+        })?;
+
+        let decode_zlib = |content: &[u8]| -> Result<(Vec<u8>, u64)> {
+            let (content, bytes_read) = decompress_slice(content).with_context(|| {
+                format!("PackfileObject::decode({obj_type}): corrupt zlib stream")
+            })?;
+            if expected_size != content.len() {
+                bail!(
+                    "PackfileObject::decode({obj_type}): header declares {expected_size} bytes, but {} were decompressed",
+                    content.len()
+                );
+            }
+            Ok((content, bytes_read))
+        };
+
+        match obj_type {
+            1 => {
+                let (content, bytes_read) = decode_zlib(content)?;
+                Ok((
+                    Self::Commit(Commit::decode_body(content)?),
+                    bytes_read + bytes_read_varint,
+                ))
+            }
+            2 => {
+                let (content, bytes_read) = decode_zlib(content)?;
+                Ok((
+                    Self::Tree(Tree::decode_body(content)?),
+                    bytes_read + bytes_read_varint,
+                ))
+            }
+            3 => {
+                let (content, bytes_read) = decode_zlib(content)?;
+                Ok((
+                    Self::Blob(Blob::new(content)),
+                    bytes_read + bytes_read_varint,
+                ))
+            }
+            4 => {
+                let (content, bytes_read) = decode_zlib(content)?;
+                Ok((
+                    Self::Tag(Tag::decode_body(content)?),
+                    bytes_read + bytes_read_varint,
+                ))
+            }
+            6 => {
+                let (base_distance, bytes_read_offset) = read_offset_encoding(content)
+                    .with_context(|| {
+                        anyhow!("PackfileObject::decode({obj_type}): failed to read base offset")
+                    })?;
+                let (content, bytes_read) = decode_zlib(&content[bytes_read_offset..])?;
+                let (base_obj_size, target_obj_size, instructions) = read_delta_data(content)
+                    .with_context(|| {
+                        anyhow!("PackfileObject::decode({obj_type}): failed to read delta data")
+                    })?;
+                let obj = Self::ObjOfsDelta(ObjOfsDelta {
+                    base_obj_size,
+                    target_obj_size,
+                    base_distance,
+                    instructions,
+                });
+                Ok((
+                    obj,
+                    bytes_read + bytes_read_offset as u64 + bytes_read_varint,
+                ))
+            }
+            7 => {
+                let obj_name = Sha(content.get(..20).ok_or_else(|| {
+                  anyhow!(
+                      "PackfileObject::decode({obj_type}): expected object name to be 20 bytes, got {}",
+                      content.len()
+                  )
+              })?.to_vec().try_into().map_err(|_| {
+                  anyhow!(
+                      "PackfileObject::decode({obj_type}): failed to convert object name to Sha"
+                  )
+              })?);
+                let (content, bytes_read) = decode_zlib(content.get(20..).ok_or_else(|| {
+                  anyhow!(
+                      "PackfileObject::decode({obj_type}): content bytes are missing, expected more than 20 bytes in content but got {}",
+                      content.len()
+                  )
+              })?)?;
+                let (base_obj_size, target_obj_size, instructions) = read_delta_data(content)
+                    .with_context(|| {
+                        anyhow!("PackfileObject::decode({obj_type}): failed to read delta data")
+                    })?;
+                let obj = Self::ObjRefDelta(ObjRefDelta {
+                    base_obj_size,
+                    target_obj_size,
+                    instructions,
+                    obj_name,
+                });
+                Ok((obj, bytes_read + 20 + bytes_read_varint))
+            }
+            _ => bail!("PackfileObject::decode({obj_type}): unsupported object type"),
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub(crate) enum DeltaInstruction {
+    Copy { offset: usize, length: usize },
+    Insert(BlobContent),
+}
+
+impl DeltaInstruction {
+    fn read<T: IntoIterator<Item = u8>>(iter: T) -> Result<Self> {
+        let mut iter = iter.into_iter().peekable();
+        let first_byte = iter
+            .next()
+            .ok_or_else(|| anyhow!("DeltaInstruction::read: empty iterator"))?;
+        let is_insert = (first_byte & 0b1000_0000) == 0;
+        if is_insert {
+            if first_byte == 0 {
+                bail!("DeltaInstruction::read: reserved instruction 0");
+            }
+            let byte_count = first_byte as usize;
+            // println!("byte_count: {byte_count} ({first_byte:#08b})");
+            Ok(Self::Insert(
+                iter.take(byte_count).collect::<Vec<_>>().into(),
+            ))
+        } else {
+            let flags = first_byte & !0b1000_0000;
+            // println!("flags: {flags:#07b}");
+            let mut offset: usize = 0;
+            for i in 0..4 {
+                if (flags & (1 << i)) != 0 {
+                    let to_apply = (iter
+                        .next()
+                        .ok_or_else(|| anyhow!("DeltaInstruction::read: expected offset byte"))?
+                        as usize)
+                        << (i * 8);
+                    offset |= to_apply;
+                }
+            }
+            // git writes up to three size bytes, and a size of 0 stands for 0x10000
+            let mut length: usize = 0;
+            for i in 4..7 {
+                if (flags & (1 << i)) != 0 {
+                    let to_apply = (iter
+                        .next()
+                        .ok_or_else(|| anyhow!("DeltaInstruction::read: expected size byte"))?
+                        as usize)
+                        << ((i - 4) * 8);
+                    length |= to_apply;
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            Ok(Self::Copy { offset, length })
+        }
+    }
+
+    fn read_many<T: IntoIterator<Item = u8>>(iter: T) -> impl Iterator<Item = Result<Self>> {
+        let mut iter = iter.into_iter().peekable();
+        std::iter::from_fn(move || {
+            if iter.peek().is_some() {
+                Some(
+                    Self::read(&mut iter)
+                        .with_context(|| "DeltaInstruction::read_many: failed to read instruction"),
+                )
+            } else {
+                None
+            }
+        })
+    }
+
+    fn apply(instructions: &Vec<DeltaInstruction>, source: &[u8]) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        for instruction in instructions {
+            match instruction {
+                DeltaInstruction::Copy { offset, length } => {
+                    let copied = offset
+                        .checked_add(*length)
+                        .and_then(|end| source.get(*offset..end))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "DeltaInstruction::apply: copy of {length} bytes at {offset} is outside the {} byte base",
+                                source.len()
+                            )
+                        })?;
+                    output.extend(copied);
+                }
+                DeltaInstruction::Insert(data) => {
+                    output.extend(data.as_ref());
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{object_store::ObjectStore, pack_index::index_pack, pack_reader::PackReader};
+
+    // three versions of a file, each a small edit of the one before
+    fn versions() -> [Blob; 3] {
+        let base = (1..=20).map(|n| format!("line {n}\n")).collect::<String>();
+        let delta = base.replace("line 7\n", "line seven\n");
+        let delta_of_delta = delta.replace("line 15\n", "") + "line 21\n";
+        [base, delta, delta_of_delta].map(|content| Blob::new(content.into_bytes()))
+    }
+
+    // the base distance of an offset delta, the inverse of read_offset_encoding
+    fn write_offset(mut distance: u64) -> Vec<u8> {
+        let mut bytes = vec![distance as u8 & !VARINT_CONTINUE_FLAG];
+        distance >>= VARINT_ENCODING_BITS;
+        while distance > 0 {
+            distance -= 1;
+            bytes.insert(0, distance as u8 | VARINT_CONTINUE_FLAG);
+            distance >>= VARINT_ENCODING_BITS;
+        }
+        bytes
+    }
+
+    // a pack of the base, a delta against it and a delta against that delta. offset deltas follow
+    // their base, the ref deltas come in reverse so the last one's base is only found through the
+    // first
+    fn chained_pack(ofs: bool) -> Result<Vec<u8>> {
+        let [base, delta, delta_of_delta] = versions();
+        let compress = |data: Vec<u8>| compress(data, Compression::default());
+        let mut pack = b"PACK".to_vec();
+        pack.extend(2u32.to_be_bytes());
+        pack.extend(3u32.to_be_bytes());
+
+        let base_offset = pack.len() as u64;
+        pack.extend(write_object_header(3, base.content().len()));
+        pack.extend(compress(base.content().clone())?);
+
+        let entries = [(&base, &delta), (&delta, &delta_of_delta)];
+        let mut previous_offset = base_offset;
+        for (from, to) in match ofs {
+            true => entries.to_vec(),
+            false => entries.into_iter().rev().collect(),
+        } {
+            let data = encode_delta(from.content(), to.content());
+            let offset = pack.len() as u64;
+            match ofs {
+                true => {
+                    pack.extend(write_object_header(6, data.len()));
+                    pack.extend(write_offset(offset - previous_offset));
+                }
+                false => {
+                    pack.extend(write_object_header(7, data.len()));
+                    pack.extend(from.sha1()?.0);
+                }
+            }
+            pack.extend(compress(data)?);
+            previous_offset = offset;
+        }
+
+        let checksum = Sha1::from(&pack).digest().bytes();
+        pack.extend(checksum);
+        Ok(pack)
+    }
+
+    fn assert_unpacks_every_version(ofs: bool) -> Result<()> {
+        let pack = Packfile::read(chained_pack(ofs)?)?;
+        for (_, delta) in &pack.chunks[1..] {
+            match ofs {
+                true => assert!(matches!(delta, PackfileObject::ObjOfsDelta(_))),
+                false => assert!(matches!(delta, PackfileObject::ObjRefDelta(_))),
+            }
+        }
+
+        let objects = pack.unpack(DEFAULT_MAX_DELTA_DEPTH)?;
+        assert_eq!(objects.len(), 3);
+        for blob in versions() {
+            match objects.get(&blob.sha1()?) {
+                Some(AnyGitObject::Blob(unpacked)) => {
+                    assert_eq!(unpacked.content(), blob.content())
+                }
+                other => panic!("expected blob {}, got {other:?}", blob.sha1()?),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn offset_delta_chain_resolves() -> Result<()> {
+        assert_unpacks_every_version(true)
+    }
+
+    #[test]
+    fn ref_delta_chain_resolves_whatever_the_order() -> Result<()> {
+        assert_unpacks_every_version(false)
+    }
+
+    #[test]
+    fn input_that_is_not_a_pack_is_an_error() -> Result<()> {
+        let error = Packfile::read(b"garbage\n".to_vec()).unwrap_err();
+        assert!(error.to_string().contains("should start with"), "{error}");
+
+        let mut pack = chained_pack(true)?;
+        pack[7] = 3;
+        let error = Packfile::read(pack).unwrap_err();
+        assert!(error.to_string().contains("expected version 2"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn delta_chain_depth_is_limited() -> Result<()> {
+        for ofs in [true, false] {
+            let error = Packfile::read(chained_pack(ofs)?)?.unpack(1).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("maximum depth of 1"),
+                "{error:#}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn every_read_path_takes_chains_up_to_the_configured_depth() -> Result<()> {
+        let [_, _, delta_of_delta] = versions();
+        for ofs in [true, false] {
+            let pack = chained_pack(ofs)?;
+            assert!(Packfile::read(pack.clone())?.unpack(1).is_err());
+            Packfile::read(pack.clone())?.unpack(2)?;
+            assert!(index_pack(&pack, 1).is_err());
+            let (index, checksum) = index_pack(&pack, 2)?;
+
+            for depth in [1, 2] {
+                let dir = tempfile::tempdir()?;
+                let pack_dir = dir.path().join(".git/objects/pack");
+                std::fs::create_dir_all(&pack_dir)?;
+                // enough of a git directory for the store to find its config
+                std::fs::create_dir(dir.path().join(".git/refs"))?;
+                std::fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/main\n")?;
+                let path = pack_dir.join(format!("pack-{checksum}.pack"));
+                std::fs::write(&path, &pack)?;
+                std::fs::write(path.with_extension("idx"), &index)?;
+                std::fs::write(
+                    dir.path().join(".git/config"),
+                    format!("[core]\n\tmaxDeltaDepth = {depth}\n"),
+                )?;
+
+                let read = ObjectStore::new(dir.path()).read(&delta_of_delta.sha1()?);
+                match depth {
+                    1 => {
+                        let error = read.unwrap_err();
+                        assert!(
+                            format!("{error:#}").contains("maximum depth of 1"),
+                            "{error:#}"
+                        );
+                    }
+                    _ => assert!(read.is_ok(), "{read:?}"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pack_reader_follows_offset_delta_chains() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pack-chain.pack");
+        std::fs::write(&path, chained_pack(true)?)?;
+        let reader = PackReader::open(&path)?;
+        let no_ref_deltas =
+            |sha: &Sha, _| -> Result<AnyGitObject> { bail!("unexpected ref delta base {sha}") };
+
+        for blob in versions() {
+            let offset = reader
+                .find(&blob.sha1()?)?
+                .expect("every version is in the pack");
+            match reader.read_at(offset, 0, &no_ref_deltas)? {
+                AnyGitObject::Blob(read) => assert_eq!(read.content(), blob.content()),
+                other => panic!("expected a blob, got {other:?}"),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::Write;
+
+// reports how far a long running command got, `done` out of `total` units of work
+pub trait Progress {
+    fn update(&mut self, done: usize, total: usize);
+    fn finish(&mut self);
+}
+
+// for callers that don't want any output
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn update(&mut self, _done: usize, _total: usize) {}
+    fn finish(&mut self) {}
+}
+
+// `<title>: 42% (21/50)` on stderr, redrawn in place like git's progress lines
+pub struct StderrProgress {
+    title: &'static str,
+    done: usize,
+    total: usize,
+    // the percentage on screen, the line is only redrawn when it moves
+    shown: Option<usize>,
+}
+
+impl StderrProgress {
+    pub fn new(title: &'static str) -> Self {
+        Self {
+            title,
+            done: 0,
+            total: 0,
+            shown: None,
+        }
+    }
+
+    fn percent(&self) -> usize {
+        match self.total {
+            0 => 100,
+            total => self.done * 100 / total,
+        }
+    }
+
+    fn draw(&self, end: &str) {
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r{}: {:3}% ({}/{}){end}",
+            self.title,
+            self.percent(),
+            self.done,
+            self.total
+        );
+        let _ = stderr.flush();
+    }
+}
+
+impl Progress for StderrProgress {
+    fn update(&mut self, done: usize, total: usize) {
+        self.done = done;
+        self.total = total;
+        if self.shown != Some(self.percent()) {
+            self.shown = Some(self.percent());
+            self.draw("");
+        }
+    }
+
+    fn finish(&mut self) {
+        self.draw(", done.\n");
+    }
+}
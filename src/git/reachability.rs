@@ -0,0 +1,546 @@
+use crate::git::{
+    any_git_object::{AnyGitObject, Sha},
+    commit_graph::CommitNode,
+    commits::Commit,
+    object_store::ObjectStore,
+    refs::RefStore,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+};
+
+// guards against tag objects pointing at each other
+const MAX_TAG_DEPTH: usize = 20;
+
+// how merge_bases paints commits: reached from the first commit, from the others, and below a
+// common ancestor already found. RESULT marks the commits taken as merge bases
+const PARENT1: u8 = 1;
+const PARENT2: u8 = 2;
+const STALE: u8 = 4;
+const RESULT: u8 = 8;
+
+pub fn reachable_objects<'a>(
+    store: &'a ObjectStore,
+    roots: &[Sha],
+) -> impl Iterator<Item = Result<Sha>> + 'a {
+    reachable_objects_excluding(store, roots, HashSet::new())
+}
+
+// like reachable_objects, but objects in `excluded` are neither yielded nor walked into, so
+// everything they reference is skipped as well unless it's reachable some other way
+pub fn reachable_objects_excluding<'a>(
+    store: &'a ObjectStore,
+    roots: &[Sha],
+    excluded: HashSet<Sha>,
+) -> impl Iterator<Item = Result<Sha>> + 'a {
+    let mut visited = excluded;
+    let mut pending = roots.to_vec();
+
+    std::iter::from_fn(move || loop {
+        let sha = pending.pop()?;
+        if !visited.insert(sha.clone()) {
+            continue;
+        }
+
+        let referenced = store
+            .read(&sha)
+            .map(|object| object.referenced_objects())
+            .with_context(|| {
+                format!("reachable_objects: failed to walk objects referenced by {sha}")
+            });
+
+        return Some(referenced.map(|referenced| {
+            pending.extend(referenced);
+            sha
+        }));
+    })
+}
+
+// breadth-first over the commit graph from `descendant`, so a nearby ancestor is found without
+// walking the whole history. a commit counts as its own ancestor, like `merge-base --is-ancestor`.
+// with a commit graph, nothing of a lower generation than `maybe_ancestor` is walked past
+pub fn is_ancestor(store: &ObjectStore, maybe_ancestor: &Sha, descendant: &Sha) -> Result<bool> {
+    let min_generation = store.commit_generation(maybe_ancestor)?;
+    let mut visited = HashSet::new();
+    let mut pending = VecDeque::from([descendant.clone()]);
+
+    while let Some(sha) = pending.pop_front() {
+        if &sha == maybe_ancestor {
+            return Ok(true);
+        }
+        if !visited.insert(sha.clone()) {
+            continue;
+        }
+
+        let node = store
+            .read_commit_node(&sha)
+            .with_context(|| format!("is_ancestor: failed to read commit {sha}"))?;
+        if node.generation < min_generation {
+            continue;
+        }
+        pending.extend(node.parents);
+    }
+
+    Ok(false)
+}
+
+// the best common ancestors of `one` and all of `others`, like `git merge-base`: the common
+// ancestors that aren't ancestors of another one, newest first. commits are painted from both
+// sides in order of generation, then commit date, until only commits below the common ancestors
+// found so far are left
+pub fn merge_bases(store: &ObjectStore, one: &Sha, others: &[Sha]) -> Result<Vec<Sha>> {
+    if others.contains(one) {
+        return Ok(vec![one.clone()]);
+    }
+
+    let mut flags = HashMap::<Sha, u8>::new();
+    let mut queue = BinaryHeap::new();
+    let enqueue = |queue: &mut BinaryHeap<_>, sha: &Sha| -> Result<()> {
+        let node = store
+            .read_commit_node(sha)
+            .with_context(|| format!("merge_bases: failed to read commit {sha}"))?;
+        queue.push((node.generation, node.commit_time, sha.0));
+        Ok(())
+    };
+    flags.insert(one.clone(), PARENT1);
+    enqueue(&mut queue, one)?;
+    for other in others {
+        *flags.entry(other.clone()).or_default() |= PARENT2;
+        enqueue(&mut queue, other)?;
+    }
+
+    let mut found = vec![];
+    while queue
+        .iter()
+        .any(|(_, _, sha)| flags[&Sha(*sha)] & STALE == 0)
+    {
+        let (_, commit_time, sha) = queue.pop().expect("the queue isn't empty");
+        let sha = Sha(sha);
+        let commit_flags = flags.get_mut(&sha).expect("queued commits are painted");
+        let mut paint = *commit_flags & (PARENT1 | PARENT2 | STALE);
+        if paint == PARENT1 | PARENT2 {
+            if *commit_flags & RESULT == 0 {
+                *commit_flags |= RESULT;
+                found.push((commit_time, sha.clone()));
+            }
+            // whatever is below a common ancestor is a worse one
+            paint |= STALE;
+        }
+
+        for parent in store.read_commit_node(&sha)?.parents {
+            let parent_flags = flags.entry(parent.clone()).or_default();
+            if *parent_flags & paint == paint {
+                continue;
+            }
+            *parent_flags |= paint;
+            enqueue(&mut queue, &parent)?;
+        }
+    }
+
+    // found ones painted stale later are below another one
+    found.retain(|(_, sha)| flags[sha] & STALE == 0);
+    found.sort_by_key(|(commit_time, _)| Reverse(*commit_time));
+    let candidates = found.into_iter().map(|(_, sha)| sha).collect::<Vec<_>>();
+
+    // painting in order doesn't catch every common ancestor that's below another
+    let mut bases = vec![];
+    for candidate in &candidates {
+        let mut redundant = false;
+        for other in &candidates {
+            if other != candidate && is_ancestor(store, candidate, other)? {
+                redundant = true;
+                break;
+            }
+        }
+        if !redundant {
+            bases.push(candidate.clone());
+        }
+    }
+    Ok(bases)
+}
+
+// commits reachable from `roots` but not from `excluded`, newest committer date first like
+// `rev-list`. commits with the same date keep the order they were discovered in
+pub fn reachable_commits(
+    store: &ObjectStore,
+    roots: &[Sha],
+    excluded: &[Sha],
+) -> Result<Vec<(Sha, Commit)>> {
+    let mut hidden = HashSet::new();
+    walk_commits(
+        excluded,
+        &mut hidden,
+        |sha| read_node(store, sha),
+        |_, _| {},
+    )?;
+
+    let mut commits = vec![];
+    walk_commits(
+        roots,
+        &mut hidden,
+        |sha| {
+            let commit = store.read_commit(sha)?;
+            Ok((commit.parent_hash.clone(), commit))
+        },
+        |sha, commit| commits.push((sha, commit)),
+    )?;
+
+    commits.sort_by_key(|(_, commit)| Reverse(commit.committer().epoch));
+    Ok(commits)
+}
+
+// like reachable_commits, for walks that need no more of the commits than the commit graph has
+pub fn reachable_commit_nodes(
+    store: &ObjectStore,
+    roots: &[Sha],
+    excluded: &[Sha],
+) -> Result<Vec<(Sha, CommitNode)>> {
+    let mut hidden = HashSet::new();
+    walk_commits(
+        excluded,
+        &mut hidden,
+        |sha| read_node(store, sha),
+        |_, _| {},
+    )?;
+
+    let mut commits = vec![];
+    walk_commits(
+        roots,
+        &mut hidden,
+        |sha| read_node(store, sha),
+        |sha, node| commits.push((sha, node)),
+    )?;
+
+    commits.sort_by_key(|(_, node)| Reverse(node.commit_time));
+    Ok(commits)
+}
+
+// `commits`, newest first as reachable_commit_nodes gives them, reordered so that no commit comes
+// before one of its descendants, like `rev-list --topo-order`. starting from the tips in the order
+// they come in, a commit is shown once all its children are, and the parent shown last is
+// followed first, so each line of history is shown as far as it goes before the next
+pub fn topo_order(commits: Vec<(Sha, CommitNode)>) -> Vec<(Sha, CommitNode)> {
+    // one more than the children of each commit that are still to be shown, 0 once it's shown
+    let mut indegrees = commits
+        .iter()
+        .map(|(sha, _)| (sha.clone(), 1))
+        .collect::<HashMap<_, usize>>();
+    for (_, node) in &commits {
+        for parent in &node.parents {
+            if let Some(indegree) = indegrees.get_mut(parent) {
+                *indegree += 1;
+            }
+        }
+    }
+
+    let mut pending = commits
+        .iter()
+        .filter(|(sha, _)| indegrees[sha] == 1)
+        .map(|(sha, _)| sha.clone())
+        .rev()
+        .collect::<Vec<_>>();
+    let mut nodes = commits.into_iter().collect::<HashMap<_, _>>();
+    let mut ordered = vec![];
+    while let Some(sha) = pending.pop() {
+        let node = nodes.remove(&sha).expect("every commit is shown once");
+        for parent in &node.parents {
+            match indegrees.get_mut(parent) {
+                Some(indegree) if *indegree > 0 => {
+                    *indegree -= 1;
+                    if *indegree == 1 {
+                        pending.push(parent.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        indegrees.insert(sha.clone(), 0);
+        ordered.push((sha, node));
+    }
+    ordered
+}
+
+fn read_node(store: &ObjectStore, sha: &Sha) -> Result<(Vec<Sha>, CommitNode)> {
+    let node = store.read_commit_node(sha)?;
+    Ok((node.parents.clone(), node))
+}
+
+// depth-first from `roots`, `read` gives a commit's parents along with what `visit` gets of it
+fn walk_commits<T, R, F>(
+    roots: &[Sha],
+    visited: &mut HashSet<Sha>,
+    read: R,
+    mut visit: F,
+) -> Result<()>
+where
+    R: Fn(&Sha) -> Result<(Vec<Sha>, T)>,
+    F: FnMut(Sha, T),
+{
+    let mut pending = roots.to_vec();
+
+    while let Some(sha) = pending.pop() {
+        if !visited.insert(sha.clone()) {
+            continue;
+        }
+
+        let (parents, commit) =
+            read(&sha).with_context(|| format!("walk_commits: failed to read commit {sha}"))?;
+        pending.extend(parents);
+        visit(sha, commit);
+    }
+
+    Ok(())
+}
+
+// the object an annotated tag (or a chain of them) ultimately points at
+pub fn peel_tag(store: &ObjectStore, sha: &Sha) -> Result<Sha> {
+    let mut sha = sha.clone();
+    for _ in 0..MAX_TAG_DEPTH {
+        match store
+            .read(&sha)
+            .with_context(|| format!("peel_tag: failed to read {sha}"))?
+        {
+            AnyGitObject::Tag(tag) => sha = tag.object_hash,
+            _ => return Ok(sha),
+        }
+    }
+    bail!("peel_tag: tags nested more than {MAX_TAG_DEPTH} deep at {sha}")
+}
+
+// the tree a tree-ish names: a tree itself, or the tree of a commit or of whatever a tag points at
+pub fn peel_tree(store: &ObjectStore, sha: &Sha) -> Result<Sha> {
+    let sha = peel_tag(store, sha)?;
+    match store
+        .read(&sha)
+        .with_context(|| format!("peel_tree: failed to read {sha}"))?
+    {
+        AnyGitObject::Commit(commit) => Ok(commit.tree_hash),
+        AnyGitObject::Tree(_) => Ok(sha),
+        _ => bail!("peel_tree: {sha} is not a tree-ish"),
+    }
+}
+
+// the object of type `object_type` that `sha` leads to, following tags and going from a commit to
+// its tree. "object" accepts whatever `sha` is
+pub fn peel_to_type(store: &ObjectStore, sha: &Sha, object_type: &str) -> Result<Sha> {
+    let mut sha = sha.clone();
+    for _ in 0..MAX_TAG_DEPTH {
+        let object = store
+            .read(&sha)
+            .with_context(|| format!("peel_to_type: failed to read {sha}"))?;
+        if object_type == "object" || object.get_type().as_ref() == object_type {
+            return Ok(sha);
+        }
+        sha = match object {
+            AnyGitObject::Tag(tag) => tag.object_hash,
+            AnyGitObject::Commit(commit) if object_type == "tree" => commit.tree_hash,
+            _ => bail!("peel_to_type: {sha} can't be peeled to a {object_type}"),
+        };
+    }
+    bail!("peel_to_type: tags nested more than {MAX_TAG_DEPTH} deep at {sha}")
+}
+
+// the refs under `refs/`, packed ones included, whose object is `sha` or a tag peeling to it
+pub fn refs_pointing_at(store: &ObjectStore, refs: &RefStore, sha: &Sha) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for (name, target) in refs
+        .list()
+        .with_context(|| "refs_pointing_at: failed to list refs")?
+    {
+        if target == *sha || (name.starts_with("refs/tags/") && peel_tag(store, &target)? == *sha) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// a revision as understood by RefStore::resolve_revision, optionally followed by `~<n>` (n-th
+// first-parent ancestor), `^<n>` (n-th parent), `^{<type>}` (peeled to that type) and `^{}`
+// (peeled to a non-tag) steps
+pub fn rev_parse(store: &ObjectStore, refs: &RefStore, rev: &str) -> Result<Sha> {
+    let (name, mut steps) = rev
+        .find(['~', '^'])
+        .map(|index| rev.split_at(index))
+        .unwrap_or((rev, ""));
+    let mut sha = refs.resolve_revision(name)?;
+
+    while let Some(operator) = steps.chars().next() {
+        if let Some(peel) = steps.strip_prefix("^{") {
+            let end = peel
+                .find('}')
+                .ok_or_else(|| anyhow!("rev_parse: unterminated ^{{ in {rev:?}"))?;
+            sha = match &peel[..end] {
+                "" => peel_tag(store, &sha)?,
+                object_type @ ("commit" | "tree" | "blob" | "tag" | "object") => {
+                    peel_to_type(store, &sha, object_type)
+                        .with_context(|| format!("rev_parse: failed to resolve {rev:?}"))?
+                }
+                object_type => bail!("rev_parse: unknown object type {object_type:?} in {rev:?}"),
+            };
+            steps = &peel[end + 1..];
+            continue;
+        }
+
+        let digits = steps[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(&steps[1..], |end| &steps[1..end + 1]);
+        let count = if digits.is_empty() {
+            1
+        } else {
+            digits
+                .parse::<usize>()
+                .with_context(|| format!("rev_parse: invalid count in {rev:?}"))?
+        };
+        steps = &steps[1 + digits.len()..];
+
+        let parents = |sha: &Sha| -> Result<Vec<Sha>> {
+            Ok(store
+                .read_commit(&peel_tag(store, sha)?)
+                .with_context(|| format!("rev_parse: {sha} is not a commit in {rev:?}"))?
+                .parent_hash)
+        };
+
+        // `^0` and `~0` still peel down to the commit
+        sha = match (operator, count) {
+            (_, 0) => peel_to_type(store, &sha, "commit")?,
+            ('^', n) => parents(&sha)?
+                .into_iter()
+                .nth(n - 1)
+                .ok_or_else(|| anyhow!("rev_parse: {sha} has no parent {n} in {rev:?}"))?,
+            (_, n) => {
+                for _ in 0..n {
+                    sha = parents(&sha)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| anyhow!("rev_parse: {sha} has no parent in {rev:?}"))?;
+                }
+                sha
+            }
+        };
+    }
+
+    Ok(sha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{
+        git_blob::Blob,
+        git_object_trait::GitObject,
+        git_tree::{FileMode, Tree, TreeEntry, EMPTY_TREE_SHA},
+    };
+    use flate2::Compression;
+    use tempfile::TempDir;
+
+    // root ── left ── merge ── tip
+    //     └── right ─┘
+    struct History {
+        _dir: TempDir,
+        store: ObjectStore,
+        root: Sha,
+        left: Sha,
+        right: Sha,
+        merge: Sha,
+        tip: Sha,
+    }
+
+    fn history() -> Result<History> {
+        let dir = tempfile::tempdir()?;
+        let commit = |parents: &[&Sha], message: &str| -> Result<Sha> {
+            let commit = Commit::new(
+                EMPTY_TREE_SHA.0,
+                parents.iter().map(|parent| parent.0).collect(),
+                "A U Thor <author@example.com> 1700000000 +0000".parse()?,
+                None,
+                format!("{message}\n"),
+            )?;
+            commit.write_with_level(dir.path(), Compression::default())?;
+            commit.sha1()
+        };
+
+        let root = commit(&[], "root")?;
+        let left = commit(&[&root], "left")?;
+        let right = commit(&[&root], "right")?;
+        let merge = commit(&[&left, &right], "merge")?;
+        let tip = commit(&[&merge], "tip")?;
+        Ok(History {
+            store: ObjectStore::new(dir.path()),
+            _dir: dir,
+            root,
+            left,
+            right,
+            merge,
+            tip,
+        })
+    }
+
+    #[test]
+    fn both_sides_of_a_merge_are_ancestors() -> Result<()> {
+        let history = history()?;
+        for ancestor in [&history.root, &history.left, &history.right, &history.merge] {
+            assert!(is_ancestor(&history.store, ancestor, &history.tip)?);
+        }
+        assert!(is_ancestor(&history.store, &history.right, &history.merge)?);
+        Ok(())
+    }
+
+    #[test]
+    fn siblings_and_descendants_are_not_ancestors() -> Result<()> {
+        let history = history()?;
+        assert!(!is_ancestor(&history.store, &history.left, &history.right)?);
+        assert!(!is_ancestor(&history.store, &history.right, &history.left)?);
+        assert!(!is_ancestor(&history.store, &history.merge, &history.left)?);
+        assert!(!is_ancestor(&history.store, &history.tip, &history.root)?);
+        Ok(())
+    }
+
+    #[test]
+    fn a_commit_is_its_own_ancestor() -> Result<()> {
+        let history = history()?;
+        for commit in [&history.root, &history.right, &history.merge] {
+            assert!(is_ancestor(&history.store, commit, commit)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn the_walk_does_not_enter_submodule_commits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let blob = Blob::new(b"one\n".to_vec());
+        blob.write_with_level(dir.path(), Compression::default())?;
+        // the submodule's commit isn't in this repository
+        let tree = Tree::new(vec![
+            TreeEntry {
+                mode: FileMode::Regular,
+                name: "a".to_owned(),
+                hash: blob.sha1()?,
+            },
+            TreeEntry {
+                mode: FileMode::Gitlink,
+                name: "sub".to_owned(),
+                hash: Sha([0x11; 20]),
+            },
+        ]);
+        tree.write_with_level(dir.path(), Compression::default())?;
+        let commit = Commit::new(
+            tree.sha1()?.0,
+            vec![],
+            "A U Thor <author@example.com> 1700000000 +0000".parse()?,
+            None,
+            "submodule\n".to_owned(),
+        )?;
+        commit.write_with_level(dir.path(), Compression::default())?;
+
+        let store = ObjectStore::new(dir.path());
+        let reachable =
+            reachable_objects(&store, &[commit.sha1()?]).collect::<Result<HashSet<_>>>()?;
+        assert_eq!(
+            reachable,
+            HashSet::from([commit.sha1()?, tree.sha1()?, blob.sha1()?])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+use crate::{
+    git::{any_git_object::Sha, commits::CommitActor},
+    utils::helpers::{common_dir, git_dir},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// symbolic refs are followed at most this many times, like git's SYMREF_MAXDEPTH
+const MAX_SYMREF_DEPTH: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct RefStore {
+    path: PathBuf,
+}
+
+impl RefStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    // the directories holding this worktree's own refs and the ones shared by every worktree,
+    // the same directory unless the repository has several worktrees
+    fn git_dir(&self) -> PathBuf {
+        git_dir(&self.path)
+    }
+
+    fn common_dir(&self) -> PathBuf {
+        common_dir(&self.path)
+    }
+
+    fn ref_path(&self, name: &str) -> PathBuf {
+        match is_per_worktree(name) {
+            true => self.git_dir().join(name),
+            false => self.common_dir().join(name),
+        }
+    }
+
+    fn reflog_path(&self, name: &str) -> PathBuf {
+        match is_per_worktree(name) {
+            true => self.git_dir().join("logs").join(name),
+            false => self.common_dir().join("logs").join(name),
+        }
+    }
+
+    // the directories refs and reflogs are spread over, the worktree's own one only if it differs
+    fn state_dirs(&self) -> Vec<PathBuf> {
+        let (git_dir, common_dir) = (self.git_dir(), self.common_dir());
+        if git_dir == common_dir {
+            vec![common_dir]
+        } else {
+            vec![common_dir, git_dir]
+        }
+    }
+
+    // all refs under `refs/`, loose refs taking precedence over packed ones
+    pub fn list(&self) -> Result<BTreeMap<String, Sha>> {
+        let mut refs = self
+            .packed_refs()
+            .with_context(|| "RefStore::list: failed to read packed refs")?;
+
+        let mut loose = vec![];
+        for dir in self.state_dirs() {
+            let mut paths = vec![];
+            collect_files(&dir.join("refs"), &mut paths)
+                .with_context(|| "RefStore::list: failed to walk loose refs")?;
+            loose.extend(paths.into_iter().map(|path| (dir.clone(), path)));
+        }
+
+        for (dir, path) in loose {
+            let name = path
+                .strip_prefix(&dir)
+                .with_context(|| format!("RefStore::list: unexpected ref path {path:?}"))?
+                .to_string_lossy()
+                .into_owned();
+            // a shared ref name found in a worktree's own directory isn't one of its refs
+            if self.ref_path(&name) != path {
+                continue;
+            }
+            if let Some(sha) = self
+                .resolve(&name)
+                .with_context(|| format!("RefStore::list: failed to resolve {name}"))?
+            {
+                refs.insert(name, sha);
+            }
+        }
+
+        Ok(refs)
+    }
+
+    pub fn head(&self) -> Result<Option<Sha>> {
+        self.resolve("HEAD")
+    }
+
+    // a full object id or a ref name, shortened names are tried in the order of
+    // https://git-scm.com/docs/gitrevisions#_specifying_revisions
+    pub fn resolve_revision(&self, rev: &str) -> Result<Sha> {
+        if rev.len() == 40 {
+            if let Ok(sha) = rev.parse() {
+                return Ok(sha);
+            }
+        }
+
+        for candidate in [
+            rev.to_owned(),
+            format!("refs/{rev}"),
+            format!("refs/tags/{rev}"),
+            format!("refs/heads/{rev}"),
+            format!("refs/remotes/{rev}"),
+            format!("refs/remotes/{rev}/HEAD"),
+        ] {
+            if let Some(sha) = self.resolve(&candidate)? {
+                return Ok(sha);
+            }
+        }
+
+        bail!("RefStore::resolve_revision: unknown revision {rev:?}")
+    }
+
+    // the ref a symbolic ref like HEAD points at, or None when it holds an object id
+    pub fn symbolic_target(&self, name: &str) -> Result<Option<String>> {
+        let path = self.ref_path(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Ok(fs::read_to_string(&path)
+            .with_context(|| format!("RefStore::symbolic_target: failed to read {path:?}"))?
+            .trim()
+            .strip_prefix("ref: ")
+            .map(str::to_owned))
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<Option<Sha>> {
+        let mut name = name.to_owned();
+
+        for _ in 0..MAX_SYMREF_DEPTH {
+            let path = self.ref_path(&name);
+            let content = if path.is_file() {
+                fs::read_to_string(&path)
+                    .with_context(|| format!("RefStore::resolve: failed to read {path:?}"))?
+            } else {
+                return Ok(self.packed_refs()?.remove(&name));
+            };
+            let content = content.trim();
+
+            match content.strip_prefix("ref: ") {
+                Some(target) => name = target.to_owned(),
+                None => return content.parse().map(Some),
+            }
+        }
+
+        bail!("RefStore::resolve: symbolic ref {name} is nested too deeply")
+    }
+
+    fn packed_refs(&self) -> Result<BTreeMap<String, Sha>> {
+        let path = self.common_dir().join("packed-refs");
+        if !path.is_file() {
+            return Ok(BTreeMap::new());
+        }
+
+        fs::read_to_string(&path)
+            .with_context(|| format!("RefStore::packed_refs: failed to read {path:?}"))?
+            .lines()
+            // `#` starts the header, `^` the peeled object of the preceding tag
+            .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+            .map(|line| {
+                let (sha, name) = line
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("RefStore::packed_refs: malformed line {line:?}"))?;
+                Ok((name.to_owned(), sha.parse()?))
+            })
+            .collect()
+    }
+
+    // points the loose ref `name` at `sha`, replacing the file in one rename so readers never see
+    // a partially written ref
+    pub fn update(&self, name: &str, sha: &Sha, message: &str) -> Result<()> {
+        let old = self
+            .resolve(name)
+            .with_context(|| format!("RefStore::update: failed to resolve {name}"))?;
+
+        let path = self.ref_path(name);
+        write_atomically(&path, format!("{sha}\n").as_bytes())
+            .with_context(|| format!("RefStore::update: failed to write {name}"))?;
+
+        self.append_reflog(name, old.as_ref(), Some(sha), message)
+    }
+
+    // makes `name` a symbolic ref to `target`, the way HEAD follows a branch. `update` on HEAD
+    // instead writes the object id itself and detaches it
+    pub fn update_symbolic(&self, name: &str, target: &str, message: &str) -> Result<()> {
+        let old = self
+            .resolve(name)
+            .with_context(|| format!("RefStore::update_symbolic: failed to resolve {name}"))?;
+        let new = self
+            .resolve(target)
+            .with_context(|| format!("RefStore::update_symbolic: failed to resolve {target}"))?;
+
+        let path = self.ref_path(name);
+        write_atomically(&path, format!("ref: {target}\n").as_bytes())
+            .with_context(|| format!("RefStore::update_symbolic: failed to write {name}"))?;
+
+        self.append_reflog(name, old.as_ref(), new.as_ref(), message)
+    }
+
+    // removes `name` from both the loose refs and packed-refs
+    pub fn delete(&self, name: &str, message: &str) -> Result<()> {
+        let old = self
+            .resolve(name)
+            .with_context(|| format!("RefStore::delete: failed to resolve {name}"))?;
+
+        let packed_path = self.common_dir().join("packed-refs");
+        if self.packed_refs()?.contains_key(name) {
+            let content = fs::read_to_string(&packed_path)
+                .with_context(|| format!("RefStore::delete: failed to read {packed_path:?}"))?;
+
+            // a peeled line belongs to the ref above it, so it goes along with that ref
+            let mut kept = String::new();
+            let mut skipping = false;
+            for line in content.lines() {
+                if line.starts_with('^') {
+                    if skipping {
+                        continue;
+                    }
+                } else {
+                    skipping = line
+                        .split_once(' ')
+                        .is_some_and(|(_, ref_name)| ref_name == name);
+                    if skipping {
+                        continue;
+                    }
+                }
+                kept.push_str(line);
+                kept.push('\n');
+            }
+            write_atomically(&packed_path, kept.as_bytes())
+                .with_context(|| format!("RefStore::delete: failed to rewrite {packed_path:?}"))?;
+        }
+
+        let path = self.ref_path(name);
+        if path.is_file() {
+            fs::remove_file(&path)
+                .with_context(|| format!("RefStore::delete: failed to remove {path:?}"))?;
+        }
+
+        self.append_reflog(name, old.as_ref(), None, message)
+    }
+
+    fn append_reflog(
+        &self,
+        name: &str,
+        old: Option<&Sha>,
+        new: Option<&Sha>,
+        message: &str,
+    ) -> Result<()> {
+        let path = self.reflog_path(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("RefStore::append_reflog: failed to create {parent:?}"))?;
+        }
+
+        let zero = Sha([0; 20]);
+        let entry = format!(
+            "{} {} {}\t{message}\n",
+            old.unwrap_or(&zero),
+            new.unwrap_or(&zero),
+            CommitActor::current()
+        );
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(entry.as_bytes()))
+            .with_context(|| format!("RefStore::append_reflog: failed to append to {path:?}"))
+    }
+
+    // object ids recorded in `.git/logs`, these keep objects alive until the reflog expires
+    pub fn reflog_objects(&self) -> Result<Vec<Sha>> {
+        let mut logs = vec![];
+        for dir in self.state_dirs() {
+            collect_files(&dir.join("logs"), &mut logs)
+                .with_context(|| "RefStore::reflog_objects: failed to walk reflogs")?;
+        }
+
+        let mut shas = vec![];
+        for path in logs {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("RefStore::reflog_objects: failed to read {path:?}"))?;
+            for line in content.lines() {
+                let mut fields = line.split(' ');
+                for sha in [fields.next(), fields.next()].into_iter().flatten() {
+                    let sha: Sha = sha.parse().with_context(|| {
+                        format!("RefStore::reflog_objects: malformed entry in {path:?}")
+                    })?;
+                    if sha.0 != [0; 20] {
+                        shas.push(sha);
+                    }
+                }
+            }
+        }
+        Ok(shas)
+    }
+}
+
+// the rules of https://git-scm.com/docs/git-check-ref-format for a full ref name like
+// `refs/heads/main`
+pub fn check_ref_format(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "@"
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.chars().any(|c| {
+            c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+        })
+        && name.split('/').all(|component| {
+            !component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+        });
+    if !valid {
+        bail!("check_ref_format: {name:?} is not a valid ref name");
+    }
+    Ok(())
+}
+
+// HEAD and the other pseudo refs outside `refs/`, and a few namespaces below it, belong to a
+// single worktree, every other ref is shared by all of them
+fn is_per_worktree(name: &str) -> bool {
+    !name.starts_with("refs/")
+        || ["refs/worktree/", "refs/bisect/", "refs/rewritten/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("failed to create {parent:?}"))?;
+    }
+
+    let lock_path = path.with_file_name(format!(
+        "{}.lock",
+        path.file_name()
+            .ok_or_else(|| anyhow!("invalid ref path {path:?}"))?
+            .to_string_lossy()
+    ));
+    fs::write(&lock_path, content)
+        .and_then(|_| fs::rename(&lock_path, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&lock_path);
+            anyhow!(err)
+        })
+        .with_context(|| format!("failed to write {path:?}"))
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read directory {dir:?}"))? {
+        let path = entry
+            .with_context(|| format!("failed to read directory entry in {dir:?}"))?
+            .path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use crate::git::any_git_object::Sha;
+use anyhow::{bail, Error, Result};
+use std::str::FromStr;
+
+// `[+]<src>[:<dst>]`, which refs to take from one side and the names they get on the other:
+// https://git-scm.com/docs/git-fetch#Documentation/git-fetch.txt-ltrefspecgt. a `*` in both
+// sides matches any part of a name and carries it over
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    // updates that aren't fast-forwards are allowed
+    pub force: bool,
+    pub src: String,
+    // None when nothing is stored locally, like `git fetch origin main`
+    pub dst: Option<String>,
+}
+
+impl FromStr for Refspec {
+    type Err = Error;
+
+    fn from_str(refspec: &str) -> Result<Self, Self::Err> {
+        let (force, rest) = match refspec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, refspec),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, dst)) => (src, Some(dst)),
+            None => (rest, None),
+        };
+        if src.is_empty() {
+            bail!("Refspec::from_str: missing source in {refspec:?}");
+        }
+
+        let stars = |side: &str| side.matches('*').count();
+        if stars(src) > 1 || dst.is_some_and(|dst| stars(dst) > 1) {
+            bail!("Refspec::from_str: more than one `*` on a side of {refspec:?}");
+        }
+        if dst.is_some_and(|dst| stars(src) != stars(dst)) {
+            bail!("Refspec::from_str: `*` has to be on both sides of {refspec:?} or neither");
+        }
+
+        Ok(Self {
+            force,
+            src: src.to_owned(),
+            dst: dst.filter(|dst| !dst.is_empty()).map(str::to_owned),
+        })
+    }
+}
+
+impl Refspec {
+    // the name `name` gets on the destination side, or None when the source doesn't match it or
+    // there's no destination
+    pub fn map_to_dst(&self, name: &str) -> Option<String> {
+        let matched = match_pattern(&self.src, name)?;
+        Some(self.dst.as_ref()?.replacen('*', matched, 1))
+    }
+
+    // whether `name` is one of the refs this refspec writes to
+    pub fn dst_matches(&self, name: &str) -> bool {
+        self.dst
+            .as_ref()
+            .is_some_and(|dst| match_pattern(dst, name).is_some())
+    }
+
+    // `(ref, object, destination ref)` for each of `refs` the source matches, in the order of
+    // `refs`. peeled tag entries (`refs/tags/v1.0^{}`) are no refs of their own and never match
+    pub fn expand<'a, I>(&self, refs: I) -> Vec<(&'a str, &'a Sha, String)>
+    where
+        I: IntoIterator<Item = (&'a String, &'a Sha)>,
+    {
+        refs.into_iter()
+            .filter(|(name, _)| !name.ends_with("^{}"))
+            .filter_map(|(name, sha)| Some((name.as_str(), sha, self.map_to_dst(name)?)))
+            .collect()
+    }
+}
+
+// the part of `name` the `*` in `pattern` stands for, "" for a pattern without one that is
+// `name` itself, or None when they don't match
+fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => name
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)
+            .filter(|matched| !matched.is_empty()),
+        None => (pattern == name).then_some(""),
+    }
+}
@@ -0,0 +1,523 @@
+use crate::{
+    git::{
+        any_git_object::Sha,
+        diff::{is_binary, matching_lines, split_lines},
+        diff_driver::DiffDrivers,
+        git_blob::Blob,
+        git_object_trait::GitObject,
+        git_tree::FileMode,
+        object_store::ObjectStore,
+        tree_walk::TreeChange,
+    },
+    utils::color::{Color, Colors},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    ops::Range,
+    os::unix::ffi::OsStringExt,
+    path::Path,
+};
+
+// lines of unchanged content shown around every change unless asked otherwise
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+// git cuts the function name shown in hunk headers at 80 bytes
+const FUNCTION_CONTEXT_SIZE: usize = 80;
+const NULL_SHA_ABBREV: &str = "0000000";
+
+#[derive(Debug, Clone, Copy)]
+pub struct PatchOptions<'a> {
+    // unchanged lines shown around every change
+    pub context: usize,
+    pub colors: Colors,
+    // the repository's diff attributes and textconv commands, without them binary content is
+    // told apart by looking at it
+    pub drivers: Option<&'a DiffDrivers>,
+}
+
+// a `git diff` style patch for one change, binary content is only reported as differing
+pub fn write_patch<'a, W: Write>(
+    out: &mut W,
+    store: &ObjectStore,
+    change: &'a TreeChange,
+    options: PatchOptions<'_>,
+) -> Result<()> {
+    let (old_name, new_name, old, new) = match change {
+        TreeChange::Added { name, entry } => (name, name, None, Some(entry)),
+        TreeChange::Deleted { name, entry } => (name, name, Some(entry), None),
+        TreeChange::Modified { name, old, new } => (name, name, Some(old), Some(new)),
+        TreeChange::Renamed {
+            from, to, old, new, ..
+        }
+        | TreeChange::Copied {
+            from, to, old, new, ..
+        } => (from, to, Some(old), Some(new)),
+    };
+
+    let mut header = vec![format!("diff --git a/{old_name} b/{new_name}")];
+    match (old, new) {
+        (None, Some((mode, _))) => header.push(format!("new file mode {:06o}", mode.mode())),
+        (Some((mode, _)), None) => header.push(format!("deleted file mode {:06o}", mode.mode())),
+        _ => {}
+    }
+    if let TreeChange::Renamed {
+        from,
+        to,
+        similarity,
+        ..
+    } = change
+    {
+        header.push(format!("similarity index {similarity}%"));
+        header.push(format!("rename from {from}"));
+        header.push(format!("rename to {to}"));
+    }
+    if let TreeChange::Copied {
+        from,
+        to,
+        similarity,
+        ..
+    } = change
+    {
+        header.push(format!("similarity index {similarity}%"));
+        header.push(format!("copy from {from}"));
+        header.push(format!("copy to {to}"));
+    }
+    if let (Some((old_mode, _)), Some((new_mode, _))) = (old, new) {
+        if old_mode.as_ref() != new_mode.as_ref() {
+            header.push(format!("old mode {:06o}", old_mode.mode()));
+            header.push(format!("new mode {:06o}", new_mode.mode()));
+        }
+    }
+
+    if old.map(|(_, sha)| sha) == new.map(|(_, sha)| sha) {
+        // a pure rename or mode change has no content to show
+        return write_header(out, options.colors, header);
+    }
+
+    let read = |entry: Option<&'a (FileMode, Sha)>| -> Result<Option<PatchFile<'a>>> {
+        let Some((mode, sha)) = entry else {
+            return Ok(None);
+        };
+        let content = store
+            .read(sha)
+            .with_context(|| format!("write_patch: failed to read blob {sha}"))?
+            .try_as_blob()
+            .ok_or_else(|| anyhow!("write_patch: expected {sha} to be a blob"))?
+            .content()
+            .to_vec();
+        Ok(Some(PatchFile {
+            mode,
+            sha: sha.clone(),
+            content,
+        }))
+    };
+    write_content_patch(
+        out,
+        header,
+        (old_name, read(old)?),
+        (new_name, read(new)?),
+        options,
+    )
+}
+
+// what a patch knows about a file on one of its sides
+struct PatchFile<'a> {
+    mode: &'a FileMode,
+    sha: Sha,
+    content: Vec<u8>,
+}
+
+// the rest of a patch after the header lines describing the change: the `index` line, the file
+// labels and the hunks, or a note that binary content differs
+fn write_content_patch<W: Write>(
+    out: &mut W,
+    mut header: Vec<String>,
+    (old_name, old): (&str, Option<PatchFile>),
+    (new_name, new): (&str, Option<PatchFile>),
+    options: PatchOptions<'_>,
+) -> Result<()> {
+    let abbrev = |file: &Option<PatchFile>| {
+        file.as_ref().map_or(NULL_SHA_ABBREV.to_owned(), |file| {
+            file.sha.to_string()[..7].to_owned()
+        })
+    };
+    let index = format!("index {}..{}", abbrev(&old), abbrev(&new));
+    header.push(match (&old, &new) {
+        (Some(old), Some(new)) if old.mode.as_ref() == new.mode.as_ref() => {
+            format!("{index} {:06o}", old.mode.mode())
+        }
+        _ => index,
+    });
+
+    let old_label = old
+        .as_ref()
+        .map_or("/dev/null".to_owned(), |_| format!("a/{old_name}"));
+    let new_label = new
+        .as_ref()
+        .map_or("/dev/null".to_owned(), |_| format!("b/{new_name}"));
+    let content = |name: &str, file: Option<PatchFile>| -> Result<(Vec<u8>, bool)> {
+        let Some(file) = file else {
+            return Ok((vec![], false));
+        };
+        match options.drivers {
+            Some(drivers) => drivers.prepare(name, file.content),
+            None => {
+                let binary = is_binary(&file.content);
+                Ok((file.content, binary))
+            }
+        }
+    };
+    let (old_content, old_binary) = content(old_name, old)?;
+    let (new_content, new_binary) = content(new_name, new)?;
+    if old_binary || new_binary {
+        write_header(out, options.colors, header)?;
+        writeln!(out, "Binary files {old_label} and {new_label} differ")?;
+        return Ok(());
+    }
+
+    header.push(format!("--- {old_label}"));
+    header.push(format!("+++ {new_label}"));
+    write_header(out, options.colors, header)?;
+    let hunks = unified_diff(&old_content, &new_content, options.context);
+    for line in split_lines(&hunks) {
+        out.write_all(&paint_hunk_line(options.colors, line))?;
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(out: &mut W, colors: Colors, header: Vec<String>) -> Result<()> {
+    for line in header {
+        out.write_all(&colors.paint(Color::Bold, line.as_bytes()))?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// `git diff --no-index`: patches between two files on disk, or between the files of two
+// directories matched up by their paths below them. a path that doesn't exist is taken as an
+// empty side, so its counterpart shows up as added or deleted. a file compared with a directory
+// is compared with the file of the same name in it, like in git
+pub fn write_no_index_diff<W: Write>(
+    out: &mut W,
+    old: &Path,
+    new: &Path,
+    options: PatchOptions<'_>,
+) -> Result<()> {
+    let (old, new) = match (old.is_dir(), new.is_dir()) {
+        (true, false) if new.is_file() => (old.join(file_name(new)?), new.to_path_buf()),
+        (false, true) if old.is_file() => (old.to_path_buf(), new.join(file_name(old)?)),
+        _ => (old.to_path_buf(), new.to_path_buf()),
+    };
+    let (old_files, new_files) = (disk_files(&old)?, disk_files(&new)?);
+
+    let names = old_files
+        .keys()
+        .chain(new_files.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let old_file = old_files.get(name);
+        let new_file = new_files.get(name);
+        // a side that's missing takes the other side's name
+        let path = |root: &Path, present: bool| {
+            let root = if present {
+                root
+            } else if old_file.is_some() {
+                &old
+            } else {
+                &new
+            };
+            let path = match name.as_str() {
+                "" => root.to_string_lossy().into_owned(),
+                name => root.join(name).to_string_lossy().into_owned(),
+            };
+            path.trim_start_matches('/').to_owned()
+        };
+        let old_name = path(&old, old_file.is_some());
+        let new_name = path(&new, new_file.is_some());
+
+        let old_file = patch_file(old_file)?;
+        let new_file = patch_file(new_file)?;
+        let same_content =
+            old_file.as_ref().map(|file| &file.sha) == new_file.as_ref().map(|file| &file.sha);
+        let mode_changed = match (&old_file, &new_file) {
+            (Some(old), Some(new)) => old.mode.as_ref() != new.mode.as_ref(),
+            _ => false,
+        };
+        if same_content && !mode_changed {
+            continue;
+        }
+
+        let mut header = vec![format!("diff --git a/{old_name} b/{new_name}")];
+        match (&old_file, &new_file) {
+            (None, Some(new)) => header.push(format!("new file mode {:06o}", new.mode.mode())),
+            (Some(old), None) => header.push(format!("deleted file mode {:06o}", old.mode.mode())),
+            (Some(old), Some(new)) if mode_changed => {
+                header.push(format!("old mode {:06o}", old.mode.mode()));
+                header.push(format!("new mode {:06o}", new.mode.mode()));
+            }
+            _ => {}
+        }
+        if same_content {
+            write_header(out, options.colors, header)?;
+            continue;
+        }
+        write_content_patch(
+            out,
+            header,
+            (&old_name, old_file),
+            (&new_name, new_file),
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+// a file read from disk, hashed the way it would be stored
+fn patch_file(file: Option<&(FileMode, Vec<u8>)>) -> Result<Option<PatchFile<'_>>> {
+    file.map(|(mode, content)| {
+        Ok(PatchFile {
+            mode,
+            sha: Blob::new(content.clone()).sha1()?,
+            content: content.clone(),
+        })
+    })
+    .transpose()
+}
+
+fn file_name(path: &Path) -> Result<&OsStr> {
+    path.file_name()
+        .ok_or_else(|| anyhow!("write_no_index_diff: {path:?} has no file name"))
+}
+
+// the files at `path` keyed by their path below it, "" for `path` itself when it's a file. a
+// symlink's content is its target, like in a blob
+fn disk_files(path: &Path) -> Result<BTreeMap<String, (FileMode, Vec<u8>)>> {
+    let mut files = BTreeMap::new();
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(files);
+    };
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)
+            .with_context(|| format!("disk_files: failed to read directory {path:?}"))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("disk_files: failed to read directory {path:?}"))?;
+        entries.sort();
+        for entry in entries {
+            let name = entry.to_string_lossy().into_owned();
+            for (below, file) in disk_files(&path.join(&entry))? {
+                let key = match below.as_str() {
+                    "" => name.clone(),
+                    below => format!("{name}/{below}"),
+                };
+                files.insert(key, file);
+            }
+        }
+    } else if metadata.is_symlink() {
+        let target = fs::read_link(path)
+            .with_context(|| format!("disk_files: failed to read link {path:?}"))?;
+        files.insert(
+            String::new(),
+            (FileMode::Symbolic, target.into_os_string().into_vec()),
+        );
+    } else if metadata.is_file() {
+        let content =
+            fs::read(path).with_context(|| format!("disk_files: failed to read {path:?}"))?;
+        files.insert(String::new(), (FileMode::from(metadata), content));
+    }
+    Ok(files)
+}
+
+fn paint_hunk_line(colors: Colors, line: &[u8]) -> Vec<u8> {
+    match line.first() {
+        Some(b'+') => colors.paint(Color::Green, line),
+        Some(b'-') => colors.paint(Color::Red, line),
+        // only the ranges are colored, the function context after them isn't
+        Some(b'@') => {
+            let end = line
+                .windows(2)
+                .skip(2)
+                .position(|window| window == b"@@")
+                .map_or(line.len(), |index| index + 4);
+            [
+                colors.paint(Color::Cyan, &line[..end]),
+                line[end..].to_vec(),
+            ]
+            .concat()
+        }
+        _ => line.to_vec(),
+    }
+}
+
+// the hunks of a unified diff between two texts with `context` unchanged lines around every
+// change, without the `---`/`+++` file header
+pub fn unified_diff(old: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+    let (old, new) = (split_lines(old), split_lines(new));
+
+    // the runs of lines between two matched lines are the changes
+    let mut changes: Vec<(Range<usize>, Range<usize>)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (x, y) in matching_lines(&old, &new)
+        .into_iter()
+        .chain(std::iter::once((old.len(), new.len())))
+    {
+        if x > i || y > j {
+            changes.push((i..x, j..y));
+        }
+        (i, j) = (x + 1, y + 1);
+    }
+
+    let mut output = vec![];
+    let mut index = 0;
+    while index < changes.len() {
+        // changes whose context would touch or overlap end up in the same hunk
+        let mut last = index;
+        while last + 1 < changes.len()
+            && changes[last + 1].0.start - changes[last].0.end <= 2 * context
+        {
+            last += 1;
+        }
+
+        let (first_change, last_change) = (&changes[index], &changes[last]);
+        let old_start = first_change.0.start.saturating_sub(context);
+        let new_start = first_change.1.start - (first_change.0.start - old_start);
+        let old_end = (last_change.0.end + context).min(old.len());
+        let new_end = last_change.1.end + (old_end - last_change.0.end);
+
+        output.extend(
+            format!(
+                "@@ -{} +{} @@",
+                hunk_range(old_start, old_end - old_start),
+                hunk_range(new_start, new_end - new_start)
+            )
+            .into_bytes(),
+        );
+        if let Some(function) = function_context(&old[..old_start]) {
+            output.push(b' ');
+            output.extend(function);
+        }
+        output.push(b'\n');
+
+        let mut position = old_start;
+        for (old_lines, new_lines) in &changes[index..=last] {
+            for line in &old[position..old_lines.start] {
+                push_line(&mut output, b' ', line);
+            }
+            for line in &old[old_lines.clone()] {
+                push_line(&mut output, b'-', line);
+            }
+            for line in &new[new_lines.clone()] {
+                push_line(&mut output, b'+', line);
+            }
+            position = old_lines.end;
+        }
+        for line in &old[position..old_end] {
+            push_line(&mut output, b' ', line);
+        }
+
+        index = last + 1;
+    }
+    output
+}
+
+// `start,count` with a 1-based start, an empty range names the line before it like git does
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+// git's default function header: the closest line above the hunk that starts with a letter,
+// `_` or `$`, without its trailing whitespace
+fn function_context<'a>(lines: &[&'a [u8]]) -> Option<&'a [u8]> {
+    let line = lines.iter().rev().find(|line| {
+        line.first()
+            .is_some_and(|b| b.is_ascii_alphabetic() || *b == b'_' || *b == b'$')
+    })?;
+    let line = &line[..line.len().min(FUNCTION_CONTEXT_SIZE)];
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |index| index + 1);
+    Some(&line[..end])
+}
+
+fn push_line(output: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    output.push(prefix);
+    output.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        output.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lines 1 to `count`, none of which git would take as a function header
+    fn numbers(count: usize) -> String {
+        (1..=count).map(|n| format!("{n}\n")).collect()
+    }
+
+    // 1 to 12 with lines 3 and 8 changed, four unchanged lines apart
+    fn two_changes() -> (String, String) {
+        let new = numbers(12)
+            .replace("\n3\n", "\nthree\n")
+            .replace("\n8\n", "\neight\n");
+        (numbers(12), new)
+    }
+
+    fn diff(old: &str, new: &str, context: usize) -> String {
+        String::from_utf8(unified_diff(old.as_bytes(), new.as_bytes(), context)).unwrap()
+    }
+
+    #[test]
+    fn zero_context_shows_only_the_changes() {
+        let (old, new) = two_changes();
+        assert_eq!(
+            diff(&old, &new, 0),
+            "@@ -3 +3 @@\n-3\n+three\n@@ -8 +8 @@\n-8\n+eight\n"
+        );
+
+        // an empty side names the line before it
+        let inserted = "1\n2\n3\nnew\n4\n5\n";
+        assert_eq!(diff(&numbers(5), inserted, 0), "@@ -3,0 +4 @@\n+new\n");
+        assert_eq!(diff(&numbers(6), &numbers(5), 0), "@@ -6 +5,0 @@\n-6\n");
+    }
+
+    #[test]
+    fn hunks_stay_apart_while_their_context_does_not_touch() {
+        let (old, new) = two_changes();
+        assert_eq!(
+            diff(&old, &new, 1),
+            "@@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n@@ -7,3 +7,3 @@\n 7\n-8\n+eight\n 9\n"
+        );
+    }
+
+    #[test]
+    fn context_that_touches_merges_hunks() {
+        let (old, new) = two_changes();
+        let merged = |first: usize, last: usize| {
+            let count = last - first + 1;
+            let mut hunk = format!("@@ -{first},{count} +{first},{count} @@\n");
+            for n in first..=last {
+                hunk += &match n {
+                    3 => "-3\n+three\n".to_owned(),
+                    8 => "-8\n+eight\n".to_owned(),
+                    _ => format!(" {n}\n"),
+                };
+            }
+            hunk
+        };
+        // the four lines between the changes are exactly both hunks' context
+        assert_eq!(diff(&old, &new, 2), merged(1, 10));
+        assert_eq!(diff(&old, &new, 3), merged(1, 11));
+        // context past either end of the file is cut off
+        assert_eq!(diff(&old, &new, 100), merged(1, 12));
+    }
+}
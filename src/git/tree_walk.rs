@@ -0,0 +1,568 @@
+use crate::git::{
+    any_git_object::Sha,
+    diff::{matching_lines, split_lines},
+    filter::Filters,
+    git_blob::Blob,
+    git_object_trait::GitObject,
+    git_tree::{FileMode, Tree, TreeEntry},
+    index::Index,
+    object_store::ObjectStore,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsStr,
+    fs,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+    },
+    path::Path,
+};
+
+// like git's GET_TREE_ENTRY_FOLLOW_SYMLINKS_MAX_LINKS, more links than this are taken for a loop
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+// every non-directory entry below the tree, keyed by its `/`-separated path
+pub fn flatten_tree(store: &ObjectStore, sha: &Sha) -> Result<BTreeMap<String, (FileMode, Sha)>> {
+    let mut entries = BTreeMap::new();
+    flatten_into(store, sha, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn flatten_into(
+    store: &ObjectStore,
+    sha: &Sha,
+    prefix: &str,
+    entries: &mut BTreeMap<String, (FileMode, Sha)>,
+) -> Result<()> {
+    let tree = store
+        .read(sha)
+        .with_context(|| format!("flatten_tree: failed to read tree {sha}"))?
+        .try_as_tree()
+        .ok_or_else(|| anyhow!("flatten_tree: expected {sha} to be a tree"))?;
+
+    for entry in tree.entries() {
+        let path = format!("{prefix}{}", entry.name);
+        match entry.mode {
+            FileMode::Directory => flatten_into(store, &entry.hash, &format!("{path}/"), entries)?,
+            _ => {
+                entries.insert(path, (entry.mode.clone(), entry.hash.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// inverse of flatten_tree: writes the nested trees for `entries` and returns the root's sha
+pub fn build_tree<P: AsRef<Path>>(
+    path: P,
+    entries: &BTreeMap<String, (FileMode, Sha)>,
+) -> Result<Sha> {
+    let mut files = vec![];
+    let mut directories: BTreeMap<&str, BTreeMap<String, (FileMode, Sha)>> = BTreeMap::new();
+
+    for (name, (mode, sha)) in entries {
+        match name.split_once('/') {
+            Some((directory, rest)) => {
+                directories
+                    .entry(directory)
+                    .or_default()
+                    .insert(rest.to_owned(), (mode.clone(), sha.clone()));
+            }
+            None => files.push(TreeEntry {
+                mode: mode.clone(),
+                name: name.clone(),
+                hash: sha.clone(),
+            }),
+        }
+    }
+
+    for (name, entries) in directories {
+        files.push(TreeEntry {
+            mode: FileMode::Directory,
+            name: name.to_owned(),
+            hash: build_tree(path.as_ref(), &entries)?,
+        });
+    }
+
+    let tree = Tree::new(files);
+    tree.write(path.as_ref())
+        .with_context(|| "build_tree: failed to write tree object")?;
+    tree.sha1()
+}
+
+// the entry `path` names below the tree `root`, like `git cat-file -p <tree>:<path>`. with
+// `follow_symlinks` a symlink on the way (or at the end) is replaced by its target, relative to
+// the directory holding it, the way git's --follow-symlinks resolves it
+pub fn find_path(
+    store: &ObjectStore,
+    root: &Sha,
+    path: &str,
+    follow_symlinks: bool,
+) -> Result<(FileMode, Sha)> {
+    // components still to look up, the next one last
+    let mut pending: Vec<String> = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .rev()
+        .map(str::to_owned)
+        .collect();
+    // the directories walked into so far, the one to look in last
+    let mut trees = vec![root.clone()];
+    let mut found = None;
+    let mut links = 0;
+
+    while let Some(component) = pending.pop() {
+        // only directories are walked into, `found` is anything else
+        if found.is_some() {
+            bail!("find_path: {path:?} goes through a file that is not a directory");
+        }
+        if follow_symlinks && component == "." {
+            continue;
+        }
+        if follow_symlinks && component == ".." {
+            if trees.len() == 1 {
+                bail!("find_path: {path:?} leads outside the tree");
+            }
+            trees.pop();
+            continue;
+        }
+
+        let tree = trees.last().expect("the root is never popped");
+        let entry = store
+            .read(tree)
+            .with_context(|| format!("find_path: failed to read tree {tree}"))?
+            .try_as_tree()
+            .ok_or_else(|| anyhow!("find_path: expected {tree} to be a tree"))?
+            .entries()
+            .iter()
+            .find(|entry| entry.name == component)
+            .cloned()
+            .ok_or_else(|| anyhow!("find_path: {path:?} does not exist in {root}"))?;
+
+        match entry.mode {
+            FileMode::Directory => trees.push(entry.hash),
+            FileMode::Symbolic if follow_symlinks => {
+                links += 1;
+                if links > MAX_SYMLINK_FOLLOWS {
+                    bail!("find_path: too many levels of symbolic links in {path:?}");
+                }
+                let target = store
+                    .read(&entry.hash)
+                    .with_context(|| format!("find_path: failed to read symlink {component}"))?
+                    .try_as_blob()
+                    .ok_or_else(|| anyhow!("find_path: expected {} to be a blob", entry.hash))?
+                    .content()
+                    .clone();
+                let target = String::from_utf8(target)
+                    .with_context(|| format!("find_path: symlink {component} is not UTF-8"))?;
+                if target.starts_with('/') {
+                    bail!("find_path: {path:?} leads outside the tree to {target:?}");
+                }
+                pending.extend(
+                    target
+                        .split('/')
+                        .filter(|component| !component.is_empty())
+                        .rev()
+                        .map(str::to_owned),
+                );
+            }
+            mode => found = Some((mode, entry.hash)),
+        }
+    }
+
+    Ok(found.unwrap_or_else(|| {
+        (
+            FileMode::Directory,
+            trees.pop().expect("the root is never popped"),
+        )
+    }))
+}
+
+// makes the working tree at `root` go from the `from` entries to the `to` entries, files that are
+// the same in both are left alone
+pub fn update_worktree<P: AsRef<Path>>(
+    root: P,
+    store: &ObjectStore,
+    from: &BTreeMap<String, (FileMode, Sha)>,
+    to: &BTreeMap<String, (FileMode, Sha)>,
+) -> Result<()> {
+    let root = root.as_ref();
+    let filters = Filters::new(root)?;
+
+    for name in from.keys().filter(|name| !to.contains_key(*name)) {
+        let path = root.join(name);
+        match path.symlink_metadata() {
+            // an uninitialized submodule, one with content is left alone
+            Ok(metadata) if metadata.is_dir() => {
+                let _ = fs::remove_dir(&path);
+            }
+            Ok(_) => fs::remove_file(&path)
+                .with_context(|| format!("update_worktree: failed to remove {path:?}"))?,
+            Err(_) => {}
+        }
+        // directories left empty go as well, git doesn't track them
+        let mut parent = path.parent();
+        while let Some(directory) = parent.filter(|directory| *directory != root) {
+            if fs::remove_dir(directory).is_err() {
+                break;
+            }
+            parent = directory.parent();
+        }
+    }
+
+    for (name, entry) in to {
+        if from.get(name).map(|(mode, sha)| (mode.as_ref(), sha))
+            == Some((entry.0.as_ref(), &entry.1))
+        {
+            continue;
+        }
+        checkout_entry(root, store, &filters, name, entry)?;
+    }
+    Ok(())
+}
+
+pub fn checkout_entry(
+    root: &Path,
+    store: &ObjectStore,
+    filters: &Filters,
+    name: &str,
+    (mode, sha): &(FileMode, Sha),
+) -> Result<()> {
+    let path = root.join(name);
+    // like git without the submodule initialized, a submodule is an empty directory
+    if let FileMode::Gitlink = mode {
+        return fs::create_dir_all(&path)
+            .with_context(|| format!("checkout_entry: failed to create {path:?}"));
+    }
+
+    let content = store
+        .read(sha)
+        .with_context(|| format!("checkout_entry: failed to read blob for {name}"))?
+        .try_as_blob()
+        .ok_or_else(|| anyhow!("checkout_entry: expected {sha} to be a blob for {name}"))?
+        .content()
+        .to_vec();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("checkout_entry: failed to create {parent:?}"))?;
+    }
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(&path)
+            .with_context(|| format!("checkout_entry: failed to replace {path:?}"))?;
+    }
+
+    match mode {
+        FileMode::Symbolic => std::os::unix::fs::symlink(OsStr::from_bytes(&content), &path)
+            .with_context(|| format!("checkout_entry: failed to create symlink {path:?}"))?,
+        _ => {
+            let content = filters
+                .smudge(name, content)
+                .with_context(|| format!("checkout_entry: failed to filter {name}"))?;
+            fs::write(&path, content)
+                .with_context(|| format!("checkout_entry: failed to write {path:?}"))?;
+            let permissions = match mode {
+                FileMode::Executable => 0o755,
+                _ => 0o644,
+            };
+            fs::set_permissions(&path, fs::Permissions::from_mode(permissions))
+                .with_context(|| format!("checkout_entry: failed to set mode of {path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum TreeChange {
+    Added {
+        name: String,
+        entry: (FileMode, Sha),
+    },
+    Deleted {
+        name: String,
+        entry: (FileMode, Sha),
+    },
+    Modified {
+        name: String,
+        old: (FileMode, Sha),
+        new: (FileMode, Sha),
+    },
+    Renamed {
+        from: String,
+        to: String,
+        old: (FileMode, Sha),
+        new: (FileMode, Sha),
+        // percentage of the old content found in the new one
+        similarity: u8,
+    },
+    // a new file made from one that's still there
+    Copied {
+        from: String,
+        to: String,
+        old: (FileMode, Sha),
+        new: (FileMode, Sha),
+        similarity: u8,
+    },
+}
+
+impl TreeChange {
+    // the path the change is listed under, a rename goes by its new name
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Added { name, .. } | Self::Deleted { name, .. } | Self::Modified { name, .. } => {
+                name
+            }
+            Self::Renamed { to, .. } | Self::Copied { to, .. } => to,
+        }
+    }
+}
+
+// the changes turning the `old` entries into the `new` ones, sorted by path. with a
+// `rename_threshold` (a percentage), deleted and added files at least that similar are paired up
+// into renames. with a `copy_threshold`, files still added after that are compared with every
+// file the old side keeps, changed or not, and reported as copies of the most similar one
+pub fn diff_entries(
+    store: &ObjectStore,
+    old: &BTreeMap<String, (FileMode, Sha)>,
+    new: &BTreeMap<String, (FileMode, Sha)>,
+    rename_threshold: Option<u8>,
+    copy_threshold: Option<u8>,
+) -> Result<Vec<TreeChange>> {
+    let mut changes = vec![];
+    let mut deleted = vec![];
+    let mut added = vec![];
+
+    for (name, old_entry) in old {
+        match new.get(name) {
+            Some(new_entry)
+                if new_entry.0.as_ref() == old_entry.0.as_ref() && new_entry.1 == old_entry.1 => {}
+            Some(new_entry) => changes.push(TreeChange::Modified {
+                name: name.clone(),
+                old: old_entry.clone(),
+                new: new_entry.clone(),
+            }),
+            None => deleted.push((name, old_entry)),
+        }
+    }
+    added.extend(new.iter().filter(|(name, _)| !old.contains_key(*name)));
+
+    if let Some(threshold) = rename_threshold {
+        let renames = find_renames(store, &deleted, &added, threshold)?;
+        for &(from, to, similarity) in &renames {
+            let ((from, old), (to, new)) = (deleted[from], added[to]);
+            changes.push(TreeChange::Renamed {
+                from: from.clone(),
+                to: to.clone(),
+                old: old.clone(),
+                new: new.clone(),
+                similarity,
+            });
+        }
+        let renamed_from = renames
+            .iter()
+            .map(|rename| rename.0)
+            .collect::<HashSet<_>>();
+        let renamed_to = renames
+            .iter()
+            .map(|rename| rename.1)
+            .collect::<HashSet<_>>();
+        deleted = (0..deleted.len())
+            .filter(|index| !renamed_from.contains(index))
+            .map(|index| deleted[index])
+            .collect();
+        added = (0..added.len())
+            .filter(|index| !renamed_to.contains(index))
+            .map(|index| added[index])
+            .collect();
+    }
+
+    if let Some(threshold) = copy_threshold {
+        let kept = old
+            .iter()
+            .filter(|(name, _)| new.contains_key(*name))
+            .collect::<Vec<_>>();
+        let copies = find_copies(store, &kept, &added, threshold)?;
+        for &(from, to, similarity) in &copies {
+            let ((from, old), (to, new)) = (kept[from], added[to]);
+            changes.push(TreeChange::Copied {
+                from: from.clone(),
+                to: to.clone(),
+                old: old.clone(),
+                new: new.clone(),
+                similarity,
+            });
+        }
+        let copied_to = copies.iter().map(|copy| copy.1).collect::<HashSet<_>>();
+        added = (0..added.len())
+            .filter(|index| !copied_to.contains(index))
+            .map(|index| added[index])
+            .collect();
+    }
+
+    changes.extend(
+        deleted
+            .into_iter()
+            .map(|(name, entry)| TreeChange::Deleted {
+                name: name.clone(),
+                entry: entry.clone(),
+            }),
+    );
+    changes.extend(added.into_iter().map(|(name, entry)| TreeChange::Added {
+        name: name.clone(),
+        entry: entry.clone(),
+    }));
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(changes)
+}
+
+// `(deleted index, added index, similarity)` for each rename. identical content pairs up first,
+// then the most similar pairs win, every file taking part in at most one rename
+fn find_renames(
+    store: &ObjectStore,
+    deleted: &[(&String, &(FileMode, Sha))],
+    added: &[(&String, &(FileMode, Sha))],
+    threshold: u8,
+) -> Result<Vec<(usize, usize, u8)>> {
+    let mut renames: Vec<(usize, usize, u8)> = vec![];
+    for (similarity, i, j) in similar_pairs(store, deleted, added, threshold)? {
+        if renames.iter().all(|&(from, to, _)| from != i && to != j) {
+            renames.push((i, j, similarity));
+        }
+    }
+    Ok(renames)
+}
+
+// `(source index, added index, similarity)` for each copy. like renames, but a source can be
+// copied any number of times, every added file just takes its best match
+fn find_copies(
+    store: &ObjectStore,
+    sources: &[(&String, &(FileMode, Sha))],
+    added: &[(&String, &(FileMode, Sha))],
+    threshold: u8,
+) -> Result<Vec<(usize, usize, u8)>> {
+    let mut copies: Vec<(usize, usize, u8)> = vec![];
+    for (similarity, i, j) in similar_pairs(store, sources, added, threshold)? {
+        if copies.iter().all(|&(_, to, _)| to != j) {
+            copies.push((i, j, similarity));
+        }
+    }
+    Ok(copies)
+}
+
+// `(similarity, old index, new index)` for every pair at least `threshold` similar, the most
+// similar first and identical content ahead of everything else
+fn similar_pairs(
+    store: &ObjectStore,
+    old: &[(&String, &(FileMode, Sha))],
+    new: &[(&String, &(FileMode, Sha))],
+    threshold: u8,
+) -> Result<Vec<(u8, usize, usize)>> {
+    let read = |sha: &Sha| -> Result<Vec<u8>> {
+        Ok(store
+            .read(sha)
+            .with_context(|| format!("similar_pairs: failed to read blob {sha}"))?
+            .try_as_blob()
+            .ok_or_else(|| anyhow!("similar_pairs: expected {sha} to be a blob"))?
+            .content()
+            .to_vec())
+    };
+    let old_content = old
+        .iter()
+        .map(|(_, (_, sha))| read(sha))
+        .collect::<Result<Vec<_>>>()?;
+    let new_content = new
+        .iter()
+        .map(|(_, (_, sha))| read(sha))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut candidates = vec![];
+    for (i, (_, (_, old_sha))) in old.iter().enumerate() {
+        for (j, (_, (_, new_sha))) in new.iter().enumerate() {
+            let similarity = if old_sha == new_sha {
+                100
+            } else {
+                similarity(&old_content[i], &new_content[j])
+            };
+            if similarity >= threshold {
+                candidates.push((old_sha == new_sha, similarity, i, j));
+            }
+        }
+    }
+    candidates.sort_by_key(|&(exact, similarity, _, _)| std::cmp::Reverse((exact, similarity)));
+
+    Ok(candidates
+        .into_iter()
+        .map(|(_, similarity, i, j)| (similarity, i, j))
+        .collect())
+}
+
+// share of the bigger side made up of lines both have in common, as a percentage. an edited
+// file stays similar to its old self, while one that mostly grew or shrank doesn't
+fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    let size = old.len().max(new.len());
+    if size == 0 {
+        return 100;
+    }
+
+    let (old_lines, new_lines) = (split_lines(old), split_lines(new));
+    let common = matching_lines(&old_lines, &new_lines)
+        .into_iter()
+        .map(|(i, _)| old_lines[i].len())
+        .sum::<usize>();
+    // rounded down, so a rename is never reported as more similar than it is
+    (common * 100 / size) as u8
+}
+
+// paths among `names` whose working tree content differs from `entries`, a path missing from
+// `entries` is expected to be absent from the working tree as well. files the index has unchanged
+// stat data for aren't read again
+pub fn dirty_paths<'a, P: AsRef<Path>, I: IntoIterator<Item = &'a String>>(
+    root: P,
+    index: &Index,
+    entries: &BTreeMap<String, (FileMode, Sha)>,
+    names: I,
+) -> Result<Vec<String>> {
+    let filters = Filters::new(root.as_ref())?;
+    let index_entries = index.entries_at_stage(0);
+    let mut dirty = vec![];
+    for name in names {
+        let path = root.as_ref().join(name);
+        let metadata = path.symlink_metadata();
+
+        if let (Ok(metadata), Some((mode, sha))) = (&metadata, entries.get(name)) {
+            let clean = index_entries.get(name.as_str()).is_some_and(|entry| {
+                entry.sha == *sha
+                    && entry.mode == mode.mode()
+                    && index.is_stat_clean(entry, metadata)
+            });
+            if clean {
+                continue;
+            }
+        }
+
+        let current = match metadata {
+            Ok(metadata) if metadata.is_symlink() => Some(
+                fs::read_link(&path)
+                    .with_context(|| format!("dirty_paths: failed to read link {path:?}"))?
+                    .into_os_string()
+                    .into_vec(),
+            ),
+            Ok(metadata) if metadata.is_file() => Some(filters.clean(
+                name,
+                fs::read(&path).with_context(|| format!("dirty_paths: failed to read {path:?}"))?,
+            )?),
+            _ => None,
+        };
+
+        let expected = entries.get(name).map(|(_, sha)| sha);
+        let current = current
+            .map(|content| Blob::new(content).sha1())
+            .transpose()?;
+        if current.as_ref() != expected {
+            dirty.push(name.clone());
+        }
+    }
+    Ok(dirty)
+}
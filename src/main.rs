@@ -1,79 +1,481 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::Compression;
 use git::{
-    any_git_object::AnyGitObject,
-    commits::{Commit, CommitActor},
+    any_git_object::{AnyGitObject, Sha},
+    archive::{write_archive, ArchiveFormat},
+    attributes::{AttributeMatcher, AttributeValue},
+    blame::blame,
+    clean::Cleaner,
+    commit_graph::write_commit_graph,
+    commits::{parse_date, Commit, CommitActor},
+    compression::{compress, loose_compression},
+    config::Config,
+    diff_driver::DiffDrivers,
     file_tree::FileTree,
-    git_client::GitClient,
-    git_object_trait::GitObject,
+    filter::Filters,
+    git_blob::Blob,
+    git_client::{Cancelled, GitClient},
+    git_object_trait::{hash_object_stream, write_encoded_object, GitObject, GitObjectType},
+    git_tree::{FileMode, Tree, TreeEntry, EMPTY_TREE_SHA},
+    ignore::{wildmatch, IgnoreMatcher},
+    index::{Index, IndexEntry},
+    merge::merge_trees,
+    name_rev::name_commits,
+    object_store::ObjectStore,
+    pack_index::index_pack,
+    packfile::{max_delta_depth, Packfile},
+    progress::{NoProgress, StderrProgress},
+    reachability::{
+        is_ancestor, merge_bases, peel_tag, peel_tree, reachable_commit_nodes, reachable_commits,
+        reachable_objects, refs_pointing_at, rev_parse, topo_order,
+    },
+    refs::{check_ref_format, RefStore},
+    refspec::Refspec,
+    textdiff::{write_no_index_diff, write_patch, PatchOptions, DEFAULT_CONTEXT_LINES},
+    tree_walk::{
+        build_tree, checkout_entry, diff_entries, dirty_paths, find_path, flatten_tree,
+        update_worktree, TreeChange,
+    },
 };
+use regex::{Regex, RegexBuilder};
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     env, fs,
-    io::{stdout, Write},
+    io::{self, stdin, stdout, Read, Write},
+    os::unix::ffi::OsStringExt,
     path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use utils::{
+    color::{Color, Colors},
+    helpers::{get_object_file_path, git_dir, parse_with_context},
+    pager::Pager,
 };
-use tokio;
 
 mod git;
 mod utils;
 
+// commands whose output can get long enough to be paged
+const PAGED_COMMANDS: &[&str] = &["diff", "log"];
+// what `init` names the first branch when neither -b nor the config say otherwise
+const DEFAULT_BRANCH: &str = "main";
+// how similar a deleted and an added file must be, in percent, to count as a rename when no
+// threshold is given
+const DEFAULT_RENAME_THRESHOLD: u8 = 50;
+// short object ids have at least this many hex digits, more when that's still ambiguous
+const DEFAULT_ABBREV: usize = 7;
+// --abbrev values below this are raised to it, like in git
+const MIN_ABBREV: usize = 4;
+const DEFAULT_REF_FORMAT: &str = "%(objectname) %(objecttype)\t%(refname)";
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // global options go before the command. like git, the repository paths are passed on
+    // through GIT_DIR and GIT_WORK_TREE, so they reach everything that honors the variables
+    let mut no_pager = false;
+    while let Some(option) = args.get(1).filter(|arg| arg.starts_with('-')).cloned() {
+        args.remove(1);
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (option.as_str(), None),
+        };
+        match name {
+            "--no-pager" | "-P" => no_pager = true,
+            "--git-dir" | "--work-tree" => {
+                let value = match value {
+                    Some(value) => value,
+                    None if args.len() > 1 => args.remove(1),
+                    None => bail!("no directory given for {name}"),
+                };
+                let variable = match name {
+                    "--git-dir" => "GIT_DIR",
+                    _ => "GIT_WORK_TREE",
+                };
+                env::set_var(variable, value);
+            }
+            _ => bail!("unknown option: {option}"),
+        }
+    }
+    enter_work_tree()?;
 
-    let mut stdout = stdout();
+    let paged = args
+        .get(1)
+        .is_some_and(|command| PAGED_COMMANDS.contains(&command.as_str()));
+    let pager = if paged && !no_pager {
+        Pager::start()?
+    } else {
+        None
+    };
+    let result = match pager {
+        Some(mut pager) => {
+            let result = run(&args, pager.stdin()).await;
+            pager.finish()?;
+            result
+        }
+        None => run(&args, &mut stdout()).await,
+    };
+    // the reader going away early, like `head` or the user quitting the pager, isn't an error
+    match result {
+        Err(err) if is_broken_pipe(&err) => Ok(()),
+        result => result,
+    }
+}
+
+// makes the work tree the current directory, which every command treats as the repository root.
+// a relative GIT_DIR or GIT_COMMON_DIR is resolved first, since it's relative to where the
+// command was started
+fn enter_work_tree() -> Result<()> {
+    for variable in ["GIT_DIR", "GIT_COMMON_DIR"] {
+        if let Some(dir) = env::var_os(variable).filter(|dir| !dir.is_empty()) {
+            let dir = std::path::absolute(&dir)
+                .with_context(|| format!("failed to resolve {variable} {dir:?}"))?;
+            env::set_var(variable, dir);
+        }
+    }
+    if let Some(work_tree) = env::var_os("GIT_WORK_TREE").filter(|work_tree| !work_tree.is_empty())
+    {
+        env::set_current_dir(&work_tree)
+            .with_context(|| format!("failed to enter work tree {work_tree:?}"))?;
+    }
+    Ok(())
+}
 
-    match args[1].as_str() {
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+async fn run(args: &[String], mut stdout: impl Write) -> Result<()> {
+    let Some(command) = args.get(1) else {
+        bail!("usage: codecrafters-git <command> [<args>]");
+    };
+
+    match command.as_str() {
         "init" => {
-            fs::create_dir(".git")?;
-            fs::create_dir(".git/objects")?;
-            fs::create_dir(".git/refs")?;
-            fs::write(".git/HEAD", "ref: refs/heads/main\n")?;
-            println!("Initialized git directory")
+            let mut branch = None;
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "-b" | "--initial-branch" => {
+                        branch = Some(
+                            options
+                                .next()
+                                .ok_or_else(|| anyhow!("init: {arg} requires a branch name"))?
+                                .clone(),
+                        )
+                    }
+                    _ => match arg.strip_prefix("--initial-branch=") {
+                        Some(name) => branch = Some(name.to_owned()),
+                        None => bail!("usage: init [-b <branch-name>]"),
+                    },
+                }
+            }
+            // the flag wins over GIT_DEFAULT_BRANCH, which wins over init.defaultBranch
+            let branch = match branch.or_else(|| {
+                env::var("GIT_DEFAULT_BRANCH")
+                    .ok()
+                    .filter(|name| !name.is_empty())
+            }) {
+                Some(branch) => branch,
+                None => Config::global()?
+                    .get("init.defaultBranch")
+                    .unwrap_or(DEFAULT_BRANCH)
+                    .to_owned(),
+            };
+            // like `check-ref-format --branch`, a leading dash would read as an option
+            let head = format!("refs/heads/{branch}");
+            if branch.starts_with('-') {
+                bail!("init: invalid branch name {branch:?}");
+            }
+            check_ref_format(&head)
+                .with_context(|| format!("init: invalid branch name {branch:?}"))?;
+
+            let git_dir = git_dir(".");
+            fs::create_dir(&git_dir)?;
+            fs::create_dir(git_dir.join("objects"))?;
+            fs::create_dir(git_dir.join("refs"))?;
+            fs::write(git_dir.join("HEAD"), format!("ref: {head}\n"))?;
+            writeln!(stdout, "Initialized git directory")?;
         }
         "cat-file" => {
-            assert_eq!(args[2], "-p");
-            let blob_sha = &args[3];
+            let allow_unknown_type = args[2..].iter().any(|arg| arg == "--allow-unknown-type");
+            let follow_symlinks = args[2..].iter().any(|arg| arg == "--follow-symlinks");
+            let buffer = args[2..].iter().any(|arg| arg == "--buffer");
+            let options = args[2..]
+                .iter()
+                .filter(|arg| {
+                    !["--allow-unknown-type", "--follow-symlinks", "--buffer"]
+                        .contains(&arg.as_str())
+                })
+                .collect::<Vec<_>>();
+            let store = ObjectStore::new(".");
 
-            let blob = AnyGitObject::read(blob_sha, ".")
-                .with_context(|| format!("failed to read object file content for {blob_sha}"))?
-                .try_as_blob()
-                .ok_or_else(|| {
-                    anyhow!(
-                        "failed to parse object file content for {blob_sha}: expected it to be a blob"
-                    )
-                })?;
+            // names read from stdin, one per line, each answered with `<sha> <type> <size>` and
+            // for --batch the object's content
+            let batch_mode = match options[..] {
+                [mode] if mode == "--batch" || mode == "--batch-check" => Some(mode),
+                _ => None,
+            };
+            if let Some(mode) = batch_mode {
+                let refs = RefStore::new(".");
+                let mut out = io::BufWriter::new(&mut stdout);
+                for line in stdin().lines() {
+                    let name = line.with_context(|| "cat-file: failed to read stdin")?;
+                    let name = name.trim();
+                    let Some(sha) = resolve_batch_object(&store, &refs, name, follow_symlinks)?
+                    else {
+                        writeln!(out, "{name} missing")?;
+                        continue;
+                    };
+                    let object = store
+                        .read(&sha)
+                        .with_context(|| format!("cat-file: failed to read {sha}"))?;
+                    let body = object.encode_body()?;
+                    writeln!(out, "{sha} {} {}", object.get_type().as_ref(), body.len())?;
+                    if mode == "--batch" {
+                        out.write_all(&body)?;
+                        writeln!(out)?;
+                    }
+                    // without --buffer each answer goes out right away, for callers that wait on
+                    // it before asking for the next object
+                    if !buffer {
+                        out.flush()?;
+                    }
+                }
+                out.flush()?;
+                return Ok(());
+            }
 
-            stdout.write_all(blob.content()).with_context(|| {
-                format!("failed to write object file content to stdout for {blob_sha}")
-            })?;
+            let [mode, object_sha] = options[..] else {
+                bail!("usage: cat-file (-p | -t [--allow-unknown-type] | --size-on-disk) [--follow-symlinks] <object>\n   or: cat-file (--batch | --batch-check) [--buffer] [--follow-symlinks]");
+            };
+
+            // `<tree-ish>:<path>` names an entry of a tree, anything else an object id
+            let object_sha = match object_sha.split_once(':') {
+                Some((rev, path)) => {
+                    let refs = RefStore::new(".");
+                    let tree = peel_tree(&store, &rev_parse(&store, &refs, rev)?)?;
+                    let (_, sha) = find_path(&store, &tree, path, follow_symlinks)
+                        .with_context(|| format!("cat-file: failed to resolve {object_sha}"))?;
+                    sha.to_string()
+                }
+                None => object_sha.to_owned(),
+            };
+
+            match mode.as_str() {
+                "-t" => {
+                    let object_type = store
+                        .read_type(&object_sha.parse()?)
+                        .with_context(|| format!("failed to read object type for {object_sha}"))?;
+                    if !allow_unknown_type && object_type.parse::<GitObjectType>().is_err() {
+                        bail!("invalid object type {object_type:?} for {object_sha}");
+                    }
+                    writeln!(stdout, "{object_type}")?;
+                }
+                "--size-on-disk" => {
+                    let sha = object_sha.parse()?;
+                    let size = store
+                        .read_size(&sha)
+                        .with_context(|| format!("failed to read object size for {object_sha}"))?;
+                    let disk_size = store
+                        .disk_size(&sha)
+                        .with_context(|| format!("failed to read size on disk for {object_sha}"))?;
+                    writeln!(stdout, "{size} {disk_size}")?;
+                }
+                "-p" => {
+                    let object = store.read(&object_sha.parse()?).with_context(|| {
+                        format!("failed to read object file content for {object_sha}")
+                    })?;
+
+                    match object {
+                        // entry types come from the modes, so the entries' objects needn't be
+                        // present
+                        AnyGitObject::Tree(tree) => {
+                            for entry in tree.entries() {
+                                writeln!(
+                                    stdout,
+                                    "{:06o} {} {}\t{}",
+                                    entry.mode.mode(),
+                                    entry.mode.object_type().as_ref(),
+                                    entry.hash,
+                                    entry.name
+                                )?;
+                            }
+                        }
+                        object => stdout.write_all(&object.encode_body()?).with_context(|| {
+                            format!(
+                                "failed to write object file content to stdout for {object_sha}"
+                            )
+                        })?,
+                    }
+                }
+                mode => bail!("cat-file: unsupported mode {mode}"),
+            }
         }
         "hash-object" => {
-            assert_eq!(args[2], "-w");
-            let path = &args[3];
+            let mut write = false;
+            let mut dry_run = false;
+            let mut verbose = false;
+            let mut literally = false;
+            let mut object_type = "blob";
+            let mut path = None;
+            let mut from_stdin = false;
+            // the path whose filters apply, which needn't be where the content comes from
+            let mut filter_path = None;
+            let mut no_filters = false;
 
-            let blob = AnyGitObject::generate(path)
-                .with_context(|| format!("failed to generate object file from {path}"))?
-                .try_as_blob()
-                .ok_or_else(|| {
-                    anyhow!("failed to generate object file from {path}: expected it to be a blob")
-                })?;
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "-w" => write = true,
+                    "--dry-run" => dry_run = true,
+                    "-v" | "--verbose" => verbose = true,
+                    "--literally" => literally = true,
+                    "--stdin" => from_stdin = true,
+                    "--no-filters" => no_filters = true,
+                    "-t" => {
+                        object_type = options
+                            .next()
+                            .ok_or_else(|| anyhow!("hash-object: -t requires a type"))?
+                    }
+                    _ => match arg.strip_prefix("--path=") {
+                        Some(value) => filter_path = Some(value),
+                        None => path = Some(arg.as_str()),
+                    },
+                }
+            }
 
-            blob.write(".")
-                .with_context(|| format!("failed to write object file for blob from {path}"))?;
+            let usage = "usage: hash-object [-w | --dry-run] [-v] [-t <type>] [--literally] [--path=<path> | --no-filters] (--stdin | <file>)";
+            let path = match (path, from_stdin) {
+                (Some(path), false) => path,
+                (None, true) => "<stdin>",
+                _ => bail!(usage),
+            };
+            if write && dry_run {
+                bail!("hash-object: -w and --dry-run are mutually exclusive");
+            }
+            if no_filters && filter_path.is_some() {
+                bail!("hash-object: --path and --no-filters are mutually exclusive");
+            }
+            let read_content = || -> Result<Vec<u8>> {
+                if from_stdin {
+                    let mut content = vec![];
+                    stdin()
+                        .read_to_end(&mut content)
+                        .with_context(|| "hash-object: failed to read stdin")?;
+                    return Ok(content);
+                }
+                fs::read(path).with_context(|| format!("failed to read file at {path}"))
+            };
 
-            let sha = hex::encode(
-                blob.sha1()
-                    .with_context(|| "failed to generate blob hash")?,
-            );
+            // the blob holds what the clean filter of the file, or of --path, makes of it. content
+            // from stdin has no path of its own to pick filters by
+            let filter_name = match (filter_path, from_stdin) {
+                (Some(filter_path), _) => Some(filter_path),
+                (None, false) => Some(path),
+                (None, true) => None,
+            }
+            .filter(|_| !no_filters && !literally && object_type == "blob")
+            .map(|filter_path| filter_path.strip_prefix("./").unwrap_or(filter_path));
+            let filters = match filter_name {
+                Some(_) => Some(Filters::new(".")?),
+                None => None,
+            };
+            let filtered = match (&filters, filter_name) {
+                (Some(filters), Some(name)) => filters.applies_to(name)?,
+                _ => false,
+            };
+
+            let sha = if !write && !from_stdin && !filtered && (literally || object_type == "blob")
+            {
+                // a file that's neither written nor filtered is hashed as it's read, instead of
+                // being held in memory whole
+                let file = fs::File::open(path)
+                    .with_context(|| format!("failed to open file at {path}"))?;
+                let metadata = file
+                    .metadata()
+                    .with_context(|| format!("failed to read metadata of {path}"))?;
+                if !metadata.is_file() {
+                    bail!("hash-object: {path} is not a file");
+                }
+                hash_object_stream(object_type, metadata.len(), file)
+                    .with_context(|| format!("failed to generate object hash for {path}"))?
+            } else if literally {
+                // the declared type and content are taken as-is, without any structural checks
+                let content = read_content()?;
+                let sha = hash_object_stream(object_type, content.len() as u64, content.as_slice())
+                    .with_context(|| format!("failed to generate object hash for {path}"))?;
+
+                if write {
+                    let mut uncompressed =
+                        format!("{object_type} {}\0", content.len()).into_bytes();
+                    uncompressed.extend(content);
+                    let level = loose_compression(&Config::load(".")?)?;
+                    write_encoded_object(compress(uncompressed, level)?, &sha, ".")
+                        .with_context(|| format!("failed to write object file from {path}"))?;
+                }
+                sha
+            } else {
+                let filters = filters.as_ref().zip(filter_name);
+                let object = if object_type == "blob" {
+                    if from_stdin {
+                        let content = match filters {
+                            Some((filters, name)) => filters
+                                .clean(name, read_content()?)
+                                .with_context(|| format!("failed to filter {path} as {name}"))?,
+                            None => read_content()?,
+                        };
+                        AnyGitObject::Blob(Blob::new(content))
+                    } else {
+                        let object = AnyGitObject::generate(path, filters).with_context(|| {
+                            format!("failed to generate object file from {path}")
+                        })?;
+                        if !matches!(object, AnyGitObject::Blob(_)) {
+                            bail!("failed to generate object file from {path}: expected it to be a blob");
+                        }
+                        object
+                    }
+                } else {
+                    let content = read_content()?;
+                    AnyGitObject::from_body(parse_with_context(object_type)?, content)
+                        .with_context(|| format!("{path} is not a valid {object_type} object"))?
+                };
+
+                if write {
+                    object
+                        .write(&".")
+                        .with_context(|| format!("failed to write object file from {path}"))?;
+                }
+                object
+                    .sha1()
+                    .with_context(|| "failed to generate object hash")?
+            };
 
-            println!("{sha}");
+            writeln!(stdout, "{sha}")?;
+            // where the object is or would be written, which follows --git-dir
+            if verbose {
+                writeln!(
+                    stdout,
+                    "{}",
+                    get_object_file_path(&sha.to_string(), ".").display()
+                )?;
+            }
         }
         "ls-tree" => {
             assert_eq!(args[2], "--name-only");
 
             let tree_sha = &args[3];
 
-            let tree = AnyGitObject::read(&tree_sha, ".")
+            let tree = ObjectStore::new(".")
+                .read(&tree_sha.parse()?)
                 .with_context(|| format!("failed to parse object file content for {tree_sha}"))?
                 .try_as_tree()
                 .ok_or_else(|| {
@@ -83,35 +485,248 @@ async fn main() -> Result<()> {
                 })?;
 
             for entry in tree.entries() {
-                println!("{}", entry.name);
+                writeln!(stdout, "{}", entry.name)?;
             }
         }
+        "archive" => {
+            let mut format = None;
+            let mut output = None;
+            let mut level = Compression::default();
+            let mut prefix = "";
+            let mut revs = vec![];
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                if let Some(value) = arg.strip_prefix("--format=") {
+                    format = Some(
+                        value
+                            .parse::<ArchiveFormat>()
+                            .map_err(|_| anyhow!("archive: unknown format {value:?}"))?,
+                    );
+                } else if let Some(path) = arg.strip_prefix("--output=") {
+                    output = Some(path);
+                } else if let Some(value) = arg.strip_prefix("--prefix=") {
+                    prefix = value;
+                } else if arg == "-o" {
+                    output = Some(
+                        options
+                            .next()
+                            .ok_or_else(|| anyhow!("archive: -o requires a file"))?,
+                    );
+                } else if let Some(digit) = arg
+                    .strip_prefix('-')
+                    .filter(|digit| digit.len() == 1)
+                    .and_then(|digit| digit.parse::<u32>().ok())
+                {
+                    level = Compression::new(digit);
+                } else {
+                    revs.push(arg);
+                }
+            }
+            let [rev] = revs[..] else {
+                bail!("usage: archive [--format=(tar | tar.gz | tgz | zip)] [--prefix=<prefix>/] [-o <file>] [-<0-9>] <tree-ish>");
+            };
+            // without --format the output file's extension picks it, tar being the default
+            let format = format
+                .or_else(|| output.and_then(|path| ArchiveFormat::from_path(Path::new(path))))
+                .unwrap_or(ArchiveFormat::Tar);
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+
+            // a commit dates the entries and goes in the archive's header, a bare tree is
+            // archived as of now
+            let sha = peel_tag(&store, &rev_parse(&store, &refs, rev)?)?;
+            let (tree, commit, mtime) = match store
+                .read(&sha)
+                .with_context(|| format!("archive: failed to read {rev}"))?
+            {
+                AnyGitObject::Commit(commit) => (
+                    commit.tree_hash.clone(),
+                    Some(sha),
+                    commit.committer().epoch,
+                ),
+                AnyGitObject::Tree(_) => (sha, None, CommitActor::current().epoch),
+                _ => bail!("archive: {rev} is not a tree-ish"),
+            };
+
+            match output {
+                Some(path) => {
+                    let file = fs::File::create(path)
+                        .with_context(|| format!("archive: failed to create {path}"))?;
+                    let out = io::BufWriter::new(file);
+                    write_archive(
+                        out,
+                        &store,
+                        &tree,
+                        prefix,
+                        commit.as_ref(),
+                        mtime,
+                        format,
+                        level,
+                    )?;
+                }
+                None => write_archive(
+                    &mut stdout,
+                    &store,
+                    &tree,
+                    prefix,
+                    commit.as_ref(),
+                    mtime,
+                    format,
+                    level,
+                )?,
+            }
+        }
+        "mktree" => {
+            // like git, entries must point at existing objects unless --missing is given
+            let allow_missing = args[2..].iter().any(|arg| arg == "--missing");
+            let store = ObjectStore::new(".");
+
+            let mut entries = vec![];
+            let mut names = HashSet::new();
+            for line in stdin().lines() {
+                let line = line.with_context(|| "mktree: failed to read stdin")?;
+                if line.is_empty() {
+                    continue;
+                }
+                let (info, name) = line
+                    .split_once('\t')
+                    .ok_or_else(|| anyhow!("mktree: input format error: {line}"))?;
+                let [mode, object_type, sha] = info.split(' ').collect::<Vec<_>>()[..] else {
+                    bail!("mktree: input format error: {line}");
+                };
+
+                // ls-tree prints directories as 040000, the tree encoding drops the leading zero
+                let mode = u32::from_str_radix(mode, 8)
+                    .ok()
+                    .and_then(|mode| FileMode::from_mode(mode).ok())
+                    .ok_or_else(|| anyhow!("mktree: unsupported mode {mode} for {name}"))?;
+                let expected_type = mode.object_type();
+                let expected_type = expected_type.as_ref();
+                if object_type != expected_type {
+                    bail!(
+                        "mktree: entry {name} has type {object_type} but mode {} expects {expected_type}",
+                        mode.as_ref()
+                    );
+                }
+                if name.contains('/') {
+                    bail!("mktree: path {name} contains a slash");
+                }
+                if !names.insert(name.to_owned()) {
+                    bail!("mktree: duplicate entry {name}");
+                }
+
+                let sha: Sha = sha
+                    .parse()
+                    .with_context(|| format!("mktree: invalid sha for {name}"))?;
+                // a submodule's commit lives in the other repository
+                if !allow_missing && !matches!(mode, FileMode::Gitlink) {
+                    if !store.contains(&sha)? {
+                        bail!("mktree: entry {name} object {sha} is unavailable");
+                    }
+                    let actual_type = store.read_type(&sha)?;
+                    if actual_type != object_type {
+                        bail!("mktree: entry {name} object {sha} is a {actual_type}, not a {object_type}");
+                    }
+                }
+
+                entries.push(TreeEntry {
+                    mode,
+                    name: name.to_owned(),
+                    hash: sha,
+                });
+            }
+
+            let tree = Tree::new(entries);
+            tree.write(".")
+                .with_context(|| "mktree: failed to write tree object")?;
+            writeln!(stdout, "{}", tree.sha1()?)?;
+        }
         "write-tree" => {
-            let file_tree = FileTree::new(
-                env::current_dir().with_context(|| "failed to get current directory")?,
-            )
-            .with_context(|| "failed to create file tree")?;
+            let mut root = env::current_dir().with_context(|| "failed to get current directory")?;
+
+            // only the subtree at the prefix is written, its sha is the one a full write-tree
+            // records for that directory
+            let prefix = args[2..]
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--prefix="))
+                .unwrap_or("")
+                .trim_end_matches('/');
+            if !prefix.is_empty() {
+                // the prefix names a directory inside the work tree, not any path on disk
+                verify_index_path(prefix)
+                    .with_context(|| format!("write-tree: invalid prefix {prefix:?}"))?;
+                root.push(prefix);
+                if !root.is_dir() {
+                    bail!("prefix {prefix:?} is not a directory in the working tree");
+                }
+            }
 
-            #[cfg(debug_assertions)]
-            eprintln!("{:#?}", file_tree);
+            let file_tree = FileTree::new(&root)
+                .with_context(|| "failed to create file tree")?
+                .with_prefix(prefix);
+            // files are stored as their clean filters make them, like `git add` stores them
+            let filters = Filters::new(".")?;
 
-            let tree_object = file_tree.write(".")?;
+            // progress and the write stats go to stderr, stdout is only the tree. a dry run
+            // hashes the same objects but writes none of them
+            let show_progress = args[2..].iter().any(|arg| arg == "--progress");
+            let (tree_object, stats) = if args[2..].iter().any(|arg| arg == "--dry-run") {
+                (file_tree.tree_object(Some(&filters))?, None)
+            } else {
+                let (tree_object, stats) = match show_progress {
+                    true => file_tree.write(
+                        ".",
+                        Some(&filters),
+                        &mut StderrProgress::new("Writing objects"),
+                    ),
+                    false => file_tree.write(".", Some(&filters), &mut NoProgress),
+                }
+                .with_context(|| "write-tree: failed to write tree")?;
+                (tree_object, Some(stats))
+            };
             let sha = hex::encode(
                 tree_object
                     .sha1()
                     .with_context(|| "failed to generate tree hash")?,
             );
 
-            println!("{sha}");
+            writeln!(stdout, "{sha}")?;
+            if let Some(stats) = stats.filter(|_| show_progress) {
+                eprintln!(
+                    "{} objects written, {} reused, {} bytes",
+                    stats.written, stats.reused, stats.bytes
+                );
+            }
         }
         "commit-tree" => {
-            let tree_hash_str = &args[2];
-            assert_eq!(args[3], "-p");
-            let parent_hash_str = &args[4];
-            assert_eq!(args[5], "-m");
-            let message = args[6..].join(" ");
-            #[cfg(debug_assertions)]
-            eprintln!("commit-tree {tree_hash_str} -p {parent_hash_str} -m {message}");
+            let mut tree_hash_str = None;
+            let mut parent_hash_strs = vec![];
+            let mut message = None;
+            let mut allow_duplicate_parents = false;
+            // the commit is hashed and printed but not written
+            let mut dry_run = false;
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "-p" => parent_hash_strs.push(
+                        rest.next()
+                            .ok_or_else(|| anyhow!("commit-tree: -p requires a parent"))?,
+                    ),
+                    "-m" => {
+                        message = Some(rest.by_ref().cloned().collect::<Vec<_>>().join(" "));
+                    }
+                    "--allow-duplicate-parents" => allow_duplicate_parents = true,
+                    "--dry-run" => dry_run = true,
+                    _ if tree_hash_str.is_none() && !arg.starts_with('-') => {
+                        tree_hash_str = Some(arg)
+                    }
+                    _ => bail!("commit-tree: unexpected argument {arg:?}"),
+                }
+            }
+            let tree_hash_str =
+                tree_hash_str.ok_or_else(|| anyhow!("commit-tree: a tree is required"))?;
+            let message = message.ok_or_else(|| anyhow!("commit-tree: -m is required"))?;
 
             let tree_hash = hex::decode(tree_hash_str)
                 .with_context(|| "failed to decode tree sha")?
@@ -123,15 +738,20 @@ async fn main() -> Result<()> {
                     )
                 })?;
 
-            let parent_hash = hex::decode(parent_hash_str)
-                .with_context(|| "failed to decode tree sha")?
-                .try_into()
-                .map_err(|vec: Vec<_>| {
-                    anyhow!(
-                        "failed to convert tree sha: expected 20 bytes, got {}",
-                        vec.len()
-                    )
-                })?;
+            let parent_hashes = parent_hash_strs
+                .into_iter()
+                .map(|parent_hash_str| {
+                    hex::decode(parent_hash_str)
+                        .with_context(|| "failed to decode parent sha")?
+                        .try_into()
+                        .map_err(|vec: Vec<_>| {
+                            anyhow!(
+                                "failed to convert parent sha: expected 20 bytes, got {}",
+                                vec.len()
+                            )
+                        })
+                })
+                .collect::<Result<Vec<[u8; 20]>>>()?;
 
             let mock_actor = CommitActor {
                 name: "John Doe".to_string(),
@@ -140,37 +760,2670 @@ async fn main() -> Result<()> {
                 timezone: "+0000".to_string(),
             };
 
-            let commit = Commit::new(
-                tree_hash,
-                vec![parent_hash],
-                mock_actor,
-                None,
-                format!("{}\n", message),
-            );
+            let message = format!("{}\n", message);
+            let commit = if allow_duplicate_parents {
+                Commit::with_duplicate_parents(tree_hash, parent_hashes, mock_actor, None, message)
+            } else {
+                Commit::new(tree_hash, parent_hashes, mock_actor, None, message)?
+            };
 
-            commit
-                .write(".")
-                .with_context(|| "failed to write commit object")?;
-            println!("{}", hex::encode(commit.sha1()?));
+            if !dry_run {
+                commit
+                    .write(".")
+                    .with_context(|| "failed to write commit object")?;
+            }
+            writeln!(stdout, "{}", hex::encode(commit.sha1()?))?;
+        }
+        "unpack-objects" => {
+            let mut max_depth = None;
+            for arg in &args[2..] {
+                match arg.strip_prefix("--max-delta-depth=") {
+                    Some(depth) => max_depth = Some(parse_max_delta_depth(depth)?),
+                    None => bail!("unpack-objects: unexpected argument {arg:?}"),
+                }
+            }
+            let config = Config::load(".")?;
+            let max_depth = max_depth.map_or_else(|| max_delta_depth(&config), Ok)?;
+            let mut pack = vec![];
+            stdin()
+                .read_to_end(&mut pack)
+                .with_context(|| "unpack-objects: failed to read pack from stdin")?;
+            let objects = Packfile::read(pack)
+                .with_context(|| "unpack-objects: failed to read pack")?
+                .unpack(max_depth)
+                .with_context(|| "unpack-objects: failed to unpack pack")?;
+
+            let level = loose_compression(&config)?;
+            for (sha, object) in &objects {
+                object
+                    .write_with_level(&".", level)
+                    .with_context(|| format!("unpack-objects: failed to write {sha}"))?;
+            }
+        }
+        "index-pack" => {
+            let mut pack_path = None;
+            let mut index_path = None;
+            let mut max_depth = None;
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                if let Some(depth) = arg.strip_prefix("--max-delta-depth=") {
+                    max_depth = Some(parse_max_delta_depth(depth)?);
+                    continue;
+                }
+                match arg.as_str() {
+                    "-o" => {
+                        index_path =
+                            Some(Path::new(rest.next().ok_or_else(|| {
+                                anyhow!("index-pack: -o requires an index file")
+                            })?))
+                    }
+                    _ if pack_path.is_none() => pack_path = Some(Path::new(arg)),
+                    _ => bail!("index-pack: unexpected argument {arg:?}"),
+                }
+            }
+            let pack_path = pack_path.ok_or_else(|| {
+                anyhow!("usage: index-pack [-o <index>] [--max-delta-depth=<n>] <pack>")
+            })?;
+            let max_depth = max_depth.map_or_else(|| max_delta_depth(&Config::load(".")?), Ok)?;
+            // the index sits next to the pack unless told otherwise
+            let index_path = match index_path {
+                Some(index_path) => index_path.to_path_buf(),
+                None => {
+                    if !pack_path
+                        .extension()
+                        .is_some_and(|extension| extension == "pack")
+                    {
+                        bail!("index-pack: pack file name {pack_path:?} doesn't end in .pack");
+                    }
+                    pack_path.with_extension("idx")
+                }
+            };
+
+            let pack = fs::read(pack_path)
+                .with_context(|| format!("index-pack: failed to read {pack_path:?}"))?;
+            let (index, checksum) = index_pack(&pack, max_depth)
+                .with_context(|| format!("index-pack: failed to index {pack_path:?}"))?;
+            fs::write(&index_path, index)
+                .with_context(|| format!("index-pack: failed to write {index_path:?}"))?;
+            writeln!(stdout, "{checksum}")?;
         }
         "clone" => {
-            let url = &args[2];
-            let dir_name = Path::new(&args[3]);
-            println!(
+            let quiet = args[2..].iter().any(|arg| arg == "-q" || arg == "--quiet");
+            let mirror = args[2..].iter().any(|arg| arg == "--mirror");
+            // only the cloned branch unless asked for all of them, the last flag winning
+            let single_branch = !args[2..]
+                .iter()
+                .rev()
+                .find(|arg| *arg == "--single-branch" || *arg == "--no-single-branch")
+                .is_some_and(|arg| arg == "--no-single-branch");
+            let max_delta_depth = args[2..]
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--max-delta-depth="))
+                .map(parse_max_delta_depth)
+                .transpose()?;
+            // only the object directories are synced once at the end, unless --fsync asks for the
+            // object files to be synced too, in batches of this many
+            let write_batch_size = args[2..]
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--write-batch-size="))
+                .map(|size| {
+                    size.parse()
+                        .with_context(|| format!("invalid --write-batch-size {size:?}"))
+                })
+                .transpose()?;
+            // the last of --fsync and --no-fsync wins
+            let fsync = args[2..]
+                .iter()
+                .rev()
+                .find_map(|arg| match arg.as_str() {
+                    "--fsync" => Some(true),
+                    "--no-fsync" => Some(false),
+                    _ => None,
+                })
+                .unwrap_or(false);
+            let mut origin = None;
+            let mut positional = vec![];
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "-o" | "--origin" => {
+                        origin = Some(
+                            options
+                                .next()
+                                .ok_or_else(|| anyhow!("clone: {arg} requires a remote name"))?
+                                .as_str(),
+                        )
+                    }
+                    _ => match arg.strip_prefix("--origin=") {
+                        Some(name) => origin = Some(name),
+                        None if !arg.starts_with('-') => positional.push(arg),
+                        None => {}
+                    },
+                }
+            }
+            // a mirror has no remote-tracking refs for the name to apply to, git refuses it too
+            if mirror && origin.is_some() {
+                bail!("clone: --mirror and --origin are incompatible");
+            }
+            let origin = origin.unwrap_or("origin");
+            check_ref_format(&format!("refs/remotes/{origin}/HEAD"))
+                .with_context(|| format!("clone: invalid remote name {origin:?}"))?;
+            let url = positional[0];
+            let dir_name = Path::new(positional[1]);
+            writeln!(
+                stdout,
                 "cloning {url} into {:?}",
                 std::path::absolute(dir_name).unwrap()
-            );
+            )?;
             assert!(!dir_name.exists(), "directory already exists");
-            fs::create_dir(&dir_name).with_context(|| "failed to create directory")?;
-            let client = GitClient::new(url).with_context(|| "failed to create GitClient")?;
+            fs::create_dir(dir_name).with_context(|| "failed to create directory")?;
+            let mut client = GitClient::new(url).with_context(|| "failed to create GitClient")?;
+            if let Some(max_delta_depth) = max_delta_depth {
+                client = client.with_max_delta_depth(max_delta_depth);
+            }
+            if let Some(write_batch_size) = write_batch_size {
+                client = client.with_write_batch_size(write_batch_size);
+            }
+            if fsync {
+                client = client.with_fsync();
+            }
+
+            // Ctrl-C stops the clone at its next step instead of killing it mid-write
+            let cancel = Arc::new(AtomicBool::new(false));
+            let interrupted = cancel.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupted.store(true, Ordering::Relaxed);
+                }
+            });
+            client = client.with_cancellation(cancel);
+
+            let result = match mirror {
+                true => client.mirror(&dir_name, quiet).await,
+                false => client.clone(&dir_name, origin, single_branch, quiet).await,
+            };
+            // a cancelled clone leaves nothing behind
+            if result
+                .as_ref()
+                .is_err_and(|err| err.downcast_ref::<Cancelled>().is_some())
+            {
+                fs::remove_dir_all(dir_name)
+                    .with_context(|| format!("failed to remove {dir_name:?}"))?;
+                bail!("clone cancelled");
+            }
+            result.with_context(|| "failed to negotiate")?;
+        }
+        "fetch" => {
+            let prune = args[2..].iter().any(|arg| arg == "-p" || arg == "--prune");
+            let config = Config::load(".")?;
+            // a remote from the config, or a url fetched as origin. with neither, the remote the
+            // current branch follows, or origin
+            let (remote, url) = match args[2..].iter().find(|arg| !arg.starts_with('-')) {
+                Some(arg) => match config.get(&format!("remote.{arg}.url")) {
+                    Some(url) => (arg.clone(), url.to_owned()),
+                    None => ("origin".to_owned(), arg.clone()),
+                },
+                None => {
+                    let branch = RefStore::new(".")
+                        .symbolic_target("HEAD")
+                        .with_context(|| "failed to read HEAD")?
+                        .and_then(|head| head.strip_prefix("refs/heads/").map(str::to_owned));
+                    let remote = branch
+                        .and_then(|branch| config.get(&format!("branch.{branch}.remote")))
+                        .unwrap_or("origin")
+                        .to_owned();
+                    let url = config
+                        .get(&format!("remote.{remote}.url"))
+                        .ok_or_else(|| anyhow!("fetch: no url is configured for {remote}"))?
+                        .to_owned();
+                    (remote, url)
+                }
+            };
+
+            // the remote's refspecs, or its branches as remote-tracking refs like clone sets up
+            let refspecs = match config.get_all(&format!("remote.{remote}.fetch"))[..] {
+                [] => vec![format!("+refs/heads/*:refs/remotes/{remote}/*").parse()?],
+                ref refspecs => refspecs
+                    .iter()
+                    .map(|refspec| refspec.parse::<Refspec>())
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("invalid remote.{remote}.fetch"))?,
+            };
 
-            client
-                .clone(&dir_name)
+            GitClient::new(&url)
+                .with_context(|| "failed to create GitClient")?
+                .with_max_delta_depth(max_delta_depth(&config)?)
+                .fetch(&".", &remote, &refspecs, prune)
                 .await
-                .with_context(|| "failed to negotiate")?;
+                .with_context(|| format!("failed to fetch from {url}"))?;
+        }
+        "push" => {
+            let force = args[2..].iter().any(|arg| arg == "-f" || arg == "--force");
+            // a full pack unless asked for a thin one, the last flag winning
+            let thin = args[2..]
+                .iter()
+                .rev()
+                .find(|arg| *arg == "--thin" || *arg == "--no-thin")
+                .is_some_and(|arg| arg == "--thin");
+            let positional = args[2..]
+                .iter()
+                .filter(|arg| !arg.starts_with('-'))
+                .collect::<Vec<_>>();
+            let url = positional.first().ok_or_else(|| {
+                anyhow!("usage: push [-f | --force] [--[no-]thin] <url> [<src>[:<dst>]]")
+            })?;
+
+            let refs = RefStore::new(".");
+            let (src, dst) = match positional.get(1) {
+                Some(refspec) => {
+                    let (src, dst) = refspec.split_once(':').unwrap_or((refspec, refspec));
+                    (src.to_owned(), dst.to_owned())
+                }
+                None => {
+                    let current = refs
+                        .symbolic_target("HEAD")
+                        .with_context(|| "failed to read HEAD")?
+                        .ok_or_else(|| anyhow!("HEAD is detached, name the ref to push"))?;
+                    (current.clone(), current)
+                }
+            };
+            let qualify = |name: String| {
+                if name.starts_with("refs/") {
+                    name
+                } else {
+                    format!("refs/heads/{name}")
+                }
+            };
+
+            GitClient::new(url)
+                .with_context(|| "failed to create GitClient")?
+                .push(&".", &qualify(src), &qualify(dst), "origin", force, thin)
+                .await
+                .with_context(|| format!("failed to push to {url}"))?;
+        }
+        "describe" => {
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut abbrev = DEFAULT_ABBREV;
+            let mut rev = "HEAD";
+            for arg in &args[2..] {
+                match arg.strip_prefix("--abbrev=") {
+                    // 0 leaves out everything but the tag name
+                    Some("0") => abbrev = 0,
+                    Some(value) => abbrev = parse_abbrev(value)?,
+                    None => rev = arg,
+                }
+            }
+            let target = peel_tag(&store, &rev_parse(&store, &refs, rev)?)?;
+
+            // only annotated tags name a commit, lightweight ones are skipped like git does
+            let mut tagged = HashMap::new();
+            for (name, sha) in refs.list().with_context(|| "failed to list refs")? {
+                let Some(tag_name) = name.strip_prefix("refs/tags/") else {
+                    continue;
+                };
+                if store.read(&sha)?.try_as_tag().is_some() {
+                    tagged.insert(peel_tag(&store, &sha)?, tag_name.to_owned());
+                }
+            }
+
+            // the nearest tag is the first one met walking breadth-first from the target
+            let mut visited = HashSet::new();
+            let mut pending = VecDeque::from([target.clone()]);
+            let mut nearest = None;
+            while let Some(sha) = pending.pop_front() {
+                if let Some(tag_name) = tagged.get(&sha) {
+                    nearest = Some((sha, tag_name));
+                    break;
+                }
+                if visited.insert(sha.clone()) {
+                    pending.extend(store.read_commit(&sha)?.parent_hash);
+                }
+            }
+
+            let (tag_commit, tag_name) =
+                nearest.ok_or_else(|| anyhow!("no annotated tags can describe {target}"))?;
+            let distance =
+                reachable_commit_nodes(&store, std::slice::from_ref(&target), &[tag_commit])?.len();
+
+            if distance == 0 || abbrev == 0 {
+                writeln!(stdout, "{tag_name}")?;
+            } else {
+                writeln!(
+                    stdout,
+                    "{tag_name}-{distance}-g{}",
+                    store.find_unique_abbrev(&target, abbrev)?
+                )?;
+            }
+        }
+        "name-rev" => {
+            let mut tags_only = false;
+            let mut name_only = false;
+            let mut revs = vec![];
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--tags" => tags_only = true,
+                    "--name-only" => name_only = true,
+                    _ => revs.push(arg.as_str()),
+                }
+            }
+            if revs.is_empty() {
+                bail!("usage: name-rev [--tags] [--name-only] <commit-ish>...");
+            }
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let names = name_commits(&store, &refs, tags_only)?;
+            for rev in revs {
+                let sha = peel_tag(&store, &rev_parse(&store, &refs, rev)?)?;
+                let name = names.get(&sha).map_or("undefined", String::as_str);
+                // only tags being candidates, their names go without the `tags/`
+                let name = match tags_only && name_only {
+                    true => name.strip_prefix("tags/").unwrap_or(name),
+                    false => name,
+                };
+                match name_only {
+                    true => writeln!(stdout, "{name}")?,
+                    false => writeln!(stdout, "{rev} {name}")?,
+                }
+            }
+        }
+        "rev-parse" => {
+            if args.len() < 3 {
+                bail!("usage: rev-parse (--all | <rev>)...");
+            }
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            for rev in &args[2..] {
+                if rev == "--all" {
+                    for sha in refs.list().with_context(|| "failed to list refs")?.values() {
+                        writeln!(stdout, "{sha}")?;
+                    }
+                } else {
+                    writeln!(stdout, "{}", rev_parse(&store, &refs, rev)?)?;
+                }
+            }
+        }
+        "rev-list" => {
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let resolve =
+                |rev: &str| -> Result<Sha> { peel_tag(&store, &rev_parse(&store, &refs, rev)?) };
+
+            let mut count = false;
+            let mut topo = false;
+            let mut max_count = None;
+            let mut roots = vec![];
+            let mut excluded = vec![];
+
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                if arg == "--count" {
+                    count = true;
+                } else if arg == "--topo-order" {
+                    topo = true;
+                } else if let Some(value) = arg.strip_prefix("--max-count=") {
+                    max_count = Some(parse_with_context::<usize>(value)?);
+                } else if arg == "-n" {
+                    let value = options
+                        .next()
+                        .ok_or_else(|| anyhow!("rev-list: -n requires a number"))?;
+                    max_count = Some(parse_with_context::<usize>(value)?);
+                } else if let Some((left, right)) = arg.split_once("...") {
+                    // the symmetric difference: what either side reaches but not both, which is
+                    // everything below their merge bases
+                    let left = resolve(if left.is_empty() { "HEAD" } else { left })?;
+                    let right = resolve(if right.is_empty() { "HEAD" } else { right })?;
+                    excluded.extend(merge_bases(&store, &left, std::slice::from_ref(&right))?);
+                    roots.extend([left, right]);
+                } else if let Some((from, to)) = arg.split_once("..") {
+                    // an empty side of the range stands for HEAD
+                    excluded.push(resolve(if from.is_empty() { "HEAD" } else { from })?);
+                    roots.push(resolve(if to.is_empty() { "HEAD" } else { to })?);
+                } else if let Some(rev) = arg.strip_prefix('^') {
+                    excluded.push(resolve(rev)?);
+                } else {
+                    roots.push(resolve(arg)?);
+                }
+            }
+
+            if roots.is_empty() {
+                bail!("usage: rev-list [--count] [--topo-order] [--max-count=<n>] <rev>... [<a>..<b>] [<a>...<b>] [^<rev>]");
+            }
+
+            let mut commits = reachable_commit_nodes(&store, &roots, &excluded)?;
+            if topo {
+                commits = topo_order(commits);
+            }
+            let commits = commits.iter().take(max_count.unwrap_or(usize::MAX));
+            if count {
+                writeln!(stdout, "{}", commits.count())?;
+            } else {
+                for (sha, _) in commits {
+                    writeln!(stdout, "{sha}")?;
+                }
+            }
+        }
+        "merge-base" => {
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let resolve =
+                |rev: &str| -> Result<Sha> { peel_tag(&store, &rev_parse(&store, &refs, rev)?) };
+
+            let mut all = false;
+            let mut check_ancestor = false;
+            let mut commits = vec![];
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "-a" | "--all" => all = true,
+                    "--is-ancestor" => check_ancestor = true,
+                    _ => commits.push(resolve(arg)?),
+                }
+            }
+
+            if check_ancestor {
+                let [ancestor, descendant] = &commits[..] else {
+                    bail!("usage: merge-base --is-ancestor <commit> <commit>");
+                };
+                // like git, the answer is the exit status
+                if !is_ancestor(&store, ancestor, descendant)? {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+
+            let Some((one, others)) = commits
+                .split_first()
+                .filter(|(_, others)| !others.is_empty())
+            else {
+                bail!("usage: merge-base [-a | --all] <commit> <commit>...");
+            };
+            let bases = merge_bases(&store, one, others)?;
+            if bases.is_empty() {
+                stdout.flush()?;
+                std::process::exit(1);
+            }
+            for base in bases.iter().take(if all { usize::MAX } else { 1 }) {
+                writeln!(stdout, "{base}")?;
+            }
+        }
+        "commit-graph" => {
+            let usage = "usage: commit-graph write [--reachable]";
+            if args.get(2).map(String::as_str) != Some("write") {
+                bail!(usage);
+            }
+            // the graph is always built from the commits reachable from refs
+            if args[3..].iter().any(|arg| arg != "--reachable") {
+                bail!(usage);
+            }
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut tips = vec![];
+            for sha in refs
+                .list()
+                .with_context(|| "failed to list refs")?
+                .into_values()
+                .chain(refs.head().with_context(|| "failed to resolve HEAD")?)
+            {
+                // tags may lead to trees and blobs, which have no place in the graph
+                let sha = peel_tag(&store, &sha)?;
+                if store.read_type(&sha)? == "commit" {
+                    tips.push(sha);
+                }
+            }
+            write_commit_graph(&store, &tips).with_context(|| "failed to write commit graph")?;
+        }
+        "log" => {
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut oneline = false;
+            let mut decorate = false;
+            let mut max_count = None;
+            let (mut since, mut until) = (None, None);
+            let (mut authors, mut greps, mut all_match) = (vec![], vec![], false);
+            let mut abbrev = DEFAULT_ABBREV;
+            let (mut name_only, mut name_status, mut merges) = (false, false, false);
+            let mut roots = vec![];
+
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "--oneline" => oneline = true,
+                    "--name-only" => name_only = true,
+                    "--name-status" => name_status = true,
+                    "-m" => merges = true,
+                    "--decorate" | "--decorate=short" => decorate = true,
+                    "--no-decorate" => decorate = false,
+                    "--all-match" => all_match = true,
+                    "-n" => {
+                        let value = options
+                            .next()
+                            .ok_or_else(|| anyhow!("log: -n requires a number"))?;
+                        max_count = Some(parse_with_context::<usize>(value)?);
+                    }
+                    _ => {
+                        if let Some(value) = arg.strip_prefix("--max-count=") {
+                            max_count = Some(parse_with_context::<usize>(value)?);
+                        } else if let Some(value) = arg.strip_prefix("--abbrev=") {
+                            abbrev = parse_abbrev(value)?;
+                        } else if let Some(value) = arg
+                            .strip_prefix("--since=")
+                            .or_else(|| arg.strip_prefix("--after="))
+                        {
+                            since = Some(parse_date(value, CommitActor::current().epoch)?);
+                        } else if let Some(value) = arg
+                            .strip_prefix("--until=")
+                            .or_else(|| arg.strip_prefix("--before="))
+                        {
+                            until = Some(parse_date(value, CommitActor::current().epoch)?);
+                        } else if let Some(value) = arg.strip_prefix("--author=") {
+                            authors.push(parse_log_pattern(value)?);
+                        } else if let Some(value) = arg.strip_prefix("--grep=") {
+                            greps.push(parse_log_pattern(value)?);
+                        } else {
+                            roots.push(peel_tag(&store, &rev_parse(&store, &refs, arg)?)?);
+                        }
+                    }
+                }
+            }
+            if roots.is_empty() {
+                roots.push(
+                    refs.head()?
+                        .ok_or_else(|| anyhow!("log: HEAD does not have any commits yet"))?,
+                );
+            }
+
+            // the files a commit changed, against one of its parents or, for a root commit, the
+            // empty tree. renames are detected like git log does by default
+            let changes = |commit: &Commit, parent: Option<&Sha>| -> Result<Vec<TreeChange>> {
+                let old = match parent {
+                    Some(parent) => peel_tree(&store, parent)?,
+                    None => EMPTY_TREE_SHA,
+                };
+                let old = flatten_tree(&store, &old)?;
+                let new = flatten_tree(&store, &commit.tree_hash)?;
+                diff_entries(&store, &old, &new, Some(DEFAULT_RENAME_THRESHOLD), None)
+            };
+
+            // commits outside the dates are left out, but their ancestors are still walked
+            let commits = reachable_commits(&store, &roots, &[])?;
+            let in_range = |commit: &Commit| {
+                let epoch = commit.committer().epoch;
+                !since.is_some_and(|since| epoch < since)
+                    && !until.is_some_and(|until| epoch > until)
+            };
+            // any --author has to match, and any --grep, or every one of them with --all-match
+            let matches = |commit: &Commit| {
+                let author = commit.author();
+                let author = format!("{} <{}>", author.name, author.email);
+                let message = commit.message();
+                (authors.is_empty() || authors.iter().any(|pattern| pattern.is_match(&author)))
+                    && (greps.is_empty()
+                        || match all_match {
+                            true => greps.iter().all(|pattern| pattern.is_match(message)),
+                            false => greps.iter().any(|pattern| pattern.is_match(message)),
+                        })
+            };
+            let mut first = true;
+            for (sha, commit) in commits
+                .iter()
+                .filter(|(_, commit)| in_range(commit) && matches(commit))
+                .take(max_count.unwrap_or(usize::MAX))
+            {
+                // each entry is shown with the commit's header: the parent a merge was diffed
+                // against, and the files changed if they're listed at all. merges list nothing
+                // unless -m asks for a diff against every parent, like in git
+                let mut shown = vec![];
+                match commit.parent_hash.as_slice() {
+                    _ if !name_only && !name_status => shown.push((None, None)),
+                    [] => shown.push((None, Some(changes(commit, None)?))),
+                    [parent] => shown.push((None, Some(changes(commit, Some(parent))?))),
+                    parents if merges => {
+                        for parent in parents {
+                            let changes = changes(commit, Some(parent))?;
+                            if !changes.is_empty() {
+                                shown.push((Some(parent), Some(changes)));
+                            }
+                        }
+                        if shown.is_empty() {
+                            shown.push((None, None));
+                        }
+                    }
+                    _ => shown.push((None, None)),
+                }
+
+                let decorations = match decorate {
+                    true => decorations(&store, &refs, sha)?,
+                    false => String::new(),
+                };
+                let message = commit.message().trim_end_matches('\n');
+                for (from, changes) in shown {
+                    let from = match from {
+                        Some(parent) if oneline => {
+                            format!(" (from {})", store.find_unique_abbrev(parent, abbrev)?)
+                        }
+                        Some(parent) => format!(" (from {parent})"),
+                        None => String::new(),
+                    };
+
+                    if oneline {
+                        let subject = message.lines().next().unwrap_or_default();
+                        writeln!(
+                            stdout,
+                            "{}{from}{decorations} {subject}",
+                            store.find_unique_abbrev(sha, abbrev)?
+                        )?;
+                    } else {
+                        if !first {
+                            writeln!(stdout)?;
+                        }
+                        writeln!(stdout, "commit {sha}{from}{decorations}")?;
+                        if commit.parent_hash.len() > 1 {
+                            let parents = commit
+                                .parent_hash
+                                .iter()
+                                .map(|parent| store.find_unique_abbrev(parent, abbrev))
+                                .collect::<Result<Vec<_>>>()?;
+                            writeln!(stdout, "Merge: {}", parents.join(" "))?;
+                        }
+                        let author = commit.author();
+                        writeln!(stdout, "Author: {} <{}>", author.name, author.email)?;
+                        writeln!(stdout, "Date:   {}", author.date())?;
+                        writeln!(stdout)?;
+                        for line in message.lines() {
+                            writeln!(stdout, "    {line}")?;
+                        }
+                    }
+                    first = false;
+
+                    let Some(changes) = changes.filter(|changes| !changes.is_empty()) else {
+                        continue;
+                    };
+                    if !oneline {
+                        writeln!(stdout)?;
+                    }
+                    for change in &changes {
+                        match name_status {
+                            true => writeln!(stdout, "{}", name_status_line(change))?,
+                            false => writeln!(stdout, "{}", change.name())?,
+                        }
+                    }
+                }
+            }
+        }
+        "blame" => {
+            let positional = args[2..]
+                .iter()
+                .filter(|arg| *arg != "--")
+                .collect::<Vec<_>>();
+            let (rev, path) = match positional[..] {
+                [path] => ("HEAD", path),
+                [rev, path] => (rev.as_str(), path),
+                _ => bail!("usage: blame [<rev>] [--] <file>"),
+            };
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let start = peel_tag(&store, &rev_parse(&store, &refs, rev)?)?;
+            let lines = blame(&store, &start, path)?;
+
+            let mut commits = HashMap::new();
+            for line in &lines {
+                if !commits.contains_key(&line.commit) {
+                    commits.insert(line.commit.clone(), store.read_commit(&line.commit)?);
+                }
+            }
+            // one digit more than the ids need, so a root commit's id fits after its `^`
+            let mut abbrev = 0;
+            for sha in commits.keys() {
+                abbrev = abbrev.max(store.find_unique_abbrev(sha, DEFAULT_ABBREV)?.len() + 1);
+            }
+            let author_width = commits
+                .values()
+                .map(|commit| commit.author().name.chars().count())
+                .max()
+                .unwrap_or(0);
+            let number_width = lines.len().to_string().len();
+
+            for (number, line) in lines.iter().enumerate() {
+                let commit = &commits[&line.commit];
+                let hex = line.commit.to_string();
+                let id = match commit.parent_hash.is_empty() {
+                    true => format!("^{}", &hex[..abbrev - 1]),
+                    false => hex[..abbrev].to_owned(),
+                };
+                let author = commit.author();
+                write!(
+                    stdout,
+                    "{id} ({:<author_width$} {} {:>number_width$}) ",
+                    author.name,
+                    author.iso_date(),
+                    number + 1
+                )?;
+                stdout.write_all(&line.content)?;
+                if !line.content.ends_with(b"\n") {
+                    writeln!(stdout)?;
+                }
+            }
+        }
+        "checkout" => {
+            let mut detach = false;
+            let mut target = None;
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--detach" => detach = true,
+                    _ if target.is_none() => target = Some(arg.as_str()),
+                    _ => bail!("checkout: unexpected argument {arg:?}"),
+                }
+            }
+            let target =
+                target.ok_or_else(|| anyhow!("usage: checkout [--detach] <branch | commit>"))?;
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut index = Index::read(".")?;
+            if index.has_conflicts() {
+                bail!("checkout: you need to resolve your current index first");
+            }
+
+            // a branch name attaches HEAD to the branch, any other commit detaches HEAD at it
+            let branch = format!("refs/heads/{target}");
+            let branch = (!detach && refs.resolve(&branch)?.is_some()).then_some(branch);
+            let commit = peel_tag(&store, &rev_parse(&store, &refs, target)?)?;
+            let tree = store
+                .read_commit(&commit)
+                .with_context(|| format!("checkout: {target} is not a commit"))?
+                .tree_hash;
+
+            let head = refs.head()?;
+            // an unborn branch compares like a commit of the empty tree
+            let head_tree = match &head {
+                Some(head) => store.read_commit(head)?.tree_hash,
+                None => EMPTY_TREE_SHA,
+            };
+            let head_entries = flatten_tree(&store, &head_tree)?;
+            let entries = flatten_tree(&store, &tree)?;
+            let changed = changed_paths(&head_entries, &entries);
+            let dirty = local_changes(&index, &head_entries, &changed)?;
+            if !dirty.is_empty() {
+                bail!(
+                    "checkout: local changes would be overwritten in:\n\t{}",
+                    dirty.into_iter().collect::<Vec<_>>().join("\n\t")
+                );
+            }
+
+            update_worktree(".", &store, &head_entries, &entries)?;
+            stage_checkout(&mut index, &entries, &changed);
+            index.write(".")?;
+
+            let from = match (refs.symbolic_target("HEAD")?, &head) {
+                (Some(current), _) => current.trim_start_matches("refs/heads/").to_owned(),
+                (None, Some(head)) => head.to_string(),
+                (None, None) => "HEAD".to_owned(),
+            };
+            let message = format!("checkout: moving from {from} to {target}");
+            match branch {
+                Some(branch) => {
+                    refs.update_symbolic("HEAD", &branch, &message)?;
+                    writeln!(stdout, "Switched to branch '{target}'")?;
+                }
+                None => {
+                    refs.update("HEAD", &commit, &message)?;
+                    let subject = store
+                        .read_commit(&commit)?
+                        .message()
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned();
+                    writeln!(
+                        stdout,
+                        "HEAD is now at {} {subject}",
+                        store.find_unique_abbrev(&commit, DEFAULT_ABBREV)?
+                    )?;
+                }
+            }
+        }
+        "commit" => {
+            let mut message = None;
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "-m" => {
+                        message = Some(
+                            rest.next()
+                                .ok_or_else(|| anyhow!("commit: -m requires a message"))?,
+                        )
+                    }
+                    _ => bail!("commit: unexpected argument {arg:?}"),
+                }
+            }
+            let message = message.ok_or_else(|| anyhow!("usage: commit -m <message>"))?;
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let index = Index::read(".")?;
+            if index.has_conflicts() {
+                bail!("commit: not possible because you have unmerged files");
+            }
+
+            let entries = index
+                .entries
+                .iter()
+                .map(|entry| {
+                    Ok((
+                        entry.name.clone(),
+                        (FileMode::from_mode(entry.mode)?, entry.sha.clone()),
+                    ))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            let tree = build_tree(".", &entries)?;
+            let head = refs.head()?;
+            if let Some(head) = &head {
+                if store.read_commit(head)?.tree_hash == tree {
+                    bail!("commit: nothing to commit");
+                }
+            }
+
+            let commit = Commit::new(
+                tree.0,
+                head.iter().map(|head| head.0).collect(),
+                CommitActor::current(),
+                None,
+                format!("{message}\n"),
+            )?;
+            commit
+                .write(".")
+                .with_context(|| "failed to write commit object")?;
+            let sha = commit.sha1()?;
+
+            // a detached HEAD moves itself, otherwise the branch it's on moves
+            let subject = message.lines().next().unwrap_or_default();
+            let branch = refs.symbolic_target("HEAD")?;
+            refs.update(
+                branch.as_deref().unwrap_or("HEAD"),
+                &sha,
+                &format!("commit: {subject}"),
+            )?;
+            writeln!(
+                stdout,
+                "[{} {}] {subject}",
+                branch
+                    .as_deref()
+                    .map(|branch| branch.trim_start_matches("refs/heads/"))
+                    .unwrap_or("detached HEAD"),
+                store.find_unique_abbrev(&sha, DEFAULT_ABBREV)?
+            )?;
+        }
+        "branch" => {
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let current = refs.symbolic_target("HEAD")?;
+            match args.get(2).map(String::as_str) {
+                // nothing at all when HEAD is detached, like git
+                Some("--show-current") => {
+                    if let Some(branch) = current
+                        .as_deref()
+                        .and_then(|current| current.strip_prefix("refs/heads/"))
+                    {
+                        writeln!(stdout, "{branch}")?;
+                    }
+                }
+                Some(arg) => bail!("branch: unexpected argument {arg:?}"),
+                None => {
+                    if current.is_none() {
+                        if let Some(head) = refs.head()? {
+                            writeln!(
+                                stdout,
+                                "* (HEAD detached at {})",
+                                store.find_unique_abbrev(&head, DEFAULT_ABBREV)?
+                            )?;
+                        }
+                    }
+                    for name in refs.list()?.keys() {
+                        if let Some(branch) = name.strip_prefix("refs/heads/") {
+                            let marker = if current.as_ref() == Some(name) {
+                                '*'
+                            } else {
+                                ' '
+                            };
+                            writeln!(stdout, "{marker} {branch}")?;
+                        }
+                    }
+                }
+            }
+        }
+        "status" => {
+            // the last --color wins, a bare one means always
+            let color = args[2..].iter().rev().find_map(|arg| match arg.as_str() {
+                "--color" => Some("always"),
+                _ => arg.strip_prefix("--color="),
+            });
+            let colors = Colors::new(color)?;
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let config = Config::load(".")?;
+            let index = Index::read(".")?;
+            let head = refs.head()?;
+
+            match refs.symbolic_target("HEAD")? {
+                Some(current) => {
+                    let branch = current.trim_start_matches("refs/heads/");
+                    writeln!(stdout, "On branch {branch}")?;
+                    if let Some(tracking) = tracking_status(&store, &refs, &config, branch)? {
+                        writeln!(stdout, "{tracking}")?;
+                    }
+                }
+                None => writeln!(
+                    stdout,
+                    "HEAD detached at {}",
+                    match &head {
+                        Some(head) => store.find_unique_abbrev(head, DEFAULT_ABBREV)?,
+                        None => "HEAD".to_owned(),
+                    }
+                )?,
+            }
+            if head.is_none() {
+                writeln!(stdout, "\nNo commits yet")?;
+            }
+
+            // an unborn branch compares like a commit of the empty tree
+            let head_tree = match &head {
+                Some(head) => store.read_commit(head)?.tree_hash,
+                None => EMPTY_TREE_SHA,
+            };
+            let head_entries = flatten_tree(&store, &head_tree)?;
+            // the conflict stages each unmerged path has, a bit per stage
+            let mut unmerged = BTreeMap::<String, u8>::new();
+            for entry in index.entries.iter().filter(|entry| entry.stage() != 0) {
+                *unmerged.entry(entry.name.clone()).or_default() |= 1 << (entry.stage() - 1);
+            }
+            let staged_entries = index
+                .entries
+                .iter()
+                .filter(|entry| entry.stage() == 0)
+                .map(|entry| {
+                    Ok((
+                        entry.name.clone(),
+                        (FileMode::from_mode(entry.mode)?, entry.sha.clone()),
+                    ))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+
+            // HEAD against the index, then the index against the working tree
+            let staged = changed_paths(&head_entries, &staged_entries)
+                .into_iter()
+                .filter(|name| !unmerged.contains_key(*name))
+                .map(|name| {
+                    let status = match (head_entries.get(name), staged_entries.get(name)) {
+                        (None, _) => "new file",
+                        (_, None) => "deleted",
+                        _ => "modified",
+                    };
+                    (status, name.clone())
+                })
+                .collect::<Vec<_>>();
+            let unstaged = dirty_paths(".", &index, &staged_entries, staged_entries.keys())?
+                .into_iter()
+                .map(|name| {
+                    let status = match Path::new(&name).symlink_metadata() {
+                        Ok(_) => "modified",
+                        Err(_) => "deleted",
+                    };
+                    (status, name)
+                })
+                .collect::<Vec<_>>();
+            let mut untracked = vec![];
+            untracked_files(&index, Some(&IgnoreMatcher::new(".")?), "", &mut untracked)?;
+            untracked.sort();
+
+            let summary = if !staged.is_empty() || !unmerged.is_empty() {
+                None
+            } else if !unstaged.is_empty() {
+                Some("no changes added to commit")
+            } else if !untracked.is_empty() {
+                Some("nothing added to commit but untracked files present")
+            } else {
+                Some("nothing to commit, working tree clean")
+            };
+            // like git, an unmerged path is labelled by which sides still have it: stage 1 is the
+            // base, 2 ours and 3 theirs
+            let unmerged = unmerged
+                .into_iter()
+                .map(|(name, stages)| {
+                    let status = match stages {
+                        0b001 => "both deleted",
+                        0b010 => "added by us",
+                        0b011 => "deleted by them",
+                        0b100 => "added by them",
+                        0b101 => "deleted by us",
+                        0b110 => "both added",
+                        _ => "both modified",
+                    };
+                    (status, name)
+                })
+                .collect::<Vec<_>>();
+            let untracked = untracked.into_iter().map(|name| ("", name)).collect();
+
+            // labels are padded to the longest one a section can have, plus a space
+            for (title, color, width, paths) in [
+                ("Changes to be committed", Color::Green, 12, staged),
+                ("Unmerged paths", Color::Red, 17, unmerged),
+                ("Changes not staged for commit", Color::Red, 12, unstaged),
+                ("Untracked files", Color::Red, 0, untracked),
+            ] {
+                if paths.is_empty() {
+                    continue;
+                }
+                writeln!(stdout, "\n{title}:")?;
+                for (status, name) in paths {
+                    let line = match status {
+                        "" => name,
+                        status => format!("{:<width$}{name}", format!("{status}:")),
+                    };
+                    // the tab stays outside the color, like git
+                    stdout.write_all(b"\t")?;
+                    stdout.write_all(&colors.paint(color, format!("{line}\n").as_bytes()))?;
+                }
+            }
+            if let Some(summary) = summary {
+                writeln!(stdout, "\n{summary}")?;
+            }
+        }
+        "revert" => {
+            let rev = args
+                .get(2)
+                .ok_or_else(|| anyhow!("usage: revert <commit>"))?;
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut index = Index::read(".")?;
+            if index.has_conflicts() {
+                bail!("revert: not possible because you have unmerged files");
+            }
+
+            let target = peel_tag(&store, &rev_parse(&store, &refs, rev)?)?;
+            let commit = store.read_commit(&target)?;
+            let [parent] = commit.parent_hash.as_slice() else {
+                bail!(
+                    "revert: {target} has {} parents, only single-parent commits can be reverted",
+                    commit.parent_hash.len()
+                );
+            };
+            let head = refs
+                .head()?
+                .ok_or_else(|| anyhow!("revert: HEAD does not point at a commit"))?;
+            let head_tree = store.read_commit(&head)?.tree_hash;
+
+            let short = store.find_unique_abbrev(&target, DEFAULT_ABBREV)?;
+            let subject = commit.message().lines().next().unwrap_or_default();
+
+            // undoing the commit is merging its parent into HEAD with the commit as the base
+            let merge = merge_trees(
+                ".",
+                &store,
+                &commit.tree_hash,
+                &head_tree,
+                &store.read_commit(parent)?.tree_hash,
+                ("HEAD", &format!("parent of {short} ({subject})")),
+            )?;
+
+            let head_entries = flatten_tree(&store, &head_tree)?;
+            let changed = changed_paths(&head_entries, &merge.entries);
+            let dirty = local_changes(&index, &head_entries, &changed)?;
+            if !dirty.is_empty() {
+                bail!(
+                    "revert: local changes would be overwritten in:\n\t{}",
+                    dirty.into_iter().collect::<Vec<_>>().join("\n\t")
+                );
+            }
+
+            update_worktree(".", &store, &head_entries, &merge.entries)?;
+            stage_checkout(&mut index, &merge.entries, &changed);
+            // conflicted paths are recorded as their base, ours and theirs versions
+            for conflict in &merge.conflicts {
+                index.remove(&conflict.name);
+                for (stage, side) in [
+                    (1, &conflict.base),
+                    (2, &conflict.ours),
+                    (3, &conflict.theirs),
+                ] {
+                    if let Some((mode, sha)) = side {
+                        index.entries.push(
+                            IndexEntry::new(&conflict.name, mode.mode(), sha.clone())
+                                .with_stage(stage),
+                        );
+                    }
+                }
+            }
+            index.write(".")?;
+
+            if !merge.conflicts.is_empty() {
+                bail!(
+                    "could not revert {short}... {subject}\nresolve the conflicts in:\n\t{}",
+                    merge
+                        .conflicts
+                        .iter()
+                        .map(|conflict| conflict.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n\t")
+                );
+            }
+
+            let tree = build_tree(".", &merge.entries)?;
+            let revert = Commit::new(
+                tree.0,
+                vec![head.0],
+                CommitActor::current(),
+                None,
+                format!("Revert \"{subject}\"\n\nThis reverts commit {target}.\n"),
+            )?;
+            revert
+                .write(".")
+                .with_context(|| "failed to write revert commit")?;
+            let sha = revert.sha1()?;
+
+            let branch = refs.symbolic_target("HEAD")?;
+            refs.update(
+                branch.as_deref().unwrap_or("HEAD"),
+                &sha,
+                &format!("revert: Revert \"{subject}\""),
+            )?;
+            writeln!(
+                stdout,
+                "[{} {}] Revert \"{subject}\"",
+                branch
+                    .as_deref()
+                    .map(|branch| branch.trim_start_matches("refs/heads/"))
+                    .unwrap_or("detached HEAD"),
+                store.find_unique_abbrev(&sha, DEFAULT_ABBREV)?
+            )?;
+        }
+        "clean" => {
+            let (mut dry_run, mut force, mut directories, mut include_ignored) =
+                (false, false, false, false);
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--dry-run" => dry_run = true,
+                    "--force" => force = true,
+                    _ => match arg
+                        .strip_prefix('-')
+                        .filter(|flags| !flags.is_empty() && !flags.starts_with('-'))
+                    {
+                        // short flags may be combined, like `-fdx`
+                        Some(flags) => {
+                            for flag in flags.chars() {
+                                match flag {
+                                    'n' => dry_run = true,
+                                    'f' => force = true,
+                                    'd' => directories = true,
+                                    'x' => include_ignored = true,
+                                    _ => bail!("clean: unknown option -{flag}"),
+                                }
+                            }
+                        }
+                        None => bail!("clean: unknown argument {arg:?}"),
+                    },
+                }
+            }
+            if !dry_run && !force {
+                bail!("clean: refusing to clean without -n or -f");
+            }
+
+            let cleaner = Cleaner::new(".", directories, include_ignored)?;
+            for name in cleaner.untracked()? {
+                if dry_run {
+                    writeln!(stdout, "Would remove {name}")?;
+                    continue;
+                }
+                writeln!(stdout, "Removing {name}")?;
+                cleaner.remove(&name)?;
+            }
+        }
+        "read-tree" => {
+            let rev = args
+                .get(2)
+                .ok_or_else(|| anyhow!("usage: read-tree <tree-ish>"))?;
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+
+            let tree = peel_tree(&store, &rev_parse(&store, &refs, rev)?)?;
+            let mut index = Index::read(".")?;
+            // the previous entries are dropped, including any conflict stages
+            index.entries = flatten_tree(&store, &tree)?
+                .into_iter()
+                .map(|(name, (mode, sha))| IndexEntry::new(&name, mode.mode(), sha))
+                .collect();
+            index.write(".")?;
+        }
+        "update-index" => {
+            let mut index = Index::read(".")?;
+            let filters = Filters::new(".")?;
+            let (mut add, mut remove, mut force_remove) = (false, false, false);
+
+            // options only affect the paths after them, like in git
+            let mut args = args[2..].iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--add" => add = true,
+                    "--remove" => remove = true,
+                    "--force-remove" => force_remove = true,
+                    "--cacheinfo" => {
+                        // either `<mode>,<sha>,<path>` or three separate arguments
+                        let (mode, sha, name) = cacheinfo_args(&mut args).ok_or_else(|| {
+                            anyhow!("usage: update-index --cacheinfo <mode>,<sha>,<path>")
+                        })?;
+                        let mode = u32::from_str_radix(&mode, 8)
+                            .ok()
+                            .and_then(|mode| FileMode::from_mode(mode).ok())
+                            .filter(|mode| !matches!(mode, FileMode::Directory))
+                            .ok_or_else(|| {
+                                anyhow!("update-index: invalid mode {mode} for {name}")
+                            })?;
+                        let sha: Sha = sha.parse().with_context(|| {
+                            format!("update-index: invalid sha {sha} for {name}")
+                        })?;
+                        verify_index_path(&name)?;
+                        if !add && !index.contains(&name) {
+                            bail!("update-index: {name}: cannot add to the index - missing --add option?");
+                        }
+                        index.insert(IndexEntry::new(&name, mode.mode(), sha));
+                    }
+                    name if force_remove => {
+                        index.remove(name);
+                    }
+                    name => {
+                        verify_index_path(name)?;
+                        match fs::symlink_metadata(name) {
+                            Ok(metadata) if metadata.is_dir() => {
+                                bail!("update-index: {name} is a directory - add files inside instead")
+                            }
+                            Ok(metadata) => {
+                                if !add && !index.contains(name) {
+                                    bail!("update-index: {name}: cannot add to the index - missing --add option?");
+                                }
+                                let content = if metadata.is_symlink() {
+                                    fs::read_link(name)
+                                        .with_context(|| {
+                                            format!("update-index: failed to read link {name}")
+                                        })?
+                                        .into_os_string()
+                                        .into_vec()
+                                } else {
+                                    let content = fs::read(name).with_context(|| {
+                                        format!("update-index: failed to read {name}")
+                                    })?;
+                                    filters.clean(name, content).with_context(|| {
+                                        format!("update-index: failed to filter {name}")
+                                    })?
+                                };
+                                let blob = Blob::new(content);
+                                blob.write(".").with_context(|| {
+                                    format!("update-index: failed to write blob for {name}")
+                                })?;
+                                index.insert(IndexEntry::from_metadata(
+                                    name,
+                                    blob.sha1()?,
+                                    &metadata,
+                                ));
+                            }
+                            Err(_) if remove => {
+                                index.remove(name);
+                            }
+                            Err(_) => {
+                                bail!("update-index: {name} does not exist and --remove not passed")
+                            }
+                        }
+                    }
+                }
+            }
+
+            index.write(".")?;
+        }
+        "check-attr" => {
+            let (mut all, mut from_stdin) = (false, false);
+            let mut names = vec![];
+            let mut paths = vec![];
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "-a" | "--all" => all = true,
+                    "--stdin" => from_stdin = true,
+                    "--" => {
+                        paths.extend(options.by_ref().cloned());
+                        break;
+                    }
+                    _ => names.push(arg.to_owned()),
+                }
+            }
+            // without `--` the first argument is an attribute and the rest are paths. with -a
+            // they're all paths, with --stdin all attributes
+            if !args[2..].iter().any(|arg| arg == "--") {
+                let split = match (all, from_stdin) {
+                    (true, _) => 0,
+                    (false, true) => names.len(),
+                    (false, false) => names.len().min(1),
+                };
+                paths = names.split_off(split);
+            }
+            if all && !names.is_empty() {
+                bail!("check-attr: -a cannot be given with attributes");
+            }
+            if from_stdin {
+                if !paths.is_empty() {
+                    bail!("check-attr: --stdin cannot be given with paths");
+                }
+                for line in stdin().lines() {
+                    paths.push(line.with_context(|| "check-attr: failed to read stdin")?);
+                }
+            }
+            if (names.is_empty() && !all) || paths.is_empty() {
+                bail!("usage: check-attr [--stdin] (-a | <attr>...) [--] <path>...");
+            }
+
+            let attributes = AttributeMatcher::new(".")?;
+            let describe = |value: Option<&AttributeValue>| match value {
+                Some(AttributeValue::Set) => "set".to_owned(),
+                Some(AttributeValue::Unset) => "unset".to_owned(),
+                Some(AttributeValue::Value(value)) => value.clone(),
+                None => "unspecified".to_owned(),
+            };
+            for path in &paths {
+                if all {
+                    for (name, value) in attributes.get_all(path)? {
+                        writeln!(stdout, "{path}: {name}: {}", describe(Some(&value)))?;
+                    }
+                    continue;
+                }
+                for name in &names {
+                    let value = attributes.get(path, name)?;
+                    writeln!(stdout, "{path}: {name}: {}", describe(value.as_ref()))?;
+                }
+            }
+        }
+        "check-ignore" => {
+            let (mut quiet, mut verbose, mut non_matching, mut no_index, mut from_stdin) =
+                (false, false, false, false, false);
+            let mut paths = vec![];
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "-q" | "--quiet" => quiet = true,
+                    "-v" | "--verbose" => verbose = true,
+                    "-n" | "--non-matching" => non_matching = true,
+                    "--no-index" => no_index = true,
+                    "--stdin" => from_stdin = true,
+                    _ => paths.push(arg.to_owned()),
+                }
+            }
+            if quiet && verbose {
+                bail!("check-ignore: cannot have both --quiet and --verbose");
+            }
+            if non_matching && !verbose {
+                bail!("check-ignore: --non-matching is only valid with --verbose");
+            }
+            if from_stdin {
+                for line in stdin().lines() {
+                    paths.push(line.with_context(|| "check-ignore: failed to read stdin")?);
+                }
+            } else if paths.is_empty() {
+                bail!("usage: check-ignore [-q | -v [-n]] [--no-index] (--stdin | <path>...)");
+            }
+            if quiet && paths.len() != 1 {
+                bail!("check-ignore: --quiet is only valid with a single pathname");
+            }
+
+            let ignore = IgnoreMatcher::new(".")?;
+            // tracked files aren't subject to the ignore rules, so they never match
+            let index = match no_index {
+                true => None,
+                false => Some(Index::read(".")?),
+            };
+            let mut any_ignored = false;
+            for path in &paths {
+                let name = path.trim_end_matches('/');
+                let tracked = index
+                    .as_ref()
+                    .is_some_and(|index| index.entries.iter().any(|entry| entry.name == name));
+                let is_dir = path.ends_with('/') || Path::new(name).is_dir();
+                let pattern = match tracked {
+                    true => None,
+                    false => ignore.matching_pattern(name, is_dir)?,
+                }
+                // a negated pattern un-ignores the path, which only -v shows
+                .filter(|pattern| verbose || !pattern.is_negated());
+
+                any_ignored |= pattern.is_some();
+                if quiet {
+                    continue;
+                }
+                match (&pattern, verbose) {
+                    (Some(pattern), true) => {
+                        let (file, line) = pattern.source().unwrap_or_default();
+                        writeln!(stdout, "{file}:{line}:{}\t{path}", pattern.text())?;
+                    }
+                    (Some(_), false) => writeln!(stdout, "{path}")?,
+                    (None, _) if non_matching => writeln!(stdout, "::\t{path}")?,
+                    (None, _) => {}
+                }
+            }
+            // like git, the exit status says whether anything matched
+            if !any_ignored {
+                stdout.flush()?;
+                std::process::exit(1);
+            }
+        }
+        "ls-files" => {
+            let (mut stage, mut unmerged, mut others, mut exclude_standard) =
+                (false, false, false, false);
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "-s" | "--stage" => stage = true,
+                    "-u" | "--unmerged" => unmerged = true,
+                    "-o" | "--others" => others = true,
+                    "--exclude-standard" => exclude_standard = true,
+                    "-c" | "--cached" => {}
+                    other => bail!("ls-files: unknown option {other}"),
+                }
+            }
+            let index = Index::read(".")?;
+
+            if others {
+                // like git, ignored files are only left out with --exclude-standard
+                let ignore = exclude_standard
+                    .then(|| IgnoreMatcher::new("."))
+                    .transpose()?;
+                let mut untracked = vec![];
+                untracked_files(&index, ignore.as_ref(), "", &mut untracked)?;
+                untracked.sort();
+                for name in untracked {
+                    writeln!(stdout, "{name}")?;
+                }
+            } else {
+                // unmerged entries are always listed with their stage
+                for entry in index
+                    .entries
+                    .iter()
+                    .filter(|entry| !unmerged || entry.stage() != 0)
+                {
+                    if stage || unmerged {
+                        writeln!(
+                            stdout,
+                            "{:06o} {} {}\t{}",
+                            entry.mode,
+                            entry.sha,
+                            entry.stage(),
+                            entry.name
+                        )?;
+                    } else {
+                        writeln!(stdout, "{}", entry.name)?;
+                    }
+                }
+            }
+        }
+        "diff" => {
+            let mut rename_threshold = None;
+            let mut copy_threshold = None;
+            let mut name_status = false;
+            let mut context = DEFAULT_CONTEXT_LINES;
+            let mut color = None;
+            let mut no_index = false;
+            let mut revs = vec![];
+            for arg in &args[2..] {
+                if arg == "--no-index" {
+                    no_index = true;
+                } else if let Some(value) = arg
+                    .strip_prefix("-M")
+                    .or_else(|| {
+                        arg.strip_prefix("--find-renames=")
+                            .filter(|v| !v.is_empty())
+                    })
+                    .or_else(|| (arg == "--find-renames").then_some(""))
+                {
+                    rename_threshold = Some(parse_rename_threshold(value)?);
+                } else if let Some(value) = arg
+                    .strip_prefix("-C")
+                    .or_else(|| arg.strip_prefix("--find-copies=").filter(|v| !v.is_empty()))
+                    .or_else(|| (arg == "--find-copies").then_some(""))
+                {
+                    copy_threshold = Some(parse_rename_threshold(value)?);
+                } else if let Some(value) = arg
+                    .strip_prefix("-U")
+                    .or_else(|| arg.strip_prefix("--unified="))
+                {
+                    context = value
+                        .parse()
+                        .with_context(|| format!("diff: invalid context size {value:?}"))?;
+                } else if let Some(when) = arg.strip_prefix("--color=") {
+                    color = Some(when);
+                } else if arg == "--color" {
+                    color = Some("always");
+                } else if arg == "--name-status" {
+                    name_status = true;
+                } else {
+                    revs.push(arg);
+                }
+            }
+            let [old, new] = revs[..] else {
+                bail!(
+                    "usage: diff [--name-status] [-M[<n>]] [-C[<n>]] [-U<n>] <tree-ish> <tree-ish>\n       diff --no-index [-U<n>] <path> <path>"
+                );
+            };
+            let mut options = PatchOptions {
+                context,
+                colors: Colors::new(color)?,
+                drivers: None,
+            };
+            // two paths on disk, no repository involved
+            if no_index {
+                write_no_index_diff(&mut stdout, Path::new(old), Path::new(new), options)?;
+                return Ok(());
+            }
+            let drivers = DiffDrivers::new(".")?;
+            options.drivers = Some(&drivers);
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let entries = |rev: &str| -> Result<_> {
+                flatten_tree(&store, &peel_tree(&store, &rev_parse(&store, &refs, rev)?)?)
+            };
+            // looking for copies implies looking for renames, like in git
+            if copy_threshold.is_some() && rename_threshold.is_none() {
+                rename_threshold = Some(DEFAULT_RENAME_THRESHOLD);
+            }
+            let changes = diff_entries(
+                &store,
+                &entries(old)?,
+                &entries(new)?,
+                rename_threshold,
+                copy_threshold,
+            )?;
+
+            for change in changes {
+                if !name_status {
+                    write_patch(&mut stdout, &store, &change, options)?;
+                    continue;
+                }
+                writeln!(stdout, "{}", name_status_line(&change))?;
+            }
+        }
+        "restore" => {
+            let (mut staged, mut worktree, mut source) = (false, false, None);
+            let mut paths = vec![];
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "-S" | "--staged" => staged = true,
+                    "-W" | "--worktree" => worktree = true,
+                    _ => match arg.strip_prefix("--source=") {
+                        Some(rev) => source = Some(rev),
+                        None => paths.push(arg.trim_end_matches('/')),
+                    },
+                }
+            }
+            if paths.is_empty() {
+                bail!("usage: restore [--staged] [--worktree] [--source=<rev>] <path>...");
+            }
+            // the working tree is the default destination, unless only the index is asked for
+            worktree |= !staged;
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+            let mut index = Index::read(".")?;
+
+            let tree_entries = |sha: &Sha| flatten_tree(&store, &peel_tree(&store, sha)?);
+            // restoring the index defaults to HEAD as the source, the working tree to the index
+            let entries = match (source, staged) {
+                (Some(rev), _) => tree_entries(&rev_parse(&store, &refs, rev)?)?,
+                (None, true) => match refs.head()? {
+                    Some(head) => tree_entries(&head)?,
+                    None => Default::default(),
+                },
+                (None, false) => index
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.stage() == 0)
+                    .map(|entry| {
+                        Ok((
+                            entry.name.clone(),
+                            (FileMode::from_mode(entry.mode)?, entry.sha.clone()),
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            };
+
+            let selected = |name: &str| paths.iter().any(|path| pathspec_matches(path, name));
+            for path in &paths {
+                // a path staged but missing from the source gets unstaged rather than rejected
+                let in_index = staged
+                    && index
+                        .entries
+                        .iter()
+                        .any(|entry| pathspec_matches(path, &entry.name));
+                if !entries.keys().any(|name| pathspec_matches(path, name)) && !in_index {
+                    bail!("restore: pathspec '{path}' did not match any file(s) known to git");
+                }
+            }
+
+            // tracked paths the source doesn't have are removed, like git's default no-overlay mode
+            let removed = index
+                .entries
+                .iter()
+                .filter(|entry| selected(&entry.name) && !entries.contains_key(&entry.name))
+                .map(|entry| {
+                    Ok((
+                        entry.name.clone(),
+                        (FileMode::from_mode(entry.mode)?, entry.sha.clone()),
+                    ))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+
+            if staged {
+                let mut restored = vec![];
+                for (name, (mode, sha)) in entries.iter().filter(|(name, _)| selected(name)) {
+                    // entries that don't change keep their stat data
+                    let current = index.entries.iter().find(|entry| {
+                        entry.name == *name
+                            && entry.stage() == 0
+                            && entry.sha == *sha
+                            && entry.mode == mode.mode()
+                    });
+                    restored.push(match current {
+                        Some(entry) => entry.clone(),
+                        None => IndexEntry::new(name, mode.mode(), sha.clone()),
+                    });
+                }
+                index.entries.retain(|entry| !selected(&entry.name));
+                index.entries.extend(restored);
+                index.write(".")?;
+            }
+
+            if worktree {
+                update_worktree(".", &store, &removed, &BTreeMap::new())?;
+                let filters = Filters::new(".")?;
+                for (name, entry) in entries.iter().filter(|(name, _)| selected(name)) {
+                    checkout_entry(Path::new("."), &store, &filters, name, entry)?;
+                }
+            }
+        }
+        "for-each-ref" => {
+            let mut format = DEFAULT_REF_FORMAT.to_owned();
+            let mut points_at = None;
+            let mut patterns = vec![];
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "--format" => {
+                        format = options
+                            .next()
+                            .ok_or_else(|| anyhow!("for-each-ref: --format requires a format"))?
+                            .clone()
+                    }
+                    "--points-at" => {
+                        points_at = Some(
+                            options
+                                .next()
+                                .ok_or_else(|| {
+                                    anyhow!("for-each-ref: --points-at requires an object")
+                                })?
+                                .as_str(),
+                        )
+                    }
+                    _ => {
+                        if let Some(value) = arg.strip_prefix("--format=") {
+                            format = value.to_owned();
+                        } else if let Some(value) = arg.strip_prefix("--points-at=") {
+                            points_at = Some(value);
+                        } else {
+                            patterns.push(arg.as_str());
+                        }
+                    }
+                }
+            }
+
+            let store = ObjectStore::new(".");
+            let ref_store = RefStore::new(".");
+            let pointing = points_at
+                .map(|rev| {
+                    let sha = rev_parse(&store, &ref_store, rev)?;
+                    refs_pointing_at(&store, &ref_store, &sha)
+                })
+                .transpose()?;
+            let refs = ref_store.list().with_context(|| "failed to list refs")?;
+            for (name, sha) in refs {
+                if pointing
+                    .as_ref()
+                    .is_some_and(|pointing| !pointing.contains(&name))
+                {
+                    continue;
+                }
+                if !patterns.is_empty()
+                    && !patterns
+                        .iter()
+                        .any(|pattern| ref_pattern_matches(pattern, &name))
+                {
+                    continue;
+                }
+                writeln!(
+                    stdout,
+                    "{}",
+                    expand_ref_format(&format, &name, &sha, &store)?
+                )?;
+            }
+        }
+        "show-ref" => {
+            let mut heads = false;
+            let mut tags = false;
+            let mut verify = false;
+            let mut names = vec![];
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "--heads" => heads = true,
+                    "--tags" => tags = true,
+                    "--verify" => verify = true,
+                    _ => names.push(arg.as_str()),
+                }
+            }
+
+            let refs = RefStore::new(".");
+            if verify {
+                if names.is_empty() {
+                    bail!("show-ref: --verify requires a reference");
+                }
+                // only full names count, a shortened one isn't a valid ref here
+                for name in names {
+                    let sha = (name == "HEAD" || name.starts_with("refs/"))
+                        .then(|| refs.resolve(name))
+                        .transpose()
+                        .with_context(|| format!("failed to resolve {name}"))?
+                        .flatten()
+                        .ok_or_else(|| anyhow!("show-ref: '{name}' - not a valid ref"))?;
+                    writeln!(stdout, "{sha} {name}")?;
+                }
+                return Ok(());
+            }
+
+            let mut found = false;
+            for (name, sha) in refs.list().with_context(|| "failed to list refs")? {
+                let wanted = match (heads, tags) {
+                    (false, false) => true,
+                    _ => {
+                        (heads && name.starts_with("refs/heads/"))
+                            || (tags && name.starts_with("refs/tags/"))
+                    }
+                };
+                if wanted {
+                    writeln!(stdout, "{sha} {name}")?;
+                    found = true;
+                }
+            }
+            // like git, finding nothing is a failure scripts can test for
+            if !found {
+                bail!("show-ref: no matching refs");
+            }
+        }
+        "fsck" => {
+            let store = ObjectStore::new(".");
+            let now = CommitActor::current().epoch;
+            let mut problems = 0;
+            for sha in store
+                .object_ids()
+                .with_context(|| "failed to list objects")?
+            {
+                let object = match store.read(sha) {
+                    Ok(object) => object,
+                    Err(err) => {
+                        writeln!(stdout, "error in {sha}: {err:#}")?;
+                        problems += 1;
+                        continue;
+                    }
+                };
+                // object parsing accepts any number as a timestamp and anything as a timezone,
+                // this is where broken clocks and imports show up
+                let Some(commit) = object.try_as_commit() else {
+                    continue;
+                };
+                for problem in commit.date_problems(now) {
+                    writeln!(stdout, "warning in commit {sha}: {problem}")?;
+                    problems += 1;
+                }
+            }
+            if problems > 0 {
+                bail!("fsck: found {problems} problems");
+            }
+        }
+        "prune" => {
+            let dry_run = args[2..]
+                .iter()
+                .any(|arg| arg == "-n" || arg == "--dry-run");
+            let expire = args[2..]
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--expire="))
+                .unwrap_or("2.weeks.ago");
+
+            let store = ObjectStore::new(".");
+            let refs = RefStore::new(".");
+
+            let mut roots = refs
+                .list()
+                .with_context(|| "failed to list refs")?
+                .into_values()
+                .chain(refs.head().with_context(|| "failed to resolve HEAD")?)
+                .collect::<Vec<_>>();
+            if expire != "now" {
+                roots.extend(
+                    refs.reflog_objects()
+                        .with_context(|| "failed to read reflogs")?,
+                );
+            }
+
+            let reachable = reachable_objects(&store, &roots)
+                .collect::<Result<HashSet<_>>>()
+                .with_context(|| "failed to walk reachable objects")?;
+
+            for sha in store
+                .loose_objects()
+                .with_context(|| "failed to list loose objects")?
+            {
+                if reachable.contains(&sha) {
+                    continue;
+                }
+
+                let path = store.loose_object_path(&sha);
+                writeln!(
+                    stdout,
+                    "{sha} {}",
+                    if dry_run { "would be pruned" } else { "pruned" }
+                )?;
+                if !dry_run {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove object file at {path:?}"))?;
+                    // like git, drop the fan-out directory once it's empty
+                    if let Some(folder) = path.parent() {
+                        let _ = fs::remove_dir(folder);
+                    }
+                }
+            }
+        }
+        command => writeln!(stdout, "unknown command: {}", command)?,
+    }
+
+    Ok(())
+}
+
+// the object a `cat-file --batch` line names, a revision or `<tree-ish>:<path>`. names that don't
+// resolve to an object in the store are missing rather than an error, like in git
+fn resolve_batch_object(
+    store: &ObjectStore,
+    refs: &RefStore,
+    name: &str,
+    follow_symlinks: bool,
+) -> Result<Option<Sha>> {
+    let sha = match name.split_once(':') {
+        Some((rev, path)) => rev_parse(store, refs, rev)
+            .and_then(|sha| peel_tree(store, &sha))
+            .and_then(|tree| find_path(store, &tree, path, follow_symlinks))
+            .map(|(_, sha)| sha),
+        None => rev_parse(store, refs, name),
+    };
+    let Ok(sha) = sha else {
+        return Ok(None);
+    };
+    Ok(store.contains(&sha)?.then_some(sha))
+}
+
+// a log filter pattern, a regular expression where `^` and `$` match at every line
+fn parse_log_pattern(pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .multi_line(true)
+        .build()
+        .with_context(|| format!("invalid pattern {pattern:?}"))
+}
+
+// the `--cacheinfo` arguments, given as one comma-separated argument or as three
+fn cacheinfo_args<'a, I: Iterator<Item = &'a String>>(
+    args: &mut I,
+) -> Option<(String, String, String)> {
+    let first = args.next()?;
+    if let Some((mode, rest)) = first.split_once(',') {
+        let (sha, name) = rest.split_once(',')?;
+        return Some((mode.to_owned(), sha.to_owned(), name.to_owned()));
+    }
+    Some((first.clone(), args.next()?.clone(), args.next()?.clone()))
+}
+
+// git refuses index paths that could escape the working tree or reach into `.git`
+fn verify_index_path(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name
+            .split('/')
+            .any(|component| matches!(component, "" | "." | ".." | ".git"))
+    {
+        bail!("invalid path '{name}'");
+    }
+    Ok(())
+}
+
+// the value of `--max-delta-depth=`, overriding `core.maxDeltaDepth`
+fn parse_max_delta_depth(depth: &str) -> Result<usize> {
+    depth
+        .parse()
+        .with_context(|| format!("invalid --max-delta-depth {depth:?}"))
+}
+
+// the value of `-M`, either a percentage like `60%` or digits read as a fraction like git does,
+// so `-M6` means 60% too. plain `-M` uses git's default of 50%
+fn parse_rename_threshold(value: &str) -> Result<u8> {
+    if value.is_empty() {
+        return Ok(DEFAULT_RENAME_THRESHOLD);
+    }
+    let percentage = match value.strip_suffix('%') {
+        Some(percentage) => percentage
+            .parse::<u8>()
+            .ok()
+            .filter(|percentage| *percentage <= 100),
+        None => format!("0.{value}")
+            .parse::<f64>()
+            .ok()
+            .filter(|_| value.bytes().all(|b| b.is_ascii_digit()))
+            .map(|fraction| (fraction * 100.0) as u8),
+    };
+    percentage.ok_or_else(|| anyhow!("invalid rename threshold {value:?}"))
+}
+
+// a change as `--name-status` lists it: its status letter, with the similarity for a rename, and
+// its paths
+fn name_status_line(change: &TreeChange) -> String {
+    match change {
+        TreeChange::Added { name, .. } => format!("A\t{name}"),
+        TreeChange::Deleted { name, .. } => format!("D\t{name}"),
+        TreeChange::Modified { name, .. } => format!("M\t{name}"),
+        TreeChange::Renamed {
+            from,
+            to,
+            similarity,
+            ..
+        } => format!("R{similarity:03}\t{from}\t{to}"),
+        TreeChange::Copied {
+            from,
+            to,
+            similarity,
+            ..
+        } => format!("C{similarity:03}\t{from}\t{to}"),
+    }
+}
+
+// an --abbrev length, raised to the shortest one git allows
+fn parse_abbrev(value: &str) -> Result<usize> {
+    Ok(parse_with_context::<usize>(value)?.max(MIN_ABBREV))
+}
+
+// a for-each-ref pattern is either a leading part of the ref name ending at a `/`, or a glob
+fn ref_pattern_matches(pattern: &str, name: &str) -> bool {
+    name.strip_prefix(pattern)
+        .is_some_and(|rest| rest.is_empty() || pattern.ends_with('/') || rest.starts_with('/'))
+        || wildmatch(pattern.as_bytes(), name.as_bytes())
+}
+
+// ` (HEAD -> main, tag: v1.0, origin/main)` for the refs pointing at `sha`: HEAD and the branch
+// it's on first, then the other refs in reverse refname order like git, or nothing without refs
+fn decorations(store: &ObjectStore, refs: &RefStore, sha: &Sha) -> Result<String> {
+    let names = refs_pointing_at(store, refs, sha)?;
+    let mut decorations = vec![];
+
+    // the branch HEAD is on shows up as part of HEAD's decoration instead of on its own
+    let mut head_branch = None;
+    if refs.head()?.as_ref() == Some(sha) {
+        head_branch = refs
+            .symbolic_target("HEAD")?
+            .filter(|branch| names.contains(branch));
+        match &head_branch {
+            Some(branch) => decorations.push(format!(
+                "HEAD -> {}",
+                branch.trim_start_matches("refs/heads/")
+            )),
+            None => decorations.push("HEAD".to_owned()),
         }
-        command => println!("unknown command: {}", command),
     }
 
+    for name in names.iter().rev() {
+        if head_branch.as_ref() == Some(name) {
+            continue;
+        }
+        decorations.push(if let Some(tag) = name.strip_prefix("refs/tags/") {
+            format!("tag: {tag}")
+        } else if let Some(short) = name
+            .strip_prefix("refs/heads/")
+            .or_else(|| name.strip_prefix("refs/remotes/"))
+        {
+            short.to_owned()
+        } else {
+            name.to_owned()
+        });
+    }
+
+    Ok(match decorations.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", decorations.join(", ")),
+    })
+}
+
+// a for-each-ref format with its `%(field)` placeholders filled in for one ref. `%%` is a
+// literal percent sign and `%xx` the byte with that hex code
+fn expand_ref_format(format: &str, name: &str, sha: &Sha, store: &ObjectStore) -> Result<String> {
+    let mut output = vec![];
+    let mut rest = format;
+    while let Some(start) = rest.find('%') {
+        output.extend(&rest.as_bytes()[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('%') {
+            output.push(b'%');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('(') {
+            let end = after
+                .find(')')
+                .ok_or_else(|| anyhow!("for-each-ref: unterminated field in {format:?}"))?;
+            let value = match &after[..end] {
+                "refname" => name.to_owned(),
+                "objectname" => sha.to_string(),
+                "objectname:short" => store.find_unique_abbrev(sha, DEFAULT_ABBREV)?,
+                "objecttype" => store
+                    .read_type(sha)
+                    .with_context(|| format!("failed to read object type of {name}"))?,
+                field => bail!("for-each-ref: unknown field name {field:?}"),
+            };
+            output.extend(value.into_bytes());
+            rest = &after[end + 1..];
+        } else if let Some(byte) = rest
+            .get(..2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            output.push(byte);
+            rest = &rest[2..];
+        } else {
+            output.push(b'%');
+        }
+    }
+    output.extend(rest.as_bytes());
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+// how `branch` compares to its upstream, the `branch.<name>.merge` branch of
+// `branch.<name>.remote`, or None when it has none
+fn tracking_status(
+    store: &ObjectStore,
+    refs: &RefStore,
+    config: &Config,
+    branch: &str,
+) -> Result<Option<String>> {
+    let (Some(remote), Some(merge)) = (
+        config.get(&format!("branch.{branch}.remote")),
+        config.get(&format!("branch.{branch}.merge")),
+    ) else {
+        return Ok(None);
+    };
+    // `.` stands for the repository itself, the upstream is then a local branch
+    let (upstream_ref, upstream) = match remote {
+        "." => (
+            merge.to_owned(),
+            merge.trim_start_matches("refs/heads/").to_owned(),
+        ),
+        remote => {
+            let name = format!("{remote}/{}", merge.trim_start_matches("refs/heads/"));
+            (format!("refs/remotes/{name}"), name)
+        }
+    };
+
+    let Some(upstream_sha) = refs.resolve(&upstream_ref)? else {
+        return Ok(Some(format!(
+            "Your branch is based on '{upstream}', but the upstream is gone."
+        )));
+    };
+    let Some(head) = refs.resolve(&format!("refs/heads/{branch}"))? else {
+        return Ok(None);
+    };
+    // `rev-list <upstream>..<branch>` and the other way around
+    let ahead = reachable_commit_nodes(
+        store,
+        std::slice::from_ref(&head),
+        std::slice::from_ref(&upstream_sha),
+    )?
+    .len();
+    let behind = reachable_commit_nodes(
+        store,
+        std::slice::from_ref(&upstream_sha),
+        std::slice::from_ref(&head),
+    )?
+    .len();
+    let commits = |count: usize| match count {
+        1 => "1 commit".to_owned(),
+        count => format!("{count} commits"),
+    };
+
+    Ok(Some(match (ahead, behind) {
+        (0, 0) => format!("Your branch is up to date with '{upstream}'."),
+        (ahead, 0) => format!(
+            "Your branch is ahead of '{upstream}' by {}.",
+            commits(ahead)
+        ),
+        (0, behind) => format!(
+            "Your branch is behind '{upstream}' by {}, and can be fast-forwarded.",
+            commits(behind)
+        ),
+        (ahead, behind) => format!(
+            "Your branch and '{upstream}' have diverged,\n\
+             and have {ahead} and {behind} different commits each, respectively."
+        ),
+    }))
+}
+
+// the paths whose entry isn't the same in `from` and `to`
+fn changed_paths<'a>(
+    from: &'a BTreeMap<String, (FileMode, Sha)>,
+    to: &'a BTreeMap<String, (FileMode, Sha)>,
+) -> BTreeSet<&'a String> {
+    from.keys()
+        .chain(to.keys())
+        .filter(|name| {
+            from.get(*name).map(|(mode, sha)| (mode.as_ref(), sha))
+                != to.get(*name).map(|(mode, sha)| (mode.as_ref(), sha))
+        })
+        .collect()
+}
+
+// the paths among `changed` that differ from `head_entries` in the index or the working tree,
+// changes that moving the working tree away from HEAD would lose
+fn local_changes(
+    index: &Index,
+    head_entries: &BTreeMap<String, (FileMode, Sha)>,
+    changed: &BTreeSet<&String>,
+) -> Result<BTreeSet<String>> {
+    let index_entries = index.entries_at_stage(0);
+    let staged = changed.iter().filter(|name| {
+        index_entries
+            .get(name.as_str())
+            .map(|entry| (entry.mode, &entry.sha))
+            != head_entries
+                .get(**name)
+                .map(|(mode, sha)| (mode.mode(), sha))
+    });
+    Ok(
+        dirty_paths(".", index, head_entries, changed.iter().copied())?
+            .into_iter()
+            .chain(staged.map(|name| name.to_string()))
+            .collect(),
+    )
+}
+
+// records the `entries` version of every changed path in the index
+fn stage_checkout(
+    index: &mut Index,
+    entries: &BTreeMap<String, (FileMode, Sha)>,
+    changed: &BTreeSet<&String>,
+) {
+    for name in changed {
+        match entries.get(*name) {
+            // the checked out file's stat data spares hashing it in later checks
+            Some((mode, sha)) => index.insert(match fs::symlink_metadata(name) {
+                Ok(metadata) => IndexEntry::from_metadata(name, sha.clone(), &metadata),
+                Err(_) => IndexEntry::new(name, mode.mode(), sha.clone()),
+            }),
+            None => {
+                index.remove(name);
+            }
+        }
+    }
+}
+
+// whether `name` is the path itself or lies below it, `.` standing for everything
+fn pathspec_matches(path: &str, name: &str) -> bool {
+    path == "."
+        || name == path
+        || name
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+// every file below `directory` (`""` for the root) that the index doesn't track
+fn untracked_files(
+    index: &Index,
+    ignore: Option<&IgnoreMatcher>,
+    directory: &str,
+    untracked: &mut Vec<String>,
+) -> Result<()> {
+    let entries = fs::read_dir(if directory.is_empty() { "." } else { directory })
+        .with_context(|| format!("failed to read directory {directory:?}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to read entry in {directory:?}"))?;
+
+    for entry in entries {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if file_name == ".git" {
+            continue;
+        }
+        let name = if directory.is_empty() {
+            file_name
+        } else {
+            format!("{directory}/{file_name}")
+        };
+        let is_dir = entry.file_type()?.is_dir();
+
+        if let Some(ignore) = ignore {
+            if ignore.is_ignored(&name, is_dir)? {
+                continue;
+            }
+        }
+        if is_dir {
+            untracked_files(index, ignore, &name, untracked)?;
+        } else if !index.contains(&name) {
+            untracked.push(name);
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::git_blob::EMPTY_BLOB_SHA;
+    use std::sync::Mutex;
+
+    // commands work on the current directory, which every test shares
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+    fn git(args: &[&str]) -> Result<String> {
+        let args = std::iter::once("git")
+            .chain(args.iter().copied())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let mut output = vec![];
+        tokio::runtime::Runtime::new()?.block_on(run(&args, &mut output))?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    fn commit_file(name: &str, content: &str, message: &str) -> Result<Sha> {
+        fs::write(name, content)?;
+        git(&["update-index", "--add", name])?;
+        git(&["commit", "-m", message])?;
+        head()
+    }
+
+    fn head() -> Result<Sha> {
+        RefStore::new(".")
+            .head()?
+            .ok_or_else(|| anyhow!("HEAD doesn't point at a commit"))
+    }
+
+    fn head_branch() -> Result<Option<String>> {
+        RefStore::new(".").symbolic_target("HEAD")
+    }
+
+    fn branch(name: &str) -> Result<Sha> {
+        RefStore::new(".")
+            .resolve(&format!("refs/heads/{name}"))?
+            .ok_or_else(|| anyhow!("no branch {name}"))
+    }
+
+    // runs `test` in a new repository whose main branch has one commit, holding on to the
+    // current directory until it's done
+    fn in_repository(test: impl FnOnce() -> Result<()>) -> Result<()> {
+        let _current_dir = CURRENT_DIR.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = tempfile::tempdir()?;
+        env::set_current_dir(dir.path())?;
+        git(&["init", "-b", "main"])?;
+        commit_file("a", "one\n", "first")?;
+        test()
+    }
+
+    #[test]
+    fn checkout_of_a_commit_detaches_head() -> Result<()> {
+        in_repository(|| {
+            let first = head()?;
+            commit_file("a", "two\n", "second")?;
+
+            let output = git(&["checkout", &first.to_string()])?;
+            assert!(output.starts_with("HEAD is now at "), "{output}");
+            assert!(output.ends_with(" first\n"), "{output}");
+            assert_eq!(head_branch()?, None);
+            assert_eq!(head()?, first);
+            assert_eq!(fs::read_to_string("a")?, "one\n");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn commit_while_detached_moves_head_and_no_branch() -> Result<()> {
+        in_repository(|| {
+            let main = branch("main")?;
+            git(&["checkout", "--detach", "main"])?;
+
+            let output = git(&["commit", "-m", "nothing"]);
+            assert!(output.is_err(), "an unchanged tree was committed");
+            fs::write("b", "detached\n")?;
+            git(&["update-index", "--add", "b"])?;
+            let output = git(&["commit", "-m", "on top"])?;
+            assert!(output.starts_with("[detached HEAD "), "{output}");
+
+            let commit = head()?;
+            assert_ne!(commit, main);
+            assert_eq!(head_branch()?, None);
+            assert_eq!(branch("main")?, main);
+            assert_eq!(
+                ObjectStore::new(".").read_commit(&commit)?.parent_hash,
+                [main]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn checkout_detach_of_a_branch_leaves_the_branch() -> Result<()> {
+        in_repository(|| {
+            let output = git(&["checkout", "--detach", "main"])?;
+            assert!(output.starts_with("HEAD is now at "), "{output}");
+            assert_eq!(head_branch()?, None);
+            assert_eq!(head()?, branch("main")?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn checkout_of_a_branch_reattaches_head() -> Result<()> {
+        in_repository(|| {
+            let first = head()?;
+            let main = commit_file("a", "two\n", "second")?;
+            git(&["checkout", &first.to_string()])?;
+            let detached = commit_file("c", "detached\n", "detached work")?;
+
+            let output = git(&["checkout", "main"])?;
+            assert_eq!(output, "Switched to branch 'main'\n");
+            assert_eq!(head_branch()?.as_deref(), Some("refs/heads/main"));
+            assert_eq!(head()?, main);
+            assert_ne!(head()?, detached);
+            assert_eq!(fs::read_to_string("a")?, "two\n");
+            assert!(!Path::new("c").exists());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ref_format_fields() -> Result<()> {
+        in_repository(|| {
+            let (store, sha) = (ObjectStore::new("."), head()?);
+            let expand = |format: &str| expand_ref_format(format, "refs/heads/main", &sha, &store);
+
+            assert_eq!(expand("%(refname)")?, "refs/heads/main");
+            assert_eq!(expand("%(objectname)")?, sha.to_string());
+            assert_eq!(expand("%(objectname:short)")?, sha.to_string()[..7]);
+            assert_eq!(
+                expand("%(objecttype) %(refname)")?,
+                "commit refs/heads/main"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ref_format_escapes() -> Result<()> {
+        in_repository(|| {
+            let (store, sha) = (ObjectStore::new("."), head()?);
+            let expand = |format: &str| expand_ref_format(format, "refs/heads/main", &sha, &store);
+
+            assert_eq!(expand("100%% %(refname)")?, "100% refs/heads/main");
+            assert_eq!(
+                expand("%(refname)%09%(objecttype)%0a")?,
+                "refs/heads/main\tcommit\n"
+            );
+            // not followed by hex digits, the percent sign stays as it is
+            assert_eq!(expand("50% off%z")?, "50% off%z");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ref_format_rejects_unknown_and_unterminated_fields() -> Result<()> {
+        in_repository(|| {
+            let (store, sha) = (ObjectStore::new("."), head()?);
+            let expand = |format: &str| expand_ref_format(format, "refs/heads/main", &sha, &store);
+
+            let err = expand("%(refname) %(upstream)").unwrap_err();
+            assert!(err.to_string().contains("unknown field name"), "{err}");
+            let err = expand("%(refname").unwrap_err();
+            assert!(err.to_string().contains("unterminated field"), "{err}");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn index_pack_of_a_file_that_is_not_a_pack_is_an_error() -> Result<()> {
+        in_repository(|| {
+            fs::write("garbage.pack", "garbage\n")?;
+            let err = git(&["index-pack", "garbage.pack"]).unwrap_err();
+            assert!(format!("{err:#}").contains("should start with"), "{err:#}");
+            assert!(!Path::new("garbage.idx").exists());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn prune_skips_submodule_commits() -> Result<()> {
+        in_repository(|| {
+            let submodule = "1111111111111111111111111111111111111111";
+            git(&[
+                "update-index",
+                "--add",
+                "--cacheinfo",
+                &format!("160000,{submodule},sub"),
+            ])?;
+            git(&["commit", "-m", "add a submodule"])?;
+            fs::write("loose", "unreachable\n")?;
+            let sha = git(&["hash-object", "-w", "loose"])?;
+            let sha = sha.trim();
+
+            assert_eq!(
+                git(&["prune", "-n", "--expire=now"])?,
+                format!("{sha} would be pruned\n")
+            );
+            assert_eq!(git(&["prune", "--expire=now"])?, format!("{sha} pruned\n"));
+            assert!(!ObjectStore::new(".").contains(&sha.parse()?)?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn hash_object_of_a_file_matches_git() -> Result<()> {
+        in_repository(|| {
+            let one = "5626abf0f72e58d7a153368ba57db4c673c0e171\n";
+            assert_eq!(git(&["hash-object", "a"])?, one);
+            assert_eq!(git(&["hash-object", "--literally", "a"])?, one);
+            assert_eq!(git(&["hash-object", "-w", "a"])?, one);
+            assert!(ObjectStore::new(".").contains(&one.trim().parse()?)?);
+            Ok(())
+        })
+    }
+
+    // a clean filter `upper` that upper-cases content, for the paths `attributes` gives it to
+    fn upper_case_filter(attributes: &str) -> Result<()> {
+        fs::write(".gitattributes", attributes)?;
+        let mut config = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(".git/config")?;
+        config.write_all(b"[filter \"upper\"]\n\tclean = tr a-z A-Z\n")?;
+        Ok(())
+    }
+
+    #[test]
+    fn hash_object_applies_the_clean_filter() -> Result<()> {
+        in_repository(|| {
+            upper_case_filter("a filter=upper\n")?;
+
+            let upper = "a2628c1e0953c4bbb3f2195093dab29f1f7ee77e\n";
+            assert_eq!(git(&["hash-object", "a"])?, upper);
+            assert_eq!(
+                git(&["hash-object", "--no-filters", "a"])?,
+                "5626abf0f72e58d7a153368ba57db4c673c0e171\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn write_tree_applies_the_clean_filter() -> Result<()> {
+        in_repository(|| {
+            upper_case_filter("sub/*.txt filter=upper\n")?;
+            fs::create_dir("sub")?;
+            fs::write("sub/b.txt", "two\n")?;
+            let store = ObjectStore::new(".");
+            let entry = |tree: &str, name: &str| -> Result<Sha> {
+                let tree = store.read(&tree.trim().parse()?)?.try_as_tree().unwrap();
+                let entry = tree.entries().iter().find(|entry| entry.name == name);
+                Ok(entry.expect("the entry is in the tree").hash.clone())
+            };
+
+            let root = git(&["write-tree"])?;
+            let sub = entry(&root, "sub")?;
+            assert_eq!(
+                entry(&sub.to_string(), "b.txt")?,
+                Blob::new(b"TWO\n".to_vec()).sha1()?
+            );
+            assert_eq!(entry(&root, "a")?, Blob::new(b"one\n".to_vec()).sha1()?);
+
+            // the filter goes by the path below the work tree, not below the prefix
+            assert_eq!(git(&["write-tree", "--prefix=sub"])?, format!("{sub}\n"));
+            assert_eq!(git(&["write-tree", "--dry-run"])?, root);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn write_tree_prefix_stays_inside_the_work_tree() -> Result<()> {
+        in_repository(|| {
+            fs::create_dir("sub")?;
+            fs::write("sub/b", "")?;
+            let sub = git(&["write-tree", "--prefix=sub"])?;
+            assert_eq!(git(&["write-tree", "--prefix=sub/"])?, sub);
+
+            let outside = env::temp_dir();
+            for prefix in [
+                "..",
+                "sub/..",
+                "sub/../..",
+                ".git",
+                outside.to_str().unwrap(),
+            ] {
+                assert!(git(&["write-tree", &format!("--prefix={prefix}")]).is_err());
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn clean_reads_combined_and_long_flags() -> Result<()> {
+        in_repository(|| {
+            fs::write("b", "")?;
+            fs::create_dir("d")?;
+            fs::write("d/c", "")?;
+
+            assert!(git(&["clean"]).is_err());
+            assert!(git(&["clean", "-nq"]).is_err());
+            assert!(git(&["clean", "-n", "d"]).is_err());
+            assert_eq!(git(&["clean", "-n"])?, "Would remove b\n");
+            let dry_run = "Would remove b\nWould remove d/\n";
+            assert_eq!(git(&["clean", "-nd"])?, dry_run);
+            assert_eq!(git(&["clean", "--dry-run", "-d"])?, dry_run);
+
+            assert_eq!(
+                git(&["clean", "--force", "-d"])?,
+                "Removing b\nRemoving d/\n"
+            );
+            assert!(!Path::new("b").exists() && !Path::new("d").exists());
+            assert!(Path::new("a").is_file());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn status_colors_each_section() -> Result<()> {
+        in_repository(|| {
+            fs::write("new", "")?;
+            git(&["update-index", "--add", "new"])?;
+            fs::write("a", "two\n")?;
+            fs::write("u", "")?;
+
+            let status = git(&["status", "--color=always"])?;
+            let sections = status.split("\n\n").skip(1).collect::<Vec<_>>();
+            assert_eq!(
+                sections,
+                [
+                    "Changes to be committed:\n\t\x1b[32mnew file:   new\x1b[m",
+                    "Changes not staged for commit:\n\t\x1b[31mmodified:   a\x1b[m",
+                    "Untracked files:\n\t\x1b[31mu\x1b[m\n",
+                ]
+            );
+            assert!(!git(&["status"])?.contains('\x1b'));
+            assert!(!git(&["status", "--color=always", "--color=never"])?.contains('\x1b'));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn status_labels_unmerged_paths_by_their_stages() -> Result<()> {
+        in_repository(|| {
+            fs::write("new", "")?;
+            git(&["update-index", "--add", "new"])?;
+            fs::write("u", "")?;
+            let mut index = Index::read(".")?;
+            for (name, stages) in [
+                ("m", &[1, 2, 3][..]),
+                ("t", &[1, 2]),
+                ("b", &[2, 3]),
+                ("h", &[3]),
+            ] {
+                for stage in stages {
+                    index
+                        .entries
+                        .push(IndexEntry::new(name, 0o100644, EMPTY_BLOB_SHA).with_stage(*stage));
+                }
+            }
+            index.write(".")?;
+
+            let status = git(&["status", "--color=always"])?;
+            let sections = status.split("\n\n").skip(1).collect::<Vec<_>>();
+            assert_eq!(
+                sections,
+                [
+                    "Changes to be committed:\n\t\x1b[32mnew file:   new\x1b[m",
+                    "Unmerged paths:\n\
+                     \t\x1b[31mboth added:      b\x1b[m\n\
+                     \t\x1b[31madded by them:   h\x1b[m\n\
+                     \t\x1b[31mboth modified:   m\x1b[m\n\
+                     \t\x1b[31mdeleted by them: t\x1b[m",
+                    "Untracked files:\n\t\x1b[31mu\x1b[m\n",
+                ]
+            );
+            assert!(!git(&["status"])?.contains('\x1b'));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rev_list_symmetric_difference_lists_both_sides() -> Result<()> {
+        in_repository(|| {
+            let first = head()?;
+            let second = commit_file("a", "two\n", "second")?;
+            git(&["checkout", &first.to_string()])?;
+            let side = commit_file("b", "", "side")?;
+
+            let mut listed = git(&["rev-list", "main...HEAD"])?
+                .lines()
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            listed.sort();
+            let mut expected = vec![second.to_string(), side.to_string()];
+            expected.sort();
+            assert_eq!(listed, expected);
+            assert_eq!(git(&["rev-list", "--count", "HEAD...main"])?, "2\n");
+            assert_eq!(git(&["rev-list", "main..HEAD"])?, format!("{side}\n"));
+            Ok(())
+        })
+    }
+}
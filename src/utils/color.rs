@@ -0,0 +1,62 @@
+use anyhow::{bail, Result};
+use std::{
+    env,
+    io::{stdout, IsTerminal},
+};
+
+const RESET: &str = "\x1b[m";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Bold,
+    Red,
+    Green,
+    Cyan,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Self::Bold => "\x1b[1m",
+            Self::Red => "\x1b[31m",
+            Self::Green => "\x1b[32m",
+            Self::Cyan => "\x1b[36m",
+        }
+    }
+}
+
+// whether output gets colored, decided once per command from `--color=<when>`
+#[derive(Debug, Clone, Copy)]
+pub struct Colors {
+    enabled: bool,
+}
+
+impl Colors {
+    // `auto` (the default) colors only a terminal and backs off when NO_COLOR is set, an explicit
+    // `always` wins over both: https://no-color.org
+    pub fn new(when: Option<&str>) -> Result<Self> {
+        let enabled = match when.unwrap_or("auto") {
+            "always" => true,
+            "never" => false,
+            "auto" => {
+                !matches!(env::var_os("NO_COLOR"), Some(value) if !value.is_empty())
+                    && stdout().is_terminal()
+            }
+            other => bail!("invalid --color value {other:?}, expected always, never or auto"),
+        };
+        Ok(Self { enabled })
+    }
+
+    // wraps `text` in the color's escape codes, a trailing newline stays outside of them like git
+    // does so pagers don't carry the color over to the next line
+    pub fn paint(&self, color: Color, text: &[u8]) -> Vec<u8> {
+        if !self.enabled || text.is_empty() {
+            return text.to_vec();
+        }
+        let (text, newline) = match text.strip_suffix(b"\n") {
+            Some(text) => (text, &b"\n"[..]),
+            None => (text, &b""[..]),
+        };
+        [color.code().as_bytes(), text, RESET.as_bytes(), newline].concat()
+    }
+}
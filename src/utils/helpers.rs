@@ -1,13 +1,64 @@
 use anyhow::{anyhow, Context, Result};
 use std::{
+    env, fs,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
+// the git directory of the repository whose work tree is at `path`, or `path` itself for a bare
+// repository. GIT_DIR (which --git-dir sets as well) overrides it for the whole process, like in
+// git
+pub fn git_dir<P: AsRef<Path>>(path: P) -> PathBuf {
+    if let Some(git_dir) = env::var_os("GIT_DIR").filter(|git_dir| !git_dir.is_empty()) {
+        return PathBuf::from(git_dir);
+    }
+
+    let path = path.as_ref();
+    let dot_git = path.join(".git");
+    if dot_git.is_file() {
+        if let Some(git_dir) = read_gitfile(&dot_git) {
+            return path.join(git_dir);
+        }
+    }
+    if !dot_git.exists() && is_git_dir(path) {
+        return path.to_path_buf();
+    }
+    dot_git
+}
+
+// the directory a `.git` file points at with its `gitdir: <path>` line, the way linked
+// worktrees and submodules refer to their git directory. a relative path is relative to the
+// directory holding the file
+fn read_gitfile(path: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(path).ok()?;
+    let git_dir = content
+        .strip_prefix("gitdir:")?
+        .trim_end_matches(['\n', '\r'])
+        .trim_start();
+    (!git_dir.is_empty()).then(|| PathBuf::from(git_dir))
+}
+
+// the layout git itself checks for before treating a directory as a repository
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+// where the state shared by all worktrees of a repository lives, objects and most refs among
+// it: GIT_COMMON_DIR, the directory named by the git directory's `commondir` file (relative to
+// the git directory), or the git directory itself
+pub fn common_dir<P: AsRef<Path>>(path: P) -> PathBuf {
+    if let Some(common_dir) = env::var_os("GIT_COMMON_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(common_dir);
+    }
+    let git_dir = git_dir(path);
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim_end_matches(['\n', '\r'])),
+        Err(_) => git_dir,
+    }
+}
+
 pub fn get_object_folder_path<P: AsRef<Path>>(sha1: &str, path: P) -> PathBuf {
-    path.as_ref()
-        .to_path_buf()
-        .join(format!(".git/objects/{}", &sha1[..2]))
+    common_dir(path).join("objects").join(&sha1[..2])
 }
 
 pub fn get_object_file_path<P: AsRef<Path>>(sha1: &str, path: P) -> PathBuf {
@@ -43,13 +94,6 @@ where
         .with_context(|| format!("failed to parse {:?}", input))
 }
 
-pub fn parse_bytes_with_context<Output: FromStr>(input: Vec<u8>) -> Result<Output>
-where
-    Output::Err: std::error::Error + Send + Sync + 'static,
-{
-    from_utf8_with_context(input).and_then(|str| parse_with_context(&str))
-}
-
 // pub fn into_single_bytes(value: [u32; 5]) -> Result<[u8; 20]> {
 //     Ok(value
 //         .into_iter()
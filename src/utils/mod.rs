@@ -1 +1,3 @@
+pub mod color;
 pub mod helpers;
+pub mod pager;
@@ -0,0 +1,55 @@
+use anyhow::{Context, Result};
+use std::{
+    env,
+    io::{stdout, IsTerminal},
+    process::{Child, ChildStdin, Command, Stdio},
+};
+
+const DEFAULT_PAGER: &str = "less -FRX";
+
+// a pager process reading the command's output, like git runs for long output
+pub struct Pager {
+    child: Child,
+}
+
+impl Pager {
+    // nothing gets paged unless stdout is a terminal, and a pager set to `cat` or to nothing
+    // turns paging off like in git
+    pub fn start() -> Result<Option<Self>> {
+        if !stdout().is_terminal() {
+            return Ok(None);
+        }
+        let command = env::var("GIT_PAGER")
+            .or_else(|_| env::var("PAGER"))
+            .unwrap_or_else(|_| DEFAULT_PAGER.to_owned());
+        if matches!(command.trim(), "" | "cat") {
+            return Ok(None);
+        }
+
+        // the pager is run by the shell, so it can carry its own arguments
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Pager::start: failed to run pager {command:?}"))?;
+        Ok(Some(Self { child }))
+    }
+
+    pub fn stdin(&mut self) -> &mut ChildStdin {
+        self.child
+            .stdin
+            .as_mut()
+            .expect("the pager's stdin is piped until it's finished")
+    }
+
+    // closes the pager's input so it sees the end of the output, then waits for the user to
+    // quit it
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.child.stdin.take());
+        self.child
+            .wait()
+            .with_context(|| "Pager::finish: failed to wait for the pager")?;
+        Ok(())
+    }
+}